@@ -0,0 +1,69 @@
+//! Compares the payload size and encode/decode speed of each codec on image messages.
+//!
+//! Run with `cargo bench --bench codec`. Encoded sizes are printed before the timings.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hw11_rust_metrics::{codec, MessageType};
+
+// A real screenshot, plus a larger synthetic image to show how each codec scales
+const TEST_IMAGE: &[u8] = include_bytes!("../test.png");
+const LARGE_IMAGE_BYTES: usize = 1024 * 1024;
+
+fn image_payloads() -> Vec<(&'static str, MessageType)> {
+    let large = (0..LARGE_IMAGE_BYTES)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect();
+
+    vec![
+        (
+            "test.png",
+            MessageType::Image(Some("Alice".to_string()), TEST_IMAGE.to_vec()),
+        ),
+        ("1MiB", MessageType::Image(Some("Alice".to_string()), large)),
+    ]
+}
+
+fn bench_codecs(c: &mut Criterion) {
+    let payloads = image_payloads();
+
+    for (label, message) in &payloads {
+        for name in codec::SUPPORTED_CODECS {
+            let size = codec::by_name(name).unwrap().encode(message).unwrap().len();
+            println!("{:>8} {:<8} {:>9} bytes", label, name, size);
+        }
+    }
+
+    let mut encode = c.benchmark_group("encode_image");
+    for (label, message) in &payloads {
+        let MessageType::Image(_, ref data) = message else {
+            unreachable!()
+        };
+        encode.throughput(Throughput::Bytes(data.len() as u64));
+        for name in codec::SUPPORTED_CODECS {
+            let codec = codec::by_name(name).unwrap();
+            encode.bench_with_input(BenchmarkId::new(*name, label), message, |b, message| {
+                b.iter(|| codec.encode(black_box(message)).unwrap())
+            });
+        }
+    }
+    encode.finish();
+
+    let mut decode = c.benchmark_group("decode_image");
+    for (label, message) in &payloads {
+        let MessageType::Image(_, ref data) = message else {
+            unreachable!()
+        };
+        decode.throughput(Throughput::Bytes(data.len() as u64));
+        for name in codec::SUPPORTED_CODECS {
+            let codec = codec::by_name(name).unwrap();
+            let encoded = codec.encode(message).unwrap();
+            decode.bench_with_input(BenchmarkId::new(*name, label), &encoded, |b, encoded| {
+                b.iter(|| codec.decode(black_box(encoded)).unwrap())
+            });
+        }
+    }
+    decode.finish();
+}
+
+criterion_group!(benches, bench_codecs);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+-- The messages table used to be created by the server itself, so make sure it exists before extending it
+CREATE TABLE IF NOT EXISTS messages
+(
+    id          INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    content     TEXT                              NOT NULL,
+    user_id     INTEGER                           NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+-- Messages stored before this migration have neither: kind is 'text', 'file' or 'image', and created_at is in
+-- milliseconds since the Unix epoch
+ALTER TABLE messages ADD COLUMN kind TEXT;
+ALTER TABLE messages ADD COLUMN created_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS messages_created_at ON messages (created_at);
+CREATE INDEX IF NOT EXISTS messages_user_id ON messages (user_id);
@@ -0,0 +1,7 @@
+-- Accounts registered before passwords existed have no hash, and can be claimed by setting one
+ALTER TABLE users ADD COLUMN password_hash TEXT;
+
+-- Consecutive failed logins, reset on success, and the time (in milliseconds since the Unix epoch) until which the
+-- account refuses logins after too many of them
+ALTER TABLE users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN locked_until INTEGER;
@@ -0,0 +1,22 @@
+-- Names used to be registered more than once. Fold every duplicate into a single account, preferring one with a
+-- password and then the oldest, and hand its messages over to that account
+CREATE TEMP TABLE merged_users AS
+SELECT duplicate.id AS id, (
+    SELECT kept.id FROM users AS kept
+    WHERE kept.name = duplicate.name
+    ORDER BY kept.password_hash IS NULL, kept.id
+    LIMIT 1
+) AS kept_id
+FROM users AS duplicate;
+
+DELETE FROM merged_users WHERE id = kept_id;
+
+UPDATE messages
+SET user_id = (SELECT kept_id FROM merged_users WHERE merged_users.id = messages.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+
+DELETE FROM users WHERE id IN (SELECT id FROM merged_users);
+
+DROP TABLE merged_users;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_name ON users (name);
@@ -0,0 +1,13 @@
+-- Tokens handed out after logging in, which log back in without a password until they expire (in milliseconds since
+-- the Unix epoch) or are revoked. Only their SHA-256 hash is stored.
+CREATE TABLE IF NOT EXISTS session_tokens
+(
+    id          INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id     INTEGER                           NOT NULL,
+    token_hash  TEXT                              NOT NULL UNIQUE,
+    created_at  INTEGER                           NOT NULL,
+    expires_at  INTEGER                           NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS session_tokens_user_id ON session_tokens (user_id);
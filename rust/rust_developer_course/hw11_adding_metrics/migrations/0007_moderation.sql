@@ -0,0 +1,38 @@
+-- 'user', 'moderator' or 'admin'; moderators and admins can kick, ban and mute those below them
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+
+-- A banned user has a row without an ip, and one for every address they were connected from when banned. Times are
+-- in milliseconds since the Unix epoch, and bans without expires_at last until lifted.
+CREATE TABLE IF NOT EXISTS bans
+(
+    id          INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id     INTEGER                           NOT NULL,
+    ip          TEXT,
+    banned_by   TEXT                              NOT NULL,
+    created_at  INTEGER                           NOT NULL,
+    expires_at  INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS bans_user_id ON bans (user_id);
+CREATE INDEX IF NOT EXISTS bans_ip ON bans (ip);
+
+CREATE TABLE IF NOT EXISTS mutes
+(
+    user_id     INTEGER PRIMARY KEY               NOT NULL,
+    muted_by    TEXT                              NOT NULL,
+    created_at  INTEGER                           NOT NULL,
+    expires_at  INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+-- Every moderation action and role change, by whom and to whom, by name since either may be renamed later
+CREATE TABLE IF NOT EXISTS audit_log
+(
+    id          INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    actor       TEXT                              NOT NULL,
+    action      TEXT                              NOT NULL,
+    target      TEXT                              NOT NULL,
+    detail      TEXT,
+    created_at  INTEGER                           NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Rooms where moderators have limited how often each user may speak. Rooms without a row have no slowmode.
+CREATE TABLE IF NOT EXISTS slowmode
+(
+    room          TEXT PRIMARY KEY                  NOT NULL,
+    interval_secs INTEGER                           NOT NULL,
+    set_by        TEXT                              NOT NULL,
+    set_at        INTEGER                           NOT NULL
+);
@@ -0,0 +1,26 @@
+-- Named rooms. Everyone is always in the lobby; other rooms are created by the first user to join them
+CREATE TABLE IF NOT EXISTS rooms
+(
+    name       TEXT PRIMARY KEY                  NOT NULL,
+    topic      TEXT,
+    created_by TEXT                              NOT NULL,
+    created_at INTEGER                           NOT NULL
+);
+
+INSERT OR IGNORE INTO rooms (name, created_by, created_at) VALUES ('lobby', 'server', 0);
+
+-- Rooms each user has joined, restored whenever they log in. The lobby isn't listed
+CREATE TABLE IF NOT EXISTS room_members
+(
+    user_id   INTEGER                           NOT NULL,
+    room      TEXT                              NOT NULL,
+    joined_at INTEGER                           NOT NULL,
+    PRIMARY KEY (user_id, room),
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    FOREIGN KEY (room) REFERENCES rooms (name)
+);
+
+-- Messages stored before rooms existed were all sent to the lobby
+ALTER TABLE messages ADD COLUMN room TEXT NOT NULL DEFAULT 'lobby';
+
+CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);
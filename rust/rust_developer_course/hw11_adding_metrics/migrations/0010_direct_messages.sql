@@ -0,0 +1,4 @@
+-- Messages sent directly to a single user. They are private, so are left out of the history everyone can read
+ALTER TABLE messages ADD COLUMN recipient_id INTEGER REFERENCES users (id);
+
+CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient_id, id);
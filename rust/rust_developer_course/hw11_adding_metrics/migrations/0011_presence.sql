@@ -0,0 +1,2 @@
+-- When each user was last connected, in ms since the Unix epoch. NULL until they first disconnect
+ALTER TABLE users ADD COLUMN last_seen INTEGER;
@@ -0,0 +1,3 @@
+-- Accounts created for the common name of a client certificate. They are only ever logged into with the certificate,
+-- so can't be registered or given a password by anyone else
+ALTER TABLE users ADD COLUMN certificate INTEGER NOT NULL DEFAULT 0;
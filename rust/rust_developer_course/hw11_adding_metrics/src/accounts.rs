@@ -0,0 +1,556 @@
+//! Password protected accounts.
+//!
+//! Peers that negotiate the accounts feature create an account with `CreateAccount` and log back into it with
+//! `Login`, both carrying a password. The server only stores an argon2id hash of it, and locks accounts for a while
+//! after too many wrong passwords in a row. A bare `Register` creates an account without a password, which whoever
+//! is logged into it can later protect with one; it never logs into an account that already exists. Accounts created
+//! for a client certificate are only ever logged into with that certificate.
+//!
+//! Names are unique, and peers that negotiate the rename feature can change theirs with `Rename` once logged in.
+
+use crate::{config::LockoutConfig, history, AppError, ErrorCode};
+use anyhow::{Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+
+/// Feature name advertised during the handshake by peers that log in with a password.
+pub const ACCOUNTS_FEATURE: &str = "accounts";
+
+/// Feature name advertised during the handshake by peers that can rename their account.
+pub const RENAME_FEATURE: &str = "rename";
+
+/// Account used by clients that haven't logged in, registered or presented a client certificate.
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+/// Shortest password accepted for a new account, in characters.
+pub const MIN_PASSWORD_CHARS: usize = 8;
+
+/// Represents a password on its way to the server, kept out of debug output so it can't end up in a log.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::accounts::Password;
+/// let password = Password::new("hunter22");
+/// assert_eq!(password.expose(), "hunter22");
+/// assert!(!format!("{:?}", password).contains("hunter22"));
+/// ```
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: impl Into<String>) -> Self {
+        Password(password.into())
+    }
+
+    /// Returns the password itself, for hashing or checking against a hash.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Password(<redacted>)")
+    }
+}
+
+/// Hashes a password with argon2id and a random salt, returning it in the PHC string format.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::accounts::{hash_password, verify_password, Password};
+/// let hash = hash_password(&Password::new("hunter22")).unwrap();
+/// assert!(hash.starts_with("$argon2id$"));
+/// assert!(verify_password(&Password::new("hunter22"), &hash));
+/// ```
+///
+/// # Errors
+/// This function returns an error if the password can't be hashed.
+pub fn hash_password(password: &Password) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.expose().as_bytes(), &salt)
+        .map_err(|e| AppError::Message(format!("Failed to hash password: {}", e)))?;
+    Ok(hash.to_string())
+}
+
+/// Returns true if `password` is the one `hash` was made from. Hashes that can't be parsed match nothing.
+pub fn verify_password(password: &Password, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.expose().as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Creates an account protected by `password`, returning its user id.
+///
+/// An account without a password, e.g. one registered before passwords existed, can only be given one by the client
+/// logged into it, passed as `holder`, which keeps its history.
+///
+/// # Example
+/// ```ignore
+/// let holder = (!session.is_anonymous()).then(|| session.user_id());
+/// let user_id = create_account(&db, "alice", &Password::new("hunter22"), holder).await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::WeakPassword` if the password is too short, or with
+/// `ErrorCode::NameTaken` if the name is reserved, belongs to another account or to a client certificate, or is
+/// already protected by a password. It returns other errors if the database can't be queried.
+pub async fn create_account(
+    db: &Pool<Sqlite>,
+    name: &str,
+    password: &Password,
+    holder: Option<i64>,
+) -> Result<i64> {
+    if name == ANONYMOUS_USER {
+        return Err(name_taken(name).into());
+    }
+    if password.expose().chars().count() < MIN_PASSWORD_CHARS {
+        return Err(AppError::Auth(
+            ErrorCode::WeakPassword,
+            format!("Passwords need at least {} characters", MIN_PASSWORD_CHARS),
+        )
+        .into());
+    }
+
+    let existing = sqlx::query("SELECT id, password_hash, certificate FROM users WHERE name = ?")
+        .bind(name)
+        .fetch_optional(db)
+        .await
+        .context("Failed to look up account")?;
+    if let Some(row) = &existing {
+        let claimable = holder == Some(row.get("id"))
+            && row.get::<Option<String>, _>("password_hash").is_none()
+            && !row.get::<bool, _>("certificate");
+        if !claimable {
+            return Err(name_taken(name).into());
+        }
+    }
+
+    // Hashing is deliberately slow, so keep it off the async workers
+    let to_hash = password.clone();
+    let hash = tokio::task::spawn_blocking(move || hash_password(&to_hash)).await??;
+
+    let user_id = match existing {
+        // Someone else may have claimed the account while the password was being hashed
+        Some(row) => {
+            let user_id: i64 = row.get("id");
+            let claimed = sqlx::query(
+                "UPDATE users SET password_hash = ? WHERE id = ? AND password_hash IS NULL AND NOT certificate",
+            )
+            .bind(&hash)
+            .bind(user_id)
+            .execute(db)
+            .await
+            .context("Failed to set the account's password")?
+            .rows_affected()
+                == 1;
+            if !claimed {
+                return Err(name_taken(name).into());
+            }
+            user_id
+        }
+        // Someone else may have taken the name while the password was being hashed
+        None => sqlx::query("INSERT INTO users (name, password_hash) VALUES (?, ?)")
+            .bind(name)
+            .bind(&hash)
+            .execute(db)
+            .await
+            .map_err(|e| unique_violation(e, name))?
+            .last_insert_rowid(),
+    };
+    log::debug!("Account {} created with user ID: {}", name, user_id);
+
+    Ok(user_id)
+}
+
+/// Checks `password` against the account called `name`, returning its user id if it matches.
+///
+/// Consecutive failures are counted, and once there have been `lockout.max_failures` of them the account refuses
+/// every login, right or wrong, for `lockout.duration`.
+///
+/// # Example
+/// ```ignore
+/// let user_id = login(&db, "alice", &Password::new("hunter22"), &config.lockout).await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::AuthFailed` if there is no such account or the password
+/// is wrong, and with `ErrorCode::LockedOut` if the account is locked. It returns other errors if the database can't
+/// be queried.
+pub async fn login(
+    db: &Pool<Sqlite>,
+    name: &str,
+    password: &Password,
+    lockout: &LockoutConfig,
+) -> Result<i64> {
+    // Don't give away which accounts exist
+    let refused = || {
+        AppError::Auth(
+            ErrorCode::AuthFailed,
+            "Wrong account name or password".to_string(),
+        )
+    };
+
+    let row = sqlx::query(
+        "SELECT id, password_hash, failed_logins, locked_until FROM users \
+         WHERE name = ? AND password_hash IS NOT NULL LIMIT 1",
+    )
+    .bind(name)
+    .fetch_optional(db)
+    .await
+    .context("Failed to look up account")?
+    .ok_or_else(refused)?;
+    let user_id: i64 = row.get("id");
+    let hash: String = row.get("password_hash");
+    let failed_logins: u32 = row.get("failed_logins");
+    let locked_until: Option<i64> = row.get("locked_until");
+
+    let now = history::now_millis();
+    if let Some(locked_until) = locked_until.filter(|until| *until > now) {
+        return Err(locked_out(locked_until - now).into());
+    }
+
+    let to_verify = password.clone();
+    if tokio::task::spawn_blocking(move || verify_password(&to_verify, &hash)).await? {
+        sqlx::query("UPDATE users SET failed_logins = 0, locked_until = NULL WHERE id = ?")
+            .bind(user_id)
+            .execute(db)
+            .await
+            .context("Failed to reset failed logins")?;
+        return Ok(user_id);
+    }
+
+    let failed_logins = failed_logins + 1;
+    if lockout.max_failures > 0 && failed_logins >= lockout.max_failures {
+        let lockout_ms = lockout.duration.as_millis() as i64;
+        sqlx::query("UPDATE users SET failed_logins = 0, locked_until = ? WHERE id = ?")
+            .bind(now + lockout_ms)
+            .bind(user_id)
+            .execute(db)
+            .await
+            .context("Failed to lock account")?;
+        log::warn!(
+            "Account {} locked after {} failed logins",
+            name,
+            failed_logins
+        );
+        return Err(locked_out(lockout_ms).into());
+    }
+
+    sqlx::query("UPDATE users SET failed_logins = ? WHERE id = ?")
+        .bind(failed_logins)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .context("Failed to count failed login")?;
+    Err(refused().into())
+}
+
+/// Creates an account without a password for a bare `Register`, returning its user id.
+///
+/// # Example
+/// ```ignore
+/// let user_id = register(&db, "alice").await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::NameTaken` if any account, including the anonymous user,
+/// is already called `name`. It returns other errors if the database can't be updated.
+pub async fn register(db: &Pool<Sqlite>, name: &str) -> Result<i64> {
+    let user_id = sqlx::query("INSERT INTO users (name) VALUES (?)")
+        .bind(name)
+        .execute(db)
+        .await
+        .map_err(|e| unique_violation(e, name))?
+        .last_insert_rowid();
+    log::debug!("Account {} registered with user ID: {}", name, user_id);
+
+    Ok(user_id)
+}
+
+/// Returns the id of the account for a client certificate whose subject's common name is `name`, creating it, marked
+/// as belonging to the certificate, the first time the certificate is presented.
+///
+/// # Example
+/// ```ignore
+/// let user_id = certificate_account(&db, &tls::subject_common_name(cert)?).await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::NameTaken` if the name is reserved or belongs to an account
+/// that wasn't created for a certificate. It returns other errors if the database can't be queried or updated.
+pub async fn certificate_account(db: &Pool<Sqlite>, name: &str) -> Result<i64> {
+    if name == ANONYMOUS_USER {
+        return Err(name_taken(name).into());
+    }
+
+    sqlx::query("INSERT OR IGNORE INTO users (name, certificate) VALUES (?, 1)")
+        .bind(name)
+        .execute(db)
+        .await
+        .context("Failed to create certificate account")?;
+    let row = sqlx::query("SELECT id, certificate FROM users WHERE name = ?")
+        .bind(name)
+        .fetch_one(db)
+        .await
+        .context("Failed to look up certificate account")?;
+    // A certificate never stands in for the password of an account someone registered
+    if !row.get::<bool, _>("certificate") {
+        return Err(name_taken(name).into());
+    }
+    Ok(row.get("id"))
+}
+
+/// Renames the account `user_id` to `new_name`, keeping its password and history.
+///
+/// # Example
+/// ```ignore
+/// rename(&db, session.user_id(), "alicia").await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::NameTaken` if another account already has the name or it
+/// is reserved. It returns other errors if the database can't be updated.
+pub async fn rename(db: &Pool<Sqlite>, user_id: i64, new_name: &str) -> Result<()> {
+    if new_name == ANONYMOUS_USER {
+        return Err(name_taken(new_name).into());
+    }
+
+    sqlx::query("UPDATE users SET name = ? WHERE id = ?")
+        .bind(new_name)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .map_err(|e| unique_violation(e, new_name))?;
+    log::debug!("User ID {} renamed to {}", user_id, new_name);
+
+    Ok(())
+}
+
+/// Builds the error reported when `name` belongs to another account.
+fn name_taken(name: &str) -> AppError {
+    AppError::Auth(
+        ErrorCode::NameTaken,
+        format!("The name '{}' is already taken", name),
+    )
+}
+
+/// Turns a write that broke the unique constraint on user names into `ErrorCode::NameTaken`.
+fn unique_violation(error: sqlx::Error, name: &str) -> anyhow::Error {
+    if error
+        .as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+    {
+        name_taken(name).into()
+    } else {
+        anyhow::Error::new(error).context("Failed to write account to the database")
+    }
+}
+
+/// Builds the error reported for a login to an account locked for another `remaining_ms`.
+fn locked_out(remaining_ms: i64) -> AppError {
+    AppError::Auth(
+        ErrorCode::LockedOut,
+        format!(
+            "Too many failed logins; try again in {} seconds",
+            (remaining_ms + 999) / 1000
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+    use std::time::Duration;
+
+    fn auth_error(result: Result<i64>) -> ErrorCode {
+        match result.unwrap_err().downcast::<AppError>() {
+            Ok(AppError::Auth(code, _)) => code,
+            other => panic!("Expected an authentication error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn accounts_are_created_once_and_logged_into() {
+        let db = test_db().await;
+        let password = Password::new("correct horse");
+        let lockout = LockoutConfig::default();
+
+        let user_id = create_account(&db, "alice", &password, None).await.unwrap();
+
+        assert_eq!(
+            login(&db, "alice", &password, &lockout).await.unwrap(),
+            user_id
+        );
+        assert_eq!(
+            auth_error(register(&db, "alice").await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(create_account(&db, "alice", &Password::new("battery staple"), None).await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(login(&db, "bob", &password, &lockout).await),
+            ErrorCode::AuthFailed
+        );
+        assert_eq!(
+            auth_error(create_account(&db, "bob", &Password::new("short"), None).await),
+            ErrorCode::WeakPassword
+        );
+    }
+
+    #[tokio::test]
+    async fn passwordless_accounts_are_claimed_by_their_holder_setting_a_password() {
+        let db = test_db().await;
+        let legacy_id = register(&db, "carol").await.unwrap();
+
+        let password = Password::new("correct horse");
+        assert_eq!(
+            auth_error(create_account(&db, "carol", &password, None).await),
+            ErrorCode::NameTaken
+        );
+        let user_id = create_account(&db, "carol", &password, Some(legacy_id))
+            .await
+            .unwrap();
+
+        assert_eq!(user_id, legacy_id);
+        assert_eq!(
+            login(&db, "carol", &password, &LockoutConfig::default())
+                .await
+                .unwrap(),
+            legacy_id
+        );
+    }
+
+    #[tokio::test]
+    async fn certificate_accounts_are_never_claimed() {
+        let db = test_db().await;
+        let bot = certificate_account(&db, "build-bot").await.unwrap();
+        let password = Password::new("correct horse");
+
+        assert_eq!(certificate_account(&db, "build-bot").await.unwrap(), bot);
+        // A plaintext client can neither register as the bot nor give it a password, even while holding it
+        assert_eq!(
+            auth_error(register(&db, "build-bot").await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(create_account(&db, "build-bot", &password, None).await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(create_account(&db, "build-bot", &password, Some(bot)).await),
+            ErrorCode::NameTaken
+        );
+    }
+
+    #[tokio::test]
+    async fn registering_a_taken_name_is_refused() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO users (name) VALUES (?)")
+            .bind(ANONYMOUS_USER)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let dave = register(&db, "dave").await.unwrap();
+        assert_eq!(
+            auth_error(register(&db, "dave").await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(register(&db, ANONYMOUS_USER).await),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            sqlx::query("SELECT id FROM users WHERE name = 'dave'")
+                .fetch_one(&db)
+                .await
+                .unwrap()
+                .get::<i64, _>("id"),
+            dave
+        );
+    }
+
+    #[tokio::test]
+    async fn certificates_never_log_into_other_accounts() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO users (name) VALUES (?)")
+            .bind(ANONYMOUS_USER)
+            .execute(&db)
+            .await
+            .unwrap();
+        create_account(&db, "alice", &Password::new("correct horse"), None)
+            .await
+            .unwrap();
+        register(&db, "bob").await.unwrap();
+
+        for name in [ANONYMOUS_USER, "alice", "bob"] {
+            assert_eq!(
+                auth_error(certificate_account(&db, name).await),
+                ErrorCode::NameTaken
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn renames_keep_the_account_and_refuse_taken_names() {
+        let db = test_db().await;
+        let password = Password::new("correct horse");
+        let lockout = LockoutConfig::default();
+        let alice = create_account(&db, "alice", &password, None).await.unwrap();
+        create_account(&db, "bob", &password, None).await.unwrap();
+
+        rename(&db, alice, "alicia").await.unwrap();
+
+        assert_eq!(
+            login(&db, "alicia", &password, &lockout).await.unwrap(),
+            alice
+        );
+        assert_eq!(
+            auth_error(rename(&db, alice, "bob").await.map(|_| alice)),
+            ErrorCode::NameTaken
+        );
+        assert_eq!(
+            auth_error(rename(&db, alice, ANONYMOUS_USER).await.map(|_| alice)),
+            ErrorCode::NameTaken
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_the_account() {
+        let db = test_db().await;
+        let password = Password::new("correct horse");
+        let wrong = Password::new("wrong horse");
+        let lockout = LockoutConfig {
+            max_failures: 2,
+            duration: Duration::from_secs(60),
+        };
+        create_account(&db, "alice", &password, None).await.unwrap();
+
+        assert_eq!(
+            auth_error(login(&db, "alice", &wrong, &lockout).await),
+            ErrorCode::AuthFailed
+        );
+        assert_eq!(
+            auth_error(login(&db, "alice", &wrong, &lockout).await),
+            ErrorCode::LockedOut
+        );
+        // Even the right password is refused until the lock expires
+        assert_eq!(
+            auth_error(login(&db, "alice", &password, &lockout).await),
+            ErrorCode::LockedOut
+        );
+    }
+}
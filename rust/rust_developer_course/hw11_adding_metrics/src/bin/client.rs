@@ -1,23 +1,64 @@
 use anyhow::{Context, Result};
 use env_logger::{Builder, Env};
-use hw11_rust_metrics::{get_hostname, receive_msg, Command, MessageType};
-use std::{env, str::FromStr};
+use hw11_rust_metrics::{
+    accounts::Password,
+    config::{ClientConfig, DEFAULT_HEARTBEAT_INTERVAL},
+    delivery::{self, PendingMessages},
+    get_hostname,
+    handshake::{client_handshake, Features, Negotiated},
+    heartbeat,
+    moderation::Moderation,
+    presence::{Presence, UserPresence},
+    receive_msg,
+    resume::Backoff,
+    rooms::{room_name, RoomInfo, RoomRequest},
+    tls,
+    tokens::Token,
+    transfer::{Attachment, TRANSFER_CHUNK_BYTES},
+    AppError, Command, ErrorCode, MessageType, WireFormat,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ErrorKind},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ErrorKind,
     },
+    net::TcpStream,
     select,
-    sync::mpsc,
+    sync::{mpsc, watch},
+    time,
 };
 use tokio_util::sync;
 
+// Name the client announces to the server during the handshake
+const CLIENT_NAME: &str = concat!("hw11-client/", env!("CARGO_PKG_VERSION"));
+
+// Number of transfer frames allowed to queue up for the writer; keeps memory bounded while sending large files
+const TRANSFER_QUEUE_DEPTH: usize = 8;
+
+// Ids for the transfers this client sends
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connection to the server, either plain TCP or wrapped in TLS.
+trait ServerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ServerStream for T {}
+
 /// Entry point for the client application.
 ///
 /// This function initializes logging, processes command-line arguments to determine the server address, and manages
-/// the connection to the server. It spawns separate tasks for handling terminal input, reading from the server, and
-/// writing to the server.
+/// the connection to the server. It spawns a task for handling terminal input, and runs a reader and a writer for
+/// every connection to the server. Whenever the connection is lost the client reconnects, waiting longer after each
+/// failed attempt, and picks up where it left off.
 ///
 /// # Example
 /// ```
@@ -30,7 +71,8 @@ use tokio_util::sync;
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to connect to the server or if there are issues with any of the spawned tasks.
+/// This function returns an error if it fails to connect to the server in the first place, or if there are issues
+/// with the stdin task.
 #[tokio::main]
 async fn main() -> Result<()> {
     // Establish our logger
@@ -41,28 +83,43 @@ async fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let address = get_hostname(args);
 
+    // Load TLS settings from the environment
+    let config = ClientConfig::from_env().context("Failed to load client config")?;
+
     log::info!("Connecting to server: {}", address);
-    // Establish network and stdin readers
-    let stream = TcpStream::connect(&address).await.map_err(|e| {
-        log::error!("Client failed to connect to server at {}: {}", &address, e);
+    let (mut stream, mut negotiated) = open_connection(&address, &config).await.map_err(|e| {
+        log::error!(
+            "Client failed to connect to server at {}: {:?}",
+            &address,
+            e
+        );
         e
     })?;
 
     // Create a mpsc channel to send stdin from the terminal task to server writer task
     let (tx, mut rx) = mpsc::channel::<MessageType>(1024);
 
+    // Attachments get their own small channel so text typed mid-transfer isn't stuck behind queued chunks
+    let (transfer_tx, mut transfer_rx) = mpsc::channel::<MessageType>(TRANSFER_QUEUE_DEPTH);
+
+    // Lets the stdin task know what the current connection supports, if there is one
+    let (features_tx, features_rx) = watch::channel(None);
+
+    // What the client needs to pick up where it left off after reconnecting
+    let session = Arc::new(Mutex::new(Session::default()));
+    let stdin_session = session.clone();
+
     // Create and clone shutdown token to handle managing graceful shutdowns across tasks
     let shutdown_token = sync::CancellationToken::new();
     let stdin_shutdown = shutdown_token.clone();
-    let rdr_shutdown = shutdown_token.clone();
-    let wtr_shutdown = shutdown_token.clone();
+    let stdin_tx = tx.clone();
 
     // Spawn tokio task to manage capturing terminal inputs
     let stdin_task = tokio::spawn(async move {
         // Wait for cancellation or handle stdin from user
         select! {
             _ = stdin_shutdown.cancelled() => log::debug!("Cancel signal initiated, stdin_task shutting down..."),
-            res = process_stdin(tx, stdin_shutdown.clone()) => {
+            res = process_stdin(stdin_tx, transfer_tx, features_rx, stdin_session, stdin_shutdown.clone()) => {
                 match res {
                     Ok(_) => log::debug!("stdin reader exiting task successfully.\nShutting down..."),
                     Err(e) => log::error!("stdin reader encountered an error: {:?}\nShutting down...", e),
@@ -71,60 +128,436 @@ async fn main() -> Result<()> {
         }
     });
 
-    // Split stream into separate reader and writer; we want independant mut refs to pass to separate tokio tasks
-    let (mut reader, mut writer) = stream.into_split();
+    let mut backoff = Backoff::new(config.reconnect);
+    loop {
+        features_tx.send_replace(Some(Features::from_names(&negotiated.features)));
+        run_connection(
+            stream,
+            negotiated,
+            &config,
+            &mut rx,
+            &mut transfer_rx,
+            tx.clone(),
+            &session,
+            &shutdown_token,
+        )
+        .await;
+        features_tx.send_replace(None);
+
+        if shutdown_token.is_cancelled() {
+            break;
+        }
+        // A kicked or banned client would only be turned away again
+        if session.lock().unwrap().shut_out {
+            log::error!("Disconnected by a moderator. Shutting down...");
+            shutdown_token.cancel();
+            break;
+        }
+        match reconnect(&address, &config, &mut backoff, &session, &shutdown_token).await {
+            Some(connection) => (stream, negotiated) = connection,
+            None => {
+                shutdown_token.cancel();
+                break;
+            }
+        }
+    }
 
-    // Spawn tokio task to manage reading from server stream
-    let rdr_task = tokio::spawn(async move {
-        // Wait for cancellation or handle stdin from user
+    if let Err(e) = stdin_task.await {
+        log::error!("Client task failed to complete: {:?}", e);
+    }
+
+    Ok(())
+}
+
+/// Talks to the server over a single connection, until it is lost or the user quits.
+///
+/// Messages the server never confirmed are reported once the connection is gone, since they may not have been
+/// delivered.
+///
+/// # Example
+/// ```
+/// run_connection(stream, negotiated, &config, &mut rx, &mut transfer_rx, tx.clone(), &session, &shutdown_token).await;
+/// ```
+///
+/// This function does not return any errors; failures of the reader and writer are logged and end the connection.
+#[allow(clippy::too_many_arguments)]
+async fn run_connection(
+    stream: Box<dyn ServerStream>,
+    negotiated: Negotiated,
+    config: &ClientConfig,
+    rx: &mut mpsc::Receiver<MessageType>,
+    transfer_rx: &mut mpsc::Receiver<MessageType>,
+    tx: mpsc::Sender<MessageType>,
+    session: &Arc<Mutex<Session>>,
+    shutdown: &sync::CancellationToken,
+) {
+    let features = Features::from_names(&negotiated.features);
+    // Messages the server hasn't acknowledged yet, shared between the writer that submits them and the reader that
+    // sees them confirmed
+    let pending = features
+        .acks
+        .then(|| Arc::new(Mutex::new(PendingMessages::default())));
+    // A server that answers pings is never quiet for long, so one that is has gone away
+    let idle_timeout = features.heartbeats.then_some(config.heartbeat.idle_timeout);
+    let heartbeat_interval = features.heartbeats.then_some(config.heartbeat.interval);
+    let format = wire_format(&negotiated);
+
+    // Cancelled when this connection is lost, as well as when the whole client shuts down
+    let connection = shutdown.child_token();
+
+    // Split stream into separate reader and writer; we want independant mut refs for the reader and the writer
+    let (reader, writer) = tokio::io::split(stream);
+
+    let rdr = async {
         select! {
-            _ = rdr_shutdown.cancelled() => log::debug!("Cancel signal initiated, stdin_task shutting down..."),
-            res = process_server_rdr(reader, rdr_shutdown.clone()) => {
+            _ = connection.cancelled() => log::debug!("Cancel signal initiated, server reader shutting down..."),
+            res = process_server_rdr(reader, format, pending.clone(), tx, idle_timeout, session.clone(), connection.clone()) => {
                 match res {
-                    Ok(_) => log::debug!("Server reader exitign task successfully.\nShutting down..."),
-                    Err(e) => log::error!("Server reader encountered an error: {:?}\nShutting down...", e),
+                    Ok(_) => log::debug!("Server reader exiting task successfully."),
+                    Err(e) => log::error!("Server reader encountered an error: {:?}", e),
                 }
             }
         }
-    });
-
-    // Spawn tokio task to manage writing to server stream
-    let wtr_task = tokio::spawn(async move {
+        connection.cancel();
+    };
+    let wtr = async {
         select! {
-            _ = wtr_shutdown.cancelled() => {
-                log::debug!("Cancel signal initiated, stdin_task shutting down...");
-                // FIXME: Server shut be notifed client is disconnecting
-            },
-            res = process_server_wtr(writer, &mut rx, wtr_shutdown.clone()) => {
+            _ = connection.cancelled() => log::debug!("Cancel signal initiated, server writer shutting down..."),
+            res = process_server_wtr(writer, format, rx, transfer_rx, pending.clone(), heartbeat_interval, shutdown.clone()) => {
                 match res {
-                    Ok(_) => log::debug!("Server reader exitign task successfully.\nShutting down..."),
-                    Err(e) => log::error!("Server writer encountered an error: {:?}\nShutting down...", e),
+                    Ok(_) => log::debug!("Server writer exiting task successfully."),
+                    Err(e) => log::error!("Server writer encountered an error: {:?}", e),
                 }
             }
         }
-    });
+        connection.cancel();
+    };
+    tokio::join!(rdr, wtr);
+
+    if let Some(pending) = pending {
+        for summary in pending.lock().unwrap().abandon() {
+            log::warn!("[UNCONFIRMED] {}", summary);
+        }
+    }
+}
+
+/// Dials the server again after the connection was lost, waiting longer after every failed attempt, and picks up the
+/// `session` where it left off.
+///
+/// # Example
+/// ```
+/// let (stream, negotiated) = reconnect(&address, &config, &mut backoff, &session, &shutdown_token).await?;
+/// ```
+///
+/// This function does not return any errors; it returns `None` if the client gave up, or was shut down while
+/// waiting to try again.
+async fn reconnect(
+    address: &str,
+    config: &ClientConfig,
+    backoff: &mut Backoff,
+    session: &Arc<Mutex<Session>>,
+    shutdown: &sync::CancellationToken,
+) -> Option<(Box<dyn ServerStream>, Negotiated)> {
+    while let Some(delay) = backoff.next_delay() {
+        log::info!(
+            "Lost the connection to the server. Reconnecting in {:.1?} (attempt {})...",
+            delay,
+            backoff.attempts()
+        );
+        select! {
+            _ = shutdown.cancelled() => return None,
+            _ = time::sleep(delay) => {}
+        }
+
+        let resumed = async {
+            let (mut stream, negotiated) = open_connection(address, config).await?;
+            let features = Features::from_names(&negotiated.features);
+            resume_session(&mut stream, &wire_format(&negotiated), features, session).await?;
+            anyhow::Ok((stream, negotiated))
+        };
+        match resumed.await {
+            Ok(connection) => {
+                log::info!("Reconnected to server: {}", address);
+                backoff.reset();
+                return Some(connection);
+            }
+            Err(e) => log::warn!("Failed to reconnect: {:#}", e),
+        }
+    }
+
+    match backoff.attempts() {
+        0 => log::info!("Lost the connection to the server. Shutting down..."),
+        attempts => log::error!(
+            "Giving up after {} attempts to reconnect. Shutting down...",
+            attempts
+        ),
+    }
+    None
+}
+
+/// Connects to the server and introduces the client, returning the connection along with what was negotiated.
+///
+/// # Example
+/// ```
+/// let (stream, negotiated) = open_connection("localhost:11111", &config).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the server cannot be reached or rejects the handshake.
+async fn open_connection(
+    address: &str,
+    config: &ClientConfig,
+) -> Result<(Box<dyn ServerStream>, Negotiated)> {
+    let mut stream = connect(address, config).await?;
 
-    tokio::join!(stdin_task, rdr_task, wtr_task);
+    // Introduce ourselves before any messages are exchanged; the server drops clients it can't talk to
+    let negotiated = client_handshake(&mut stream, CLIENT_NAME)
+        .await
+        .context("Server rejected the connection")?;
+
+    Ok((stream, negotiated))
+}
+
+/// Logs into the previous account again, with the session token it was issued if the server supports them, and, if the
+/// server supports it, asks for the messages missed while disconnected. Both are sent before anything else goes out
+/// over the new connection.
+///
+/// # Example
+/// ```
+/// resume_session(&mut stream, &wire_format(&negotiated), features, &session).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to send the messages over the stream.
+async fn resume_session<W: AsyncWriteExt + Unpin>(
+    stream: &mut W,
+    format: &WireFormat,
+    features: Features,
+    session: &Arc<Mutex<Session>>,
+) -> Result<()> {
+    let (account, last_seen) = {
+        let session = session.lock().unwrap();
+        (session.account.clone(), session.last_seen)
+    };
+
+    match account {
+        Some(Account {
+            name,
+            credentials: Credentials::Token(token),
+        }) if features.tokens => {
+            log::info!("Logging in again as {} with a session token", name);
+            session.lock().unwrap().pending_login = Some(Account {
+                name,
+                credentials: Credentials::Token(token.clone()),
+            });
+            MessageType::TokenLogin(token)
+                .send(stream, format)
+                .await
+                .context("Failed to log in with the server again.")?;
+        }
+        Some(Account {
+            name,
+            credentials: Credentials::Password(password),
+        }) if features.accounts => {
+            log::info!("Logging in again as {}", name);
+            session.lock().unwrap().pending_login = Some(Account {
+                name: name.clone(),
+                credentials: Credentials::Password(password.clone()),
+            });
+            MessageType::Login(name, password)
+                .send(stream, format)
+                .await
+                .context("Failed to log in with the server again.")?;
+        }
+        Some(Account {
+            name,
+            credentials: Credentials::Unprotected,
+        }) => {
+            log::info!("Registering again as {}", name);
+            MessageType::Register(name)
+                .send(stream, format)
+                .await
+                .context("Failed to register with the server again.")?;
+        }
+        Some(Account { name, .. }) => {
+            log::warn!(
+                "The server no longer supports logging in, so {} is not logged in again",
+                name
+            );
+        }
+        None => {}
+    }
+    if let (true, Some(last_seen)) = (features.resume, last_seen) {
+        log::debug!("Asking for the messages sent after #{}", last_seen);
+        MessageType::Resume(last_seen)
+            .send(stream, format)
+            .await
+            .context("Failed to ask the server for missed messages.")?;
+    }
 
     Ok(())
 }
 
+/// Returns how messages are to be encoded over a connection, as negotiated during the handshake.
+fn wire_format(negotiated: &Negotiated) -> WireFormat {
+    WireFormat {
+        codec: negotiated.codec,
+        compression: negotiated.compression,
+        max_payload_bytes: negotiated.max_frame_bytes,
+        ..WireFormat::default()
+    }
+}
+
+/// Represents what the client remembers across connections, so it can pick up where it left off after reconnecting.
+#[derive(Debug, Default)]
+struct Session {
+    /// Account last registered with the server or logged into.
+    account: Option<Account>,
+    /// Account a login was sent for, until the server confirms or refuses it.
+    pending_login: Option<Account>,
+    /// Id of the newest stamped message received.
+    last_seen: Option<i64>,
+    /// Whether a moderator kicked or banned the client, which shouldn't reconnect.
+    shut_out: bool,
+    /// Room the client is talking in, as last listed by the server.
+    room: Option<String>,
+}
+
+/// Represents an account the client registered or logged into, and how to log into it again.
+#[derive(Clone, Debug, PartialEq)]
+struct Account {
+    name: String,
+    credentials: Credentials,
+}
+
+/// Represents what the client logs into an account with.
+#[derive(Clone, Debug, PartialEq)]
+enum Credentials {
+    /// Nothing; the account was registered with a server that doesn't support passwords.
+    Unprotected,
+    Password(Password),
+    /// Session token issued by the server, which stands in for the password from then on.
+    Token(Token),
+}
+
+impl Session {
+    /// Notes that the message with the given id has been seen.
+    fn saw(&mut self, id: i64) {
+        self.last_seen = self.last_seen.max(Some(id));
+    }
+
+    /// Takes on the pending login once the server confirms it, returning true if it was for the account `name`.
+    fn login_confirmed(&mut self, name: &str) -> bool {
+        if self
+            .pending_login
+            .as_ref()
+            .is_some_and(|pending| pending.name == name)
+        {
+            self.account = self.pending_login.take();
+            return true;
+        }
+        false
+    }
+
+    /// Forgets the pending login once the server refuses it. If it was for the current account, e.g. because its
+    /// password was changed, that is forgotten too rather than being tried again after every reconnect.
+    fn login_refused(&mut self) {
+        let Some(refused) = self.pending_login.take() else {
+            return;
+        };
+        if self
+            .account
+            .as_ref()
+            .is_some_and(|account| account.name == refused.name)
+        {
+            self.account = None;
+        }
+    }
+
+    /// Swaps the password of the current account for the session token the server issued for it, so the password
+    /// needn't be kept around.
+    fn token_issued(&mut self, token: Token) {
+        if let Some(account) = self.account.as_mut() {
+            account.credentials = Credentials::Token(token);
+        }
+    }
+
+    /// Forgets the current account once the server has logged the client out of it.
+    fn logged_out(&mut self) {
+        self.account = None;
+        self.pending_login = None;
+    }
+
+    /// Notes that the user `old` is now called `new`, returning true if that was the account the client is logged
+    /// into.
+    fn renamed(&mut self, old: &str, new: &str) -> bool {
+        match self.account.as_mut() {
+            Some(account) if account.name == old => {
+                account.name = new.to_string();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Turns the arguments of `.join`, `.leave`, `.rooms` or `.topic` into a request to send to the server. `.leave`
+/// without a room leaves `current`, the room the client is talking in.
+///
+/// # Example
+/// ```
+/// let request = room_request(Command::Join, "#Rust", None)?;
+/// assert_eq!(request, RoomRequest::Join("rust".to_string()));
+/// ```
+///
+/// # Errors
+/// This function returns an error if the room name is invalid, or no room was given to leave and the current one
+/// isn't known.
+fn room_request(command: Command, args: &str, current: Option<&str>) -> Result<RoomRequest> {
+    let request = match command {
+        Command::Join => RoomRequest::Join(room_name(args)?),
+        Command::Leave if args.trim().is_empty() => RoomRequest::Leave(
+            current
+                .context("Not sure which room you are in; name the room to leave")?
+                .to_string(),
+        ),
+        Command::Leave => RoomRequest::Leave(room_name(args)?),
+        Command::Topic => RoomRequest::Topic(args.trim().to_string()),
+        _ => RoomRequest::List,
+    };
+    Ok(request)
+}
+
 /// Handles user input from stdin and sends messages to the server.
 ///
 /// This function reads user input, determines the command type, and sends the appropriate message to the server through
-/// a channel.
+/// a channel. `features` holds what the current connection supports, or `None` while the client is reconnecting;
+/// messages typed in the meantime are queued until it is back. When the server supports chunked transfers, files and
+/// images are streamed through `transfer_tx` by a separate task instead of being read into memory in one go. On
+/// `.quit`, servers that negotiated `goodbyes` are sent a `Goodbye`, and the writer shuts the client down once it has
+/// gone out. Servers that negotiated `accounts` are sent logins with a password, which is asked for without echoing
+/// it if it isn't given on the command line, and can be renamed if they negotiated `renames`. `.logout` needs
+/// `tokens`, the moderation commands need `moderation`, the room commands need `rooms`, and `.msg` needs
+/// `direct-messages`; direct attachments are always sent whole rather than in chunks. Logins are noted in
+/// `session`, to log in again after reconnecting, and `.slowmode` without a room applies to the room the client is
+/// talking in.
 ///
 /// # Example
 /// ```
 /// let (tx, mut rx) = mpsc::channel::<MessageType>(1024);
+/// let (transfer_tx, mut transfer_rx) = mpsc::channel::<MessageType>(TRANSFER_QUEUE_DEPTH);
+/// let (features_tx, features_rx) = watch::channel(Some(features));
 /// let shutdown_token = sync::CancellationToken::new();
-/// process_stdin(tx, shutdown_token.clone()).await?;
+/// process_stdin(tx, transfer_tx, features_rx, session, shutdown_token.clone()).await?;
 /// ```
 ///
 /// # Errors
 /// This function returns an error if it fails to read from stdin or send messages.
 async fn process_stdin(
     tx: mpsc::Sender<MessageType>,
+    transfer_tx: mpsc::Sender<MessageType>,
+    features: watch::Receiver<Option<Features>>,
+    session: Arc<Mutex<Session>>,
     shutdown: sync::CancellationToken,
 ) -> Result<()> {
     log::trace!("Starting process: stdin Consumer.");
@@ -142,13 +575,21 @@ async fn process_stdin(
         let trimmed_input = line.trim();
         let parts: Vec<&str> = trimmed_input.splitn(2, ' ').collect();
         let command = Command::from_str(parts[0])?;
+        let connected = *features.borrow();
 
         // Handle requests to exit gracefully or display usage
         match command {
             Command::Quit => {
                 log::debug!("User requested quit. Client initiating shutdown..");
                 log::info!("Shutdown the client...");
-                shutdown.cancel();
+                if connected.is_some_and(|features| features.goodbyes) {
+                    let reason = parts.get(1).map(|reason| reason.trim().to_string());
+                    tx.send(MessageType::Goodbye(reason))
+                        .await
+                        .context("Failed to send message to the writer task")?;
+                } else {
+                    shutdown.cancel();
+                }
                 break;
             }
             Command::Help => {
@@ -166,15 +607,19 @@ async fn process_stdin(
                 }
             }
             Command::File | Command::Image => {
-                let msg = generate_message(command, parts).await?;
-                tx.send(msg)
-                    .await
-                    .context("Failed to send message to the writer task")?;
-            }
-            Command::Register => {
-                if parts[0].is_empty() {
-                    log::debug!("User attempting to register without an account. Ignoring...");
-                    continue;
+                if connected.is_some_and(|features| features.chunked_transfers) {
+                    let path_str = parts.get(1).context("Missing attachment path.")?;
+                    let attachment = match command {
+                        Command::File => Attachment::File(file_name(path_str)?),
+                        _ => Attachment::Image,
+                    };
+                    let path = path_str.to_string();
+                    let transfer_tx = transfer_tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = send_attachment(&path, attachment, &transfer_tx).await {
+                            log::error!("Failed to send {}: {:?}", path, e);
+                        }
+                    });
                 } else {
                     let msg = generate_message(command, parts).await?;
                     tx.send(msg)
@@ -182,103 +627,563 @@ async fn process_stdin(
                         .context("Failed to send message to the writer task")?;
                 }
             }
+            Command::Register | Command::Login => {
+                let Some((name, password)) = parts.get(1).and_then(|args| credentials(args)) else {
+                    log::debug!("User attempting to log in without an account. Ignoring...");
+                    continue;
+                };
+
+                // Servers that don't support passwords only know bare registrations
+                if connected.is_some_and(|features| !features.accounts) {
+                    if let Command::Login = command {
+                        log::error!(
+                            "The server doesn't support logging in; use .register <account name>"
+                        );
+                        continue;
+                    }
+                    if password.is_some() {
+                        log::warn!(
+                            "The server doesn't support passwords; registering {} without one",
+                            name
+                        );
+                    }
+                    let msg = generate_message(command, vec![parts[0], &name]).await?;
+                    session.lock().unwrap().account = Some(Account {
+                        name,
+                        credentials: Credentials::Unprotected,
+                    });
+                    tx.send(msg)
+                        .await
+                        .context("Failed to send message to the writer task")?;
+                    continue;
+                }
+
+                let password = match password {
+                    Some(password) => password,
+                    None => match read_password(&name).await {
+                        Ok(password) => password,
+                        Err(e) => {
+                            log::error!("{:#}", e);
+                            continue;
+                        }
+                    },
+                };
+                session.lock().unwrap().pending_login = Some(Account {
+                    name: name.clone(),
+                    credentials: Credentials::Password(password.clone()),
+                });
+                let msg = match command {
+                    Command::Register => MessageType::CreateAccount(name, password),
+                    _ => MessageType::Login(name, password),
+                };
+                tx.send(msg)
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Logout => {
+                if connected.is_some_and(|features| !features.tokens) {
+                    log::error!("The server doesn't support logging out");
+                    continue;
+                }
+                tx.send(MessageType::Logout)
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Rename => {
+                let Some(name) = parts.get(1).and_then(|args| args.split_whitespace().next())
+                else {
+                    log::debug!("User attempting to rename without a new name. Ignoring...");
+                    continue;
+                };
+                if connected.is_some_and(|features| !features.renames) {
+                    log::error!("The server doesn't support renaming accounts");
+                    continue;
+                }
+                tx.send(MessageType::Rename(name.to_string()))
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Kick
+            | Command::Ban
+            | Command::Mute
+            | Command::Unban
+            | Command::Unmute
+            | Command::Slowmode => {
+                if connected.is_some_and(|features| !features.moderation) {
+                    log::error!("The server doesn't support moderation");
+                    continue;
+                }
+                let mut args = parts.get(1).unwrap_or(&"").to_string();
+                let room = session.lock().unwrap().room.clone();
+                if command == Command::Slowmode && args.split_whitespace().count() == 1 {
+                    if let Some(room) = room {
+                        args = format!("{} {}", args, room);
+                    }
+                }
+                let action = match Moderation::parse(command, &args) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        log::error!("{}", e);
+                        continue;
+                    }
+                };
+                tx.send(MessageType::Moderate(action))
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Msg => {
+                let Some((recipient, rest)) = parts.get(1).and_then(|args| direct_message(args))
+                else {
+                    log::debug!("User attempting to send a direct message to nobody. Ignoring...");
+                    continue;
+                };
+                if connected.is_some_and(|features| !features.direct_messages) {
+                    log::error!("The server doesn't support direct messages");
+                    continue;
+                }
+                let inner_parts: Vec<&str> = rest.splitn(2, ' ').collect();
+                let inner_command = Command::from_str(inner_parts[0])?;
+                if !matches!(
+                    inner_command,
+                    Command::Text | Command::File | Command::Image
+                ) {
+                    log::error!("Only text, .file and .image can be sent directly");
+                    continue;
+                }
+                let msg = match generate_message(inner_command, inner_parts).await {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::error!("{:#}", e);
+                        continue;
+                    }
+                };
+                tx.send(MessageType::Direct(recipient, Box::new(msg)))
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Who => {
+                if connected.is_some_and(|features| !features.presence) {
+                    log::error!("The server doesn't say who is online");
+                    continue;
+                }
+                let user = parts
+                    .get(1)
+                    .and_then(|args| args.split_whitespace().next())
+                    .map(str::to_string);
+                tx.send(MessageType::Who(user))
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
+            Command::Join | Command::Leave | Command::Rooms | Command::Topic => {
+                if connected.is_some_and(|features| !features.rooms) {
+                    log::error!("The server doesn't support rooms");
+                    continue;
+                }
+                let current = session.lock().unwrap().room.clone();
+                let request =
+                    match room_request(command, parts.get(1).unwrap_or(&""), current.as_deref()) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            log::error!("{}", e);
+                            continue;
+                        }
+                    };
+                tx.send(MessageType::Room(request))
+                    .await
+                    .context("Failed to send message to the writer task")?;
+            }
         }
     }
 
     Ok(())
 }
 
+/// Connects to the server, wrapping the connection in TLS if the client is configured to use it.
+///
+/// The name checked against the server's certificate is `CHAT_TLS_SERVER_NAME` if set, or the host being dialed.
+///
+/// # Example
+/// ```
+/// let config = ClientConfig::from_env()?;
+/// let stream = connect("localhost:11111", &config).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the server cannot be reached or the TLS handshake fails, e.g. because the
+/// server's certificate is not trusted.
+async fn connect(address: &str, config: &ClientConfig) -> Result<Box<dyn ServerStream>> {
+    let stream = TcpStream::connect(address).await?;
+
+    let Some(tls_config) = &config.tls else {
+        return Ok(Box::new(stream));
+    };
+
+    let host = match &tls_config.server_name {
+        Some(name) => name.as_str(),
+        // Strip the port, and the brackets around IPv6 literals
+        None => address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    let connector = tls::client_connector(tls_config)?;
+    let stream = connector
+        .connect(tls::server_name(host)?, stream)
+        .await
+        .context("TLS handshake failed")?;
+    log::info!("Established TLS connection to {}", host);
+
+    Ok(Box::new(stream))
+}
+
 /// Reads and processes incoming messages from the server.
 ///
 /// This function continuously reads messages from the server, processes them, and performs appropriate actions such as
-/// logging and saving files.
+/// logging and saving files. Chunked transfers are written to disk as their chunks arrive and only moved into place
+/// once their checksum has been verified. Acknowledgements from the server confirm the messages in `pending`, and its
+/// pings are answered through `tx`. Messages said before the client connected are shown as history. The ids of
+/// stamped messages are noted in `session`, to resume from after reconnecting. Messages sent to a room are shown with
+/// its name, direct messages with who they were for, and lists of rooms note which one the client is talking in. If
+/// the server sends nothing for `idle_timeout`, it is assumed to be gone, and `connection` is cancelled as it is when
+/// the server disconnects.
 ///
 /// # Example
 /// ```
-/// let (mut reader, mut writer) = stream.into_split();
-/// let shutdown_token = sync::CancellationToken::new();
-/// process_server_rdr(reader, shutdown_token.clone()).await?;
+/// let (reader, writer) = tokio::io::split(stream);
+/// let connection = shutdown_token.child_token();
+/// process_server_rdr(reader, format, Some(pending), tx, Some(idle_timeout), session, connection.clone()).await?;
 /// ```
 ///
 /// # Errors
 /// This function returns an error if it fails to read from the stream or process messages.
-async fn process_server_rdr(
-    mut stream: OwnedReadHalf,
-    shutdown: sync::CancellationToken,
+async fn process_server_rdr<R: AsyncReadExt + Unpin>(
+    mut stream: R,
+    format: WireFormat,
+    pending: Option<Arc<Mutex<PendingMessages>>>,
+    tx: mpsc::Sender<MessageType>,
+    idle_timeout: Option<Duration>,
+    session: Arc<Mutex<Session>>,
+    connection: sync::CancellationToken,
 ) -> Result<()> {
     log::trace!("Starting process: Server Reader.");
-    let mut length_bytes = [0; 4];
+
+    // Transfers currently being received, keyed by the server's transfer id
+    let mut incoming: HashMap<u64, IncomingTransfer> = HashMap::new();
 
     loop {
-        match stream
-            .read_exact(&mut length_bytes)
+        match heartbeat::read_header(&mut stream, idle_timeout)
             .await
-            .context("Failed to read length")
+            .context("Failed to read frame header")
         {
-            Ok(_) => {
-                let msg_len = u32::from_be_bytes(length_bytes) as usize;
-
+            Ok(header) => {
                 log::debug!(
-                    "Attempting to retrieve a {}-byte message from the server.",
-                    msg_len.to_string()
+                    "Attempting to retrieve a {}-byte {:?} message from the server.",
+                    header.len,
+                    header.kind
                 );
-                let msg = receive_msg(&mut stream, msg_len)
-                    .await
-                    .context("Failed to read message")?;
-                log::debug!("{:?}", msg);
+                let msg = match receive_msg(&mut stream, &header, &format).await {
+                    Ok(msg) => msg,
+                    Err(e) => match e.downcast::<AppError>() {
+                        // An oversized payload is left unread, so there is no next frame to find
+                        Ok(AppError::Protocol(ErrorCode::FrameTooLarge, description))
+                            if header.len > format.max_payload_bytes =>
+                        {
+                            log::error!("{}. Shutting down...", description);
+                            connection.cancel();
+                            break;
+                        }
+                        // The payload has been consumed, so skip it and carry on with the next frame
+                        Ok(AppError::Protocol(code, description)) => {
+                            log::warn!(
+                                "Ignoring bad frame from the server ({:?}): {}",
+                                code,
+                                description
+                            );
+                            continue;
+                        }
+                        Ok(e) => return Err(e).context("Failed to read message"),
+                        Err(e) => return Err(e.context("Failed to read message")),
+                    },
+                };
+                log::debug!("{}", msg);
+
+                // Messages sent to a room are shown with its name in front, and direct messages with who they are for
+                let (prefix, msg) = match msg {
+                    MessageType::InRoom(room, msg) => (format!("[#{}] ", room), *msg),
+                    MessageType::Direct(recipient, msg) => {
+                        let for_us = session
+                            .lock()
+                            .unwrap()
+                            .account
+                            .as_ref()
+                            .is_some_and(|account| account.name == recipient);
+                        if for_us {
+                            ("[DIRECT] ".to_string(), *msg)
+                        } else {
+                            (format!("[DIRECT to {}] ", recipient), *msg)
+                        }
+                    }
+                    other => (String::new(), other),
+                };
+
+                let msg = match msg {
+                    MessageType::Stamped(id, created_at, msg) => {
+                        log::debug!(
+                            "Message #{} was sent at {}",
+                            id,
+                            delivery::format_timestamp(created_at)
+                        );
+                        session.lock().unwrap().saw(id);
+                        *msg
+                    }
+                    other => other,
+                };
 
                 match msg {
                     MessageType::File(Some(username), name, data) => {
-                        log::info!("[RECEIVED FILE from {}] Saving to..: {}", username, name);
+                        log::info!(
+                            "{}[RECEIVED FILE from {}] Saving to..: {}",
+                            prefix,
+                            username,
+                            name
+                        );
                         save_file(name, data).await?
                     }
                     MessageType::File(None, name, data) => {
-                        log::info!("[RECEIVED FILE from anonymous] Saving to..: {}", name);
+                        log::info!(
+                            "{}[RECEIVED FILE from anonymous] Saving to..: {}",
+                            prefix,
+                            name
+                        );
                         save_file(name, data).await?
                     }
                     MessageType::Image(Some(username), data) => {
-                        log::info!("[RECEIVED IMAGE from {}]", username);
+                        log::info!("{}[RECEIVED IMAGE from {}]", prefix, username);
                         save_image(data).await?
                     }
                     MessageType::Image(None, data) => {
-                        log::info!("[RECEIVED IMAGE from Anonymous]");
+                        log::info!("{}[RECEIVED IMAGE from Anonymous]", prefix);
                         save_image(data).await?
                     }
                     MessageType::Text(Some(username), text) => {
-                        log::info!("[{}] {}", username, text)
+                        log::info!("{}[{}] {}", prefix, username, text)
                     }
                     MessageType::Text(None, text) => {
-                        log::info!("[Anonymous] {}", text)
+                        log::info!("{}[Anonymous] {}", prefix, text)
                     }
                     MessageType::Register(account) => {
-                        log::info!("[NEW USER LOGGED IN] {}", account)
+                        if session.lock().unwrap().login_confirmed(&account) {
+                            log::info!("[LOGGED IN] as {}", account)
+                        } else {
+                            log::info!("[NEW USER LOGGED IN] {}", account)
+                        }
+                    }
+                    MessageType::Left(account, Some(reason)) => {
+                        log::info!("[USER LEFT] {} ({})", account, reason)
+                    }
+                    MessageType::Left(account, None) => log::info!("[USER LEFT] {}", account),
+                    MessageType::SessionToken(token, _) => {
+                        session.lock().unwrap().token_issued(token)
+                    }
+                    MessageType::Logout => {
+                        session.lock().unwrap().logged_out();
+                        log::info!("[LOGGED OUT]")
+                    }
+                    MessageType::Renamed(old, new) => {
+                        if session.lock().unwrap().renamed(&old, &new) {
+                            log::info!("[RENAMED] You are now {}", new)
+                        } else {
+                            log::info!("[USER RENAMED] {} is now {}", old, new)
+                        }
+                    }
+                    MessageType::Error(code, description) => {
+                        if matches!(
+                            code,
+                            ErrorCode::AuthFailed
+                                | ErrorCode::LockedOut
+                                | ErrorCode::WeakPassword
+                                | ErrorCode::NameTaken
+                                | ErrorCode::Banned
+                        ) {
+                            session.lock().unwrap().login_refused();
+                        }
+                        if matches!(code, ErrorCode::Kicked | ErrorCode::Banned) {
+                            session.lock().unwrap().shut_out = true;
+                        }
+                        log::error!("[SERVER ERROR {:?}] {}", code, description)
+                    }
+                    MessageType::Notice(text) => log::info!("{}[NOTICE] {}", prefix, text),
+                    MessageType::Rooms(current, rooms) => {
+                        log::info!("[ROOMS] Talking in #{}", current);
+                        for room in &rooms {
+                            log::info!("{}", describe_room(room));
+                        }
+                        session.lock().unwrap().room = Some(current);
+                    }
+                    MessageType::Users(users) => {
+                        let online = users
+                            .iter()
+                            .filter(|user| user.presence != Presence::Offline)
+                            .count();
+                        log::info!("[WHO] {} online", online);
+                        for user in &users {
+                            log::info!("{}", describe_user(user));
+                        }
+                    }
+                    MessageType::Presence(account, presence) => {
+                        let ours = session
+                            .lock()
+                            .unwrap()
+                            .account
+                            .as_ref()
+                            .is_some_and(|ours| ours.name == account);
+                        if ours {
+                            log::info!("[PRESENCE] You are {}", presence)
+                        } else {
+                            log::info!("[PRESENCE] {} is {}", account, presence)
+                        }
+                    }
+                    MessageType::TransferStart(username, id, attachment, total) => {
+                        let username = username.unwrap_or_else(|| "anonymous".to_string());
+                        log::info!(
+                            "{}[RECEIVING {} from {}] {} bytes...",
+                            prefix,
+                            attachment,
+                            username,
+                            total
+                        );
+                        match IncomingTransfer::create(&attachment, total).await {
+                            Ok(transfer) => {
+                                incoming.insert(id, transfer);
+                            }
+                            Err(e) => log::error!("Unable to receive {}: {:?}", attachment, e),
+                        }
+                    }
+                    MessageType::TransferChunk(id, offset, data) => {
+                        if let Some(transfer) = incoming.get_mut(&id) {
+                            if let Err(e) = transfer.write_chunk(offset, &data).await {
+                                log::error!("Dropping transfer {}: {:?}", id, e);
+                                if let Some(transfer) = incoming.remove(&id) {
+                                    transfer.discard().await;
+                                }
+                            }
+                        }
+                    }
+                    MessageType::TransferEnd(id, checksum) => {
+                        if let Some(transfer) = incoming.remove(&id) {
+                            match transfer.finish(checksum).await {
+                                Ok(path) => log::info!("[RECEIVED] Saved to..: {}", path.display()),
+                                Err(e) => log::error!("Dropping transfer {}: {:?}", id, e),
+                            }
+                        }
+                    }
+                    MessageType::TransferAbort(id, reason) => {
+                        if let Some(transfer) = incoming.remove(&id) {
+                            log::warn!("Transfer {} was aborted by the sender: {}", id, reason);
+                            transfer.discard().await;
+                        }
+                    }
+                    MessageType::Ack(nonce, id, created_at) => {
+                        session.lock().unwrap().saw(id);
+                        let confirmed = pending
+                            .as_ref()
+                            .and_then(|pending| pending.lock().unwrap().confirm(nonce));
+                        match confirmed {
+                            Some(summary) => log::info!(
+                                "[CONFIRMED #{} at {}] {}",
+                                id,
+                                delivery::format_timestamp(created_at),
+                                summary
+                            ),
+                            None => log::debug!("Ignoring ack for unknown nonce {}", nonce),
+                        }
+                    }
+                    MessageType::Rejected(nonce, code, reason) => {
+                        let rejected = pending
+                            .as_ref()
+                            .and_then(|pending| pending.lock().unwrap().reject(nonce));
+                        match rejected {
+                            Some(summary) => {
+                                log::warn!("[REJECTED {:?}] {}: {}", code, summary, reason)
+                            }
+                            None => log::debug!("Ignoring rejection of unknown nonce {}", nonce),
+                        }
+                    }
+                    MessageType::Ping(nonce) => {
+                        tx.send(MessageType::Pong(nonce))
+                            .await
+                            .context("Failed to send message to the writer task")?;
+                    }
+                    MessageType::Pong(nonce) => log::trace!("Server answered ping {}", nonce),
+                    MessageType::History(msg) => {
+                        // After reconnecting, the backlog repeats messages that were seen before
+                        if let Some(id) = msg.id() {
+                            let mut session = session.lock().unwrap();
+                            if session.last_seen.is_some_and(|last_seen| id <= last_seen) {
+                                continue;
+                            }
+                            session.saw(id);
+                        }
+                        match *msg {
+                            MessageType::Stamped(_, created_at, msg) => log::info!(
+                                "{}[HISTORY {}] {}",
+                                prefix,
+                                delivery::format_timestamp(created_at),
+                                msg
+                            ),
+                            msg => log::info!("{}[HISTORY] {}", prefix, msg),
+                        }
+                    }
+                    MessageType::Submit(..)
+                    | MessageType::Stamped(..)
+                    | MessageType::Goodbye(..)
+                    | MessageType::Resume(..)
+                    | MessageType::CreateAccount(..)
+                    | MessageType::Login(..)
+                    | MessageType::Rename(..)
+                    | MessageType::TokenLogin(..)
+                    | MessageType::Moderate(..)
+                    | MessageType::Room(..)
+                    | MessageType::InRoom(..)
+                    | MessageType::Direct(..)
+                    | MessageType::Who(..) => {
+                        log::warn!("Ignoring unexpected {:?} from the server", msg.kind())
                     }
                 }
             }
             Err(e) => {
-                if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+                if let Some(AppError::IdleTimeout(idle)) = e.downcast_ref::<AppError>() {
+                    log::error!("Server sent nothing for {:?}; assuming it is gone.", idle);
+                    connection.cancel();
+                    break;
+                } else if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
                     match io_err.kind() {
                         ErrorKind::UnexpectedEof => {
-                            log::info!("Server disconnected. Shutting down...");
-                            let _ = shutdown.cancel();
+                            log::info!("Server disconnected.");
+                            connection.cancel();
                             break;
                         }
                         ErrorKind::ConnectionReset => {
-                            log::info!("Server at connection reset. Shutting down...");
-                            let _ = shutdown.cancel();
+                            log::info!("Server at connection reset.");
+                            connection.cancel();
                             break;
                         }
                         ErrorKind::BrokenPipe => {
-                            log::info!("Server connection had broken pipe. Shutting down...");
-                            let _ = shutdown.cancel();
+                            log::info!("Server connection had broken pipe.");
+                            connection.cancel();
                             break;
                         }
                         _ => {
                             log::info!("Unexpected error reading from server: {:?}", e);
-                            let _ = shutdown.cancel();
+                            connection.cancel();
                             break;
                         }
                     }
+                } else {
+                    log::error!("Invalid frame received from server: {:?}", e);
+                    connection.cancel();
+                    break;
                 }
             }
         }
@@ -286,6 +1191,57 @@ async fn process_server_rdr(
     Ok(())
 }
 
+/// Describes a room for the list shown by `.rooms`, marking those the client is in with a `*`.
+///
+/// # Example
+/// ```
+/// let room = RoomInfo { name: "rust".to_string(), topic: None, members: 2, joined: true };
+/// assert_eq!(describe_room(&room), "  * #rust (2 members)");
+/// ```
+///
+/// This function does not return any errors.
+fn describe_room(room: &RoomInfo) -> String {
+    let marker = if room.joined { '*' } else { ' ' };
+    let mut description = format!("  {} #{} ({} members)", marker, room.name, room.members);
+    if let Some(topic) = &room.topic {
+        description.push_str(&format!(": {}", topic));
+    }
+    description
+}
+
+/// Describes a user for the list shown by `.who`, along with when they went idle or were last seen.
+///
+/// # Example
+/// ```
+/// let user = UserPresence { name: "bob".to_string(), presence: Presence::Offline, last_seen: None };
+/// assert_eq!(describe_user(&user), "  bob is offline, never seen");
+/// ```
+///
+/// This function does not return any errors.
+fn describe_user(user: &UserPresence) -> String {
+    let description = format!("  {} is {}", user.name, user.presence);
+    match (user.presence, user.last_seen) {
+        (Presence::Online, _) => description,
+        (Presence::Away, Some(last_seen)) => format!(
+            "{}, idle since {}",
+            description,
+            delivery::format_timestamp(last_seen)
+        ),
+        (Presence::Offline, Some(last_seen)) => {
+            let last_seen = chrono::DateTime::from_timestamp_millis(last_seen).map_or_else(
+                || last_seen.to_string(),
+                |time| {
+                    time.with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string()
+                },
+            );
+            format!("{}, last seen {}", description, last_seen)
+        }
+        (_, None) => format!("{}, never seen", description),
+    }
+}
+
 /// Saves a byte array as a file locally.
 ///
 /// This function creates a file in the `./files/` directory with the given name and writes the provided data to it.
@@ -380,36 +1336,90 @@ async fn generate_file_name() -> Result<String> {
 /// Manages sending messages to the server.
 ///
 /// This function listens for messages from the stdin task and sends them to the server through the provided stream.
+/// Messages typed by the user take priority over queued transfer frames so chat stays responsive during uploads. When
+/// the server acknowledges messages, chat messages are submitted with a nonce and tracked in `pending` until confirmed.
+/// The server is pinged every `heartbeat_interval`, if given. Once a `Goodbye` has been sent the stream is closed and
+/// the client shut down. Frames of transfers that were started over an earlier connection are dropped, since the
+/// server has no way of picking them up again.
 ///
 /// # Example
 /// ```
 /// let (tx, mut rx) = mpsc::channel::<MessageType>(1024);
-/// let (mut reader, mut writer) = stream.into_split();
+/// let (transfer_tx, mut transfer_rx) = mpsc::channel::<MessageType>(TRANSFER_QUEUE_DEPTH);
+/// let (reader, writer) = tokio::io::split(stream);
 /// let shutdown_token = sync::CancellationToken::new();
-/// process_server_wtr(writer, &mut rx, shutdown_token.clone()).await?;
+/// process_server_wtr(writer, format, &mut rx, &mut transfer_rx, Some(pending), Some(interval), shutdown_token.clone()).await?;
 /// ```
 ///
 /// # Errors
 /// This function returns an error if it fails to send messages over the stream.
-async fn process_server_wtr(
-    mut stream: OwnedWriteHalf,
+async fn process_server_wtr<W: AsyncWriteExt + Unpin>(
+    mut stream: W,
+    format: WireFormat,
     rx: &mut mpsc::Receiver<MessageType>,
+    transfer_rx: &mut mpsc::Receiver<MessageType>,
+    pending: Option<Arc<Mutex<PendingMessages>>>,
+    heartbeat_interval: Option<Duration>,
     shutdown: sync::CancellationToken,
 ) -> Result<()> {
     log::trace!("Starting process: Server Writer.");
 
+    // Only ticks if the server negotiated heartbeats
+    let mut ticker = heartbeat::ticker(heartbeat_interval.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL));
+    let mut pings: u64 = 0;
+
+    // Transfers started over this connection
+    let mut transfers: HashSet<u64> = HashSet::new();
+
     // Wait for messages coming from stdin and process
     loop {
         tokio::select! {
+            biased;
             Some(message) = rx.recv() => {
-                message
-                    .send(&mut stream)
+                let goodbye = matches!(message, MessageType::Goodbye(_));
+                submit(message, &pending)
+                    .send(&mut stream, &format)
                     .await
                     .context("Failed to send message over stream to server.")?;
+
+                // Nothing else is sent after saying goodbye
+                if goodbye {
+                    let _ = stream.shutdown().await;
+                    shutdown.cancel();
+                    break;
+                }
+            }
+            _ = ticker.tick(), if heartbeat_interval.is_some() => {
+                pings += 1;
+                MessageType::Ping(pings)
+                    .send(&mut stream, &format)
+                    .await
+                    .context("Failed to send ping to server.")?;
+            }
+            Some(message) = transfer_rx.recv() => {
+                match &message {
+                    MessageType::TransferStart(_, id, ..) => {
+                        transfers.insert(*id);
+                    }
+                    MessageType::TransferChunk(id, ..)
+                    | MessageType::TransferEnd(id, _)
+                    | MessageType::TransferAbort(id, _)
+                        if !transfers.contains(id) =>
+                    {
+                        if matches!(message, MessageType::TransferEnd(..)) {
+                            log::warn!("Transfer {} was cut off by the lost connection and wasn't delivered", id);
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                submit(message, &pending)
+                    .send(&mut stream, &format)
+                    .await
+                    .context("Failed to send transfer frame over stream to server.")?;
             }
             _ = shutdown.cancelled() => {
                 log::debug!("Shutdown signal received in writer, exiting...");
-                // FIXME: Server shut be notifed client is disconnecting
                 break;
             }
         }
@@ -418,6 +1428,210 @@ async fn process_server_wtr(
     Ok(())
 }
 
+/// Wraps a chat message in a `Submit` envelope and marks it as pending, if the server acknowledges messages.
+///
+/// # Example
+/// ```
+/// submit(message, &pending).send(&mut stream, &format).await?;
+/// ```
+///
+/// This function does not return any errors; messages that aren't submitted are returned unchanged.
+fn submit(message: MessageType, pending: &Option<Arc<Mutex<PendingMessages>>>) -> MessageType {
+    match pending {
+        Some(pending) if delivery::can_submit(&message) => {
+            log::info!("[PENDING] {}", message);
+            pending.lock().unwrap().submit(message)
+        }
+        _ => message,
+    }
+}
+
+/// Streams a local file to the writer task as a chunked transfer.
+///
+/// The file is read `TRANSFER_CHUNK_BYTES` at a time, so only a handful of chunks are ever held in memory regardless
+/// of the file's size. If the file cannot be read to the end the transfer is aborted.
+///
+/// # Example
+/// ```
+/// send_attachment("./test.png", Attachment::Image, &transfer_tx).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to open or read the file, or the writer task has gone away.
+async fn send_attachment(
+    path: &str,
+    attachment: Attachment,
+    tx: &mpsc::Sender<MessageType>,
+) -> Result<()> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path))?;
+    let total = file.metadata().await?.len();
+    let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+
+    log::info!("[SENDING {}] {} bytes from {}", attachment, total, path);
+    tx.send(MessageType::TransferStart(None, id, attachment, total))
+        .await
+        .context("Failed to send message to the writer task")?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; TRANSFER_CHUNK_BYTES];
+    let mut offset = 0u64;
+    loop {
+        let read = match file.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                let _ = tx.send(MessageType::TransferAbort(id, e.to_string())).await;
+                return Err(e).context("Failed to read attachment.");
+            }
+        };
+
+        hasher.update(&buffer[..read]);
+        tx.send(MessageType::TransferChunk(
+            id,
+            offset,
+            buffer[..read].to_vec(),
+        ))
+        .await
+        .context("Failed to send message to the writer task")?;
+        offset += read as u64;
+    }
+
+    if offset != total {
+        let reason = format!("{} changed size while being sent", path);
+        let _ = tx
+            .send(MessageType::TransferAbort(id, reason.clone()))
+            .await;
+        anyhow::bail!(reason);
+    }
+
+    tx.send(MessageType::TransferEnd(id, hasher.finalize()))
+        .await
+        .context("Failed to send message to the writer task")?;
+
+    Ok(())
+}
+
+/// Represents an attachment being received from the server in chunks.
+///
+/// Contents are written to a `.part` file next to their final destination and only renamed into place once the
+/// whole transfer has arrived and its checksum matches.
+struct IncomingTransfer {
+    file: tokio::fs::File,
+    part_path: PathBuf,
+    final_path: PathBuf,
+    hasher: crc32fast::Hasher,
+    received: u64,
+    total: u64,
+}
+
+impl IncomingTransfer {
+    /// Creates the partial file an incoming attachment will be written to.
+    ///
+    /// # Errors
+    /// This function returns an error if it fails to create the destination directory or file.
+    async fn create(attachment: &Attachment, total: u64) -> Result<Self> {
+        let final_path = match attachment {
+            Attachment::File(name) => {
+                let path = Path::new("./files");
+                tokio::fs::create_dir_all(path)
+                    .await
+                    .context("Failed to create directory.")?;
+                // Never trust the sender with anything more than a bare file name
+                path.join(file_name(name)?)
+            }
+            Attachment::Image => PathBuf::from(generate_file_name().await?),
+        };
+        let mut part_path = final_path.clone().into_os_string();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+
+        let file = tokio::fs::File::create(&part_path)
+            .await
+            .with_context(|| format!("Failed to create file: {}", part_path.display()))?;
+
+        Ok(IncomingTransfer {
+            file,
+            part_path,
+            final_path,
+            hasher: crc32fast::Hasher::new(),
+            received: 0,
+            total,
+        })
+    }
+
+    /// Appends a chunk to the partial file.
+    ///
+    /// # Errors
+    /// This function returns an error if the chunk is out of order, overruns the announced size, or cannot be written.
+    async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset != self.received {
+            anyhow::bail!("Expected offset {} but got {}", self.received, offset);
+        }
+        if self.received + data.len() as u64 > self.total {
+            anyhow::bail!("Received more than the {} bytes announced", self.total);
+        }
+
+        self.file
+            .write_all(data)
+            .await
+            .context("Failed to write attachment to local storage.")?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Verifies the completed transfer and moves it into place, returning its final path.
+    ///
+    /// # Errors
+    /// This function returns an error, and removes the partial file, if the transfer is incomplete or corrupt.
+    async fn finish(mut self, checksum: u32) -> Result<PathBuf> {
+        if self.received != self.total || self.hasher.clone().finalize() != checksum {
+            let (received, total) = (self.received, self.total);
+            self.discard().await;
+            anyhow::bail!(
+                "Transfer incomplete or corrupt ({} of {} bytes)",
+                received,
+                total
+            );
+        }
+
+        self.file.flush().await?;
+        tokio::fs::rename(&self.part_path, &self.final_path)
+            .await
+            .context("Failed to move attachment into place.")?;
+
+        Ok(self.final_path)
+    }
+
+    /// Removes the partial file of a transfer that will not be completed.
+    async fn discard(self) {
+        drop(self.file);
+        if let Err(e) = tokio::fs::remove_file(&self.part_path).await {
+            log::debug!("Failed to remove {}: {:?}", self.part_path.display(), e);
+        }
+    }
+}
+
+/// Returns the bare file name component of a path.
+///
+/// # Example
+/// ```
+/// assert_eq!(file_name("../../etc/passwd")?, "passwd");
+/// ```
+///
+/// # Errors
+/// This function returns an error if the path has no file name component.
+fn file_name(path_str: &str) -> Result<String> {
+    Path::new(path_str)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .context("Failed to get file name")
+}
+
 /// Displays client usage helper text.
 ///
 /// This function logs the available commands and their usage for the client.
@@ -436,13 +1650,82 @@ Message broadcast options: \n\
 \t- <message> \n\
 \t- .file <path> \n\
 \t- .image <path> \n\
-\t- .register <account name> \n\
+\t- .register <account name> [password] \n\
+\t- .login <account name> [password] \n\
+\t- .rename <new account name> \n\
+\t- .logout \n\
+\t- .kick <user> \n\
+\t- .ban <user> [duration] \n\
+\t- .mute <user> [duration] \n\
+\t- .unban <user> \n\
+\t- .unmute <user> \n\
+\t- .slowmode <interval|off> [room] \n\
+\t- .join <room> \n\
+\t- .leave [room] \n\
+\t- .rooms \n\
+\t- .topic <text> \n\
+\t- .msg <user> <message|.file <path>|.image <path>> \n\
+\t- .who [user] \n\
 \t- .help \n\
-\t- .quit \n\
+\t- .quit [reason] \n\
 ------------------------------"
     );
 }
 
+/// Splits the arguments of `.register` or `.login` into an account name and, if one was given, a password.
+///
+/// # Example
+/// ```
+/// let (name, password) = credentials("alice hunter22").unwrap();
+/// assert_eq!(password, Some(Password::new("hunter22")));
+/// ```
+///
+/// This function does not return any errors; it returns `None` if no account name was given.
+fn credentials(args: &str) -> Option<(String, Option<Password>)> {
+    let mut args = args.trim().splitn(2, char::is_whitespace);
+    let name = args.next().filter(|name| !name.is_empty())?.to_string();
+    let password = args
+        .next()
+        .map(str::trim)
+        .filter(|password| !password.is_empty())
+        .map(Password::new);
+    Some((name, password))
+}
+
+/// Splits the arguments of `.msg` into the user to send a direct message to and what to send them.
+///
+/// # Example
+/// ```
+/// let (recipient, rest) = direct_message("bob .file notes.txt").unwrap();
+/// assert_eq!((recipient.as_str(), rest), ("bob", ".file notes.txt"));
+/// ```
+///
+/// This function does not return any errors; it returns `None` if no user or nothing to send them was given.
+fn direct_message(args: &str) -> Option<(String, &str)> {
+    let (recipient, rest) = args.trim().split_once(char::is_whitespace)?;
+    let rest = rest.trim();
+    (!rest.is_empty()).then(|| (recipient.to_string(), rest))
+}
+
+/// Asks for the password to an account on the terminal, without echoing what is typed.
+///
+/// # Example
+/// ```
+/// let password = read_password("alice").await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if there is no terminal to ask on, e.g. because input is piped in.
+async fn read_password(account: &str) -> Result<Password> {
+    let prompt = format!("Password for {}: ", account);
+    let password = tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt))
+        .await?
+        .context(
+            "Failed to read a password from the terminal; give it after the account name instead",
+        )?;
+    Ok(Password::new(password))
+}
+
 /// Creates a `MessageType` based on user CLI input.
 ///
 /// This function takes a command and a vector of message parts, and generates the corresponding `MessageType`.
@@ -474,7 +1757,7 @@ async fn generate_message(command: Command, parts: Vec<&str>) -> Result<MessageT
             let account = parts
                 .iter()
                 .skip(1)
-                .map(|s| *s)
+                .copied()
                 .collect::<Vec<&str>>()
                 .join(" ");
             log::debug!("[GENERATING MessageType::Register] {}", &account);
@@ -493,7 +1776,23 @@ async fn generate_message(command: Command, parts: Vec<&str>) -> Result<MessageT
             log::debug!("[GENERATING MessageType::Text] {}", &message);
             MessageType::Text(None, message)
         }
-        Command::Help | Command::Quit => unreachable!(),
+        Command::Ban
+        | Command::Help
+        | Command::Join
+        | Command::Kick
+        | Command::Leave
+        | Command::Login
+        | Command::Logout
+        | Command::Msg
+        | Command::Mute
+        | Command::Rename
+        | Command::Rooms
+        | Command::Slowmode
+        | Command::Topic
+        | Command::Unban
+        | Command::Unmute
+        | Command::Who
+        | Command::Quit => unreachable!(),
     };
     Ok(msg)
 }
@@ -501,7 +1800,6 @@ async fn generate_message(command: Command, parts: Vec<&str>) -> Result<MessageT
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::test;
 
     #[tokio::test]
     async fn text_command_makes_text_messagetype() {
@@ -536,6 +1834,132 @@ mod tests {
         assert_eq!(base_msg, generated_msg);
     }
 
+    #[test]
+    fn logins_are_only_remembered_once_confirmed() {
+        let mut session = Session::default();
+        let alice = Account {
+            name: "alice".to_string(),
+            credentials: Credentials::Password(Password::new("hunter22")),
+        };
+
+        session.pending_login = Some(alice.clone());
+        assert!(!session.login_confirmed("bob"));
+        assert!(session.login_confirmed("alice"));
+        assert_eq!(session.account, Some(alice.clone()));
+
+        // Logging in again after the password was changed elsewhere
+        session.pending_login = Some(alice);
+        session.login_refused();
+        assert_eq!(session.account, None);
+    }
+
+    #[test]
+    fn renames_only_follow_the_logged_in_account() {
+        let mut session = Session {
+            account: Some(Account {
+                name: "alice".to_string(),
+                credentials: Credentials::Password(Password::new("hunter22")),
+            }),
+            ..Session::default()
+        };
+
+        assert!(!session.renamed("bob", "robert"));
+        assert!(session.renamed("alice", "alicia"));
+        assert_eq!(session.account.unwrap().name, "alicia");
+    }
+
+    #[test]
+    fn session_tokens_replace_the_password_until_logging_out() {
+        let mut session = Session::default();
+        let token = Token::generate();
+
+        // Nothing to attach a token to before logging in
+        session.token_issued(token.clone());
+        assert_eq!(session.account, None);
+
+        session.pending_login = Some(Account {
+            name: "alice".to_string(),
+            credentials: Credentials::Password(Password::new("hunter22")),
+        });
+        session.login_confirmed("alice");
+        session.token_issued(token.clone());
+        assert_eq!(
+            session.account.as_ref().map(|account| &account.credentials),
+            Some(&Credentials::Token(token))
+        );
+
+        session.logged_out();
+        assert_eq!(session.account, None);
+    }
+
+    #[test]
+    fn passwords_follow_the_account_name() {
+        let (name, password) = credentials("  alice   correct horse ").unwrap();
+
+        assert_eq!(name, "alice");
+        assert_eq!(password, Some(Password::new("correct horse")));
+        assert_eq!(credentials("bob"), Some(("bob".to_string(), None)));
+        assert_eq!(credentials("  "), None);
+    }
+
+    #[test]
+    fn leaving_without_a_room_leaves_the_current_one() {
+        assert_eq!(
+            room_request(Command::Leave, " ", Some("rust")).unwrap(),
+            RoomRequest::Leave("rust".to_string())
+        );
+        assert!(room_request(Command::Leave, "", None).is_err());
+        assert_eq!(
+            room_request(Command::Join, "#Go", None).unwrap(),
+            RoomRequest::Join("go".to_string())
+        );
+        assert!(room_request(Command::Join, "", Some("rust")).is_err());
+    }
+
+    #[test]
+    fn direct_messages_need_a_recipient_and_something_to_send() {
+        assert_eq!(
+            direct_message("  bob  see you at  noon "),
+            Some(("bob".to_string(), "see you at  noon"))
+        );
+        assert_eq!(direct_message("bob"), None);
+        assert_eq!(direct_message("bob   "), None);
+        assert_eq!(direct_message(""), None);
+    }
+
+    #[test]
+    fn users_are_described_by_presence() {
+        let user = |presence, last_seen| UserPresence {
+            name: "bob".to_string(),
+            presence,
+            last_seen,
+        };
+
+        assert_eq!(
+            describe_user(&user(Presence::Online, Some(0))),
+            "  bob is online"
+        );
+        assert!(
+            describe_user(&user(Presence::Away, Some(0))).starts_with("  bob is away, idle since ")
+        );
+        assert!(describe_user(&user(Presence::Offline, Some(0)))
+            .starts_with("  bob is offline, last seen "));
+        assert_eq!(
+            describe_user(&user(Presence::Offline, None)),
+            "  bob is offline, never seen"
+        );
+    }
+
+    #[test]
+    fn session_remembers_the_newest_message_seen() {
+        let mut session = Session::default();
+
+        session.saw(42);
+        session.saw(7);
+
+        assert_eq!(session.last_seen, Some(42));
+    }
+
     #[tokio::test]
     async fn register_command_does_not_make_text_messagetype() {
         let test_parts = vec![".register", "Timothy"];
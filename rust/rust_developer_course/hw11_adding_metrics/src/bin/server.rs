@@ -1,32 +1,100 @@
 use anyhow::{Context, Result};
 use chrono::Utc;
 use env_logger::{Builder, Env};
-use hw11_rust_metrics::{get_hostname, receive_msg, InternalMessage, MessageType};
+use futures_util::{SinkExt, Stream, StreamExt};
+use hw11_rust_metrics::{
+    accounts::{self, ANONYMOUS_USER},
+    codec,
+    compression::Compression,
+    config::ServerConfig,
+    delivery, direct,
+    events::{self, EventFilter, EVENTS_PATH},
+    filter::FilterChain,
+    get_hostname,
+    handshake::{Features, Handshake, HandshakeReply},
+    heartbeat,
+    history::{self, HistoryQuery, MessagePage, HISTORY_PATH},
+    moderation::{self, Expiry, Moderation, RoleChange},
+    presence::{self, Presence},
+    receive_msg,
+    resume::{self, Replayed},
+    rooms::{self, RoomRequest, DEFAULT_ROOM},
+    session::{Connection, Identity, Session, Sessions},
+    throttle::{Throttle, Traffic},
+    tls,
+    tokens::{self, USERS_PATH},
+    transfer::{Attachment, TransferTracker},
+    ws::{self, WEBSOCKET_PATH},
+    AppError, ErrorCode, FrameKind, FrameStats, InternalMessage, MessageType, WireFormat,
+};
 use hyper::{
-    server::Server,
+    server::{conn::AddrStream, Server},
     service::{make_service_fn, service_fn},
-    {Body, Request, Response},
+    upgrade::Upgraded,
+    {Body, Request, Response, StatusCode},
+};
+use prometheus::{
+    register_counter, register_counter_vec, register_gauge, register_int_counter, Counter,
+    CounterVec, Encoder, Gauge, IntCounter, TextEncoder,
 };
-use lazy_static::lazy_static;
-use prometheus::{register_counter, Counter, Encoder, TextEncoder};
 use sqlx::{migrate::MigrateDatabase, Pool, Row, Sqlite, SqlitePool};
-use std::{env, net::SocketAddr};
+use std::{
+    env, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     self,
-    io::{AsyncReadExt, ErrorKind},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind},
+    net::{TcpListener, TcpStream},
     sync::{self, mpsc},
+    time,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_tungstenite::{
+    tungstenite::{self, protocol::Role, protocol::WebSocketConfig},
+    WebSocketStream,
 };
 
 // Using as lightweight a DB as possible
 const DB_URL: &str = "sqlite://sqlite.db";
 
+// Name the server announces to clients during the handshake
+const SERVER_NAME: &str = concat!("hw11-server/", env!("CARGO_PKG_VERSION"));
+
+// How long a freshly connected client has to complete its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often an idle event feed is sent a keep-alive comment
+const EVENTS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// How often the server checks whether anyone has gone idle
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Broadcasts the server makes of its own accord come from this address, which no client has, so everyone is sent them
+const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+// Server-wide transfer ids, so transfers started by different clients never collide
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
 // Initialize the Prometheus counter in a thread-safe manner
 lazy_static::lazy_static! {
     static ref MESSAGE_COUNTER: Counter = register_counter!("messages_sent_total", "Total number of messages sent").unwrap();
+    static ref REJECTED_FRAME_COUNTER: CounterVec = register_counter_vec!("frames_rejected_total", "Total number of frames rejected for exceeding the size limit", &["kind"]).unwrap();
+    static ref PAYLOAD_RAW_BYTES_COUNTER: CounterVec = register_counter_vec!("payload_raw_bytes_total", "Total payload bytes sent to clients before compression", &["compression"]).unwrap();
+    static ref PAYLOAD_WIRE_BYTES_COUNTER: CounterVec = register_counter_vec!("payload_wire_bytes_total", "Total payload bytes sent to clients after compression", &["compression"]).unwrap();
+    static ref PROTOCOL_ERROR_COUNTER: CounterVec = register_counter_vec!("protocol_errors_total", "Total number of protocol errors reported to clients", &["code"]).unwrap();
+    static ref REPLAYED_MESSAGE_COUNTER: CounterVec = register_counter_vec!("messages_replayed_total", "Total number of stored messages replayed to clients, as backlog on connecting or to resume", &["reason"]).unwrap();
+    static ref MODERATION_COUNTER: CounterVec = register_counter_vec!("moderation_actions_total", "Total number of moderation actions taken", &["action"]).unwrap();
+    static ref THROTTLED_COUNTER: CounterVec = register_counter_vec!("messages_throttled_total", "Total number of messages refused for being sent too quickly", &["limit"]).unwrap();
+    static ref FILTERED_COUNTER: CounterVec = register_counter_vec!("messages_filtered_total", "Total number of messages changed or refused by a filter", &["filter", "verdict"]).unwrap();
+    static ref LOGIN_COUNTER: CounterVec = register_counter_vec!("logins_total", "Total number of attempts to log in or create an account", &["outcome"]).unwrap();
+    static ref ONLINE_USERS: Gauge = register_gauge!("users_online", "Number of logged in users with at least one connection, whether online or away").unwrap();
+    static ref TIMED_OUT_CONNECTIONS: IntCounter = register_int_counter!("connections_timed_out_total", "Number of connections closed because the client stopped responding").unwrap();
 }
 
 /// Entry point for the server application.
@@ -52,23 +120,27 @@ async fn main() -> Result<()> {
     let env = Env::default().filter_or("RUST_LOG", "info");
     Builder::from_env(env).init();
 
+    // Load tunables (frame limits, etc.) from the environment
+    let config = Arc::new(ServerConfig::from_env().context("Failed to load server config")?);
+    log::debug!("Server config: {:?}", config);
+
+    // Load the certificate up front so a bad path fails at startup rather than on the first connection
+    let tls_acceptor = match &config.tls {
+        Some(tls) => Some(tls::server_acceptor(tls).context("Failed to set up TLS")?),
+        None => None,
+    };
+
     // Create sqlite DB if it's not already present
     let db = setup_db().await?;
 
     // Determine the anonymous user's ID
-    let anon_user_id = get_or_create_anon_user_id(&db).await?;
+    let anon_user_id = get_or_create_user_id(ANONYMOUS_USER, &db).await?;
 
     // Process parameters to determine hostname and whatnot for Server
     let args: Vec<String> = env::args().collect();
     let address = get_hostname(args);
     log::info!("Launching server on address: {}", address);
 
-    // Spawn a task to serve the metrics endpoint
-    let metrics_address = ([0, 0, 0, 0], 8081).into();
-    tokio::spawn(async move {
-        serve_metrics(metrics_address).await;
-    });
-
     // Create tokio listener to establish client connections
     let listener = TcpListener::bind(address)
         .await
@@ -77,6 +149,46 @@ async fn main() -> Result<()> {
     // Create broadcast channel to share messages between client connections
     let (br_send, _br_recv) = sync::broadcast::channel(1024);
 
+    // Lets frames be addressed to a user, wherever they are connected
+    let sessions = Sessions::default();
+
+    // Keeps clients from flooding the chat, remembering which rooms moderators put in slowmode
+    let throttle = Throttle::load(&db, config.rate_limits).await?;
+
+    // Load the blocklist up front too, so a bad rule fails at startup
+    let filters = Arc::new(FilterChain::from_config(&config.filters)?);
+    log::info!("Filtering messages with: {:?}", filters.names());
+
+    // Users who stop talking are only noticed going away if somebody looks
+    if config.away_after.is_some() {
+        let br_send = br_send.clone();
+        let db = db.clone();
+        let config = config.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(PRESENCE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                announce_presence(&br_send, None, &db, &config, &sessions).await;
+            }
+        });
+    }
+
+    // Spawn a task to serve the metrics endpoint and the WebSocket gateway
+    let http_address = ([0, 0, 0, 0], 8081).into();
+    let http_state = HttpState {
+        br_send: br_send.clone(),
+        db: db.clone(),
+        config: config.clone(),
+        sessions: sessions.clone(),
+        throttle: throttle.clone(),
+        filters: filters.clone(),
+        anon_user_id,
+    };
+    tokio::spawn(async move {
+        serve_http(http_address, http_state).await;
+    });
+
     // Initiate accept loop for server
     loop {
         // Capture the incoming socket and address; continue looping if connection fails
@@ -87,104 +199,470 @@ async fn main() -> Result<()> {
 
         log::debug!("New client connection: {}", &addr);
 
-        // Clone the send and create a subscriber. Pass these to the task managing writing to this client's tcp stream. This is the heart of the routing mechanism for these messages
-        let sender = br_send.clone();
-        let receiver = sender.subscribe();
-        let db_clone_rdr = db.clone();
-        let db_clone_wtr = db.clone();
-        // Split stream into separate reader and writer; we want independent mut refs to pass to separate tokio tasks
-        let (stream_rdr, mut stream_wtr) = stream.into_split();
-
-        // Channel to handle internal messages
-        let (internal_tx, internal_rx) = mpsc::channel(32);
-        let internal_tx_rdr = internal_tx.clone();
-
-        // Spawn tokio task to manage reading from the client
+        // Hand the connection off to its own task so a slow handshake can't stall the accept loop
+        let br_send = br_send.clone();
+        let db = db.clone();
+        let config = config.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let sessions = sessions.clone();
+        let throttle = throttle.clone();
+        let filters = filters.clone();
         tokio::spawn(async move {
-            process_client_rdr(
-                &sender,
-                stream_rdr,
-                addr,
-                &db_clone_rdr,
-                internal_tx_rdr,
-                anon_user_id,
-            )
-            .await
-            .context("Server error handling the client reader")
-            .unwrap();
+            let result = match tls_acceptor {
+                Some(acceptor) => {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            match authenticate_client_cert(&stream, addr, &db).await {
+                                Ok(session) => {
+                                    let session = session.unwrap_or_else(|| {
+                                        Session::new(addr, anon_user_id, ANONYMOUS_USER)
+                                    });
+                                    handle_connection(
+                                        stream, br_send, db, config, session, sessions, throttle,
+                                        filters,
+                                    )
+                                    .await
+                                }
+                                Err(e) => {
+                                    log::warn!("Rejecting {}: {:?}", addr, e);
+                                    Ok(())
+                                }
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            log::warn!("Rejecting {}: TLS handshake failed: {}", addr, e);
+                            Ok(())
+                        }
+                        Err(_) => {
+                            log::warn!("Rejecting {}: no TLS handshake received in time", addr);
+                            Ok(())
+                        }
+                    }
+                }
+                None => {
+                    let session = Session::new(addr, anon_user_id, ANONYMOUS_USER);
+                    handle_connection(
+                        stream, br_send, db, config, session, sessions, throttle, filters,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Error handling connection from {}: {:?}", addr, e);
+            }
         });
+    }
+}
 
-        // Spawn tokio task to manage writing to the client
-        tokio::spawn(async move {
-            process_client_wtr(receiver, &mut stream_wtr, addr, &db_clone_wtr, internal_rx)
-                .await
-                .context("Server error handling the client writer")
-                .unwrap();
-        });
+/// Logs in a TLS client as the user named by its certificate, if it presented one.
+///
+/// The certificate has already been verified against the configured client CA, so the account is created if this
+/// is the first time it connects. Certificates named after an account that wasn't created for one are refused.
+///
+/// # Example
+/// ```
+/// let session = authenticate_client_cert(&stream, addr, &db).await?.unwrap_or_else(|| Session::new(addr, anon_user_id, ANONYMOUS_USER));
+/// ```
+///
+/// # Errors
+/// This function returns an error if the certificate's subject has no common name, names an account that doesn't
+/// belong to a certificate, or the user can't be looked up.
+async fn authenticate_client_cert(
+    stream: &TlsStream<TcpStream>,
+    addr: SocketAddr,
+    db: &Pool<Sqlite>,
+) -> Result<Option<Session>> {
+    let Some(cert) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    else {
+        return Ok(None);
+    };
+
+    let name = tls::subject_common_name(cert)?;
+    let user_id = accounts::certificate_account(db, &name).await?;
+    log::info!(
+        "{} authenticated by client certificate as {} (user {})",
+        addr,
+        name,
+        user_id
+    );
+
+    Ok(Some(Session::new(addr, user_id, name)))
+}
+
+/// Validates a new client's handshake and, if accepted, spawns its reader and writer tasks.
+///
+/// Clients that fail to send a handshake in time, do not speak the chat protocol, or announce an incompatible
+/// protocol version are sent a rejection frame and dropped before any `MessageType` traffic is exchanged, as are
+/// clients connecting from a banned address or with the certificate of a banned user. The stream is either a plain
+/// TCP connection or one the server has already wrapped in TLS. Accepted connections are listed in `sessions` until
+/// they hang up, held to the rate limits in `throttle`, and have their messages run through `filters`.
+///
+/// # Example
+/// ```
+/// handle_connection(stream, br_send, db, config, session, sessions, throttle, filters).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to read the handshake or write the reply.
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<S>(
+    mut stream: S,
+    br_send: sync::broadcast::Sender<(MessageType, SocketAddr)>,
+    db: Pool<Sqlite>,
+    config: Arc<ServerConfig>,
+    session: Session,
+    sessions: Sessions,
+    throttle: Throttle,
+    filters: Arc<FilterChain>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let addr = session.addr();
+    let handshake = match time::timeout(HANDSHAKE_TIMEOUT, Handshake::recv(&mut stream)).await {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(e)) => {
+            log::warn!("Rejecting {}: invalid handshake: {:?}", addr, e);
+            HandshakeReply::rejected(&e.to_string())
+                .send(&mut stream)
+                .await?;
+            return Ok(());
+        }
+        Err(_) => {
+            log::warn!("Rejecting {}: no handshake received in time", addr);
+            HandshakeReply::rejected("Timed out waiting for handshake")
+                .send(&mut stream)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let (version, handshake) = handshake;
+    let mut reply = handshake.evaluate(version, SERVER_NAME, config.max_frame_bytes);
+    // Banned users are turned away before they can say anything
+    if matches!(reply, HandshakeReply::Accepted { .. }) {
+        let user_id = session.user_id_if_logged_in();
+        if let Some(expiry) = moderation::active_ban(&db, user_id, Some(addr.ip())).await? {
+            reply = HandshakeReply::rejected(&format!("You are banned {}", expiry));
+        }
     }
+    reply.send(&mut stream).await?;
+    let (features, codec, compression) = match reply {
+        HandshakeReply::Accepted {
+            features,
+            codec,
+            compression,
+            ..
+        } => (features, codec, compression),
+        HandshakeReply::Rejected { reason, .. } => {
+            log::warn!("Rejecting {} ({}): {}", addr, handshake.client_name, reason);
+            return Ok(());
+        }
+    };
+    let features = Features::from_names(&features);
+    let session = session.with_features(features);
+    // Clients that don't answer pings can't be expected to keep talking, so only hold heartbeat clients to a timeout
+    let idle_timeout = features.heartbeats.then_some(config.heartbeat.idle_timeout);
+    let heartbeat_interval = config.heartbeat.interval;
+    let backlog_messages = config.backlog_messages;
+    let format = WireFormat {
+        codec: codec::by_name(&codec).context("Negotiated an unsupported codec")?,
+        compression: compression.as_deref().and_then(Compression::by_name),
+        compression_min_bytes: config.compression_min_bytes,
+        max_payload_bytes: config.max_frame_bytes,
+    };
+    log::info!(
+        "Accepted {} from {} using protocol v{}, the {} codec and compression: {}",
+        handshake.client_name,
+        addr,
+        version,
+        format.codec.name(),
+        compression.as_deref().unwrap_or("none")
+    );
+
+    // Clone the send and create a subscriber. Pass these to the task managing writing to this client's tcp stream. This is the heart of the routing mechanism for these messages
+    let sender = br_send.clone();
+    let receiver = sender.subscribe();
+    let db_clone_rdr = db.clone();
+    let db_clone_wtr = db.clone();
+    // Split stream into separate reader and writer; we want independent mut refs to pass to separate tokio tasks
+    let (stream_rdr, mut stream_wtr) = tokio::io::split(stream);
+
+    // Channel to handle internal messages
+    let (internal_tx, internal_rx) = mpsc::channel(32);
+    let internal_tx_rdr = internal_tx.clone();
+
+    // Both tasks need to know who the client is logged in as, and so do moderators
+    let session_wtr = session.clone();
+    sessions.insert(session.clone(), internal_tx);
+
+    // Spawn tokio task to manage reading from the client
+    tokio::spawn(async move {
+        // Clients logged in by certificate are back in the rooms they joined before anything else happens
+        if !session.is_anonymous() {
+            if let Err(e) = restore_rooms(&session, &db_clone_rdr, &internal_tx_rdr).await {
+                log::error!("Failed to restore the rooms of {}: {:?}", addr, e);
+            }
+            announce_presence(&sender, Some(&session), &db_clone_rdr, &config, &sessions).await;
+        }
+
+        let mut transfers = TransferTracker::new(config.max_transfer_bytes);
+        let result = process_client_rdr(
+            &sender,
+            stream_rdr,
+            addr,
+            &db_clone_rdr,
+            internal_tx_rdr,
+            &config,
+            &format,
+            &mut transfers,
+            &session,
+            &sessions,
+            &throttle,
+            &filters,
+            idle_timeout,
+        )
+        .await;
+
+        // The writer stops once nothing is left that could send it a frame
+        sessions.remove(addr);
+        throttle.disconnected(addr);
+        announce_presence(&sender, Some(&session), &db_clone_rdr, &config, &sessions).await;
+
+        // Let everyone receiving this client's unfinished transfers know they won't be completed
+        for (server_id, room) in transfers.abort_all() {
+            let abort = MessageType::TransferAbort(server_id, "Sender disconnected".to_string());
+            let _ = sender.send((MessageType::InRoom(room, Box::new(abort)), addr));
+        }
+
+        if let Err(e) = result {
+            log::error!(
+                "Server error handling the client reader for {}: {:?}",
+                addr,
+                e
+            );
+        }
+    });
+
+    // Spawn tokio task to manage writing to the client
+    tokio::spawn(async move {
+        if let Err(e) = process_client_wtr(
+            receiver,
+            &mut stream_wtr,
+            &session_wtr,
+            &db_clone_wtr,
+            internal_rx,
+            &format,
+            features,
+            heartbeat_interval,
+            backlog_messages,
+        )
+        .await
+        {
+            log::error!(
+                "Server error handling the client writer for {}: {:?}",
+                addr,
+                e
+            );
+        }
+    });
 
     Ok(())
 }
 
 /// Reads and processes incoming messages from a client.
 ///
-/// This function continuously reads messages from a client's TCP stream, processes them, and updates the user ID if
-/// necessary.
+/// This function continuously reads messages from a client's TCP stream and processes them, logging the client's
+/// `session` in or renaming it as they ask. Frames that cannot be decoded are answered with an `Error` frame and
+/// skipped; once a client has made `max_protocol_strikes` such mistakes it is disconnected. Clients that send nothing
+/// for `idle_timeout`, if given, are disconnected too, as are clients whose session is closed by a moderator.
 ///
 /// # Example
 /// ```
-/// process_client_rdr(&sender, client_stream, addr, &db, internal_tx, anon_user_id).await?;
+/// process_client_rdr(&sender, client_stream, addr, &db, internal_tx, &config, &format, &mut transfers, &session, &sessions, &throttle, &filters, idle_timeout).await?;
 /// ```
 ///
 /// # Errors
 /// This function returns an error if it fails to read from the client stream or process messages.
-async fn process_client_rdr(
+#[allow(clippy::too_many_arguments)]
+async fn process_client_rdr<R: AsyncReadExt + Unpin>(
     tx: &sync::broadcast::Sender<(MessageType, SocketAddr)>,
-    mut client_stream: OwnedReadHalf,
+    mut client_stream: R,
     addr: SocketAddr,
     db: &Pool<Sqlite>,
     internal_tx: mpsc::Sender<InternalMessage>,
-    mut user_id: i64,
+    config: &ServerConfig,
+    format: &WireFormat,
+    transfers: &mut TransferTracker,
+    session: &Session,
+    sessions: &Sessions,
+    throttle: &Throttle,
+    filters: &FilterChain,
+    idle_timeout: Option<Duration>,
 ) -> Result<()> {
     log::trace!("Starting process: Client Reader for: {}", &addr);
-    let mut length_bytes = [0; 4];
+
+    // Recoverable protocol errors made by this client so far
+    let mut strikes = 0;
 
     loop {
-        match client_stream
-            .read_exact(&mut length_bytes)
-            .await
-            .context("Failed to read length")
-        {
-            Ok(_) => {
-                let msg_len = u32::from_be_bytes(length_bytes) as usize;
+        let header = tokio::select! {
+            header = heartbeat::read_header(&mut client_stream, idle_timeout) => header,
+            _ = session.closed() => {
+                log::info!("Closing the connection to {} at {}", session.name(), addr);
+                break;
+            }
+        };
+        match header.context("Failed to read frame header") {
+            Ok(header) => {
+                // Refuse oversized frames before allocating a buffer for them
+                let limit = config.frame_limit(header.kind);
+                if header.len > limit {
+                    reject_oversized_frame(
+                        header.kind,
+                        header.len as usize,
+                        limit,
+                        session.user_id(),
+                        addr,
+                        &internal_tx,
+                    )
+                    .await;
+                    break;
+                }
 
                 log::debug!(
-                    "Attempting to retrieve a {}-byte message from {} at {}:",
-                    msg_len.to_string(),
-                    user_id,
+                    "Attempting to retrieve a {}-byte {:?} message from {} at {}:",
+                    header.len,
+                    header.kind,
+                    session.user_id(),
                     addr
                 );
-                let msg = receive_msg(&mut client_stream, msg_len)
-                    .await
-                    .context("Failed to read message")?;
+                let msg = match receive_msg(&mut client_stream, &header, format).await {
+                    Ok(msg) => msg,
+                    Err(e) => match e.downcast::<AppError>() {
+                        // The payload has been consumed, so the stream is still in sync with the client
+                        Ok(AppError::Protocol(code, description)) => {
+                            let strikes_out = record_strike(
+                                &mut strikes,
+                                code,
+                                description,
+                                session.user_id(),
+                                addr,
+                                config,
+                                &internal_tx,
+                            )
+                            .await;
+                            if strikes_out {
+                                break;
+                            }
+                            continue;
+                        }
+                        Ok(e) => return Err(e).context("Failed to read message"),
+                        Err(e) => return Err(e.context("Failed to read message")),
+                    },
+                };
 
-                let updated_msg = process_message(&msg, &mut user_id, db, &internal_tx)
-                    .await
-                    .context("Failed to process message")?;
+                let (nonce, msg) = match delivery::open_submission(&msg) {
+                    Ok(opened) => opened,
+                    Err(AppError::Protocol(code, description)) => {
+                        let strikes_out = record_strike(
+                            &mut strikes,
+                            code,
+                            description,
+                            session.user_id(),
+                            addr,
+                            config,
+                            &internal_tx,
+                        )
+                        .await;
+                        if strikes_out {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e).context("Failed to read message"),
+                };
 
-                if tx.send((updated_msg.clone(), addr)).is_err() {
-                    log::error!(
-                        "Something went wrong sending the message down the broadcast channel..."
-                    );
+                // Submissions and direct messages were only checked against the envelope's limit; hold them to the one
+                // for their contents
+                let limit = config.frame_limit(msg.content().kind());
+                if header.len > limit {
+                    reject_oversized_frame(
+                        msg.kind(),
+                        header.len as usize,
+                        limit,
+                        session.user_id(),
+                        addr,
+                        &internal_tx,
+                    )
+                    .await;
+                    break;
                 }
 
-                // Increment the Prometheus counter)
-                MESSAGE_COUNTER.inc();
+                if let Err(e) = dispatch_message(
+                    msg,
+                    nonce,
+                    tx,
+                    addr,
+                    db,
+                    &internal_tx,
+                    config,
+                    transfers,
+                    session,
+                    sessions,
+                    throttle,
+                    filters,
+                )
+                .await
+                {
+                    report_dispatch_error(
+                        e,
+                        msg.kind(),
+                        nonce,
+                        session.user_id(),
+                        addr,
+                        &internal_tx,
+                    )
+                    .await?;
+                }
 
+                // Hanging up closes the writer too, rather than leaving it waiting for a write to fail
+                if let MessageType::Goodbye(reason) = msg {
+                    log::info!(
+                        "Client {} at {} said goodbye: {}",
+                        session.user_id(),
+                        addr,
+                        reason.as_deref().unwrap_or("no reason given")
+                    );
+                    break;
+                }
                 continue;
             }
             Err(e) => {
-                if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+                if let Some(AppError::IdleTimeout(idle)) = e.downcast_ref::<AppError>() {
+                    log::info!(
+                        "Dropping client {} at {}: nothing received for {:?}",
+                        session.user_id(),
+                        addr,
+                        idle
+                    );
+                    TIMED_OUT_CONNECTIONS.inc();
+                } else if let Some(AppError::Protocol(code, description)) =
+                    e.downcast_ref::<AppError>()
+                {
+                    // Without a known kind we can't trust the rest of the header, so there's no way to resync
+                    log::warn!(
+                        "Dropping client {} at {}: {}",
+                        session.user_id(),
+                        addr,
+                        description
+                    );
+                    send_protocol_error(*code, description.clone(), &internal_tx).await;
+                } else if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
                     match io_err.kind() {
                         ErrorKind::UnexpectedEof => {
                             log::debug!("Client at {} disconnected gracefully.", addr);
@@ -198,7 +676,7 @@ async fn process_client_rdr(
                         _ => {
                             log::error!(
                                 "Error reading from user {} at {}: {:?}\nDropping client.",
-                                user_id,
+                                session.user_id(),
                                 addr,
                                 e
                             );
@@ -207,7 +685,7 @@ async fn process_client_rdr(
                 } else {
                     log::error!(
                         "Error reading from user {} at {}: {:?}\nDropping client.",
-                        user_id,
+                        session.user_id(),
                         addr,
                         e
                     );
@@ -221,291 +699,1938 @@ async fn process_client_rdr(
     Ok(())
 }
 
-/// Processes incoming messages and handles tasks such as database registrations.
+/// Processes a message received from a client and broadcasts the result to every other client.
 ///
-/// This function processes different message types, updating the user ID and storing messages in the database as needed.
+/// Messages submitted with a nonce are acknowledged to their sender once they have been stored. Direct messages aren't
+/// broadcast, but handed to the connections of their recipient and the sender's other connections. Anything but a
+/// heartbeat counts as the user doing something, and everyone is told if that, or logging in or out, changes whether
+/// they are around.
 ///
 /// # Example
 /// ```
-/// let updated_msg = process_message(&msg, &mut user_id, db, &internal_tx).await?;
+/// dispatch_message(&msg, nonce, &sender, addr, &db, &internal_tx, &config, &mut transfers, &session, &sessions, &throttle, &filters).await?;
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to register a new user, retrieve a user ID, or store a message in the
-/// database.
-async fn process_message(
+/// This function returns an error if processing the message fails; see `process_message`.
+#[allow(clippy::too_many_arguments)]
+async fn dispatch_message(
     msg: &MessageType,
-    user_id: &mut i64,
+    nonce: Option<u64>,
+    tx: &sync::broadcast::Sender<(MessageType, SocketAddr)>,
+    addr: SocketAddr,
     db: &Pool<Sqlite>,
     internal_tx: &mpsc::Sender<InternalMessage>,
-) -> Result<MessageType> {
-    match msg {
-        MessageType::Register(account) => {
-            add_user_to_db(account, db)
-                .await
-                .context("Failed to register account and add to the user database")?;
+    config: &ServerConfig,
+    transfers: &mut TransferTracker,
+    session: &Session,
+    sessions: &Sessions,
+    throttle: &Throttle,
+    filters: &FilterChain,
+) -> Result<()> {
+    // Clients send heartbeats on their own, so they don't keep anyone from being away
+    let active = !matches!(msg, MessageType::Ping(..) | MessageType::Pong(..));
+    let returning = active && sessions.presence().presence(&session.name()) == Presence::Away;
+    if active {
+        session.touch(history::now_millis());
+    }
 
-            // Retrieve the new user ID and update the user_id mutable reference
-            if let Some(new_user_id) = get_user_id_by_name(account, db).await? {
-                *user_id = new_user_id;
-                internal_tx
-                    .send(InternalMessage::UserIdUpdate(new_user_id))
-                    .await
-                    .unwrap();
-            }
-            Ok(MessageType::Register(account.clone()))
-        }
-        _ => {
-            let username = get_username_by_id(*user_id, db)
-                .await?
-                .unwrap_or_else(|| "anonymous".to_string());
-            let updated_msg = match msg {
-                MessageType::Text(_, content) => MessageType::Text(Some(username), content.clone()),
-                MessageType::File(_, file_name, data) => {
-                    MessageType::File(Some(username), file_name.clone(), data.clone())
-                }
-                MessageType::Image(_, data) => MessageType::Image(Some(username), data.clone()),
-                MessageType::Register(_) => unreachable!(),
-            };
+    let updated_msg = process_message(
+        msg,
+        nonce,
+        session,
+        db,
+        internal_tx,
+        config,
+        transfers,
+        sessions,
+        throttle,
+        filters,
+    )
+    .await
+    .context("Failed to process message")?;
+
+    let changes_identity = matches!(
+        msg,
+        MessageType::Register(..)
+            | MessageType::CreateAccount(..)
+            | MessageType::Login(..)
+            | MessageType::TokenLogin(..)
+            | MessageType::Logout
+    );
+    if returning || changes_identity {
+        announce_presence(tx, Some(session), db, config, sessions).await;
+    }
+
+    let Some(updated_msg) = updated_msg else {
+        return Ok(());
+    };
 
-            store_message_in_db(&updated_msg, *user_id, db).await?;
-            Ok(updated_msg)
+    let stamped = match &updated_msg {
+        MessageType::InRoom(_, msg) | MessageType::Direct(_, msg) => msg.as_ref(),
+        msg => msg,
+    };
+    if let (Some(nonce), MessageType::Stamped(id, created_at, _)) = (nonce, stamped) {
+        let ack = MessageType::Ack(nonce, *id, *created_at);
+        let _ = internal_tx.send(InternalMessage::Send(ack)).await;
+    }
+
+    // Transfers count once, when they start, rather than once per chunk, and departures and notices aren't messages
+    // at all
+    let counts_as_message = match updated_msg.content() {
+        MessageType::Left(..) | MessageType::Notice(..) => false,
+        msg => !msg.is_transfer() || matches!(msg, MessageType::TransferStart(..)),
+    };
+
+    if let MessageType::Direct(recipient, _) = &updated_msg {
+        for connection in direct::recipients(sessions, recipient, session) {
+            // Older clients wouldn't know what to do with a stamp
+            let msg = if connection.session.features().acks {
+                updated_msg.clone()
+            } else {
+                updated_msg.clone().unstamped()
+            };
+            let _ = connection
+                .internal_tx
+                .send(InternalMessage::Send(msg))
+                .await;
         }
+    } else if tx.send((updated_msg, addr)).is_err() {
+        log::error!("Something went wrong sending the message down the broadcast channel...");
     }
+
+    // Increment the Prometheus counter)
+    if counts_as_message {
+        MESSAGE_COUNTER.inc();
+    }
+
+    Ok(())
 }
 
-/// Manages writing messages to a client.
+/// Tells a client its frame was too large and counts the rejection. The caller is expected to hang up afterwards.
 ///
-/// This function listens for broadcast messages and internal messages, sending the appropriate responses to the client's
-/// TCP stream.
+/// # Example
+/// ```
+/// reject_oversized_frame(header.kind, header.len as usize, limit, user_id, addr, &internal_tx).await;
+/// ```
+async fn reject_oversized_frame(
+    kind: FrameKind,
+    len: usize,
+    limit: u32,
+    user_id: i64,
+    addr: SocketAddr,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) {
+    log::warn!(
+        "Dropping client {} at {}: {}-byte {:?} frame exceeds the {}-byte limit",
+        user_id,
+        addr,
+        len,
+        kind,
+        limit
+    );
+    REJECTED_FRAME_COUNTER
+        .with_label_values(&[&format!("{:?}", kind)])
+        .inc();
+    let description = format!(
+        "{:?} frames are limited to {} bytes but {} were announced",
+        kind, limit, len
+    );
+    // The writer sends the error and exits once we hang up
+    send_protocol_error(ErrorCode::FrameTooLarge, description, internal_tx).await;
+}
+
+/// Reports a message the server failed to handle back to the client that sent it, so that a passing database error
+/// costs the client that message rather than its connection. A message submitted with a `nonce` is answered with a
+/// `Rejected` holding it.
 ///
 /// # Example
 /// ```
-/// process_client_wtr(receiver, &mut stream_wtr, addr, &db, internal_rx).await?;
+/// if let Err(e) = dispatch_message(..).await {
+///     report_dispatch_error(e, msg.kind(), nonce, session.user_id(), addr, &internal_tx).await?;
+/// }
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to send messages over the TCP stream.
-async fn process_client_wtr(
-    mut rx: sync::broadcast::Receiver<(MessageType, SocketAddr)>,
-    stream: &mut OwnedWriteHalf,
+/// This function returns `error` again if it is an I/O or protocol error, or the client's writer has gone, in which
+/// case the connection should be closed.
+async fn report_dispatch_error(
+    error: anyhow::Error,
+    kind: FrameKind,
+    nonce: Option<u64>,
+    user_id: i64,
     addr: SocketAddr,
-    db: &Pool<Sqlite>,
-    mut internal_rx: mpsc::Receiver<InternalMessage>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
 ) -> Result<()> {
-    log::trace!("Starting process: Client Writer for: {}", &addr);
-
-    // Store the current user ID
-    let mut user_id: i64 = 1;
-
-    loop {
-        tokio::select! {
-            // Handle broadcast messages
-            Ok((msg, other_addr)) = rx.recv() => {
-                // If this is the task responsible for sending to the same client the msg came from, ignore
-                if other_addr == addr {
-                    log::debug!(
-                        "Will not broadcast message from: {} to {}. Same client.",
-                        other_addr,
-                        addr
-                    );
-                    continue;
-                }
-
-                // Otherwise send it to their respective TCP Stream
-                match msg.send(stream).await {
-                    Ok(_) => {
-                        log::debug!("Server successfully sent message to: {} at {}", user_id, addr);
-                    }
-                    Err(e) => {
-                        log::error!("Error sending msg to {} tcp stream: {:?}", &addr, e);
-                        log::info!("Server killing client writer task for: {} at {}", user_id, addr);
-                        break;
-                    }
-                }
-            },
-            // Handle internal messages
-            Some(internal_msg) = internal_rx.recv() => {
-                match internal_msg {
-                    InternalMessage::UserIdUpdate(new_user_id) => {
-                        user_id = new_user_id;
-                        log::debug!("Updated user_id to: {}", user_id);
-                    },
-                }
-            }
-        }
+    // Database errors can wrap I/O errors of their own, which say nothing about the client's connection
+    let from_database = error.chain().any(|cause| cause.is::<sqlx::Error>());
+    let fatal = internal_tx.is_closed()
+        || error.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<AppError>(),
+                Some(AppError::Io(..) | AppError::Protocol(..))
+            ) || (cause.is::<io::Error>() && !from_database)
+        });
+    if fatal {
+        return Err(error);
     }
 
+    log::error!(
+        "Failed to handle {:?} from client {} at {}: {:#}",
+        kind,
+        user_id,
+        addr,
+        error
+    );
+    let description = format!("The server failed to handle your {:?} message", kind);
+    refuse(nonce, ErrorCode::Internal, description, internal_tx).await;
     Ok(())
 }
 
-/// Establishes the SQLite database for server use.
+/// Reports a recoverable protocol error back to a client and counts it as a strike against them.
 ///
-/// This function creates the database if it doesn't exist, connects to it, and executes migrations to set up necessary
-/// tables.
+/// Returns `true` once the client has reached `max_protocol_strikes` and should be disconnected, in which case they
+/// have also been sent a `TooManyErrors` error.
 ///
 /// # Example
 /// ```
-/// let db = setup_db().await?;
+/// if record_strike(&mut strikes, code, description, user_id, addr, &config, &internal_tx).await {
+///     break;
+/// }
 /// ```
-///
-/// # Errors
-/// This function returns an error if it fails to create the database, connect to it, or execute migrations.
-async fn setup_db() -> Result<Pool<Sqlite>> {
-    // Create DB if it doesn't already exist
-    if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
-        log::trace!("Entering setup_db");
-        match Sqlite::create_database(DB_URL).await {
-            Ok(_) => {
-                log::info!("New sqlite DB established: {}", DB_URL);
-            }
-            Err(error) => {
-                log::error!("Error creating new DB: {}", &error);
-                // Handle the error state as needed
-            }
-        }
-    }
-
-    // Establish connection to DB
-    let db = SqlitePool::connect(DB_URL)
-        .await
-        .context("Failed to connect to SQLite DB")?;
+async fn record_strike(
+    strikes: &mut u32,
+    code: ErrorCode,
+    description: String,
+    user_id: i64,
+    addr: SocketAddr,
+    config: &ServerConfig,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> bool {
+    *strikes += 1;
+    log::warn!(
+        "Protocol error {}/{} from user {} at {}: {}",
+        strikes,
+        config.max_protocol_strikes,
+        user_id,
+        addr,
+        description
+    );
+    send_protocol_error(code, description, internal_tx).await;
 
-    // Execute migrations
-    let crate_dir =
-        std::env::var("CARGO_MANIFEST_DIR").context("Failed to determine CARGO_MANIFEST_DIR")?;
-    let migrations = std::path::Path::new(&crate_dir).join("migrations");
-    let migrator = sqlx::migrate::Migrator::new(migrations).await.unwrap();
-    match migrator.run(&db).await {
-        Ok(_) => log::info!("Migration success"),
-        Err(error) => {
-            log::error!("Migration error: {:?}", error);
-            return Err(error.into());
-        }
+    if *strikes < config.max_protocol_strikes {
+        return false;
     }
+    log::warn!(
+        "Dropping client {} at {}: too many protocol errors",
+        user_id,
+        addr
+    );
+    let description = format!("Disconnecting after {} protocol errors", strikes);
+    send_protocol_error(ErrorCode::TooManyErrors, description, internal_tx).await;
+    true
+}
 
-    // FIXME: Figure out wtf the messages table isn't being migrated
-    // Manually create the messages table
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            content TEXT NOT NULL,
-            user_id INTEGER NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users(id)
-        );",
-    )
-    .execute(&db)
-    .await
-    .context("Failed to create messages table")?;
-
-    log::info!("Messages table created or already exists");
+/// Reports a protocol error back to a client through its writer task.
+///
+/// # Example
+/// ```
+/// send_protocol_error(ErrorCode::MalformedFrame, "Malformed message payload".to_string(), &internal_tx).await;
+/// ```
+async fn send_protocol_error(
+    code: ErrorCode,
+    description: String,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) {
+    PROTOCOL_ERROR_COUNTER
+        .with_label_values(&[&format!("{:?}", code)])
+        .inc();
 
-    Ok(db)
+    // The writer owns the stream; if it has already gone there is nobody left to tell
+    let error = MessageType::Error(code, description);
+    let _ = internal_tx.send(InternalMessage::Send(error)).await;
 }
 
-/// Adds a user to the database.
+/// Processes incoming messages and handles tasks such as database registrations.
 ///
-/// This function inserts a new user into the database.
+/// This function processes different message types, logging the client's `session` in or renaming it and storing
+/// messages in the database as needed. It returns the message to broadcast to the other clients, or `None` if the
+/// message is not meant for them. Messages from muted users are refused, as are logins by banned users, whose
+/// connection is then closed. Messages sent faster than `throttle` allows are refused too, and the rest are run through
+/// `filters`, which may change or refuse them. Messages are sent to the client's current room, and returned wrapped in
+/// it, unless they are sent directly to a user. Refused submissions are answered with a `Rejected` holding their
+/// `nonce`.
 ///
 /// # Example
 /// ```
-/// add_user_to_db("username", &db).await?;
+/// let updated_msg = process_message(&msg, nonce, &session, db, &internal_tx, &config, &mut transfers, &sessions, &throttle, &filters).await?;
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to insert the user into the database.
-async fn add_user_to_db(account: &str, db: &Pool<Sqlite>) -> Result<()> {
-    sqlx::query("INSERT INTO users (name) VALUES (?)")
-        .bind(account)
-        .execute(db)
-        .await
+/// This function returns an error if it fails to register a new user, retrieve a user ID, or store a message in the
+/// database. Logins, renames and moderation actions that are refused are reported to the client rather than returned.
+#[allow(clippy::too_many_arguments)]
+async fn process_message(
+    msg: &MessageType,
+    nonce: Option<u64>,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    config: &ServerConfig,
+    transfers: &mut TransferTracker,
+    sessions: &Sessions,
+    throttle: &Throttle,
+    filters: &FilterChain,
+) -> Result<Option<MessageType>> {
+    match msg {
+        MessageType::Register(account) => {
+            // Registering only ever creates a new account, leaving the connection anonymous if the name is taken
+            let new_user_id = match accounts::register(db, account).await {
+                Ok(user_id) => user_id,
+                Err(e) => match e.downcast::<AppError>() {
+                    Ok(AppError::Auth(code, description)) => {
+                        log::warn!(
+                            "User {} failed to register as {}: {}",
+                            session.user_id(),
+                            account,
+                            description
+                        );
+                        let error = MessageType::Error(code, description);
+                        let _ = internal_tx.send(InternalMessage::Send(error)).await;
+                        return Ok(None);
+                    }
+                    Ok(e) => return Err(e).context("Failed to register account"),
+                    Err(e) => return Err(e.context("Failed to register account")),
+                },
+            };
+            if refuse_if_banned(new_user_id, session, db, internal_tx).await? {
+                return Ok(None);
+            }
+            session.log_in(new_user_id, account.clone());
+            restore_rooms(session, db, internal_tx).await?;
+            Ok(Some(MessageType::Register(account.clone())))
+        }
+        MessageType::CreateAccount(..) | MessageType::Login(..) | MessageType::TokenLogin(..) => {
+            // Logins with a token bring the id it was stored with; the others may be issued one
+            let result = match msg {
+                MessageType::CreateAccount(account, password) => {
+                    // Only the client logged into an account without a password may give it one
+                    let holder = session.user_id_if_logged_in();
+                    accounts::create_account(db, account, password, holder)
+                        .await
+                        .map(|user_id| (None, Identity::new(user_id, account)))
+                }
+                MessageType::Login(account, password) => {
+                    accounts::login(db, account, password, &config.lockout)
+                        .await
+                        .map(|user_id| (None, Identity::new(user_id, account)))
+                }
+                MessageType::TokenLogin(token) => tokens::redeem(db, token)
+                    .await
+                    .map(|(token_id, identity)| (Some(token_id), identity)),
+                _ => unreachable!(),
+            };
+            let (token_id, identity) = match result {
+                Ok(login) => login,
+                Err(e) => match e.downcast::<AppError>() {
+                    Ok(AppError::Auth(code, description)) => {
+                        log::warn!(
+                            "User {} failed to log in ({}): {}",
+                            session.user_id(),
+                            msg,
+                            description
+                        );
+                        LOGIN_COUNTER
+                            .with_label_values(&[&format!("{:?}", code)])
+                            .inc();
+                        let error = MessageType::Error(code, description);
+                        let _ = internal_tx.send(InternalMessage::Send(error)).await;
+                        return Ok(None);
+                    }
+                    Ok(e) => return Err(e).context("Failed to log in"),
+                    Err(e) => return Err(e.context("Failed to log in")),
+                },
+            };
+            if refuse_if_banned(identity.user_id, session, db, internal_tx).await? {
+                LOGIN_COUNTER
+                    .with_label_values(&[&format!("{:?}", ErrorCode::Banned)])
+                    .inc();
+                return Ok(None);
+            }
+            log::info!("User {} logged in as {}", session.user_id(), identity.name);
+            LOGIN_COUNTER.with_label_values(&["Success"]).inc();
+
+            session.log_in(identity.user_id, identity.name.clone());
+            // The sender is told it worked the same way everyone else is told who joined
+            let registered = MessageType::Register(identity.name.clone());
+            let _ = internal_tx
+                .send(InternalMessage::Send(registered.clone()))
+                .await;
+
+            match token_id {
+                Some(token_id) => session.set_token_id(token_id),
+                None if session.features().tokens => {
+                    let (token_id, token, expires_at) =
+                        tokens::issue(db, identity.user_id, config.session_token_ttl).await?;
+                    session.set_token_id(token_id);
+                    let token = MessageType::SessionToken(token, expires_at);
+                    let _ = internal_tx.send(InternalMessage::Send(token)).await;
+                }
+                None => {}
+            }
+            restore_rooms(session, db, internal_tx).await?;
+            Ok(Some(registered))
+        }
+        MessageType::Logout => {
+            if session.is_anonymous() {
+                let description = "You aren't logged in".to_string();
+                let error = MessageType::Error(ErrorCode::AuthFailed, description);
+                let _ = internal_tx.send(InternalMessage::Send(error)).await;
+                return Ok(None);
+            }
+            if let Some(token_id) = session.token_id() {
+                tokens::revoke(db, token_id).await?;
+            }
+            log::info!(
+                "User {} logged out of {} at {}",
+                session.user_id(),
+                session.name(),
+                session.addr()
+            );
+
+            let anon_user_id = get_or_create_user_id(ANONYMOUS_USER, db).await?;
+            session.log_in(anon_user_id, ANONYMOUS_USER);
+            let _ = internal_tx
+                .send(InternalMessage::Send(MessageType::Logout))
+                .await;
+            restore_rooms(session, db, internal_tx).await?;
+            Ok(None)
+        }
+        MessageType::Rename(new_name) => {
+            if session.is_anonymous() {
+                let description = "Log in before picking a new name".to_string();
+                let error = MessageType::Error(ErrorCode::AuthFailed, description);
+                let _ = internal_tx.send(InternalMessage::Send(error)).await;
+                return Ok(None);
+            }
+            let old_name = session.name();
+            if *new_name == old_name {
+                return Ok(None);
+            }
+
+            if let Err(e) = accounts::rename(db, session.user_id(), new_name).await {
+                match e.downcast::<AppError>() {
+                    Ok(AppError::Auth(code, description)) => {
+                        log::warn!(
+                            "User {} failed to rename {} to {}: {}",
+                            session.user_id(),
+                            old_name,
+                            new_name,
+                            description
+                        );
+                        let error = MessageType::Error(code, description);
+                        let _ = internal_tx.send(InternalMessage::Send(error)).await;
+                        return Ok(None);
+                    }
+                    Ok(e) => return Err(e).context("Failed to rename user"),
+                    Err(e) => return Err(e.context("Failed to rename user")),
+                }
+            }
+            log::info!(
+                "User {} renamed from {} to {}",
+                session.user_id(),
+                old_name,
+                new_name
+            );
+
+            session.rename(new_name.clone());
+            sessions.presence().rename(&old_name, new_name);
+            // The sender hears about it the same way everyone else does
+            let renamed = MessageType::Renamed(old_name, new_name.clone());
+            let _ = internal_tx
+                .send(InternalMessage::Send(renamed.clone()))
+                .await;
+            Ok(Some(renamed))
+        }
+        MessageType::Moderate(action) => {
+            moderate(action, session, db, internal_tx, sessions, throttle).await
+        }
+        MessageType::Room(request) => {
+            change_rooms(request, session, db, internal_tx, sessions, filters).await
+        }
+        MessageType::Direct(recipient, msg) => {
+            send_direct(
+                recipient,
+                msg,
+                nonce,
+                session,
+                db,
+                internal_tx,
+                sessions,
+                throttle,
+                filters,
+            )
+            .await
+        }
+        MessageType::Who(user) => {
+            send_users(user.as_deref(), session, db, internal_tx, config, sessions).await?;
+            Ok(None)
+        }
+        MessageType::Error(code, description) => {
+            log::warn!(
+                "User {} reported an error: {:?}: {}",
+                session.user_id(),
+                code,
+                description
+            );
+            Ok(None)
+        }
+        MessageType::TransferStart(_, client_id, attachment, total) => {
+            // The rest of the transfer is ignored, rather than refused frame by frame
+            if refuse_if_muted(None, session, db, internal_tx).await?
+                || refuse_if_throttled(
+                    Traffic::Attachment,
+                    None,
+                    session,
+                    db,
+                    internal_tx,
+                    throttle,
+                )
+                .await?
+            {
+                transfers.fail(*client_id);
+                return Ok(None);
+            }
+            if filter_message(msg.clone(), None, session, internal_tx, filters)
+                .await
+                .is_none()
+            {
+                transfers.fail(*client_id);
+                return Ok(None);
+            }
+            let server_id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+            let room = session.room();
+            if let Err(e) = transfers.start(
+                *client_id,
+                server_id,
+                attachment.clone(),
+                *total,
+                room.clone(),
+            ) {
+                return reject_transfer(*client_id, e, transfers, internal_tx).await;
+            }
+
+            let username = session.name();
+            let start =
+                MessageType::TransferStart(Some(username), server_id, attachment.clone(), *total);
+            Ok(Some(MessageType::InRoom(room, Box::new(start))))
+        }
+        // The rest of a transfer goes to the room it was started in, even if the sender has moved on
+        MessageType::TransferChunk(client_id, offset, data) => {
+            let room = transfers.room(*client_id).map(str::to_string);
+            match transfers.chunk(*client_id, *offset, data) {
+                Ok(Some(server_id)) => {
+                    let chunk = MessageType::TransferChunk(server_id, *offset, data.clone());
+                    Ok(room.map(|room| MessageType::InRoom(room, Box::new(chunk))))
+                }
+                Ok(None) => Ok(None),
+                Err(e) => reject_transfer(*client_id, e, transfers, internal_tx).await,
+            }
+        }
+        MessageType::TransferEnd(client_id, checksum) => {
+            let room = transfers.room(*client_id).map(str::to_string);
+            match (transfers.finish(*client_id, *checksum), room) {
+                (Ok(Some((server_id, attachment))), Some(room)) => {
+                    let (id, created_at) =
+                        store_attachment_in_db(&attachment, session.user_id(), &room, None, db)
+                            .await?;
+                    let end = MessageType::TransferEnd(server_id, *checksum);
+                    let stamped = MessageType::Stamped(id, created_at, Box::new(end));
+                    Ok(Some(MessageType::InRoom(room, Box::new(stamped))))
+                }
+                // The sender was told why when the transfer failed, but still waits to hear about its submission
+                (Ok(_), _) => {
+                    if nonce.is_some() {
+                        let description = format!("Transfer {} was refused", client_id);
+                        refuse(nonce, ErrorCode::InvalidTransfer, description, internal_tx).await;
+                    }
+                    Ok(None)
+                }
+                (Err(e), _) => {
+                    if nonce.is_some() {
+                        refuse(
+                            nonce,
+                            ErrorCode::InvalidTransfer,
+                            e.to_string(),
+                            internal_tx,
+                        )
+                        .await;
+                    }
+                    reject_transfer(*client_id, e, transfers, internal_tx).await
+                }
+            }
+        }
+        MessageType::TransferAbort(client_id, reason) => {
+            log::info!(
+                "User {} aborted transfer {}: {}",
+                session.user_id(),
+                client_id,
+                reason
+            );
+            let room = transfers.room(*client_id).map(str::to_string);
+            Ok(transfers
+                .abort(*client_id)
+                .zip(room)
+                .map(|(server_id, room)| {
+                    let abort = MessageType::TransferAbort(server_id, reason.clone());
+                    MessageType::InRoom(room, Box::new(abort))
+                }))
+        }
+        MessageType::Ping(nonce) => {
+            let _ = internal_tx
+                .send(InternalMessage::Send(MessageType::Pong(*nonce)))
+                .await;
+            Ok(None)
+        }
+        // Any frame at all keeps the connection alive, so there is nothing more to do with an answered ping
+        MessageType::Pong(_) => Ok(None),
+        // The writer replays what was missed, so nothing it sends from then on is repeated
+        MessageType::Resume(last_seen) => {
+            let _ = internal_tx.send(InternalMessage::Resume(*last_seen)).await;
+            Ok(None)
+        }
+        MessageType::Goodbye(reason) => {
+            // Clients forget their session token when they quit, so there is no point keeping it valid
+            if let Some(token_id) = session.token_id() {
+                tokens::revoke(db, token_id).await?;
+            }
+            let username = session.name();
+            Ok(Some(MessageType::Left(username, reason.clone())))
+        }
+        MessageType::Submit(..)
+        | MessageType::Stamped(..)
+        | MessageType::Ack(..)
+        | MessageType::Left(..)
+        | MessageType::History(..)
+        | MessageType::Renamed(..)
+        | MessageType::SessionToken(..)
+        | MessageType::Notice(..)
+        | MessageType::Rooms(..)
+        | MessageType::InRoom(..)
+        | MessageType::Users(..)
+        | MessageType::Presence(..)
+        | MessageType::Rejected(..) => {
+            // Envelopes are opened, and frames only the server sends are rejected, by the readers
+            log::warn!(
+                "Ignoring unexpected {:?} from user {}",
+                msg.kind(),
+                session.user_id()
+            );
+            Ok(None)
+        }
+        MessageType::Text(..) | MessageType::File(..) | MessageType::Image(..) => {
+            let traffic = Traffic::of(msg).unwrap_or(Traffic::Text);
+            if refuse_if_muted(nonce, session, db, internal_tx).await?
+                || refuse_if_throttled(traffic, nonce, session, db, internal_tx, throttle).await?
+            {
+                return Ok(None);
+            }
+            let updated_msg = signed(msg, session.name());
+            let Some(updated_msg) =
+                filter_message(updated_msg, nonce, session, internal_tx, filters).await
+            else {
+                return Ok(None);
+            };
+
+            let room = session.room();
+            Ok(
+                store_message_in_db(&updated_msg, session.user_id(), &room, None, db)
+                    .await?
+                    .map(|(id, created_at)| {
+                        let stamped = MessageType::Stamped(id, created_at, Box::new(updated_msg));
+                        MessageType::InRoom(room, Box::new(stamped))
+                    }),
+            )
+        }
+    }
+}
+
+/// Returns a text, file or image message as sent by the user `username`.
+///
+/// # Example
+/// ```
+/// let updated_msg = signed(&MessageType::Text(None, "Hello".to_string()), session.name());
+/// ```
+fn signed(msg: &MessageType, username: String) -> MessageType {
+    match msg {
+        MessageType::Text(_, content) => MessageType::Text(Some(username), content.clone()),
+        MessageType::File(_, file_name, data) => {
+            MessageType::File(Some(username), file_name.clone(), data.clone())
+        }
+        MessageType::Image(_, data) => MessageType::Image(Some(username), data.clone()),
+        other => other.clone(),
+    }
+}
+
+/// Stores a message sent directly to the user `recipient`, returning it stamped and in its `Direct` envelope for
+/// `dispatch_message` to deliver.
+///
+/// Only users who are logged in can send direct messages, since nobody could answer anonymous ones; the rest are
+/// answered with `Error(AuthFailed, ..)`. Messages to users that don't exist, or that have no connection able to
+/// receive them, are answered with `Error(UnknownUser, ..)`. Otherwise direct messages are muted, throttled and
+/// filtered like any other.
+///
+/// # Example
+/// ```
+/// MessageType::Direct(recipient, msg) => send_direct(recipient, msg, nonce, session, db, internal_tx, sessions, throttle, filters).await,
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried or the message can't be stored.
+#[allow(clippy::too_many_arguments)]
+async fn send_direct(
+    recipient: &str,
+    msg: &MessageType,
+    nonce: Option<u64>,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    sessions: &Sessions,
+    throttle: &Throttle,
+    filters: &FilterChain,
+) -> Result<Option<MessageType>> {
+    if !session.features().direct_messages {
+        log::warn!(
+            "Ignoring direct message from {}, which didn't negotiate direct messages",
+            session.addr()
+        );
+        return Ok(None);
+    }
+    if !direct::can_send(msg) {
+        let description = format!("{:?} messages can't be sent directly", msg.kind());
+        send_protocol_error(ErrorCode::UnexpectedFrameKind, description, internal_tx).await;
+        return Ok(None);
+    }
+    if session.is_anonymous() {
+        let description = "Log in before sending direct messages".to_string();
+        refuse(nonce, ErrorCode::AuthFailed, description, internal_tx).await;
+        return Ok(None);
+    }
+
+    let recipient_id = match get_user_id_by_name(recipient, db).await? {
+        Some(recipient_id) if recipient != ANONYMOUS_USER => recipient_id,
+        _ => {
+            let description = format!("There is no user named '{}'", recipient);
+            refuse(nonce, ErrorCode::UnknownUser, description, internal_tx).await;
+            return Ok(None);
+        }
+    };
+    let reachable = direct::recipients(sessions, recipient, session)
+        .iter()
+        .any(|connection| connection.session.name() == recipient);
+    if !reachable {
+        let description = format!(
+            "{} isn't connected, or can't receive direct messages",
+            recipient
+        );
+        refuse(nonce, ErrorCode::UnknownUser, description, internal_tx).await;
+        return Ok(None);
+    }
+
+    let traffic = Traffic::of(msg).unwrap_or(Traffic::Text);
+    if refuse_if_muted(nonce, session, db, internal_tx).await?
+        || refuse_if_throttled(traffic, nonce, session, db, internal_tx, throttle).await?
+    {
+        return Ok(None);
+    }
+    let Some(updated_msg) = filter_message(
+        signed(msg, session.name()),
+        nonce,
+        session,
+        internal_tx,
+        filters,
+    )
+    .await
+    else {
+        return Ok(None);
+    };
+
+    log::info!("{} sent a direct message to {}", session.name(), recipient);
+    let stored = store_message_in_db(
+        &updated_msg,
+        session.user_id(),
+        &session.room(),
+        Some(recipient_id),
+        db,
+    )
+    .await?;
+    Ok(stored.map(|(id, created_at)| {
+        let stamped = MessageType::Stamped(id, created_at, Box::new(updated_msg));
+        MessageType::Direct(recipient.to_string(), Box::new(stamped))
+    }))
+}
+
+/// Carries out a moderation action sent by a moderator or admin, and tells them how it went with a `Notice`.
+///
+/// Only users with a higher role than the target's may act against them. Kicked and banned users are sent an `Error`
+/// saying so and disconnected everywhere they are connected, which is broadcast as them leaving; bans also cover the
+/// addresses they were connected from. Slowmode is set on a room rather than a user, and is announced in the room.
+/// Every action is recorded in the audit log. Actions that are refused are reported to the sender rather than
+/// returned.
+///
+/// # Example
+/// ```
+/// MessageType::Moderate(action) => moderate(action, session, db, internal_tx, sessions, throttle).await,
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be read or updated.
+async fn moderate(
+    action: &Moderation,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    sessions: &Sessions,
+    throttle: &Throttle,
+) -> Result<Option<MessageType>> {
+    let actor = session.name();
+    let actor_role = if session.is_anonymous() {
+        moderation::Role::User
+    } else {
+        moderation::role(db, session.user_id()).await?
+    };
+    if actor_role < moderation::Role::Moderator {
+        log::warn!("User {} isn't allowed to {}", actor, action);
+        let description = "Only moderators can do that".to_string();
+        send_error(ErrorCode::Forbidden, description, internal_tx).await;
+        return Ok(None);
+    }
+
+    if let Moderation::Slowmode(room, secs) = action {
+        let interval = (*secs > 0).then(|| Duration::from_secs(*secs));
+        throttle.set_slowmode(db, room, interval, &actor).await?;
+        let outcome = match interval {
+            Some(_) => format!(
+                "{} put {} in slowmode: one message every {}s",
+                actor, room, secs
+            ),
+            None => format!("{} turned off slowmode in {}", actor, room),
+        };
+        let detail = interval.map(|_| format!("{}s", secs));
+        moderation::record(db, &actor, action.action(), room, detail.as_deref()).await?;
+        MODERATION_COUNTER
+            .with_label_values(&[action.action()])
+            .inc();
+        notify_sender(outcome.clone(), internal_tx).await;
+        let notice = MessageType::Notice(outcome);
+        return Ok(Some(MessageType::InRoom(room.clone(), Box::new(notice))));
+    }
+
+    let target = action.target();
+    let target_id = match get_user_id_by_name(target, db).await? {
+        Some(target_id) if target != ANONYMOUS_USER => target_id,
+        _ => {
+            let description = format!("There is no user called {}", target);
+            send_error(ErrorCode::UnknownUser, description, internal_tx).await;
+            return Ok(None);
+        }
+    };
+    let target_role = moderation::role(db, target_id).await?;
+    if target_role >= actor_role {
+        log::warn!("User {} isn't allowed to {}", actor, action);
+        let description = format!(
+            "{} is a {} and can't be moderated by you",
+            target, target_role
+        );
+        send_error(ErrorCode::Forbidden, description, internal_tx).await;
+        return Ok(None);
+    }
+
+    let connections = sessions.of_user(target);
+    let (outcome, detail, broadcast) = match action {
+        Moderation::Kick(_) => {
+            if connections.is_empty() {
+                let description = format!("{} isn't connected", target);
+                send_error(ErrorCode::UnknownUser, description, internal_tx).await;
+                return Ok(None);
+            }
+            let description = format!("You were kicked by {}", actor);
+            shut_out(&connections, ErrorCode::Kicked, description).await;
+            let left = MessageType::Left(target.to_string(), Some(format!("kicked by {}", actor)));
+            (format!("Kicked {}", target), None, Some(left))
+        }
+        Moderation::Ban(_, secs) => {
+            let expiry = Expiry::after(*secs);
+            let mut ips: Vec<IpAddr> = connections
+                .iter()
+                .map(|connection| connection.session.addr().ip())
+                .collect();
+            ips.sort();
+            ips.dedup();
+            moderation::ban(db, target_id, &ips, expiry, &actor).await?;
+
+            let description = format!("You were banned by {} {}", actor, expiry);
+            shut_out(&connections, ErrorCode::Banned, description).await;
+            let left = (!connections.is_empty()).then(|| {
+                MessageType::Left(target.to_string(), Some(format!("banned by {}", actor)))
+            });
+            (
+                format!("Banned {} {}", target, expiry),
+                Some(expiry.to_string()),
+                left,
+            )
+        }
+        Moderation::Mute(_, secs) => {
+            let expiry = Expiry::after(*secs);
+            moderation::mute(db, target_id, expiry, &actor).await?;
+            notify(
+                &connections,
+                format!("You were muted by {} {}", actor, expiry),
+            )
+            .await;
+            (
+                format!("Muted {} {}", target, expiry),
+                Some(expiry.to_string()),
+                None,
+            )
+        }
+        Moderation::Unban(_) => {
+            if !moderation::unban(db, target_id).await? {
+                notify_sender(format!("{} isn't banned", target), internal_tx).await;
+                return Ok(None);
+            }
+            (format!("Unbanned {}", target), None, None)
+        }
+        Moderation::Unmute(_) => {
+            if !moderation::unmute(db, target_id).await? {
+                notify_sender(format!("{} isn't muted", target), internal_tx).await;
+                return Ok(None);
+            }
+            notify(&connections, format!("{} let you speak again", actor)).await;
+            (format!("Unmuted {}", target), None, None)
+        }
+        Moderation::Slowmode(..) => unreachable!(),
+    };
+
+    moderation::record(db, &actor, action.action(), target, detail.as_deref()).await?;
+    MODERATION_COUNTER
+        .with_label_values(&[action.action()])
+        .inc();
+    notify_sender(outcome, internal_tx).await;
+
+    Ok(broadcast)
+}
+
+/// Joins, leaves or lists rooms, or sets the topic of the client's current room, and sends the client the updated list
+/// of rooms.
+///
+/// Joining a room, which creates it if need be, sends the client's messages there from then on, and has its recent
+/// messages sent as backlog. Logged in users are remembered as members of the rooms they join, and their other
+/// connections join and leave along with this one. Topics are run through `filters` like any other message. Joins,
+/// departures and new topics are announced in the room with a `Notice`, which is returned.
+///
+/// # Example
+/// ```
+/// MessageType::Room(request) => change_rooms(request, session, db, internal_tx, sessions, filters).await,
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be read or updated.
+async fn change_rooms(
+    request: &RoomRequest,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    sessions: &Sessions,
+    filters: &FilterChain,
+) -> Result<Option<MessageType>> {
+    // Clients that can't tell rooms apart are kept in the lobby
+    if !session.features().rooms {
+        log::warn!(
+            "Ignoring room request from {}, which didn't negotiate rooms",
+            session.addr()
+        );
+        return Ok(None);
+    }
+
+    let name = session.name();
+    let user_id = session.user_id_if_logged_in();
+    let others: Vec<Connection> = match user_id {
+        Some(_) => sessions
+            .of_user(&name)
+            .into_iter()
+            .filter(|other| {
+                other.session.addr() != session.addr() && other.session.features().rooms
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let announcement = match request {
+        RoomRequest::List => None,
+        RoomRequest::Join(room) | RoomRequest::Leave(room) => {
+            let room = match rooms::room_name(room) {
+                Ok(room) => room,
+                Err(e) => {
+                    send_error(ErrorCode::Rejected, e.to_string(), internal_tx).await;
+                    return Ok(None);
+                }
+            };
+            if let RoomRequest::Join(_) = request {
+                let joining = !session.in_room(&room);
+                if rooms::join(db, user_id, &room, &name).await? {
+                    log::info!("{} created room {}", name, room);
+                }
+                session.enter_room(room.clone());
+                if joining {
+                    let _ = internal_tx
+                        .send(InternalMessage::Joined(room.clone()))
+                        .await;
+                }
+                for other in others.iter().filter(|other| !other.session.in_room(&room)) {
+                    other.session.join_room(room.clone());
+                    let _ = other
+                        .internal_tx
+                        .send(InternalMessage::Joined(room.clone()))
+                        .await;
+                    send_rooms(&other.session, db, &other.internal_tx).await?;
+                }
+                joining.then(|| (room.clone(), format!("{} joined #{}", name, room)))
+            } else {
+                if !session.leave_room(&room) {
+                    let description = if room == DEFAULT_ROOM {
+                        "Everyone stays in the lobby".to_string()
+                    } else {
+                        format!("You aren't in #{}", room)
+                    };
+                    send_error(ErrorCode::Rejected, description, internal_tx).await;
+                    return Ok(None);
+                }
+                if let Some(user_id) = user_id {
+                    rooms::leave(db, user_id, &room).await?;
+                }
+                for other in others
+                    .iter()
+                    .filter(|other| other.session.leave_room(&room))
+                {
+                    send_rooms(&other.session, db, &other.internal_tx).await?;
+                }
+                Some((room.clone(), format!("{} left #{}", name, room)))
+            }
+        }
+        RoomRequest::Topic(topic) => {
+            if refuse_if_muted(None, session, db, internal_tx).await? {
+                return Ok(None);
+            }
+            let room = session.room();
+            let text = MessageType::Text(Some(name.clone()), topic.clone());
+            let Some(MessageType::Text(_, topic)) =
+                filter_message(text, None, session, internal_tx, filters).await
+            else {
+                return Ok(None);
+            };
+            rooms::set_topic(db, &room, &topic).await?;
+            let announcement = match topic.trim() {
+                "" => format!("{} cleared the topic of #{}", name, room),
+                topic => format!("{} set the topic of #{}: {}", name, room, topic),
+            };
+            Some((room, announcement))
+        }
+    };
+    log::info!("{} at {}: {}", name, session.addr(), request);
+
+    send_rooms(session, db, internal_tx).await?;
+    Ok(announcement
+        .map(|(room, text)| MessageType::InRoom(room, Box::new(MessageType::Notice(text)))))
+}
+
+/// Puts a client that just logged in or out in the rooms its user joined before, replaying their recent messages, and
+/// sends it the list of rooms. Clients that didn't negotiate rooms stay in the lobby.
+///
+/// # Example
+/// ```
+/// session.log_in(identity.user_id, identity.name.clone());
+/// restore_rooms(session, db, internal_tx).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn restore_rooms(
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> Result<()> {
+    if !session.features().rooms {
+        return Ok(());
+    }
+    if !session.is_anonymous() {
+        let joined = rooms::memberships(db, session.user_id()).await?;
+        session.set_rooms(joined.clone());
+        for room in joined {
+            let _ = internal_tx.send(InternalMessage::Joined(room)).await;
+        }
+    }
+    send_rooms(session, db, internal_tx).await
+}
+
+/// Sends a client the list of rooms, and which of them it is talking in.
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn send_rooms(
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> Result<()> {
+    let list = rooms::list(db, session).await?;
+    let reply = MessageType::Rooms(session.room(), list);
+    let _ = internal_tx.send(InternalMessage::Send(reply)).await;
+    Ok(())
+}
+
+/// Tells a client who is online and who is away or, if it asked about a single user, whether they are around and when
+/// they were last seen.
+///
+/// # Example
+/// ```
+/// MessageType::Who(user) => send_users(user.as_deref(), session, db, internal_tx, config, sessions).await?,
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn send_users(
+    user: Option<&str>,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    config: &ServerConfig,
+    sessions: &Sessions,
+) -> Result<()> {
+    if !session.features().presence {
+        log::warn!(
+            "Ignoring presence request from {}, which didn't negotiate presence",
+            session.addr()
+        );
+        return Ok(());
+    }
+
+    let mut users = presence::online(sessions, config.away_after, history::now_millis());
+    if let Some(name) = user {
+        users.retain(|user| user.name == name);
+        if users.is_empty() {
+            match presence::last_seen(db, name).await? {
+                Some(user) if name != ANONYMOUS_USER => users.push(user),
+                _ => {
+                    let description = format!("There is no user named '{}'", name);
+                    send_error(ErrorCode::UnknownUser, description, internal_tx).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    let _ = internal_tx
+        .send(InternalMessage::Send(MessageType::Users(users)))
+        .await;
+    Ok(())
+}
+
+/// Works out who came online, went away or went offline since the last time it was called, and tells everyone. The
+/// connection of `session`, whose client prompted the check and may be hanging up, isn't told about its own user.
+/// Users going offline have the time stored as when they were last seen.
+///
+/// # Example
+/// ```
+/// sessions.remove(addr);
+/// announce_presence(&sender, Some(&session), &db, &config, &sessions).await;
+/// ```
+async fn announce_presence(
+    tx: &sync::broadcast::Sender<(MessageType, SocketAddr)>,
+    session: Option<&Session>,
+    db: &Pool<Sqlite>,
+    config: &ServerConfig,
+    sessions: &Sessions,
+) {
+    let now = history::now_millis();
+    let online = presence::online(sessions, config.away_after, now);
+    ONLINE_USERS.set(online.len() as f64);
+
+    for user in sessions.presence().changes(&online, now) {
+        log::info!("{} is {}", user.name, user.presence);
+        if user.presence == Presence::Offline {
+            if let Err(e) = presence::record_last_seen(db, &user.name, now).await {
+                log::error!("Failed to record when {} was last seen: {:?}", user.name, e);
+            }
+        }
+        let from = match session {
+            Some(session) if session.name() == user.name => session.addr(),
+            _ => SERVER_ADDR,
+        };
+        let _ = tx.send((MessageType::Presence(user.name, user.presence), from));
+    }
+}
+
+/// Tells every connection of a kicked or banned user why they are being disconnected, then closes them.
+async fn shut_out(connections: &[Connection], code: ErrorCode, description: String) {
+    for connection in connections {
+        let error = MessageType::Error(code, description.clone());
+        let _ = connection
+            .internal_tx
+            .send(InternalMessage::Send(error))
+            .await;
+        connection.session.close();
+    }
+}
+
+/// Sends a `Notice` to the connections that negotiated moderation; older clients wouldn't know what to do with one.
+async fn notify(connections: &[Connection], text: String) {
+    for connection in connections {
+        if connection.session.features().moderation {
+            let notice = MessageType::Notice(text.clone());
+            let _ = connection
+                .internal_tx
+                .send(InternalMessage::Send(notice))
+                .await;
+        }
+    }
+}
+
+/// Sends a `Notice` to the client whose message is being processed, which asked for it.
+async fn notify_sender(text: String, internal_tx: &mpsc::Sender<InternalMessage>) {
+    let notice = MessageType::Notice(text);
+    let _ = internal_tx.send(InternalMessage::Send(notice)).await;
+}
+
+/// Tells a client its request was refused. Unlike `send_protocol_error`, the client hasn't done anything wrong, so
+/// nothing is counted against it.
+async fn send_error(
+    code: ErrorCode,
+    description: String,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) {
+    let error = MessageType::Error(code, description);
+    let _ = internal_tx.send(InternalMessage::Send(error)).await;
+}
+
+/// Tells a client its message was refused. A message submitted with a `nonce` is answered with a `Rejected` holding
+/// it, so that the client stops waiting for an acknowledgement, and anything else with an `Error`.
+async fn refuse(
+    nonce: Option<u64>,
+    code: ErrorCode,
+    description: String,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) {
+    let refusal = match nonce {
+        Some(nonce) => MessageType::Rejected(nonce, code, description),
+        None => MessageType::Error(code, description),
+    };
+    let _ = internal_tx.send(InternalMessage::Send(refusal)).await;
+}
+
+/// Refuses a message from a muted user, returning true if they are muted. A message submitted with a `nonce` is
+/// answered with a `Rejected` holding it.
+///
+/// # Example
+/// ```
+/// if refuse_if_muted(nonce, session, db, internal_tx).await? {
+///     return Ok(None);
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn refuse_if_muted(
+    nonce: Option<u64>,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> Result<bool> {
+    let Some(expiry) = moderation::active_mute(db, session.user_id()).await? else {
+        return Ok(false);
+    };
+    log::info!("Dropping message from muted user {}", session.name());
+    let description = format!("You are muted {}", expiry);
+    refuse(nonce, ErrorCode::Muted, description, internal_tx).await;
+    Ok(true)
+}
+
+/// Refuses a message sent faster than `throttle` allows, returning true if it was refused. The client is told how long
+/// to wait with a `Rejected` if the message was submitted with a `nonce`, and otherwise with a `Notice`, or an `Error`
+/// if it doesn't understand notices. Slowmode is that of the client's current room, and moderators aren't held to it.
+///
+/// # Example
+/// ```
+/// if refuse_if_throttled(Traffic::Text, nonce, session, db, internal_tx, throttle).await? {
+///     return Ok(None);
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn refuse_if_throttled(
+    traffic: Traffic,
+    nonce: Option<u64>,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    throttle: &Throttle,
+) -> Result<bool> {
+    let user_id = session.user_id_if_logged_in();
+    let room = session.room();
+    // Only look up the sender's role when it could make a difference
+    let exempt = match user_id {
+        Some(user_id) if throttle.slowmode(&room).is_some() => {
+            moderation::role(db, user_id).await? >= moderation::Role::Moderator
+        }
+        _ => false,
+    };
+    let Err(throttled) = throttle.check(
+        session.addr(),
+        user_id,
+        &room,
+        traffic,
+        Instant::now(),
+        exempt,
+    ) else {
+        return Ok(false);
+    };
+
+    log::info!(
+        "Throttling {} at {}: {}",
+        session.name(),
+        session.addr(),
+        throttled
+    );
+    THROTTLED_COUNTER
+        .with_label_values(&[throttled.limit()])
+        .inc();
+    if nonce.is_none() && session.features().moderation {
+        notify_sender(throttled.to_string(), internal_tx).await;
+    } else {
+        refuse(
+            nonce,
+            ErrorCode::Throttled,
+            throttled.to_string(),
+            internal_tx,
+        )
+        .await;
+    }
+    Ok(true)
+}
+
+/// Runs a message through `filters`, returning it as changed by them, or `None` if one refused it. Refused messages
+/// are answered with `Error(Rejected, ..)` giving the reason, or a `Rejected` if they were submitted with a `nonce`.
+///
+/// # Example
+/// ```
+/// let Some(updated_msg) = filter_message(updated_msg, nonce, session, internal_tx, filters).await else {
+///     return Ok(None);
+/// };
+/// ```
+async fn filter_message(
+    msg: MessageType,
+    nonce: Option<u64>,
+    session: &Session,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+    filters: &FilterChain,
+) -> Option<MessageType> {
+    match filters.run(msg) {
+        Ok(filtered) => {
+            for filter in filtered.modified_by {
+                log::debug!(
+                    "Filter {} changed a message from {}",
+                    filter,
+                    session.name()
+                );
+                FILTERED_COUNTER
+                    .with_label_values(&[filter, "modified"])
+                    .inc();
+            }
+            Some(filtered.message)
+        }
+        Err(rejection) => {
+            log::info!(
+                "Filter {} refused a message from {}: {}",
+                rejection.filter,
+                session.name(),
+                rejection.reason
+            );
+            FILTERED_COUNTER
+                .with_label_values(&[rejection.filter, "rejected"])
+                .inc();
+            refuse(nonce, ErrorCode::Rejected, rejection.reason, internal_tx).await;
+            None
+        }
+    }
+}
+
+/// Refuses to log a client in as the user `user_id` if they, or the address the client connected from, are banned,
+/// and closes the connection. Returns true if the login was refused.
+///
+/// # Example
+/// ```
+/// if refuse_if_banned(identity.user_id, session, db, internal_tx).await? {
+///     return Ok(None);
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+async fn refuse_if_banned(
+    user_id: i64,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> Result<bool> {
+    let ip = session.addr().ip();
+    let Some(expiry) = moderation::active_ban(db, Some(user_id), Some(ip)).await? else {
+        return Ok(false);
+    };
+    log::warn!(
+        "Refusing to log {} in as banned user {}",
+        session.addr(),
+        user_id
+    );
+    let description = format!("You are banned {}", expiry);
+    send_error(ErrorCode::Banned, description, internal_tx).await;
+    session.close();
+    Ok(true)
+}
+
+/// Rejects a client's transfer, telling the sender why and returning the abort to broadcast (if the transfer had
+/// already been announced to the other clients).
+///
+/// # Example
+/// ```
+/// return reject_transfer(client_id, e, transfers, internal_tx).await;
+/// ```
+///
+/// This function does not return any errors; it always yields `Ok` so callers can return it directly.
+async fn reject_transfer(
+    client_id: u64,
+    error: AppError,
+    transfers: &mut TransferTracker,
+    internal_tx: &mpsc::Sender<InternalMessage>,
+) -> Result<Option<MessageType>> {
+    log::warn!("Rejecting transfer {}: {}", client_id, error);
+
+    send_protocol_error(ErrorCode::InvalidTransfer, error.to_string(), internal_tx).await;
+
+    let room = transfers.room(client_id).map(str::to_string);
+    Ok(transfers
+        .fail(client_id)
+        .zip(room)
+        .map(|(server_id, room)| {
+            let abort = MessageType::TransferAbort(server_id, error.to_string());
+            MessageType::InRoom(room, Box::new(abort))
+        }))
+}
+
+/// Manages writing messages to a client.
+///
+/// This function listens for broadcast messages and internal messages, sending the appropriate responses to the
+/// client's TCP stream, encoded and compressed as negotiated for this client. Only frames the client negotiated
+/// `features` for are forwarded: transfer frames need chunked transfers, `Left` notices need goodbyes, `Renamed`
+/// notices need renames, and messages are only forwarded with the id and timestamp the server stamped them with to
+/// clients that negotiated acknowledgements. Messages sent to rooms the client isn't in are skipped. Clients that
+/// negotiated heartbeats are pinged every `heartbeat_interval`. Clients that negotiated the backlog are sent the
+/// `backlog_messages` most recent messages in the lobby before anything else, and in any other room as they join it,
+/// and clients resuming after a reconnect are replayed the stored messages they missed in their rooms. Messages
+/// replayed either way aren't sent again when they come through the broadcast channel.
+///
+/// # Example
+/// ```
+/// process_client_wtr(receiver, &mut stream_wtr, &session, &db, internal_rx, &format, features, heartbeat_interval, backlog_messages).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to send messages over the TCP stream.
+#[allow(clippy::too_many_arguments)]
+async fn process_client_wtr<W: AsyncWriteExt + Unpin>(
+    mut rx: sync::broadcast::Receiver<(MessageType, SocketAddr)>,
+    stream: &mut W,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    mut internal_rx: mpsc::Receiver<InternalMessage>,
+    format: &WireFormat,
+    features: Features,
+    heartbeat_interval: Duration,
+    backlog_messages: u32,
+) -> Result<()> {
+    let addr = session.addr();
+    log::trace!("Starting process: Client Writer for: {}", &addr);
+
+    // Only ticks for clients that negotiated heartbeats
+    let mut ticker = heartbeat::ticker(heartbeat_interval);
+    let mut pings: u64 = 0;
+
+    // Messages replayed to the client, as backlog or to resume
+    let mut replayed = Replayed::default();
+
+    // Recent history goes out before anything live, so the client sees everything in order
+    if features.backlog && backlog_messages > 0 {
+        let backlog = recent_messages(db, DEFAULT_ROOM, backlog_messages, &replayed, addr).await;
+        replayed.note_backlog(&backlog);
+        if let Err(e) =
+            replay_messages(stream, session, format, backlog, "backlog", &mut replayed).await
+        {
+            log::error!("Error sending the backlog to {} tcp stream: {:?}", &addr, e);
+            return Ok(());
+        }
+    }
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if features.heartbeats => {
+                pings += 1;
+                match MessageType::Ping(pings).send(stream, format).await {
+                    Ok(stats) => record_frame_stats(format, stats),
+                    Err(e) => {
+                        log::error!("Error sending ping to {} tcp stream: {:?}", &addr, e);
+                        break;
+                    }
+                }
+            },
+            // Handle broadcast messages
+            Ok((msg, other_addr)) = rx.recv() => {
+                // If this is the task responsible for sending to the same client the msg came from, ignore
+                if other_addr == addr {
+                    log::debug!(
+                        "Will not broadcast message from: {} to {}. Same client.",
+                        other_addr,
+                        addr
+                    );
+                    continue;
+                }
+
+                // Anything stored before the client caught up has already been replayed to it
+                if replayed.already_replayed(&msg) {
+                    continue;
+                }
+
+                // Messages sent to rooms the client isn't in are none of its business
+                let Some(msg) = rooms::deliverable(msg, session) else {
+                    continue;
+                };
+
+                // Older clients wouldn't know what to do with a stamp...
+                let msg = if features.acks { msg } else { msg.unstamped() };
+
+                // ...nor with a transfer frame...
+                if !features.chunked_transfers && msg.is_transfer() {
+                    log::debug!("Not forwarding transfer frame to {}; chunked transfers not negotiated", addr);
+                    continue;
+                }
+
+                // ...nor with word that someone left...
+                if !features.goodbyes && matches!(msg, MessageType::Left(..)) {
+                    continue;
+                }
+
+                // ...or changed their name...
+                if !features.renames && matches!(msg, MessageType::Renamed(..)) {
+                    continue;
+                }
+
+                // ...or announcements from moderators...
+                if !features.moderation && matches!(msg.content(), MessageType::Notice(..)) {
+                    continue;
+                }
+
+                // ...or who is around
+                if !features.presence && matches!(msg, MessageType::Presence(..)) {
+                    continue;
+                }
+
+                // Otherwise send it to their respective TCP Stream
+                match msg.send(stream, format).await {
+                    Ok(stats) => {
+                        record_frame_stats(format, stats);
+                        log::debug!("Server successfully sent message to: {} at {}", session.user_id(), addr);
+                    }
+                    Err(e) => {
+                        log::error!("Error sending msg to {} tcp stream: {:?}", &addr, e);
+                        log::info!("Server killing client writer task for: {} at {}", session.user_id(), addr);
+                        break;
+                    }
+                }
+            },
+            // Handle internal messages
+            internal_msg = internal_rx.recv() => {
+                match internal_msg {
+                    Some(InternalMessage::Send(msg)) => {
+                        match msg.send(stream, format).await {
+                            Ok(stats) => record_frame_stats(format, stats),
+                            Err(e) => {
+                                log::error!("Error sending msg to {} tcp stream: {:?}", &addr, e);
+                                break;
+                            }
+                        }
+                    },
+                    Some(InternalMessage::Resume(last_seen)) => {
+                        let missed = missed_messages(db, last_seen, session, &replayed).await;
+                        if let Err(e) = replay_messages(stream, session, format, missed, "resume", &mut replayed).await {
+                            log::error!("Error replaying messages to {} tcp stream: {:?}", &addr, e);
+                            break;
+                        }
+                    },
+                    Some(InternalMessage::Joined(room)) => {
+                        if features.backlog && backlog_messages > 0 {
+                            let backlog = recent_messages(db, &room, backlog_messages, &replayed, addr).await;
+                            replayed.note_backlog(&backlog);
+                            if let Err(e) = replay_messages(stream, session, format, backlog, "backlog", &mut replayed).await {
+                                log::error!("Error sending the backlog of {} to {} tcp stream: {:?}", room, &addr, e);
+                                break;
+                            }
+                        }
+                    },
+                    // The reader task has hung up on this client; nothing left to do
+                    None => {
+                        log::info!("Server killing client writer task for: {} at {}", session.user_id(), addr);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Close our side cleanly; over TLS this also sends the close_notify alert
+    if let Err(e) = stream.shutdown().await {
+        log::debug!("Error shutting down stream to {}: {:?}", addr, e);
+    }
+
+    Ok(())
+}
+
+/// Sends a client stored messages it hasn't seen, noting them in `replayed`. `reason` labels the messages in the
+/// `messages_replayed_total` metric.
+///
+/// # Example
+/// ```
+/// replay_messages(stream, session, format, missed, "resume", &mut replayed).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to send a message over the TCP stream.
+async fn replay_messages<W: AsyncWriteExt + Unpin>(
+    stream: &mut W,
+    session: &Session,
+    format: &WireFormat,
+    messages: Vec<MessageType>,
+    reason: &str,
+    replayed: &mut Replayed,
+) -> Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    log::info!(
+        "Replaying {} stored messages to {} ({})",
+        messages.len(),
+        session.addr(),
+        reason
+    );
+
+    for msg in messages {
+        replayed.note(&msg);
+        let Some(msg) = rooms::deliverable(msg, session) else {
+            continue;
+        };
+        let msg = if session.features().acks {
+            msg
+        } else {
+            msg.unstamped()
+        };
+        record_frame_stats(format, msg.send(stream, format).await?);
+        REPLAYED_MESSAGE_COUNTER.with_label_values(&[reason]).inc();
+    }
+
+    Ok(())
+}
+
+/// Looks up the most recent messages in `room` to send a client as backlog, leaving out any it was already sent.
+/// Failures are logged, and nothing is sent.
+///
+/// # Example
+/// ```
+/// let backlog = recent_messages(db, &room, backlog_messages, &replayed, addr).await;
+/// ```
+async fn recent_messages(
+    db: &Pool<Sqlite>,
+    room: &str,
+    count: u32,
+    replayed: &Replayed,
+    addr: SocketAddr,
+) -> Vec<MessageType> {
+    match resume::backlog(db, room, count).await {
+        Ok(backlog) => backlog
+            .into_iter()
+            .filter(|msg| !replayed.in_backlog(msg))
+            .collect(),
+        Err(e) => {
+            log::error!(
+                "Failed to look up the backlog of {} for {}: {:?}",
+                room,
+                addr,
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Looks up the messages a resuming client missed in the rooms it is in, and the direct messages to or from its user
+/// if it can receive them, leaving out any it was sent as backlog. Failures are logged, and nothing is replayed.
+///
+/// # Example
+/// ```
+/// let missed = missed_messages(db, last_seen, session, &replayed).await;
+/// ```
+async fn missed_messages(
+    db: &Pool<Sqlite>,
+    last_seen: i64,
+    session: &Session,
+    replayed: &Replayed,
+) -> Vec<MessageType> {
+    let user_id = session
+        .user_id_if_logged_in()
+        .filter(|_| session.features().direct_messages);
+    match resume::missed_messages(db, last_seen, &session.rooms(), user_id).await {
+        // Whatever was in the backlog has been seen already
+        Ok(missed) => missed
+            .into_iter()
+            .filter(|msg| !replayed.in_backlog(msg))
+            .collect(),
+        Err(e) => {
+            log::error!(
+                "Failed to look up messages missed by {}: {:?}",
+                session.addr(),
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Accounts for the bytes a frame sent to a client took up before and after compression.
+fn record_frame_stats(format: &WireFormat, stats: FrameStats) {
+    let compression = format.compression.map_or("none", |c| c.name());
+    PAYLOAD_RAW_BYTES_COUNTER
+        .with_label_values(&[compression])
+        .inc_by(stats.raw_bytes as f64);
+    PAYLOAD_WIRE_BYTES_COUNTER
+        .with_label_values(&[compression])
+        .inc_by(stats.wire_bytes as f64);
+}
+
+/// Establishes the SQLite database for server use.
+///
+/// This function creates the database if it doesn't exist, connects to it, and executes migrations to set up necessary
+/// tables.
+///
+/// # Example
+/// ```
+/// let db = setup_db().await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to create the database, connect to it, or execute migrations.
+async fn setup_db() -> Result<Pool<Sqlite>> {
+    // Create DB if it doesn't already exist
+    if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
+        log::trace!("Entering setup_db");
+        match Sqlite::create_database(DB_URL).await {
+            Ok(_) => {
+                log::info!("New sqlite DB established: {}", DB_URL);
+            }
+            Err(error) => {
+                log::error!("Error creating new DB: {}", &error);
+                // Handle the error state as needed
+            }
+        }
+    }
+
+    // Establish connection to DB
+    let db = SqlitePool::connect(DB_URL)
+        .await
+        .context("Failed to connect to SQLite DB")?;
+
+    // Execute migrations
+    let crate_dir =
+        std::env::var("CARGO_MANIFEST_DIR").context("Failed to determine CARGO_MANIFEST_DIR")?;
+    let migrations = std::path::Path::new(&crate_dir).join("migrations");
+    let migrator = sqlx::migrate::Migrator::new(migrations).await.unwrap();
+    match migrator.run(&db).await {
+        Ok(_) => log::info!("Migration success"),
+        Err(error) => {
+            log::error!("Migration error: {:?}", error);
+            return Err(error.into());
+        }
+    }
+
+    Ok(db)
+}
+
+/// Adds a user to the database.
+///
+/// This function inserts a new user into the database, unless someone already has the name.
+///
+/// # Example
+/// ```
+/// add_user_to_db("username", &db).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to insert the user into the database.
+async fn add_user_to_db(account: &str, db: &Pool<Sqlite>) -> Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO users (name) VALUES (?)")
+        .bind(account)
+        .execute(db)
+        .await
         .context("Failed to insert user into the database")?;
     log::debug!("User {} added to the database", account);
 
     Ok(())
 }
 
-/// Stores a message in the database associated with a specific user ID.
+/// Stores a message sent to `room`, or directly to the user `recipient_id`, in the database associated with a specific
+/// user ID.
 ///
-/// This function inserts a message into the database.
+/// This function inserts a message into the database, returning the id and timestamp it was stored with, or `None` if
+/// messages of its kind are not stored.
 ///
 /// # Example
 /// ```
-/// store_message_in_db(&msg, user_id, &db).await?;
+/// let (id, created_at) = store_message_in_db(&msg, user_id, &room, None, &db).await?.unwrap();
 /// ```
 ///
 /// # Errors
 /// This function returns an error if it fails to insert the message into the database.
-async fn store_message_in_db(msg: &MessageType, user_id: i64, db: &Pool<Sqlite>) -> Result<()> {
-    match msg {
-        MessageType::Text(_, content) | MessageType::Text(None, content) => {
-            sqlx::query("INSERT INTO messages (content, user_id) VALUES (?, ?)")
-                .bind(content)
-                .bind(user_id)
-                .execute(db)
-                .await
-                .context("Failed to insert text message into the database")?;
+async fn store_message_in_db(
+    msg: &MessageType,
+    user_id: i64,
+    room: &str,
+    recipient_id: Option<i64>,
+    db: &Pool<Sqlite>,
+) -> Result<Option<(i64, i64)>> {
+    let created_at = history::now_millis();
+    let result = match msg {
+        MessageType::Text(_, content) => sqlx::query(
+            "INSERT INTO messages (content, user_id, kind, created_at, room, recipient_id) VALUES (?, ?, 'text', ?, ?, ?)",
+        )
+        .bind(content)
+        .bind(user_id)
+        .bind(created_at)
+        .bind(room)
+        .bind(recipient_id)
+        .execute(db)
+        .await
+        .context("Failed to insert text message into the database")?,
+        MessageType::File(_, name, _) => {
+            return store_attachment_in_db(
+                &Attachment::File(name.clone()),
+                user_id,
+                room,
+                recipient_id,
+                db,
+            )
+            .await
+            .map(Some);
         }
-        MessageType::File(_, name, _) | MessageType::File(None, name, _) => {
-            sqlx::query("INSERT INTO messages (content, user_id) VALUES (?, ?)")
-                .bind(name)
-                .bind(user_id)
-                .execute(db)
+        MessageType::Image(_, _) => {
+            return store_attachment_in_db(&Attachment::Image, user_id, room, recipient_id, db)
                 .await
-                .context("Failed to insert file message into the database")?;
+                .map(Some);
         }
-        MessageType::Image(_, _) | MessageType::Image(None, _) => {
+        // Should not be storing Register or Error messages. Transfers are stored once they complete.
+        _ => return Ok(None),
+    };
+
+    log::debug!("Message stored in the database with user ID: {}", user_id);
+    Ok(Some((result.last_insert_rowid(), created_at)))
+}
+
+/// Stores a file or image message sent to `room`, or directly to the user `recipient_id`, in the database associated
+/// with a specific user ID.
+///
+/// Files are recorded by name and images by the time they were received. The id and timestamp the message was stored
+/// with are returned.
+///
+/// # Example
+/// ```
+/// let (id, created_at) = store_attachment_in_db(&Attachment::File("notes.txt".to_string()), user_id, &room, None, &db).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to insert the message into the database.
+async fn store_attachment_in_db(
+    attachment: &Attachment,
+    user_id: i64,
+    room: &str,
+    recipient_id: Option<i64>,
+    db: &Pool<Sqlite>,
+) -> Result<(i64, i64)> {
+    let created_at = history::now_millis();
+    let result = match attachment {
+        Attachment::File(name) => sqlx::query(
+            "INSERT INTO messages (content, user_id, kind, created_at, room, recipient_id) VALUES (?, ?, 'file', ?, ?, ?)",
+        )
+        .bind(name)
+        .bind(user_id)
+        .bind(created_at)
+        .bind(room)
+        .bind(recipient_id)
+        .execute(db)
+        .await
+        .context("Failed to insert file message into the database")?,
+        Attachment::Image => {
             let timestamp = Utc::now().to_string();
-            sqlx::query("INSERT INTO messages (content, user_id) VALUES (?, ?)")
-                .bind(timestamp)
-                .bind(user_id)
-                .execute(db)
+            sqlx::query(
+                "INSERT INTO messages (content, user_id, kind, created_at, room, recipient_id) VALUES (?, ?, 'image', ?, ?, ?)",
+            )
+            .bind(timestamp)
+            .bind(user_id)
+            .bind(created_at)
+            .bind(room)
+            .bind(recipient_id)
+            .execute(db)
                 .await
-                .context("Failed to insert image message into the database")?;
+                .context("Failed to insert image message into the database")?
         }
-        MessageType::Register(_) => return Ok(()), // Should not be storing Register messages
-    }
+    };
 
     log::debug!("Message stored in the database with user ID: {}", user_id);
-    Ok(())
+    Ok((result.last_insert_rowid(), created_at))
 }
 
-/// Fetches or creates a new user ID for the anonymous user.
+/// Fetches or creates the user ID for a given username.
 ///
-/// This function checks if the anonymous user exists in the database, creating a new entry if it does not.
+/// This function checks if the user exists in the database, creating a new entry if it does not. It is used for the
+/// anonymous user.
 ///
 /// # Example
 /// ```
-/// let anon_user_id = get_or_create_anon_user_id(&db).await?;
+/// let anon_user_id = get_or_create_user_id(ANONYMOUS_USER, &db).await?;
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to fetch or create the anonymous user in the database.
-async fn get_or_create_anon_user_id(db: &Pool<Sqlite>) -> Result<i64> {
-    // Check if the anonymous user exists
-    let row = sqlx::query("SELECT id FROM users WHERE name = 'anonymous'")
-        .fetch_optional(db)
-        .await?;
+/// This function returns an error if it fails to fetch or create the user in the database.
+async fn get_or_create_user_id(account: &str, db: &Pool<Sqlite>) -> Result<i64> {
+    // Check if the user exists
+    if let Some(user_id) = get_user_id_by_name(account, db).await? {
+        return Ok(user_id);
+    }
 
-    if let Some(row) = row {
-        Ok(row.get("id"))
-    } else {
-        // Create the anonymous user if it does not exist
-        sqlx::query("INSERT INTO users (name) VALUES ('anonymous')")
-            .execute(db)
-            .await?;
-        let row = sqlx::query("SELECT id FROM users WHERE name = 'anonymous'")
-            .fetch_one(db)
-            .await?;
-
-        let user_id = row.get("id");
-        log::debug!(
-            "Created and added 'anonymous' user to db, user_id: {}",
-            &user_id
-        );
+    // Create the user if it does not exist
+    add_user_to_db(account, db).await?;
+    let user_id = get_user_id_by_name(account, db)
+        .await?
+        .context("User vanished right after being created")?;
+    log::debug!(
+        "Created and added '{}' user to db, user_id: {}",
+        account,
+        &user_id
+    );
 
-        Ok(user_id)
-    }
+    Ok(user_id)
 }
 
 /// Retrieves the user ID for a given username.
@@ -530,54 +2655,819 @@ async fn get_user_id_by_name(account: &str, db: &Pool<Sqlite>) -> Result<Option<
     Ok(None)
 }
 
-/// Retrieves the username for a given user ID.
+/// Everything the HTTP endpoints need to let browser clients take part in the chat.
+#[derive(Clone)]
+struct HttpState {
+    br_send: sync::broadcast::Sender<(MessageType, SocketAddr)>,
+    db: Pool<Sqlite>,
+    config: Arc<ServerConfig>,
+    sessions: Sessions,
+    throttle: Throttle,
+    filters: Arc<FilterChain>,
+    anon_user_id: i64,
+}
+
+/// Serves the metrics endpoint for Prometheus scraping, the WebSocket gateway for browser clients, the live event
+/// feed and the message history.
+///
+/// This function binds the server to the given address and routes incoming requests: upgrades on `/ws` are handed to
+/// `websocket_handler`, `/events` to `events_handler`, `/api/messages` to `history_handler`, and every other request is
+/// answered by `metrics_handler`. If the server encounters an error, it will log the error message.
+///
+/// # Arguments
 ///
-/// This function fetches the username from the database for the specified user ID.
+/// * `addr` - A `SocketAddr` specifying the address to bind the server to.
+/// * `state` - The broadcast channel, database and config shared with the TCP clients.
 ///
 /// # Example
+///
+/// ```rust
+/// let addr = ([0, 0, 0, 0], 8081).into();
+/// serve_http(addr, state).await;
 /// ```
-/// let username = get_username_by_id(user_id, &db).await?;
+///
+/// # Errors
+///
+/// This function will print an error message if the server fails to bind to the given address or if it encounters an
+/// error while running.
+async fn serve_http(addr: SocketAddr, state: HttpState) {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let remote_addr = conn.remote_addr();
+        let state = state.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                let state = state.clone();
+                async move {
+                    match req.uri().path() {
+                        WEBSOCKET_PATH => websocket_handler(req, remote_addr, state).await,
+                        EVENTS_PATH => events_handler(req, remote_addr, state).await,
+                        path if path.starts_with(HISTORY_PATH) => history_handler(req, state).await,
+                        path if path.starts_with(USERS_PATH) => users_handler(req, state).await,
+                        _ => metrics_handler(req).await,
+                    }
+                }
+            }))
+        }
+    });
+
+    let server = Server::bind(&addr).serve(make_svc);
+
+    if let Err(e) = server.await {
+        log::error!("Hyper error serving HTTP: {}", e);
+    }
+}
+
+/// Upgrades an HTTP request to a WebSocket connection and joins it to the chat.
+///
+/// The upgrade is answered straight away; the connection is then handled on its own task once hyper hands it over.
+/// Requests that aren't WebSocket upgrades are answered with `400 Bad Request`, and requests from banned addresses with
+/// `403 Forbidden`.
+///
+/// # Example
+///
+/// ```rust
+/// let response = websocket_handler(req, remote_addr, state).await?;
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to fetch the username from the database.
-async fn get_username_by_id(user_id: i64, db: &Pool<Sqlite>) -> Result<Option<String>> {
-    let row = sqlx::query("SELECT name FROM users WHERE id = ?")
-        .bind(user_id)
-        .fetch_optional(db)
-        .await?;
-    Ok(row.map(|r| r.get("name")))
+///
+/// This function never fails; the `hyper::Error` is only there to match `metrics_handler`.
+async fn websocket_handler(
+    mut req: Request<Body>,
+    addr: SocketAddr,
+    state: HttpState,
+) -> Result<Response<Body>, hyper::Error> {
+    let response = match ws::upgrade_response(&req) {
+        Ok(response) => response,
+        Err(e) => {
+            log::warn!("Rejecting WebSocket request from {}: {}", addr, e);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e.to_string()))
+                .unwrap());
+        }
+    };
+
+    // WebSocket clients start out anonymous, so only their address can be checked before letting them in
+    match moderation::active_ban(&state.db, None, Some(addr.ip())).await {
+        Ok(None) => {}
+        Ok(Some(expiry)) => {
+            log::warn!("Rejecting WebSocket request from banned address {}", addr);
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from(format!("You are banned {}", expiry)))
+                .unwrap());
+        }
+        Err(e) => {
+            log::error!("Error checking bans for {}: {:?}", addr, e);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                log::warn!("WebSocket upgrade from {} failed: {}", addr, e);
+                return;
+            }
+        };
+
+        // JSON is the only encoding spoken here, so the usual frame limit applies to each message as a whole
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(state.config.max_frame_bytes as usize),
+            max_frame_size: Some(state.config.max_frame_bytes as usize),
+            ..Default::default()
+        };
+        let stream =
+            WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config)).await;
+
+        if let Err(e) = handle_websocket(stream, addr, state).await {
+            log::error!("Error handling WebSocket client {}: {:?}", addr, e);
+        }
+    });
+
+    Ok(response)
 }
 
-/// Serves the metrics endpoint for Prometheus scraping.
+/// Serves the stored message history as JSON.
 ///
-/// This function binds the server to the given address and serves the Prometheus metrics endpoint.
-/// It creates a service using `make_service_fn` and `service_fn` to handle incoming requests with the `metrics_handler` function.
-/// If the server encounters an error, it will log the error message.
+/// `GET /api/messages` lists messages matching the query string (see `HistoryQuery`) and `GET /api/messages/<id>`
+/// fetches a single message. Invalid requests are answered with `400 Bad Request` and unknown messages with
+/// `404 Not Found`.
 ///
-/// # Arguments
+/// Like the user endpoints, the history is only served to requests carrying the admin token; without one configured,
+/// the endpoints don't exist.
 ///
-/// * `addr` - A `SocketAddr` specifying the address to bind the server to.
+/// # Example
+///
+/// ```rust
+/// let response = history_handler(req, state).await?;
+/// ```
+///
+/// # Errors
+///
+/// This function never fails; the `hyper::Error` is only there to match `metrics_handler`.
+async fn history_handler(
+    req: Request<Body>,
+    state: HttpState,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Some(refusal) = refuse_non_admin(&req, &state) {
+        return Ok(refusal);
+    }
+    if req.method() != hyper::Method::GET {
+        return Ok(json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &serde_json::json!({ "error": "Only GET is supported" }),
+        ));
+    }
+
+    let result = match req.uri().path().strip_prefix(HISTORY_PATH) {
+        Some("" | "/") => match HistoryQuery::from_query(req.uri().query()) {
+            Ok(query) => history::list_messages(&state.db, &query)
+                .await
+                .map(|messages| json_response(StatusCode::OK, &MessagePage { messages })),
+            Err(e) => Ok(json_response(
+                StatusCode::BAD_REQUEST,
+                &serde_json::json!({ "error": e.to_string() }),
+            )),
+        },
+        Some(id) => match id.trim_start_matches('/').parse() {
+            Ok(id) => history::get_message(&state.db, id)
+                .await
+                .map(|message| match message {
+                    Some(message) => json_response(StatusCode::OK, &message),
+                    None => json_response(
+                        StatusCode::NOT_FOUND,
+                        &serde_json::json!({ "error": format!("No message with id {}", id) }),
+                    ),
+                }),
+            Err(_) => Ok(json_response(
+                StatusCode::NOT_FOUND,
+                &serde_json::json!({ "error": "Not found" }),
+            )),
+        },
+        None => Ok(json_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({ "error": "Not found" }),
+        )),
+    };
+
+    Ok(result.unwrap_or_else(|e| {
+        log::error!("Error reading message history: {:?}", e);
+        json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &serde_json::json!({ "error": "Failed to read message history" }),
+        )
+    }))
+}
+
+/// Returns the response to send instead if a request to an admin endpoint doesn't carry the configured admin token as
+/// `Authorization: Bearer <token>`: `404 Not Found` when no token is configured, and `401 Unauthorized` otherwise.
+fn refuse_non_admin(req: &Request<Body>, state: &HttpState) -> Option<Response<Body>> {
+    let Some(admin_token) = &state.config.admin_token else {
+        return Some(json_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({ "error": "Not found" }),
+        ));
+    };
+
+    let presented = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| admin_token.matches(presented.trim())) {
+        return None;
+    }
+    log::warn!(
+        "Refused {} {} without the admin token",
+        req.method(),
+        req.uri().path()
+    );
+    Some(json_response(
+        StatusCode::UNAUTHORIZED,
+        &serde_json::json!({ "error": "Missing or wrong admin token" }),
+    ))
+}
+
+/// Serves the administrative user endpoints: revoking every session token of a user with
+/// `DELETE /api/users/<name>/tokens`, and giving them a new role with `PUT /api/users/<name>/role` and a body such as
+/// `{"role":"moderator"}`.
+///
+/// Requests must carry the configured admin token as `Authorization: Bearer <token>`; without one configured, the
+/// endpoints don't exist. Role changes are recorded in the audit log.
 ///
 /// # Example
 ///
 /// ```rust
-/// let addr = ([0, 0, 0, 0], 8081).into();
-/// serve_metrics(addr).await;
+/// let response = users_handler(req, state).await?;
 /// ```
 ///
 /// # Errors
 ///
-/// This function will print an error message if the server fails to bind to the given address or if it encounters an error while running.
-async fn serve_metrics(addr: SocketAddr) {
-    let make_svc =
-        make_service_fn(|_conn| async { Ok::<_, hyper::Error>(service_fn(metrics_handler)) });
+/// This function never fails; the `hyper::Error` is only there to match `metrics_handler`.
+async fn users_handler(
+    req: Request<Body>,
+    state: HttpState,
+) -> Result<Response<Body>, hyper::Error> {
+    let not_found = || {
+        json_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({ "error": "Not found" }),
+        )
+    };
+    if let Some(refusal) = refuse_non_admin(&req, &state) {
+        return Ok(refusal);
+    }
 
-    let server = Server::bind(&addr).serve(make_svc);
+    let Some((name, resource)) = tokens::user_path(req.uri().path()) else {
+        return Ok(not_found());
+    };
+    let name = name.to_string();
+    let allowed = match resource {
+        "tokens" => hyper::Method::DELETE,
+        "role" => hyper::Method::PUT,
+        _ => return Ok(not_found()),
+    };
+    if req.method() != allowed {
+        return Ok(json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &serde_json::json!({ "error": format!("Only {} is supported", allowed) }),
+        ));
+    }
 
-    if let Err(e) = server.await {
-        log::error!("Hyper error serving metrics: {}", e);
+    if allowed == hyper::Method::PUT {
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+        return Ok(match serde_json::from_slice::<RoleChange>(&body) {
+            Ok(change) => set_role(&state.db, &name, change).await,
+            Err(e) => json_response(
+                StatusCode::BAD_REQUEST,
+                &serde_json::json!({ "error": e.to_string() }),
+            ),
+        });
+    }
+
+    Ok(match tokens::revoke_all(&state.db, &name).await {
+        Ok(Some(revoked)) => json_response(
+            StatusCode::OK,
+            &serde_json::json!({ "user": name, "revoked": revoked }),
+        ),
+        Ok(None) => json_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({ "error": format!("No user named {}", name) }),
+        ),
+        Err(e) => {
+            log::error!("Error revoking the session tokens of {}: {:?}", name, e);
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &serde_json::json!({ "error": "Failed to revoke session tokens" }),
+            )
+        }
+    })
+}
+
+/// Gives the user called `name` a new role on behalf of an administrator, answering with the role they now have.
+async fn set_role(db: &Pool<Sqlite>, name: &str, change: RoleChange) -> Response<Body> {
+    let result = match moderation::set_role(db, name, change.role).await {
+        Ok(true) => moderation::record(db, "admin", "role", name, Some(change.role.as_str()))
+            .await
+            .map(|_| true),
+        other => other,
+    };
+    match result {
+        Ok(true) => json_response(
+            StatusCode::OK,
+            &serde_json::json!({ "user": name, "role": change.role }),
+        ),
+        Ok(false) => json_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({ "error": format!("No user named {}", name) }),
+        ),
+        Err(e) => {
+            log::error!("Error setting the role of {}: {:?}", name, e);
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &serde_json::json!({ "error": "Failed to set role" }),
+            )
+        }
+    }
+}
+
+/// Builds a JSON response with the given status.
+fn json_response(status: StatusCode, body: &impl serde::Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}
+
+/// Streams chat messages to a read-only consumer as Server-Sent Events.
+///
+/// The consumer subscribes to the same broadcast channel as the chat clients and is sent every message that passes
+/// the filters in the query string. Idle feeds are sent a keep-alive comment every 15 seconds, which is also how we
+/// notice that a consumer has gone away.
+///
+/// # Example
+///
+/// ```rust
+/// let response = events_handler(req, remote_addr, state).await?;
+/// ```
+///
+/// # Errors
+///
+/// This function never fails; the `hyper::Error` is only there to match `metrics_handler`.
+async fn events_handler(
+    req: Request<Body>,
+    addr: SocketAddr,
+    state: HttpState,
+) -> Result<Response<Body>, hyper::Error> {
+    let filter = EventFilter::from_query(req.uri().query());
+    let mut rx = state.br_send.subscribe();
+    let (mut body_tx, body) = Body::channel();
+    log::info!("Streaming events to {} with filter {:?}", addr, filter);
+
+    tokio::spawn(async move {
+        let mut keep_alive = time::interval(EVENTS_KEEP_ALIVE_INTERVAL);
+        loop {
+            let chunk = tokio::select! {
+                received = rx.recv() => match received {
+                    Ok((msg, _)) if filter.matches(&msg) => match events::render(&msg) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    Ok(_) => continue,
+                    // A consumer too slow to keep up misses messages rather than holding up the chat
+                    Err(sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("Event feed for {} skipped {} messages", addr, skipped);
+                        continue;
+                    }
+                    Err(sync::broadcast::error::RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => events::KEEP_ALIVE.to_string(),
+            };
+
+            if body_tx.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+        log::info!("Event feed for {} closed", addr);
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+/// Joins a WebSocket client to the chat, alongside the TCP clients.
+///
+/// WebSocket clients share the TCP clients' broadcast channel, registration and persistence, so everyone sees the same
+/// usernames and history. They don't negotiate chunked transfers, so transfer frames are never forwarded to them.
+///
+/// # Example
+/// ```
+/// handle_websocket(stream, addr, state).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if processing one of the client's messages fails.
+async fn handle_websocket(
+    stream: WebSocketStream<Upgraded>,
+    addr: SocketAddr,
+    state: HttpState,
+) -> Result<()> {
+    log::info!("Accepted WebSocket client from {}", addr);
+
+    let (sink, source) = stream.split();
+    let receiver = state.br_send.subscribe();
+    let (internal_tx, internal_rx) = mpsc::channel(32);
+    let heartbeat_interval = state.config.heartbeat.interval;
+    let backlog_messages = state.config.backlog_messages;
+    let db = state.db.clone();
+    // Browsers can't reassemble chunked transfers, so that is the one feature they are not assumed to support
+    let features = Features {
+        chunked_transfers: false,
+        ..Features::all()
+    };
+    let session = Session::new(addr, state.anon_user_id, ANONYMOUS_USER).with_features(features);
+    let session_wtr = session.clone();
+
+    // Spawn tokio task to manage writing to the client
+    tokio::spawn(async move {
+        if let Err(e) = process_websocket_wtr(
+            receiver,
+            sink,
+            &session_wtr,
+            &db,
+            internal_rx,
+            heartbeat_interval,
+            backlog_messages,
+        )
+        .await
+        {
+            log::error!(
+                "Server error handling the WebSocket writer for {}: {:?}",
+                addr,
+                e
+            );
+        }
+    });
+
+    state.sessions.insert(session.clone(), internal_tx.clone());
+
+    let mut transfers = TransferTracker::new(state.config.max_transfer_bytes);
+    let result = process_websocket_rdr(
+        &state.br_send,
+        source,
+        addr,
+        &state.db,
+        internal_tx,
+        &state.config,
+        &mut transfers,
+        &session,
+        &state.sessions,
+        &state.throttle,
+        &state.filters,
+    )
+    .await;
+    state.sessions.remove(addr);
+    state.throttle.disconnected(addr);
+    announce_presence(
+        &state.br_send,
+        Some(&session),
+        &state.db,
+        &state.config,
+        &state.sessions,
+    )
+    .await;
+
+    // Let everyone receiving this client's unfinished transfers know they won't be completed
+    for (server_id, room) in transfers.abort_all() {
+        let abort = MessageType::TransferAbort(server_id, "Sender disconnected".to_string());
+        let _ = state
+            .br_send
+            .send((MessageType::InRoom(room, Box::new(abort)), addr));
+    }
+
+    result
+}
+
+/// Reads and processes incoming messages from a WebSocket client.
+///
+/// This is the WebSocket counterpart of `process_client_rdr`: each WebSocket message holds one JSON encoded
+/// `MessageType`, and messages that can't be decoded count as strikes against the client the same way malformed
+/// frames do. Clients that send nothing, not even a pong, for the heartbeat idle timeout are disconnected, as are
+/// clients whose session is closed by a moderator.
+///
+/// # Example
+/// ```
+/// process_websocket_rdr(&sender, source, addr, &db, internal_tx, &config, &mut transfers, &session, &sessions, &throttle, &filters).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if it fails to process a message.
+#[allow(clippy::too_many_arguments)]
+async fn process_websocket_rdr<S>(
+    tx: &sync::broadcast::Sender<(MessageType, SocketAddr)>,
+    mut source: S,
+    addr: SocketAddr,
+    db: &Pool<Sqlite>,
+    internal_tx: mpsc::Sender<InternalMessage>,
+    config: &ServerConfig,
+    transfers: &mut TransferTracker,
+    session: &Session,
+    sessions: &Sessions,
+    throttle: &Throttle,
+    filters: &FilterChain,
+) -> Result<()>
+where
+    S: Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+{
+    log::trace!("Starting process: WebSocket Reader for: {}", &addr);
+
+    // Recoverable protocol errors made by this client so far
+    let mut strikes = 0;
+
+    loop {
+        let next = tokio::select! {
+            next = time::timeout(config.heartbeat.idle_timeout, source.next()) => next,
+            _ = session.closed() => {
+                log::info!("Closing the WebSocket connection to {} at {}", session.name(), addr);
+                break;
+            }
+        };
+        let frame = match next {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(e))) => {
+                log::debug!("WebSocket client at {} disconnected: {}", addr, e);
+                break;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                log::info!(
+                    "Dropping WebSocket client {} at {}: nothing received for {:?}",
+                    session.user_id(),
+                    addr,
+                    config.heartbeat.idle_timeout
+                );
+                TIMED_OUT_CONNECTIONS.inc();
+                break;
+            }
+        };
+        if frame.is_close() {
+            log::debug!("WebSocket client at {} disconnected gracefully.", addr);
+            break;
+        }
+
+        let msg = match ws::decode(&frame) {
+            Ok(Some(msg)) => msg,
+            // Pings are answered by tungstenite itself
+            Ok(None) => continue,
+            Err(AppError::Protocol(code, description)) => {
+                let strikes_out = record_strike(
+                    &mut strikes,
+                    code,
+                    description,
+                    session.user_id(),
+                    addr,
+                    config,
+                    &internal_tx,
+                )
+                .await;
+                if strikes_out {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => return Err(e).context("Failed to read message"),
+        };
+
+        let (nonce, msg) = match delivery::open_submission(&msg) {
+            Ok(opened) => opened,
+            Err(AppError::Protocol(code, description)) => {
+                let strikes_out = record_strike(
+                    &mut strikes,
+                    code,
+                    description,
+                    session.user_id(),
+                    addr,
+                    config,
+                    &internal_tx,
+                )
+                .await;
+                if strikes_out {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => return Err(e).context("Failed to read message"),
+        };
+
+        // The message is already in memory, but hold WebSocket clients to the same per-kind limits as everyone else
+        let limit = config.frame_limit(msg.content().kind());
+        if frame.len() > limit as usize {
+            reject_oversized_frame(
+                msg.kind(),
+                frame.len(),
+                limit,
+                session.user_id(),
+                addr,
+                &internal_tx,
+            )
+            .await;
+            break;
+        }
+
+        if let Err(e) = dispatch_message(
+            msg,
+            nonce,
+            tx,
+            addr,
+            db,
+            &internal_tx,
+            config,
+            transfers,
+            session,
+            sessions,
+            throttle,
+            filters,
+        )
+        .await
+        {
+            report_dispatch_error(e, msg.kind(), nonce, session.user_id(), addr, &internal_tx)
+                .await?;
+        }
+
+        if let MessageType::Goodbye(reason) = msg {
+            log::info!(
+                "WebSocket client {} at {} said goodbye: {}",
+                session.user_id(),
+                addr,
+                reason.as_deref().unwrap_or("no reason given")
+            );
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Manages writing messages to a WebSocket client.
+///
+/// This is the WebSocket counterpart of `process_client_wtr`, sending every message as JSON text. WebSocket clients
+/// always receive messages with the id and timestamp the server stamped them with, are sent the `backlog_messages`
+/// most recent messages in the lobby as they connect and in other rooms as they join them, and are sent a WebSocket
+/// ping every `heartbeat_interval`, which browsers answer on their own.
+///
+/// # Example
+/// ```
+/// process_websocket_wtr(receiver, sink, &session, &db, internal_rx, config.heartbeat.interval, config.backlog_messages).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if a message cannot be encoded.
+async fn process_websocket_wtr<S>(
+    mut rx: sync::broadcast::Receiver<(MessageType, SocketAddr)>,
+    mut sink: S,
+    session: &Session,
+    db: &Pool<Sqlite>,
+    mut internal_rx: mpsc::Receiver<InternalMessage>,
+    heartbeat_interval: Duration,
+    backlog_messages: u32,
+) -> Result<()>
+where
+    S: futures_util::Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    let addr = session.addr();
+    log::trace!("Starting process: WebSocket Writer for: {}", &addr);
+
+    let mut ticker = heartbeat::ticker(heartbeat_interval);
+
+    // Messages replayed to the client, as backlog or to resume
+    let mut replayed = Replayed::default();
+
+    if backlog_messages > 0 {
+        let backlog = recent_messages(db, DEFAULT_ROOM, backlog_messages, &replayed, addr).await;
+        replayed.note_backlog(&backlog);
+        if let Err(e) =
+            replay_to_websocket(&mut sink, session, backlog, "backlog", &mut replayed).await
+        {
+            log::error!(
+                "Error sending the backlog to WebSocket client {}: {:?}",
+                &addr,
+                e
+            );
+            return Ok(());
+        }
+    }
+
+    loop {
+        let frame = tokio::select! {
+            _ = ticker.tick() => tungstenite::Message::Ping(Vec::new()),
+            // Handle broadcast messages
+            Ok((msg, other_addr)) = rx.recv() => {
+                // Skip the sender's own messages, and transfer frames WebSocket clients can't reassemble
+                let unsupported = msg.is_transfer() && !session.features().chunked_transfers;
+                if other_addr == addr || unsupported || replayed.already_replayed(&msg) {
+                    continue;
+                }
+                match rooms::deliverable(msg, session) {
+                    Some(msg) => ws::encode(&msg)?,
+                    None => continue,
+                }
+            },
+            // Handle internal messages
+            internal_msg = internal_rx.recv() => {
+                match internal_msg {
+                    Some(InternalMessage::Send(msg)) => ws::encode(&msg)?,
+                    Some(InternalMessage::Resume(last_seen)) => {
+                        let missed = missed_messages(db, last_seen, session, &replayed).await;
+                        match replay_to_websocket(&mut sink, session, missed, "resume", &mut replayed).await {
+                            Ok(()) => continue,
+                            Err(e) => {
+                                log::error!("Error replaying messages to WebSocket client {}: {:?}", &addr, e);
+                                break;
+                            }
+                        }
+                    }
+                    Some(InternalMessage::Joined(room)) => {
+                        if backlog_messages == 0 {
+                            continue;
+                        }
+                        let backlog = recent_messages(db, &room, backlog_messages, &replayed, addr).await;
+                        replayed.note_backlog(&backlog);
+                        match replay_to_websocket(&mut sink, session, backlog, "backlog", &mut replayed).await {
+                            Ok(()) => continue,
+                            Err(e) => {
+                                log::error!("Error sending the backlog of {} to WebSocket client {}: {:?}", room, &addr, e);
+                                break;
+                            }
+                        }
+                    }
+                    // The reader task has hung up on this client; nothing left to do
+                    None => break,
+                }
+            }
+        };
+
+        if let Err(e) = sink.send(frame).await {
+            log::error!("Error sending msg to WebSocket client {}: {:?}", &addr, e);
+            break;
+        }
+    }
+
+    log::info!("Server killing WebSocket writer task for {}", addr);
+    // The client may already be gone, in which case there is nobody left to say goodbye to
+    let _ = sink.close().await;
+
+    Ok(())
+}
+
+/// Sends a WebSocket client stored messages it hasn't seen, noting them in `replayed`.
+///
+/// # Example
+/// ```
+/// replay_to_websocket(&mut sink, session, missed, "resume", &mut replayed).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if a message cannot be encoded or sent.
+async fn replay_to_websocket<S>(
+    sink: &mut S,
+    session: &Session,
+    messages: Vec<MessageType>,
+    reason: &str,
+    replayed: &mut Replayed,
+) -> Result<()>
+where
+    S: futures_util::Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    if messages.is_empty() {
+        return Ok(());
+    }
+    log::info!(
+        "Replaying {} stored messages to WebSocket client {} ({})",
+        messages.len(),
+        session.addr(),
+        reason
+    );
+
+    for msg in messages {
+        replayed.note(&msg);
+        if let Some(msg) = rooms::deliverable(msg, session) {
+            sink.send(ws::encode(&msg)?).await?;
+            REPLAYED_MESSAGE_COUNTER.with_label_values(&[reason]).inc();
+        }
     }
+
+    Ok(())
 }
 
 /// Handles incoming HTTP requests for the metrics endpoint.
@@ -588,7 +3478,7 @@ async fn serve_metrics(addr: SocketAddr) {
 ///
 /// # Arguments
 ///
-/// * `_req` - An incoming `Request<Body>` that is ignored since this endpoint only serves metrics.
+/// * `_req` - An incoming `Request<Body>` that is ignored since every path other than `/ws` serves metrics.
 ///
 /// # Returns
 ///
@@ -0,0 +1,218 @@
+//! Wire encodings for `MessageType` payloads.
+//!
+//! Frame headers are the same for every connection, but the payload that follows can be encoded in any of the formats
+//! below. The client lists the codecs it supports in its handshake, most preferred first, and the server picks the
+//! first one it also supports. Peers that don't mention codecs at all are spoken to in JSON.
+
+use crate::{AppError, ErrorCode, MessageType};
+
+/// Name of the JSON codec, understood by every peer.
+pub const JSON_CODEC: &str = "json";
+
+/// Name of the MessagePack codec.
+pub const MESSAGE_PACK_CODEC: &str = "msgpack";
+
+/// Name of the bincode codec.
+pub const BINCODE_CODEC: &str = "bincode";
+
+/// Codecs supported by this build, most preferred first.
+pub const SUPPORTED_CODECS: &[&str] = &[BINCODE_CODEC, MESSAGE_PACK_CODEC, JSON_CODEC];
+
+/// Encodes and decodes `MessageType` payloads.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{codec::{Codec, MessagePackCodec}, MessageType};
+/// let message = MessageType::Text(Some("Alice".to_string()), "Hello, World!".to_string());
+/// let bytes = MessagePackCodec.encode(&message).unwrap();
+/// assert_eq!(MessagePackCodec.decode(&bytes).unwrap(), message);
+/// ```
+pub trait Codec: Send + Sync {
+    /// Returns the name this codec is negotiated under.
+    fn name(&self) -> &'static str;
+
+    /// Encodes a message into a frame payload.
+    ///
+    /// # Errors
+    /// This function returns an error if the message cannot be represented in this format.
+    fn encode(&self, msg: &MessageType) -> Result<Vec<u8>, AppError>;
+
+    /// Decodes a frame payload into a message.
+    ///
+    /// # Errors
+    /// This function returns `AppError::Protocol` with `ErrorCode::MalformedFrame` if the payload is not a valid
+    /// `MessageType` in this format.
+    fn decode(&self, bytes: &[u8]) -> Result<MessageType, AppError>;
+}
+
+/// Encodes payloads as JSON. Readable, but binary attachments end up as arrays of numbers.
+pub struct JsonCodec;
+
+/// Encodes payloads as MessagePack.
+pub struct MessagePackCodec;
+
+/// Encodes payloads with bincode.
+pub struct BincodeCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        JSON_CODEC
+    }
+
+    fn encode(&self, msg: &MessageType) -> Result<Vec<u8>, AppError> {
+        Ok(serde_json::to_vec(msg)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<MessageType, AppError> {
+        MessageType::deserialize_msg(bytes)
+    }
+}
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        MESSAGE_PACK_CODEC
+    }
+
+    fn encode(&self, msg: &MessageType) -> Result<Vec<u8>, AppError> {
+        rmp_serde::to_vec(msg).map_err(|e| encode_error(self, e))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<MessageType, AppError> {
+        rmp_serde::from_slice(bytes).map_err(|e| decode_error(self, e))
+    }
+}
+
+impl Codec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        BINCODE_CODEC
+    }
+
+    fn encode(&self, msg: &MessageType) -> Result<Vec<u8>, AppError> {
+        bincode::serialize(msg).map_err(|e| encode_error(self, e))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<MessageType, AppError> {
+        bincode::deserialize(bytes).map_err(|e| decode_error(self, e))
+    }
+}
+
+/// Returns the codec negotiated under `name`, if this build supports it.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::codec::{by_name, JSON_CODEC};
+/// assert_eq!(by_name(JSON_CODEC).unwrap().name(), JSON_CODEC);
+/// assert!(by_name("morse").is_none());
+/// ```
+pub fn by_name(name: &str) -> Option<&'static dyn Codec> {
+    match name {
+        JSON_CODEC => Some(&JsonCodec),
+        MESSAGE_PACK_CODEC => Some(&MessagePackCodec),
+        BINCODE_CODEC => Some(&BincodeCodec),
+        _ => None,
+    }
+}
+
+/// Picks the first of the `offered` codecs this build supports, falling back to JSON.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::codec::{negotiate, MESSAGE_PACK_CODEC};
+/// let offered = vec!["morse".to_string(), MESSAGE_PACK_CODEC.to_string()];
+/// assert_eq!(negotiate(&offered).name(), MESSAGE_PACK_CODEC);
+/// ```
+pub fn negotiate(offered: &[String]) -> &'static dyn Codec {
+    offered
+        .iter()
+        .find_map(|name| by_name(name))
+        .unwrap_or(&JsonCodec)
+}
+
+fn encode_error(codec: &dyn Codec, e: impl std::fmt::Display) -> AppError {
+    AppError::Message(format!("Failed to encode {} message: {}", codec.name(), e))
+}
+
+fn decode_error(codec: &dyn Codec, e: impl std::fmt::Display) -> AppError {
+    AppError::Protocol(
+        ErrorCode::MalformedFrame,
+        format!("Malformed {} payload: {}", codec.name(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accounts::Password, transfer::Attachment};
+
+    fn sample_messages() -> Vec<MessageType> {
+        vec![
+            MessageType::Text(Some("Alice".to_string()), "Hello, World!".to_string()),
+            MessageType::Image(None, vec![0, 1, 2, 255]),
+            MessageType::File(None, "notes.txt".to_string(), b"notes".to_vec()),
+            MessageType::Error(ErrorCode::MalformedFrame, "oops".to_string()),
+            MessageType::TransferStart(None, 1, Attachment::File("a.txt".to_string()), 3),
+            MessageType::TransferChunk(1, 0, b"abc".to_vec()),
+            MessageType::Submit(7, Box::new(MessageType::Text(None, "Hi".to_string()))),
+            MessageType::Stamped(
+                42,
+                1_700_000_000_000,
+                Box::new(MessageType::Image(None, vec![9])),
+            ),
+            MessageType::Ack(7, 42, 1_700_000_000_000),
+            MessageType::History(Box::new(MessageType::Stamped(
+                41,
+                1_700_000_000_000,
+                Box::new(MessageType::Text(
+                    Some("Bob".to_string()),
+                    "Earlier".to_string(),
+                )),
+            ))),
+            MessageType::Login("Alice".to_string(), Password::new("hunter22")),
+        ]
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        for name in SUPPORTED_CODECS {
+            let codec = by_name(name).unwrap();
+            for message in sample_messages() {
+                let bytes = codec.encode(&message).unwrap();
+                assert_eq!(codec.decode(&bytes).unwrap(), message, "codec {}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn binary_codecs_keep_attachments_compact() {
+        let image = MessageType::Image(None, vec![200; 4096]);
+
+        let json = JsonCodec.encode(&image).unwrap().len();
+
+        for codec in [&MessagePackCodec as &dyn Codec, &BincodeCodec] {
+            assert!(codec.encode(&image).unwrap().len() < 4096 + 64);
+            assert!(codec.encode(&image).unwrap().len() * 3 < json);
+        }
+    }
+
+    #[test]
+    fn garbage_is_a_protocol_error() {
+        for name in SUPPORTED_CODECS {
+            let result = by_name(name).unwrap().decode(&[0xc1, 0xff, 0xff]);
+
+            assert!(
+                matches!(
+                    result,
+                    Err(AppError::Protocol(ErrorCode::MalformedFrame, _))
+                ),
+                "codec {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_codecs_fall_back_to_json() {
+        assert_eq!(negotiate(&["morse".to_string()]).name(), JSON_CODEC);
+        assert_eq!(negotiate(&[]).name(), JSON_CODEC);
+    }
+}
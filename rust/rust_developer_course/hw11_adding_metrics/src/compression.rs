@@ -0,0 +1,175 @@
+//! Optional compression of frame payloads.
+//!
+//! The client lists the algorithms it supports in its handshake, most preferred first, and the server picks the first
+//! one it also supports (or none at all). Once agreed, either side may compress any payload it sends, marking the
+//! frame with `FLAG_COMPRESSED`; small payloads, and payloads that don't shrink, are sent as they are.
+
+use crate::{AppError, ErrorCode};
+use std::io::{Read, Write};
+
+/// Name of the zstd algorithm.
+pub const ZSTD_COMPRESSION: &str = "zstd";
+
+/// Name of the deflate algorithm.
+pub const DEFLATE_COMPRESSION: &str = "deflate";
+
+/// Compression algorithms supported by this build, most preferred first.
+pub const SUPPORTED_COMPRESSION: &[&str] = &[ZSTD_COMPRESSION, DEFLATE_COMPRESSION];
+
+/// Default size, in bytes, below which payloads are never compressed. Chat lines are too small to be worth it.
+pub const DEFAULT_COMPRESSION_MIN_BYTES: usize = 1024;
+
+/// Represents a negotiated compression algorithm.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::compression::Compression;
+/// let data = vec![b'a'; 4096];
+/// let compressed = Compression::Zstd.compress(&data).unwrap();
+/// assert!(compressed.len() < data.len());
+/// assert_eq!(Compression::Zstd.decompress(&compressed, data.len()).unwrap(), data);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Deflate,
+}
+
+impl Compression {
+    /// Returns the name this algorithm is negotiated under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Zstd => ZSTD_COMPRESSION,
+            Compression::Deflate => DEFLATE_COMPRESSION,
+        }
+    }
+
+    /// Returns the algorithm negotiated under `name`, if this build supports it.
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            ZSTD_COMPRESSION => Some(Compression::Zstd),
+            DEFLATE_COMPRESSION => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Picks the first of the `offered` algorithms this build supports, if any.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::compression::{Compression, DEFLATE_COMPRESSION};
+    /// let offered = vec!["lz4".to_string(), DEFLATE_COMPRESSION.to_string()];
+    /// assert_eq!(Compression::negotiate(&offered), Some(Compression::Deflate));
+    /// ```
+    pub fn negotiate(offered: &[String]) -> Option<Self> {
+        offered.iter().find_map(|name| Compression::by_name(name))
+    }
+
+    /// Compresses a payload.
+    ///
+    /// # Errors
+    /// This function returns an error if the compressor fails, which only happens if it runs out of memory.
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, AppError> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            Compression::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    /// Decompresses a payload, refusing to produce more than `limit` bytes.
+    ///
+    /// # Errors
+    /// This function returns `AppError::Protocol` with `ErrorCode::MalformedFrame` if the payload is corrupt, or with
+    /// `ErrorCode::FrameTooLarge` if it expands beyond `limit`.
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, AppError> {
+        let mut decompressed = Vec::new();
+        // Read one byte past the limit so an oversized payload can be told apart from one that fits exactly
+        let result = match self {
+            Compression::Zstd => zstd::stream::read::Decoder::new(data).and_then(|decoder| {
+                decoder
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut decompressed)
+            }),
+            Compression::Deflate => flate2::read::DeflateDecoder::new(data)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decompressed),
+        };
+
+        if let Err(e) = result {
+            return Err(AppError::Protocol(
+                ErrorCode::MalformedFrame,
+                format!("Corrupt {} payload: {}", self.name(), e),
+            ));
+        }
+        if decompressed.len() > limit {
+            return Err(AppError::Protocol(
+                ErrorCode::FrameTooLarge,
+                format!("Compressed payload expands beyond the {}-byte limit", limit),
+            ));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_algorithm_round_trips() {
+        let data = b"hello hello hello hello hello hello".repeat(100);
+
+        for name in SUPPORTED_COMPRESSION {
+            let compression = Compression::by_name(name).unwrap();
+            let compressed = compression.compress(&data).unwrap();
+
+            assert!(compressed.len() < data.len(), "{}", name);
+            assert_eq!(
+                compression.decompress(&compressed, data.len()).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let data = vec![0u8; 1024 * 1024];
+
+        for name in SUPPORTED_COMPRESSION {
+            let compression = Compression::by_name(name).unwrap();
+            let compressed = compression.compress(&data).unwrap();
+
+            let result = compression.decompress(&compressed, 1024);
+
+            assert!(
+                matches!(result, Err(AppError::Protocol(ErrorCode::FrameTooLarge, _))),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_payloads_are_protocol_errors() {
+        for name in SUPPORTED_COMPRESSION {
+            let result = Compression::by_name(name)
+                .unwrap()
+                .decompress(b"definitely not compressed", 1024);
+
+            assert!(
+                matches!(
+                    result,
+                    Err(AppError::Protocol(ErrorCode::MalformedFrame, _))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,725 @@
+//! Server and client configuration, read from `CHAT_*` environment variables with sensible defaults.
+
+use crate::{compression::DEFAULT_COMPRESSION_MIN_BYTES, tokens::Token, AppError, FrameKind};
+use anyhow::Result;
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+/// Default upper bound on the size of any single frame, in bytes.
+pub const DEFAULT_MAX_FRAME_BYTES: u32 = 16 * 1024 * 1024;
+
+/// Default upper bound on the size of a text or registration frame, in bytes.
+pub const DEFAULT_MAX_TEXT_FRAME_BYTES: u32 = 64 * 1024;
+
+/// Default upper bound on the total size of a chunked transfer, in bytes.
+pub const DEFAULT_MAX_TRANSFER_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Default number of recoverable protocol errors tolerated from a client before it is disconnected.
+pub const DEFAULT_MAX_PROTOCOL_STRIKES: u32 = 3;
+
+/// Default time between heartbeats sent to a peer that negotiated them.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Default time a peer that negotiated heartbeats may stay silent before the connection is closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Default delay before the first attempt to reconnect to a server that went away.
+pub const DEFAULT_RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Default upper bound on the delay between attempts to reconnect.
+pub const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Default number of recent messages sent to clients as they connect.
+pub const DEFAULT_BACKLOG_MESSAGES: u32 = 20;
+
+/// Default number of consecutive failed logins after which an account is locked.
+pub const DEFAULT_LOGIN_MAX_FAILURES: u32 = 5;
+
+/// Default time an account stays locked after too many failed logins.
+pub const DEFAULT_LOGIN_LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Default time a session token logs its holder back in for.
+pub const DEFAULT_SESSION_TOKEN_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default time users may go without doing anything before they are shown as away.
+pub const DEFAULT_AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Default limits on the messages and attachments sent over a single connection.
+pub const DEFAULT_CONNECTION_BUDGET: Budget = Budget {
+    text: RateLimit {
+        per_minute: 60,
+        burst: 10,
+    },
+    attachments: RateLimit {
+        per_minute: 10,
+        burst: 3,
+    },
+};
+
+/// Default limits on the messages and attachments sent by a user, across all of their connections.
+pub const DEFAULT_USER_BUDGET: Budget = Budget {
+    text: RateLimit {
+        per_minute: 90,
+        burst: 15,
+    },
+    attachments: RateLimit {
+        per_minute: 15,
+        burst: 5,
+    },
+};
+
+/// Represents the tunable settings of the chat server.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{config::ServerConfig, FrameKind};
+/// let config = ServerConfig::default();
+/// assert!(config.frame_limit(FrameKind::Text) <= config.frame_limit(FrameKind::File));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Hard cap on any frame regardless of its kind (`CHAT_MAX_FRAME_BYTES`).
+    pub max_frame_bytes: u32,
+    /// Cap on text and registration frames (`CHAT_MAX_TEXT_FRAME_BYTES`).
+    pub max_text_frame_bytes: u32,
+    /// Cap on file and image frames, including transfer chunks (`CHAT_MAX_ATTACHMENT_FRAME_BYTES`).
+    pub max_attachment_frame_bytes: u32,
+    /// Cap on the total size of a chunked transfer (`CHAT_MAX_TRANSFER_BYTES`).
+    pub max_transfer_bytes: u64,
+    /// Recoverable protocol errors tolerated per connection before it is dropped (`CHAT_MAX_PROTOCOL_STRIKES`).
+    pub max_protocol_strikes: u32,
+    /// Payloads smaller than this are sent uncompressed, even if compression was negotiated
+    /// (`CHAT_COMPRESSION_MIN_BYTES`).
+    pub compression_min_bytes: usize,
+    /// Certificate and key to serve TLS with; plaintext when unset (`CHAT_TLS_CERT`, `CHAT_TLS_KEY`).
+    pub tls: Option<ServerTlsConfig>,
+    /// How often clients are pinged, and how long they may stay silent.
+    pub heartbeat: HeartbeatConfig,
+    /// Number of recent messages sent to clients as they connect, at most `MAX_REPLAYED_MESSAGES`
+    /// (`CHAT_BACKLOG_MESSAGES`).
+    pub backlog_messages: u32,
+    /// How many wrong passwords an account tolerates before refusing logins for a while.
+    pub lockout: LockoutConfig,
+    /// How long session tokens stay valid once issued (`CHAT_SESSION_TOKEN_TTL_SECS`).
+    pub session_token_ttl: Duration,
+    /// Bearer token guarding the administrative HTTP endpoints, which are disabled when unset (`CHAT_ADMIN_TOKEN`).
+    pub admin_token: Option<Token>,
+    /// How quickly clients may send messages and attachments.
+    pub rate_limits: RateLimitConfig,
+    /// Which filters messages are run through before they are broadcast.
+    pub filters: FilterConfig,
+    /// Time users may go without doing anything before they are shown as away; never when unset, and `0` turns it off
+    /// (`CHAT_AWAY_AFTER_SECS`).
+    pub away_after: Option<Duration>,
+}
+
+/// Represents how quickly messages and attachments may be sent over each connection, and by each user across all of
+/// their connections.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::RateLimitConfig;
+/// let rate_limits = RateLimitConfig::default();
+/// assert!(rate_limits.connection.text.per_minute > rate_limits.connection.attachments.per_minute);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Limits on every connection (`CHAT_CONNECTION_*`).
+    pub connection: Budget,
+    /// Limits on every logged in user (`CHAT_USER_*`). Anonymous clients are only held to the connection limits.
+    pub user: Budget,
+}
+
+/// Represents the filters messages are run through before they are broadcast. Every filter is off by default.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::FilterConfig;
+/// let filters = FilterConfig {
+///     max_line_chars: 500,
+///     allowed_attachments: Some(vec!["image".to_string(), "pdf".to_string()]),
+///     ..FilterConfig::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterConfig {
+    /// File of words and patterns to mask or refuse (`CHAT_BLOCKLIST_FILE`).
+    pub blocklist_path: Option<PathBuf>,
+    /// Whether links are removed from messages (`CHAT_STRIP_LINKS`).
+    pub strip_links: bool,
+    /// Longest line a message may have, in characters; `0` removes the limit (`CHAT_MAX_LINE_CHARS`).
+    pub max_line_chars: usize,
+    /// File extensions that may be sent, and `image` for images; anything goes when unset
+    /// (`CHAT_ALLOWED_ATTACHMENTS`, comma separated).
+    pub allowed_attachments: Option<Vec<String>>,
+}
+
+/// Represents separate limits on text messages and on attachments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    /// Limit on text messages (`*_TEXT_PER_MIN`, `*_TEXT_BURST`).
+    pub text: RateLimit,
+    /// Limit on files and images (`*_ATTACHMENTS_PER_MIN`, `*_ATTACHMENTS_BURST`).
+    pub attachments: RateLimit,
+}
+
+/// Represents a token bucket: up to `burst` sends in a row, refilled at `per_minute`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sends allowed per minute once the burst is spent; `0` removes the limit.
+    pub per_minute: u32,
+    /// Sends allowed in a row.
+    pub burst: u32,
+}
+
+/// Represents the certificate chain and private key the server uses for TLS, and how it authenticates clients.
+#[derive(Clone, Debug)]
+pub struct ServerTlsConfig {
+    /// PEM file holding the server's certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file holding the server's private key.
+    pub key_path: PathBuf,
+    /// PEM bundle of CAs trusted to issue client certificates (`CHAT_TLS_CLIENT_CA`). Clients presenting one are
+    /// logged in as the user named by its subject's common name.
+    pub client_ca_path: Option<PathBuf>,
+    /// Whether clients without a certificate are turned away (`CHAT_TLS_REQUIRE_CLIENT_CERT`).
+    pub require_client_cert: bool,
+}
+
+/// Represents the tunable settings of the chat client.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::ClientConfig;
+/// let config = ClientConfig::default();
+/// assert!(config.tls.is_none());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    /// How to verify the server when connecting over TLS; plaintext when unset.
+    pub tls: Option<ClientTlsConfig>,
+    /// How often the server is pinged, and how long it may stay silent.
+    pub heartbeat: HeartbeatConfig,
+    /// How the client goes about reconnecting when the connection is lost.
+    pub reconnect: ReconnectConfig,
+}
+
+/// Represents how often a peer is sent heartbeats, and how long it may go without sending anything.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::HeartbeatConfig;
+/// let heartbeat = HeartbeatConfig::default();
+/// assert!(heartbeat.interval < heartbeat.idle_timeout);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// Time between pings (`CHAT_HEARTBEAT_INTERVAL_SECS`).
+    pub interval: Duration,
+    /// Time without receiving anything after which the connection is closed (`CHAT_IDLE_TIMEOUT_SECS`).
+    pub idle_timeout: Duration,
+}
+
+/// Represents how long the client waits between attempts to reconnect, and how many it makes.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::ReconnectConfig;
+/// let reconnect = ReconnectConfig::default();
+/// assert!(reconnect.initial_delay < reconnect.max_delay);
+/// assert!(reconnect.max_attempts.is_none());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    /// Delay before the first attempt, doubled after every failure (`CHAT_RECONNECT_INITIAL_DELAY_MS`).
+    pub initial_delay: Duration,
+    /// Longest delay between attempts (`CHAT_RECONNECT_MAX_DELAY_SECS`).
+    pub max_delay: Duration,
+    /// Attempts made before giving up; unlimited when unset, and `0` disables reconnecting
+    /// (`CHAT_RECONNECT_MAX_ATTEMPTS`).
+    pub max_attempts: Option<u32>,
+}
+
+/// Represents how many failed logins an account tolerates, and how long it is locked for afterwards.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::config::LockoutConfig;
+/// let lockout = LockoutConfig::default();
+/// assert!(lockout.max_failures > 0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockoutConfig {
+    /// Consecutive failed logins after which the account is locked; `0` never locks it
+    /// (`CHAT_LOGIN_MAX_FAILURES`).
+    pub max_failures: u32,
+    /// Time the account refuses logins for once locked (`CHAT_LOGIN_LOCKOUT_SECS`).
+    pub duration: Duration,
+}
+
+/// Represents how the client verifies the server's TLS certificate, and the certificate it presents in return.
+#[derive(Clone, Debug, Default)]
+pub struct ClientTlsConfig {
+    /// PEM bundle of CAs to trust instead of the public web roots (`CHAT_TLS_CA`).
+    pub ca_path: Option<PathBuf>,
+    /// SHA-256 fingerprint the server's certificate must have (`CHAT_TLS_PIN`).
+    pub pin: Option<String>,
+    /// Name to expect on the server's certificate, if not the host being dialled (`CHAT_TLS_SERVER_NAME`).
+    pub server_name: Option<String>,
+    /// PEM certificate chain to authenticate with (`CHAT_TLS_CLIENT_CERT`).
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key for `cert_path` (`CHAT_TLS_CLIENT_KEY`).
+    pub key_path: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_text_frame_bytes: DEFAULT_MAX_TEXT_FRAME_BYTES,
+            max_attachment_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_transfer_bytes: DEFAULT_MAX_TRANSFER_BYTES,
+            max_protocol_strikes: DEFAULT_MAX_PROTOCOL_STRIKES,
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            tls: None,
+            heartbeat: HeartbeatConfig::default(),
+            backlog_messages: DEFAULT_BACKLOG_MESSAGES,
+            lockout: LockoutConfig::default(),
+            session_token_ttl: DEFAULT_SESSION_TOKEN_TTL,
+            admin_token: None,
+            rate_limits: RateLimitConfig::default(),
+            filters: FilterConfig::default(),
+            away_after: Some(DEFAULT_AWAY_AFTER),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            connection: DEFAULT_CONNECTION_BUDGET,
+            user: DEFAULT_USER_BUDGET,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: DEFAULT_LOGIN_MAX_FAILURES,
+            duration: DEFAULT_LOGIN_LOCKOUT,
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: DEFAULT_RECONNECT_INITIAL_DELAY,
+            max_delay: DEFAULT_RECONNECT_MAX_DELAY,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds a `ServerConfig` from the environment, falling back to the defaults for unset variables.
+    ///
+    /// # Errors
+    /// This function returns an error if a variable is set but cannot be parsed.
+    pub fn from_env() -> Result<Self> {
+        let defaults = ServerConfig::default();
+
+        Ok(ServerConfig {
+            max_frame_bytes: env_or("CHAT_MAX_FRAME_BYTES", defaults.max_frame_bytes)?,
+            max_text_frame_bytes: env_or(
+                "CHAT_MAX_TEXT_FRAME_BYTES",
+                defaults.max_text_frame_bytes,
+            )?,
+            max_attachment_frame_bytes: env_or(
+                "CHAT_MAX_ATTACHMENT_FRAME_BYTES",
+                defaults.max_attachment_frame_bytes,
+            )?,
+            max_transfer_bytes: env_or("CHAT_MAX_TRANSFER_BYTES", defaults.max_transfer_bytes)?,
+            max_protocol_strikes: env_or(
+                "CHAT_MAX_PROTOCOL_STRIKES",
+                defaults.max_protocol_strikes,
+            )?,
+            compression_min_bytes: env_or(
+                "CHAT_COMPRESSION_MIN_BYTES",
+                defaults.compression_min_bytes,
+            )?,
+            tls: ServerTlsConfig::from_env()?,
+            heartbeat: HeartbeatConfig::from_env()?,
+            backlog_messages: env_or("CHAT_BACKLOG_MESSAGES", defaults.backlog_messages)?,
+            lockout: LockoutConfig {
+                max_failures: env_or("CHAT_LOGIN_MAX_FAILURES", defaults.lockout.max_failures)?,
+                duration: Duration::from_secs(env_or(
+                    "CHAT_LOGIN_LOCKOUT_SECS",
+                    defaults.lockout.duration.as_secs(),
+                )?),
+            },
+            session_token_ttl: Duration::from_secs(env_or(
+                "CHAT_SESSION_TOKEN_TTL_SECS",
+                defaults.session_token_ttl.as_secs(),
+            )?),
+            admin_token: env_opt::<String>("CHAT_ADMIN_TOKEN")?.map(Token::new),
+            rate_limits: RateLimitConfig {
+                connection: Budget::from_env("CHAT_CONNECTION", defaults.rate_limits.connection)?,
+                user: Budget::from_env("CHAT_USER", defaults.rate_limits.user)?,
+            },
+            filters: FilterConfig {
+                blocklist_path: env_opt("CHAT_BLOCKLIST_FILE")?,
+                strip_links: env_or("CHAT_STRIP_LINKS", defaults.filters.strip_links)?,
+                max_line_chars: env_or("CHAT_MAX_LINE_CHARS", defaults.filters.max_line_chars)?,
+                allowed_attachments: env_opt::<String>("CHAT_ALLOWED_ATTACHMENTS")?
+                    .map(|allowed| parse_extensions(&allowed)),
+            },
+            away_after: Some(Duration::from_secs(env_or(
+                "CHAT_AWAY_AFTER_SECS",
+                defaults
+                    .away_after
+                    .map_or(0, |away_after| away_after.as_secs()),
+            )?))
+            .filter(|away_after| !away_after.is_zero()),
+        })
+    }
+
+    /// Returns the largest payload, in bytes, accepted for a frame of the given kind.
+    pub fn frame_limit(&self, kind: FrameKind) -> u32 {
+        let kind_limit = match kind {
+            FrameKind::Text
+            | FrameKind::Register
+            | FrameKind::Error
+            | FrameKind::TransferStart
+            | FrameKind::TransferEnd
+            | FrameKind::TransferAbort
+            | FrameKind::Ack
+            | FrameKind::Ping
+            | FrameKind::Pong
+            | FrameKind::Goodbye
+            | FrameKind::Left
+            | FrameKind::Resume
+            | FrameKind::CreateAccount
+            | FrameKind::Login
+            | FrameKind::Rename
+            | FrameKind::Renamed
+            | FrameKind::SessionToken
+            | FrameKind::TokenLogin
+            | FrameKind::Logout
+            | FrameKind::Moderate
+            | FrameKind::Notice
+            | FrameKind::Room
+            | FrameKind::Rooms
+            | FrameKind::Who
+            | FrameKind::Users
+            | FrameKind::Presence
+            | FrameKind::Rejected => self.max_text_frame_bytes,
+            // Envelopes are checked again against the kind of message they wrap once decoded
+            FrameKind::File
+            | FrameKind::Image
+            | FrameKind::TransferChunk
+            | FrameKind::Submit
+            | FrameKind::Stamped
+            | FrameKind::History
+            | FrameKind::InRoom
+            | FrameKind::Direct => self.max_attachment_frame_bytes,
+        };
+        kind_limit.min(self.max_frame_bytes)
+    }
+}
+
+impl ServerTlsConfig {
+    /// Builds a `ServerTlsConfig` from the environment, returning `None` if no certificate is configured.
+    ///
+    /// # Errors
+    /// This function returns an error if only one of `CHAT_TLS_CERT` and `CHAT_TLS_KEY` is set, or client
+    /// certificates are required without a CA to verify them against.
+    fn from_env() -> Result<Option<Self>> {
+        let (cert_path, key_path) = match (env_opt("CHAT_TLS_CERT")?, env_opt("CHAT_TLS_KEY")?) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(AppError::Message(
+                    "CHAT_TLS_CERT and CHAT_TLS_KEY must be set together".to_string(),
+                )
+                .into())
+            }
+        };
+
+        let client_ca_path = env_opt("CHAT_TLS_CLIENT_CA")?;
+        let require_client_cert = env_or("CHAT_TLS_REQUIRE_CLIENT_CERT", false)?;
+        if require_client_cert && client_ca_path.is_none() {
+            return Err(AppError::Message(
+                "CHAT_TLS_REQUIRE_CLIENT_CERT needs CHAT_TLS_CLIENT_CA to verify certificates against"
+                    .to_string(),
+            )
+            .into());
+        }
+
+        Ok(Some(ServerTlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path,
+            require_client_cert,
+        }))
+    }
+}
+
+impl HeartbeatConfig {
+    /// Builds a `HeartbeatConfig` from the environment, falling back to the defaults for unset variables.
+    ///
+    /// # Errors
+    /// This function returns an error if a variable cannot be parsed, or the idle timeout is not longer than the
+    /// interval between pings, since a live peer would then be timed out.
+    pub fn from_env() -> Result<Self> {
+        let defaults = HeartbeatConfig::default();
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_secs(env_or(
+                "CHAT_HEARTBEAT_INTERVAL_SECS",
+                defaults.interval.as_secs(),
+            )?),
+            idle_timeout: Duration::from_secs(env_or(
+                "CHAT_IDLE_TIMEOUT_SECS",
+                defaults.idle_timeout.as_secs(),
+            )?),
+        };
+        heartbeat.validate()?;
+        Ok(heartbeat)
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        if self.interval.is_zero() || self.idle_timeout <= self.interval {
+            return Err(AppError::Message(
+                "CHAT_IDLE_TIMEOUT_SECS must be longer than a non-zero CHAT_HEARTBEAT_INTERVAL_SECS".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Budget {
+    /// Builds a `Budget` from the environment variables starting with `prefix`, falling back to `defaults` for unset
+    /// variables.
+    ///
+    /// # Errors
+    /// This function returns an error if a variable cannot be parsed, or a limit allows no burst at all.
+    fn from_env(prefix: &str, defaults: Budget) -> Result<Self> {
+        let limit = |kind: &str, default: RateLimit| -> Result<RateLimit> {
+            let limit = RateLimit {
+                per_minute: env_or(&format!("{}_{}_PER_MIN", prefix, kind), default.per_minute)?,
+                burst: env_or(&format!("{}_{}_BURST", prefix, kind), default.burst)?,
+            };
+            limit.validate(&format!("{}_{}", prefix, kind))?;
+            Ok(limit)
+        };
+        Ok(Budget {
+            text: limit("TEXT", defaults.text)?,
+            attachments: limit("ATTACHMENTS", defaults.attachments)?,
+        })
+    }
+}
+
+impl RateLimit {
+    /// Returns true if the limit lets everything through.
+    pub fn is_unlimited(&self) -> bool {
+        self.per_minute == 0
+    }
+
+    fn validate(&self, name: &str) -> Result<(), AppError> {
+        if !self.is_unlimited() && self.burst == 0 {
+            return Err(AppError::Message(format!(
+                "{}_BURST must be at least 1 unless {}_PER_MIN is 0",
+                name, name
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ReconnectConfig {
+    /// Builds a `ReconnectConfig` from the environment, falling back to the defaults for unset variables.
+    ///
+    /// # Errors
+    /// This function returns an error if a variable cannot be parsed, or the initial delay is zero or longer than the
+    /// maximum delay.
+    pub fn from_env() -> Result<Self> {
+        let defaults = ReconnectConfig::default();
+        let reconnect = ReconnectConfig {
+            initial_delay: Duration::from_millis(env_or(
+                "CHAT_RECONNECT_INITIAL_DELAY_MS",
+                defaults.initial_delay.as_millis() as u64,
+            )?),
+            max_delay: Duration::from_secs(env_or(
+                "CHAT_RECONNECT_MAX_DELAY_SECS",
+                defaults.max_delay.as_secs(),
+            )?),
+            max_attempts: env_opt("CHAT_RECONNECT_MAX_ATTEMPTS")?,
+        };
+        reconnect.validate()?;
+        Ok(reconnect)
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        if self.initial_delay.is_zero() || self.initial_delay > self.max_delay {
+            return Err(AppError::Message(
+                "CHAT_RECONNECT_INITIAL_DELAY_MS must be non-zero and no longer than CHAT_RECONNECT_MAX_DELAY_SECS"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    /// Builds a `ClientConfig` from the environment.
+    ///
+    /// TLS is used if `CHAT_TLS` is set to `true` (or `1`), or if a CA bundle, certificate pin or client certificate
+    /// is given.
+    ///
+    /// # Errors
+    /// This function returns an error if a variable is set but cannot be parsed.
+    pub fn from_env() -> Result<Self> {
+        let tls = ClientTlsConfig {
+            ca_path: env_opt("CHAT_TLS_CA")?,
+            pin: env_opt("CHAT_TLS_PIN")?,
+            server_name: env_opt("CHAT_TLS_SERVER_NAME")?,
+            cert_path: env_opt("CHAT_TLS_CLIENT_CERT")?,
+            key_path: env_opt("CHAT_TLS_CLIENT_KEY")?,
+        };
+        if tls.cert_path.is_some() != tls.key_path.is_some() {
+            return Err(AppError::Message(
+                "CHAT_TLS_CLIENT_CERT and CHAT_TLS_CLIENT_KEY must be set together".to_string(),
+            )
+            .into());
+        }
+        let enabled = matches!(
+            env_opt::<String>("CHAT_TLS")?.as_deref(),
+            Some("1" | "true")
+        ) || tls.ca_path.is_some()
+            || tls.pin.is_some()
+            || tls.cert_path.is_some();
+
+        Ok(ClientConfig {
+            tls: enabled.then_some(tls),
+            heartbeat: HeartbeatConfig::from_env()?,
+            reconnect: ReconnectConfig::from_env()?,
+        })
+    }
+}
+
+/// Splits a comma separated list of file extensions, ignoring case and leading dots.
+fn parse_extensions(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect()
+}
+
+/// Parses the environment variable `name`, returning `default` when it is unset.
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T> {
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+/// Parses the environment variable `name`, returning `None` when it is unset or empty.
+fn env_opt<T: FromStr>(name: &str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse().map(Some).map_err(|_| {
+            AppError::Message(format!("Invalid value for {}: '{}'", name, value)).into()
+        }),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_limit_caps_kind_limits() {
+        let config = ServerConfig {
+            max_frame_bytes: 1024,
+            max_text_frame_bytes: 4096,
+            max_attachment_frame_bytes: 1_000_000,
+            max_transfer_bytes: DEFAULT_MAX_TRANSFER_BYTES,
+            max_protocol_strikes: DEFAULT_MAX_PROTOCOL_STRIKES,
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            tls: None,
+            heartbeat: HeartbeatConfig::default(),
+            backlog_messages: DEFAULT_BACKLOG_MESSAGES,
+            lockout: LockoutConfig::default(),
+            session_token_ttl: DEFAULT_SESSION_TOKEN_TTL,
+            admin_token: None,
+            rate_limits: RateLimitConfig::default(),
+            filters: FilterConfig::default(),
+            away_after: Some(DEFAULT_AWAY_AFTER),
+        };
+
+        assert_eq!(config.frame_limit(FrameKind::Text), 1024);
+        assert_eq!(config.frame_limit(FrameKind::Image), 1024);
+    }
+
+    #[test]
+    fn attachments_have_their_own_limit() {
+        let config = ServerConfig::default();
+
+        assert_eq!(config.frame_limit(FrameKind::File), DEFAULT_MAX_FRAME_BYTES);
+        assert_eq!(
+            config.frame_limit(FrameKind::Text),
+            DEFAULT_MAX_TEXT_FRAME_BYTES
+        );
+    }
+
+    #[test]
+    fn idle_timeout_must_outlast_the_heartbeat_interval() {
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+        };
+
+        assert!(heartbeat.validate().is_err());
+        assert!(HeartbeatConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reconnect_delays_must_be_in_order() {
+        let reconnect = ReconnectConfig {
+            initial_delay: Duration::from_secs(60),
+            ..ReconnectConfig::default()
+        };
+
+        assert!(reconnect.validate().is_err());
+        assert!(ReconnectConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn attachment_extensions_are_normalised() {
+        assert_eq!(
+            parse_extensions(" .PNG, pdf,,image "),
+            vec!["png".to_string(), "pdf".to_string(), "image".to_string()]
+        );
+    }
+
+    #[test]
+    fn rate_limits_need_a_burst() {
+        let limit = RateLimit {
+            per_minute: 10,
+            burst: 0,
+        };
+        let unlimited = RateLimit {
+            per_minute: 0,
+            burst: 0,
+        };
+
+        assert!(limit.validate("CHAT_USER_TEXT").is_err());
+        assert!(unlimited.validate("CHAT_USER_TEXT").is_ok());
+    }
+}
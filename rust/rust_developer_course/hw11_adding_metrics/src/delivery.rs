@@ -0,0 +1,236 @@
+//! Delivery acknowledgements.
+//!
+//! Peers that negotiate acknowledgements wrap the messages they send in a `Submit` carrying a nonce of their choice.
+//! Once the server has stored the message it answers with an `Ack` holding that nonce along with the id and timestamp
+//! it assigned, and relays the message to everyone as `Stamped` with the same id and timestamp. A message the server
+//! refuses, e.g. because the sender is muted or throttled, is answered with a `Rejected` holding the nonce and the
+//! reason instead, so the sender knows it was never delivered. Peers that didn't negotiate the feature keep sending
+//! bare messages and receive them without the stamp.
+
+use crate::{direct, AppError, ErrorCode, MessageType};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+
+/// Feature name advertised during the handshake by peers that understand acknowledgements.
+pub const ACK_FEATURE: &str = "acks";
+
+/// Returns true if a message may be wrapped in a `Submit`, i.e. it is a chat message the server stores.
+///
+/// Attachments sent in chunks are submitted with their closing `TransferEnd`, since that is when they are stored, and
+/// direct messages along with their `Direct` envelope.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{delivery::can_submit, MessageType};
+/// assert!(can_submit(&MessageType::Text(None, "Hello".to_string())));
+/// assert!(!can_submit(&MessageType::Register("Alice".to_string())));
+/// let direct = MessageType::Direct("Bob".to_string(), Box::new(MessageType::Text(None, "Psst".to_string())));
+/// assert!(can_submit(&direct));
+/// ```
+pub fn can_submit(msg: &MessageType) -> bool {
+    match msg {
+        MessageType::Direct(_, msg) => direct::can_send(msg),
+        msg => matches!(
+            msg,
+            MessageType::Text(..)
+                | MessageType::Image(..)
+                | MessageType::File(..)
+                | MessageType::TransferEnd(..)
+        ),
+    }
+}
+
+/// Takes a message received from a client out of its `Submit` envelope, returning the nonce it was submitted with
+/// (if any) alongside the message itself.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{delivery::open_submission, MessageType};
+/// let text = MessageType::Text(None, "Hello".to_string());
+/// let submit = MessageType::Submit(7, Box::new(text.clone()));
+/// assert_eq!(open_submission(&submit).unwrap(), (Some(7), &text));
+/// assert_eq!(open_submission(&text).unwrap(), (None, &text));
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Protocol` with `ErrorCode::UnexpectedFrameKind` if the envelope holds a message
+/// that can't be submitted, or the client sent a frame only the server is meant to send.
+pub fn open_submission(msg: &MessageType) -> Result<(Option<u64>, &MessageType), AppError> {
+    match msg {
+        MessageType::Submit(nonce, inner) if can_submit(inner) => Ok((Some(*nonce), inner)),
+        MessageType::Submit(_, inner) => Err(AppError::Protocol(
+            ErrorCode::UnexpectedFrameKind,
+            format!("{:?} messages can't be submitted", inner.kind()),
+        )),
+        MessageType::Stamped(..)
+        | MessageType::Ack(..)
+        | MessageType::Left(..)
+        | MessageType::History(..)
+        | MessageType::Renamed(..)
+        | MessageType::Notice(..)
+        | MessageType::SessionToken(..)
+        | MessageType::Rooms(..)
+        | MessageType::InRoom(..)
+        | MessageType::Users(..)
+        | MessageType::Presence(..)
+        | MessageType::Rejected(..) => Err(AppError::Protocol(
+            ErrorCode::UnexpectedFrameKind,
+            format!("{:?} frames are only sent by the server", msg.kind()),
+        )),
+        other => Ok((None, other)),
+    }
+}
+
+/// Formats a timestamp assigned by the server for display, in the local time zone.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::delivery::format_timestamp;
+/// assert_eq!(format_timestamp(0).len(), "00:00:00".len());
+/// ```
+pub fn format_timestamp(millis: i64) -> String {
+    match DateTime::from_timestamp_millis(millis) {
+        Some(time) => time.with_timezone(&Local).format("%H:%M:%S").to_string(),
+        None => "??:??:??".to_string(),
+    }
+}
+
+/// Tracks the messages a client has submitted that the server hasn't acknowledged yet.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{delivery::PendingMessages, MessageType};
+/// let mut pending = PendingMessages::default();
+/// let MessageType::Submit(nonce, _) = pending.submit(MessageType::Text(None, "Hello".to_string())) else {
+///     unreachable!()
+/// };
+/// assert_eq!(pending.len(), 1);
+/// assert!(pending.confirm(nonce).is_some());
+/// assert_eq!(pending.len(), 0);
+/// ```
+#[derive(Debug, Default)]
+pub struct PendingMessages {
+    next_nonce: u64,
+    pending: HashMap<u64, String>,
+}
+
+impl PendingMessages {
+    /// Wraps a message in a `Submit` with a fresh nonce and remembers it until it is confirmed.
+    pub fn submit(&mut self, msg: MessageType) -> MessageType {
+        self.next_nonce += 1;
+        self.pending.insert(self.next_nonce, msg.to_string());
+        MessageType::Submit(self.next_nonce, Box::new(msg))
+    }
+
+    /// Forgets a message the server has acknowledged, returning a description of it, or `None` if no message with
+    /// that nonce is pending.
+    pub fn confirm(&mut self, nonce: u64) -> Option<String> {
+        self.pending.remove(&nonce)
+    }
+
+    /// Forgets a message the server refused, returning a description of it, or `None` if no message with that nonce is
+    /// pending.
+    pub fn reject(&mut self, nonce: u64) -> Option<String> {
+        self.pending.remove(&nonce)
+    }
+
+    /// Forgets every message still waiting for an acknowledgement, e.g. because the connection was lost, returning
+    /// descriptions of them in the order they were submitted.
+    pub fn abandon(&mut self) -> Vec<String> {
+        let mut pending: Vec<(u64, String)> = self.pending.drain().collect();
+        pending.sort_unstable_by_key(|(nonce, _)| *nonce);
+        pending.into_iter().map(|(_, summary)| summary).collect()
+    }
+
+    /// Returns the number of messages still waiting for an acknowledgement.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns true if every submitted message has been acknowledged.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_are_unique_and_confirmed_once() {
+        let mut pending = PendingMessages::default();
+
+        let first = pending.submit(MessageType::Text(None, "one".to_string()));
+        let second = pending.submit(MessageType::Text(None, "two".to_string()));
+        let (MessageType::Submit(first, _), MessageType::Submit(second, _)) = (first, second)
+        else {
+            panic!("Expected Submit envelopes");
+        };
+
+        assert_ne!(first, second);
+        assert_eq!(pending.confirm(first).as_deref(), Some("[anonymous] one"));
+        assert!(pending.confirm(first).is_none());
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn rejected_messages_are_no_longer_pending() {
+        let mut pending = PendingMessages::default();
+        let MessageType::Submit(nonce, _) =
+            pending.submit(MessageType::Text(None, "spam".to_string()))
+        else {
+            panic!("Expected a Submit envelope");
+        };
+
+        assert_eq!(pending.reject(nonce).as_deref(), Some("[anonymous] spam"));
+        assert!(pending.confirm(nonce).is_none());
+        assert!(pending.abandon().is_empty());
+    }
+
+    #[test]
+    fn abandoned_messages_are_listed_in_order() {
+        let mut pending = PendingMessages::default();
+        for text in ["one", "two", "three"] {
+            pending.submit(MessageType::Text(None, text.to_string()));
+        }
+
+        let abandoned = pending.abandon();
+
+        assert_eq!(
+            abandoned,
+            ["[anonymous] one", "[anonymous] two", "[anonymous] three"]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn server_frames_are_not_accepted_from_clients() {
+        let ack = MessageType::Ack(1, 2, 3);
+        let left = MessageType::Left("Alice".to_string(), None);
+        let register = MessageType::Submit(1, Box::new(MessageType::Register("Alice".to_string())));
+
+        assert!(matches!(
+            open_submission(&ack),
+            Err(AppError::Protocol(ErrorCode::UnexpectedFrameKind, _))
+        ));
+        assert!(matches!(
+            open_submission(&left),
+            Err(AppError::Protocol(ErrorCode::UnexpectedFrameKind, _))
+        ));
+        assert!(matches!(
+            open_submission(&register),
+            Err(AppError::Protocol(ErrorCode::UnexpectedFrameKind, _))
+        ));
+    }
+
+    #[test]
+    fn only_stored_messages_are_submitted() {
+        assert!(can_submit(&MessageType::TransferEnd(1, 0)));
+        assert!(!can_submit(&MessageType::TransferChunk(1, 0, vec![])));
+        assert!(!can_submit(&MessageType::Submit(
+            1,
+            Box::new(MessageType::Text(None, "nested".to_string()))
+        )));
+    }
+}
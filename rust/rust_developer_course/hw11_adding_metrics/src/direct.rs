@@ -0,0 +1,103 @@
+//! Direct messages between users.
+//!
+//! A client sends a message to a single user by wrapping it in a `Direct` envelope naming them. Rather than being
+//! broadcast, the server stamps it and hands it to the connections of that user only, along with the sender's other
+//! connections so that they can follow the conversation too. Direct messages are stored with their recipient, and left
+//! out of the message history, the event feed and the backlog sent to everyone else.
+
+use crate::{
+    session::{Connection, Session, Sessions},
+    MessageType,
+};
+
+/// Feature name advertised during the handshake by peers that understand `Direct` frames.
+pub const DIRECT_MESSAGES_FEATURE: &str = "direct-messages";
+
+/// Returns true if a message may be sent directly to a user, i.e. it is text, a file or an image sent whole.
+/// Attachments aren't streamed to single users in chunks.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{direct::can_send, MessageType};
+/// assert!(can_send(&MessageType::Text(None, "Psst".to_string())));
+/// assert!(!can_send(&MessageType::Register("Alice".to_string())));
+/// ```
+pub fn can_send(msg: &MessageType) -> bool {
+    matches!(
+        msg,
+        MessageType::Text(..) | MessageType::Image(..) | MessageType::File(..)
+    )
+}
+
+/// Returns the connections a direct message from the connection `sender` to the user `recipient` is delivered to:
+/// every connection of the recipient, and every other connection of the sender, that negotiated direct messages.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{direct::recipients, handshake::Features, session::{Session, Sessions}};
+/// # use tokio::sync::mpsc;
+/// let features = Features { direct_messages: true, ..Features::default() };
+/// let sessions = Sessions::default();
+/// let alice = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice").with_features(features);
+/// let bob = Session::new("127.0.0.1:4001".parse().unwrap(), 3, "bob").with_features(features);
+/// for session in [&alice, &bob] {
+///     let (internal_tx, _internal_rx) = mpsc::channel(32);
+///     sessions.insert(session.clone(), internal_tx);
+/// }
+///
+/// let connections = recipients(&sessions, "bob", &alice);
+/// assert_eq!(connections.len(), 1);
+/// assert_eq!(connections[0].session.addr(), bob.addr());
+/// ```
+pub fn recipients(sessions: &Sessions, recipient: &str, sender: &Session) -> Vec<Connection> {
+    let mut connections = sessions.of_user(recipient);
+    if sender.name() != recipient {
+        connections.extend(sessions.of_user(&sender.name()));
+    }
+    connections.retain(|connection| {
+        connection.session.addr() != sender.addr() && connection.session.features().direct_messages
+    });
+    connections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::Features;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn direct_messages_reach_both_sides_but_nobody_else() {
+        let features = Features {
+            direct_messages: true,
+            ..Features::default()
+        };
+        let sessions = Sessions::default();
+        let alice =
+            Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice").with_features(features);
+        let alice_elsewhere =
+            Session::new("127.0.0.1:4001".parse().unwrap(), 2, "alice").with_features(features);
+        let bob = Session::new("127.0.0.1:4002".parse().unwrap(), 3, "bob").with_features(features);
+        let bob_old_client = Session::new("127.0.0.1:4003".parse().unwrap(), 3, "bob");
+        let carol =
+            Session::new("127.0.0.1:4004".parse().unwrap(), 4, "carol").with_features(features);
+        for session in [&alice, &alice_elsewhere, &bob, &bob_old_client, &carol] {
+            let (internal_tx, _) = mpsc::channel(1);
+            sessions.insert(session.clone(), internal_tx);
+        }
+
+        let mut addrs: Vec<_> = recipients(&sessions, "bob", &alice)
+            .iter()
+            .map(|connection| connection.session.addr())
+            .collect();
+        addrs.sort();
+        assert_eq!(addrs, vec![alice_elsewhere.addr(), bob.addr()]);
+
+        // Notes to self only go to the sender's other connections, once
+        let addrs: Vec<_> = recipients(&sessions, "alice", &alice)
+            .iter()
+            .map(|connection| connection.session.addr())
+            .collect();
+        assert_eq!(addrs, vec![alice_elsewhere.addr()]);
+    }
+}
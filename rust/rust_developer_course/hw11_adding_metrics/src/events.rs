@@ -0,0 +1,312 @@
+//! Server-Sent Events feed of live chat messages, for dashboards and other read-only consumers.
+//!
+//! Every chat message broadcast by the server is rendered as an SSE event whose name is the kind of message and whose
+//! data is a JSON object. Text is sent inline, while files and images are only described, since their contents would
+//! be of little use to a dashboard. Messages sent to a room say which one, and direct messages, which are private, are
+//! left out. Users coming online, going away and going offline are sent too. Consumers can narrow the feed down with
+//! query string filters.
+
+use crate::{history::format_millis, transfer::Attachment, MessageType};
+use serde_json::json;
+
+/// Path the event feed is served on.
+pub const EVENTS_PATH: &str = "/events";
+
+/// Comment sent to idle consumers so proxies don't time the connection out, and so we notice when they leave.
+pub const KEEP_ALIVE: &str = ": keep-alive\n\n";
+
+/// Represents the filters a consumer of the event feed asked for.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{events::EventFilter, MessageType};
+/// let filter = EventFilter::from_query(Some("user=alice"));
+/// assert!(filter.matches(&MessageType::Text(Some("alice".to_string()), "Hi".to_string())));
+/// assert!(!filter.matches(&MessageType::Text(Some("bob".to_string()), "Hi".to_string())));
+///
+/// let filter = EventFilter::from_query(Some("room=rust"));
+/// let text = MessageType::Text(Some("alice".to_string()), "Hi".to_string());
+/// assert!(filter.matches(&MessageType::InRoom("rust".to_string(), Box::new(text.clone()))));
+/// assert!(!filter.matches(&text));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// Only show messages sent by this user (`?user=`).
+    pub user: Option<String>,
+    /// Only show messages sent to this room (`?room=`).
+    pub room: Option<String>,
+}
+
+impl EventFilter {
+    /// Parses the filters out of a request's query string. Unknown parameters are ignored.
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut filter = EventFilter::default();
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if value.is_empty() {
+                continue;
+            }
+            match key.as_ref() {
+                "user" => filter.user = Some(value.into_owned()),
+                "room" => filter.room = Some(value.trim_start_matches('#').to_lowercase()),
+                _ => {}
+            }
+        }
+        filter
+    }
+
+    /// Returns whether a message passes the filters.
+    pub fn matches(&self, msg: &MessageType) -> bool {
+        let user_matches = match &self.user {
+            Some(user) => sender(msg) == Some(user.as_str()),
+            None => true,
+        };
+        let room_matches = match &self.room {
+            Some(room) => msg.room() == Some(room.as_str()),
+            None => true,
+        };
+        user_matches && room_matches
+    }
+}
+
+/// Renders a broadcast message as an SSE event, or returns `None` if it isn't of interest to consumers.
+///
+/// Transfers are announced once, when they start, in the same shape as a file or image sent in one piece. Messages
+/// stamped by the server carry their id, both as the SSE event id and in the data alongside their `created_at` time,
+/// and messages sent to a room carry its name.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{events::render, MessageType};
+/// let msg = MessageType::Image(Some("alice".to_string()), vec![0; 1024]);
+/// assert_eq!(
+///     render(&msg).unwrap(),
+///     "event: image\ndata: {\"bytes\":1024,\"user\":\"alice\"}\n\n"
+/// );
+/// ```
+pub fn render(msg: &MessageType) -> Option<String> {
+    let (room, msg) = match msg {
+        MessageType::InRoom(room, msg) => (Some(room), msg.as_ref()),
+        other => (None, other),
+    };
+    let (stamp, msg) = match msg {
+        MessageType::Stamped(id, created_at, msg) => (Some((*id, *created_at)), msg.as_ref()),
+        other => (None, other),
+    };
+    let (event, mut data) = match msg {
+        MessageType::Text(user, text) => ("text", json!({ "user": user, "text": text })),
+        MessageType::Image(user, data) => ("image", json!({ "user": user, "bytes": data.len() })),
+        MessageType::File(user, name, data) => (
+            "file",
+            json!({ "user": user, "name": name, "bytes": data.len() }),
+        ),
+        MessageType::Register(user) => ("register", json!({ "user": user })),
+        MessageType::Left(user, reason) => ("leave", json!({ "user": user, "reason": reason })),
+        MessageType::Renamed(previous, user) => {
+            ("rename", json!({ "user": user, "previous": previous }))
+        }
+        MessageType::Presence(user, presence) => {
+            ("presence", json!({ "user": user, "presence": presence }))
+        }
+        MessageType::TransferStart(user, _, Attachment::Image, total) => {
+            ("image", json!({ "user": user, "bytes": total }))
+        }
+        MessageType::TransferStart(user, _, Attachment::File(name), total) => (
+            "file",
+            json!({ "user": user, "name": name, "bytes": total }),
+        ),
+        MessageType::Error(..)
+        | MessageType::TransferChunk(..)
+        | MessageType::TransferEnd(..)
+        | MessageType::TransferAbort(..)
+        | MessageType::Submit(..)
+        | MessageType::Stamped(..)
+        | MessageType::Ack(..)
+        | MessageType::Rejected(..)
+        | MessageType::Ping(..)
+        | MessageType::Pong(..)
+        | MessageType::Goodbye(..)
+        | MessageType::Resume(..)
+        | MessageType::History(..)
+        | MessageType::CreateAccount(..)
+        | MessageType::Login(..)
+        | MessageType::Rename(..)
+        | MessageType::SessionToken(..)
+        | MessageType::TokenLogin(..)
+        | MessageType::Logout
+        | MessageType::Moderate(..)
+        | MessageType::Notice(..)
+        | MessageType::Room(..)
+        | MessageType::Rooms(..)
+        | MessageType::InRoom(..)
+        | MessageType::Direct(..)
+        | MessageType::Who(..)
+        | MessageType::Users(..) => return None,
+    };
+    if let Some(room) = room {
+        data["room"] = json!(room);
+    }
+
+    // serde_json never emits raw newlines, so the data always fits on the single line SSE expects
+    match stamp {
+        Some((id, created_at)) => {
+            data["id"] = json!(id);
+            data["created_at"] = json!(format_millis(created_at));
+            Some(format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data))
+        }
+        None => Some(format!("event: {}\ndata: {}\n\n", event, data)),
+    }
+}
+
+/// Returns the username a message was sent by, if it carries one.
+fn sender(msg: &MessageType) -> Option<&str> {
+    match msg.content() {
+        MessageType::Text(user, ..)
+        | MessageType::Image(user, ..)
+        | MessageType::File(user, ..)
+        | MessageType::TransferStart(user, ..) => user.as_deref(),
+        MessageType::Register(user)
+        | MessageType::Left(user, _)
+        | MessageType::Renamed(_, user)
+        | MessageType::Presence(user, _) => Some(user),
+        MessageType::Error(..)
+        | MessageType::TransferChunk(..)
+        | MessageType::TransferEnd(..)
+        | MessageType::TransferAbort(..)
+        | MessageType::Submit(..)
+        | MessageType::Stamped(..)
+        | MessageType::Ack(..)
+        | MessageType::Rejected(..)
+        | MessageType::Ping(..)
+        | MessageType::Pong(..)
+        | MessageType::Goodbye(..)
+        | MessageType::Resume(..)
+        | MessageType::History(..)
+        | MessageType::CreateAccount(..)
+        | MessageType::Login(..)
+        | MessageType::Rename(..)
+        | MessageType::SessionToken(..)
+        | MessageType::TokenLogin(..)
+        | MessageType::Logout
+        | MessageType::Moderate(..)
+        | MessageType::Notice(..)
+        | MessageType::Room(..)
+        | MessageType::Rooms(..)
+        | MessageType::InRoom(..)
+        | MessageType::Direct(..)
+        | MessageType::Who(..)
+        | MessageType::Users(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presence::Presence;
+
+    #[test]
+    fn text_is_sent_inline() {
+        let msg = MessageType::Text(Some("alice".to_string()), "line one\nline two".to_string());
+
+        assert_eq!(
+            render(&msg).unwrap(),
+            "event: text\ndata: {\"text\":\"line one\\nline two\",\"user\":\"alice\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn files_are_described_without_their_contents() {
+        let msg = MessageType::File(
+            Some("alice".to_string()),
+            "notes.txt".to_string(),
+            b"secret".to_vec(),
+        );
+
+        let event = render(&msg).unwrap();
+
+        assert!(event.starts_with("event: file\n"));
+        assert!(event.contains("\"name\":\"notes.txt\""));
+        assert!(event.contains("\"bytes\":6"));
+        assert!(!event.contains("secret"));
+    }
+
+    #[test]
+    fn transfers_are_announced_once() {
+        let start = MessageType::TransferStart(
+            Some("alice".to_string()),
+            1,
+            Attachment::File("big.iso".to_string()),
+            1 << 30,
+        );
+
+        assert!(render(&start).unwrap().starts_with("event: file\n"));
+        assert!(render(&MessageType::TransferChunk(1, 0, vec![0; 16])).is_none());
+        assert!(render(&MessageType::TransferEnd(1, 0)).is_none());
+    }
+
+    #[test]
+    fn stamped_messages_carry_their_id() {
+        let msg = MessageType::Stamped(
+            42,
+            1_000,
+            Box::new(MessageType::Text(
+                Some("alice".to_string()),
+                "Hi".to_string(),
+            )),
+        );
+
+        assert_eq!(
+            render(&msg).unwrap(),
+            "id: 42\nevent: text\ndata: {\"created_at\":\"1970-01-01T00:00:01.000Z\",\"id\":42,\"text\":\"Hi\",\"user\":\"alice\"}\n\n"
+        );
+        assert!(EventFilter::from_query(Some("user=alice")).matches(&msg));
+    }
+
+    #[test]
+    fn messages_in_rooms_say_which() {
+        let msg = MessageType::InRoom(
+            "rust".to_string(),
+            Box::new(MessageType::Stamped(
+                7,
+                1_000,
+                Box::new(MessageType::Text(
+                    Some("alice".to_string()),
+                    "Hi".to_string(),
+                )),
+            )),
+        );
+
+        let event = render(&msg).unwrap();
+
+        assert!(event.starts_with("id: 7\nevent: text\n"));
+        assert!(event.contains("\"room\":\"rust\""));
+        assert!(EventFilter::from_query(Some("room=%23Rust&user=alice")).matches(&msg));
+        assert!(!EventFilter::from_query(Some("room=lobby")).matches(&msg));
+    }
+
+    #[test]
+    fn presence_changes_are_sent() {
+        let msg = MessageType::Presence("alice".to_string(), Presence::Away);
+
+        assert_eq!(
+            render(&msg).unwrap(),
+            "event: presence\ndata: {\"presence\":\"away\",\"user\":\"alice\"}\n\n"
+        );
+        assert!(EventFilter::from_query(Some("user=alice")).matches(&msg));
+    }
+
+    #[test]
+    fn user_filter_is_url_decoded() {
+        let filter = EventFilter::from_query(Some("user=J%C3%BCrgen+M&unknown=1"));
+
+        assert_eq!(filter.user.as_deref(), Some("Jürgen M"));
+    }
+
+    #[test]
+    fn empty_filters_match_everything() {
+        let filter = EventFilter::from_query(None);
+
+        assert!(filter.matches(&MessageType::Text(None, "Hi".to_string())));
+        assert!(!EventFilter::from_query(Some("user=alice"))
+            .matches(&MessageType::Text(None, "Hi".to_string())));
+    }
+}
@@ -0,0 +1,429 @@
+//! Filters messages are run through before they are broadcast.
+//!
+//! The server passes every message a client sends through a `FilterChain`. Each `MessageFilter` in the chain may let
+//! the message through, change it (the next filter sees the changed message), or refuse it with a reason that is sent
+//! back to the sender. The chain is built from `FilterConfig` when the server starts.
+
+use crate::{config::FilterConfig, transfer::Attachment, AppError, MessageType};
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use std::{fmt, path::Path};
+
+/// Represents what a filter decided to do with a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Let the message through unchanged.
+    Accept,
+    /// Let the given message through in its place.
+    Modify(MessageType),
+    /// Refuse the message, telling the sender why.
+    Reject(String),
+}
+
+/// A check or transformation applied to messages before they are broadcast.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{filter::{MessageFilter, Verdict}, MessageType};
+/// #[derive(Debug)]
+/// struct NoShouting;
+///
+/// impl MessageFilter for NoShouting {
+///     fn name(&self) -> &'static str {
+///         "no-shouting"
+///     }
+///
+///     fn check(&self, message: &MessageType) -> Verdict {
+///         match message {
+///             MessageType::Text(user, text) if text.chars().any(char::is_uppercase) => {
+///                 Verdict::Modify(MessageType::Text(user.clone(), text.to_lowercase()))
+///             }
+///             _ => Verdict::Accept,
+///         }
+///     }
+/// }
+/// ```
+pub trait MessageFilter: fmt::Debug + Send + Sync {
+    /// Returns the name the filter is reported under in logs and metrics.
+    fn name(&self) -> &'static str;
+
+    /// Decides what to do with `message`.
+    fn check(&self, message: &MessageType) -> Verdict;
+}
+
+/// Represents a message refused by a filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    /// Name of the filter that refused the message.
+    pub filter: &'static str,
+    /// Why the message was refused, to be told to the sender.
+    pub reason: String,
+}
+
+/// Represents what happened to a message that made it through the chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filtered {
+    /// The message to broadcast.
+    pub message: MessageType,
+    /// Names of the filters that changed it, in the order they did so.
+    pub modified_by: Vec<&'static str>,
+}
+
+/// Runs messages through a list of filters in order, stopping at the first that refuses it.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{filter::{FilterChain, MaxLineLength}, MessageType};
+/// let chain = FilterChain::new(vec![Box::new(MaxLineLength(10))]);
+///
+/// let short = MessageType::Text(Some("alice".to_string()), "hi".to_string());
+/// assert!(chain.run(short).is_ok());
+///
+/// let long = MessageType::Text(Some("alice".to_string()), "x".repeat(11));
+/// assert_eq!(chain.run(long).unwrap_err().filter, "max-line-length");
+/// ```
+#[derive(Debug, Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    pub fn new(filters: Vec<Box<dyn MessageFilter>>) -> Self {
+        FilterChain { filters }
+    }
+
+    /// Builds the chain of filters turned on in `config`: the blocklist, link stripping, the line length limit and
+    /// the attachment allowlist, in that order.
+    ///
+    /// # Errors
+    /// This function returns an error if the blocklist can't be read or holds an invalid pattern.
+    pub fn from_config(config: &FilterConfig) -> Result<Self> {
+        let mut filters: Vec<Box<dyn MessageFilter>> = Vec::new();
+        if let Some(path) = &config.blocklist_path {
+            filters.push(Box::new(Blocklist::load(path)?));
+        }
+        if config.strip_links {
+            filters.push(Box::new(LinkStripper::default()));
+        }
+        if config.max_line_chars > 0 {
+            filters.push(Box::new(MaxLineLength(config.max_line_chars)));
+        }
+        if let Some(allowed) = &config.allowed_attachments {
+            filters.push(Box::new(AttachmentAllowlist(allowed.clone())));
+        }
+        Ok(FilterChain::new(filters))
+    }
+
+    /// Returns the names of the filters in the chain.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.filters.iter().map(|filter| filter.name()).collect()
+    }
+
+    /// Runs `message` through every filter, each seeing the message as changed by those before it.
+    ///
+    /// # Errors
+    /// This function returns the first filter to refuse the message, and why.
+    pub fn run(&self, message: MessageType) -> Result<Filtered, Rejection> {
+        let mut filtered = Filtered {
+            message,
+            modified_by: Vec::new(),
+        };
+        for filter in &self.filters {
+            match filter.check(&filtered.message) {
+                Verdict::Accept => {}
+                Verdict::Modify(message) => {
+                    filtered.message = message;
+                    filtered.modified_by.push(filter.name());
+                }
+                Verdict::Reject(reason) => {
+                    return Err(Rejection {
+                        filter: filter.name(),
+                        reason,
+                    })
+                }
+            }
+        }
+        Ok(filtered)
+    }
+}
+
+/// Represents a single blocklist entry.
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    /// Whether messages matching the rule are refused, rather than having the match masked.
+    reject: bool,
+}
+
+/// Masks blocked words in text messages with asterisks, or refuses the message outright.
+///
+/// Rules are listed one per line. A plain word matches that whole word; a rule between slashes, like `/fr[e3]{2}/`,
+/// is a regular expression. Both ignore case. Rules starting with `!` refuse matching messages instead of masking the
+/// match. Blank lines and lines starting with `#` are ignored.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{filter::{Blocklist, MessageFilter, Verdict}, MessageType};
+/// let blocklist = Blocklist::parse("darn\n!/buy\\s+now/").unwrap();
+///
+/// let message = MessageType::Text(None, "Darn it".to_string());
+/// assert_eq!(blocklist.check(&message), Verdict::Modify(MessageType::Text(None, "**** it".to_string())));
+///
+/// let spam = MessageType::Text(None, "Buy  now!".to_string());
+/// assert!(matches!(blocklist.check(&spam), Verdict::Reject(_)));
+/// ```
+#[derive(Debug)]
+pub struct Blocklist {
+    rules: Vec<Rule>,
+}
+
+impl Blocklist {
+    /// Parses blocklist rules, one per line.
+    ///
+    /// # Errors
+    /// This function returns an error naming the line of the first rule that isn't a valid regular expression.
+    pub fn parse(rules: &str) -> Result<Self> {
+        let rules = rules
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| {
+                let (reject, rule) = match line.strip_prefix('!') {
+                    Some(rule) => (true, rule.trim()),
+                    None => (false, line),
+                };
+                let pattern = match rule
+                    .strip_prefix('/')
+                    .and_then(|rule| rule.strip_suffix('/'))
+                {
+                    Some(regex) => regex.to_string(),
+                    None => format!(r"\b{}\b", regex::escape(rule)),
+                };
+                let pattern = RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| {
+                        AppError::Message(format!(
+                            "Invalid blocklist rule on line {}: {}",
+                            number, e
+                        ))
+                    })?;
+                Ok(Rule { pattern, reject })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Blocklist { rules })
+    }
+
+    /// Reads blocklist rules from the file at `path`.
+    ///
+    /// # Errors
+    /// This function returns an error if the file can't be read or holds an invalid rule.
+    pub fn load(path: &Path) -> Result<Self> {
+        let rules = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read blocklist {}", path.display()))?;
+        Blocklist::parse(&rules)
+            .with_context(|| format!("Failed to load blocklist {}", path.display()))
+    }
+}
+
+impl MessageFilter for Blocklist {
+    fn name(&self) -> &'static str {
+        "blocklist"
+    }
+
+    fn check(&self, message: &MessageType) -> Verdict {
+        let MessageType::Text(user, text) = message else {
+            return Verdict::Accept;
+        };
+
+        if self
+            .rules
+            .iter()
+            .any(|rule| rule.reject && rule.pattern.is_match(text))
+        {
+            return Verdict::Reject("Your message contains blocked content".to_string());
+        }
+
+        let mut masked = text.clone();
+        for rule in &self.rules {
+            masked = rule
+                .pattern
+                .replace_all(&masked, |captures: &regex::Captures| {
+                    "*".repeat(captures[0].chars().count())
+                })
+                .into_owned();
+        }
+        if masked == *text {
+            Verdict::Accept
+        } else {
+            Verdict::Modify(MessageType::Text(user.clone(), masked))
+        }
+    }
+}
+
+/// Removes links from text messages, leaving a placeholder in their place.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{filter::{LinkStripper, MessageFilter, Verdict}, MessageType};
+/// let message = MessageType::Text(None, "see https://example.com/x".to_string());
+/// assert_eq!(
+///     LinkStripper::default().check(&message),
+///     Verdict::Modify(MessageType::Text(None, "see [link removed]".to_string()))
+/// );
+/// ```
+#[derive(Debug)]
+pub struct LinkStripper {
+    pattern: Regex,
+}
+
+impl Default for LinkStripper {
+    fn default() -> Self {
+        LinkStripper {
+            pattern: Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+").unwrap(),
+        }
+    }
+}
+
+impl MessageFilter for LinkStripper {
+    fn name(&self) -> &'static str {
+        "link-stripper"
+    }
+
+    fn check(&self, message: &MessageType) -> Verdict {
+        match message {
+            MessageType::Text(user, text) if self.pattern.is_match(text) => {
+                let stripped = self.pattern.replace_all(text, "[link removed]");
+                Verdict::Modify(MessageType::Text(user.clone(), stripped.into_owned()))
+            }
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+/// Refuses text messages with a line longer than the given number of characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxLineLength(pub usize); // (characters)
+
+impl MessageFilter for MaxLineLength {
+    fn name(&self) -> &'static str {
+        "max-line-length"
+    }
+
+    fn check(&self, message: &MessageType) -> Verdict {
+        let MessageType::Text(_, text) = message else {
+            return Verdict::Accept;
+        };
+        match text.lines().map(|line| line.chars().count()).max() {
+            Some(longest) if longest > self.0 => Verdict::Reject(format!(
+                "Lines can be at most {} characters long; yours was {}",
+                self.0, longest
+            )),
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+/// Refuses attachments whose file extension isn't listed. Images are allowed if `image` is listed.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{filter::{AttachmentAllowlist, MessageFilter, Verdict}, MessageType};
+/// let allowlist = AttachmentAllowlist(vec!["pdf".to_string()]);
+///
+/// let pdf = MessageType::File(None, "Notes.PDF".to_string(), vec![]);
+/// assert_eq!(allowlist.check(&pdf), Verdict::Accept);
+///
+/// let image = MessageType::Image(None, vec![]);
+/// assert!(matches!(allowlist.check(&image), Verdict::Reject(_)));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttachmentAllowlist(pub Vec<String>); // (lowercase extensions, or "image")
+
+impl AttachmentAllowlist {
+    /// Returns the type `attachment` is listed under: its extension, or `image`.
+    fn kind(attachment: &Attachment) -> Option<String> {
+        match attachment {
+            Attachment::Image => Some("image".to_string()),
+            Attachment::File(name) => Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_lowercase()),
+        }
+    }
+}
+
+impl MessageFilter for AttachmentAllowlist {
+    fn name(&self) -> &'static str {
+        "attachment-allowlist"
+    }
+
+    fn check(&self, message: &MessageType) -> Verdict {
+        let attachment = match message {
+            MessageType::File(_, name, _) => Attachment::File(name.clone()),
+            MessageType::Image(..) => Attachment::Image,
+            MessageType::TransferStart(_, _, attachment, _) => attachment.clone(),
+            _ => return Verdict::Accept,
+        };
+        match Self::kind(&attachment) {
+            Some(kind) if self.0.contains(&kind) => Verdict::Accept,
+            _ => Verdict::Reject(format!(
+                "The server doesn't accept this {}; allowed are: {}",
+                attachment,
+                self.0.join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> MessageType {
+        MessageType::Text(Some("alice".to_string()), text.to_string())
+    }
+
+    #[test]
+    fn blocklist_rules_mask_whole_words_and_patterns() {
+        let blocklist = Blocklist::parse("# Comments are skipped\n\nheck\n/d[a4]rn/\n").unwrap();
+
+        assert_eq!(blocklist.check(&text("Checkmate")), Verdict::Accept);
+        assert_eq!(
+            blocklist.check(&text("HECK, d4rn it")),
+            Verdict::Modify(text("****, **** it"))
+        );
+        assert!(Blocklist::parse("fine\n/unclosed(/")
+            .is_err_and(|e| format!("{:#}", e).contains("line 2")));
+    }
+
+    #[test]
+    fn chain_passes_changes_along_and_stops_at_rejections() {
+        let chain = FilterChain::new(vec![
+            Box::new(LinkStripper::default()),
+            Box::new(Blocklist::parse("removed").unwrap()),
+            Box::new(MaxLineLength(20)),
+            Box::new(AttachmentAllowlist(vec!["txt".to_string()])),
+        ]);
+
+        let filtered = chain.run(text("www.example.com")).unwrap();
+        assert_eq!(filtered.message, text("[link *******]"));
+        assert_eq!(filtered.modified_by, vec!["link-stripper", "blocklist"]);
+
+        let rejection = chain
+            .run(text("a perfectly reasonable\nline, then one that goes on"))
+            .unwrap_err();
+        assert_eq!(rejection.filter, "max-line-length");
+
+        let start =
+            MessageType::TransferStart(None, 1, Attachment::File("run.exe".to_string()), 10);
+        assert_eq!(chain.run(start).unwrap_err().filter, "attachment-allowlist");
+        let start =
+            MessageType::TransferStart(None, 1, Attachment::File("notes.txt".to_string()), 10);
+        assert!(chain.run(start).is_ok());
+        assert!(chain
+            .run(MessageType::File(None, "README".to_string(), vec![]))
+            .is_err());
+    }
+}
@@ -0,0 +1,552 @@
+//! Connection handshake exchanged before any `MessageType` frames.
+//!
+//! Every connection starts with the client sending a `Handshake` and the server answering with a `HandshakeReply`.
+//! Both are sent inside a preamble whose layout is frozen across protocol versions so that any peer can always read
+//! it and explain why it is rejecting the connection:
+//!
+//! ```text
+//! [magic: 4 bytes "RDCH"][protocol version: u16 BE][body length: u32 BE][body: JSON]
+//! ```
+
+use crate::{
+    accounts::{ACCOUNTS_FEATURE, RENAME_FEATURE},
+    codec::{self, Codec, JSON_CODEC, SUPPORTED_CODECS},
+    compression::{Compression, SUPPORTED_COMPRESSION},
+    config::DEFAULT_MAX_FRAME_BYTES,
+    delivery::ACK_FEATURE,
+    direct::DIRECT_MESSAGES_FEATURE,
+    heartbeat::HEARTBEAT_FEATURE,
+    moderation::MODERATION_FEATURE,
+    presence::PRESENCE_FEATURE,
+    resume::{BACKLOG_FEATURE, RESUME_FEATURE},
+    rooms::ROOMS_FEATURE,
+    tokens::TOKENS_FEATURE,
+    transfer::CHUNKED_TRANSFER_FEATURE,
+    AppError,
+};
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Magic bytes identifying a chat protocol peer.
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"RDCH";
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest protocol version this build is still able to talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Feature name advertised during the handshake by peers that say `Goodbye` before disconnecting, and understand the
+/// `Left` notices sent when others do.
+pub const GOODBYE_FEATURE: &str = "goodbye";
+
+/// Optional protocol features supported by this build, advertised during the handshake.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    CHUNKED_TRANSFER_FEATURE,
+    ACK_FEATURE,
+    HEARTBEAT_FEATURE,
+    GOODBYE_FEATURE,
+    RESUME_FEATURE,
+    BACKLOG_FEATURE,
+    ACCOUNTS_FEATURE,
+    RENAME_FEATURE,
+    TOKENS_FEATURE,
+    MODERATION_FEATURE,
+    ROOMS_FEATURE,
+    DIRECT_MESSAGES_FEATURE,
+    PRESENCE_FEATURE,
+];
+
+/// Upper bound on the size of a handshake body. Handshakes are tiny; anything larger is not a well-behaved peer.
+pub const MAX_HANDSHAKE_LEN: u32 = 64 * 1024;
+
+/// Represents the handshake a client sends immediately after connecting.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::handshake::Handshake;
+/// let handshake = Handshake::new("hw11-client/0.1.0");
+/// assert_eq!(handshake.client_name, "hw11-client/0.1.0");
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub client_name: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Codecs the client can speak, most preferred first.
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// Compression algorithms the client can use, most preferred first.
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+/// Represents the server's answer to a `Handshake`.
+///
+/// An acceptance names the codec every following frame will be encoded with, the compression algorithm frames may
+/// be compressed with, if any, and the largest frame payload the server will send or accept. A rejection carries a human readable reason along with the range of protocol
+/// versions the server accepts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HandshakeReply {
+    Accepted {
+        server_name: String,
+        features: Vec<String>,
+        #[serde(default = "default_codec")]
+        codec: String,
+        #[serde(default)]
+        compression: Option<String>,
+        #[serde(default = "default_max_frame_bytes")]
+        max_frame_bytes: u32,
+    },
+    Rejected {
+        reason: String,
+        min_version: u16,
+        max_version: u16,
+    },
+}
+
+impl Handshake {
+    /// Returns a new `Handshake` advertising every feature, codec and compression algorithm supported by this build.
+    pub fn new(client_name: &str) -> Self {
+        Handshake {
+            client_name: client_name.to_string(),
+            features: SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect(),
+            codecs: SUPPORTED_CODECS.iter().map(|c| c.to_string()).collect(),
+            compression: SUPPORTED_COMPRESSION
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        }
+    }
+
+    /// Sends the handshake preamble to a remote stream.
+    ///
+    /// # Errors
+    /// This function returns an error if it fails to serialize the handshake or write to the stream.
+    pub async fn send<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        write_preamble(stream, self).await
+    }
+
+    /// Receives a handshake preamble from a remote stream, returning the protocol version announced by the peer
+    /// alongside the handshake itself.
+    ///
+    /// # Errors
+    /// This function returns an error if the peer does not speak the chat protocol, the body is too large, or the
+    /// body cannot be deserialized.
+    pub async fn recv<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<(u16, Self)> {
+        read_preamble(stream).await
+    }
+
+    /// Decides whether a client handshake announcing `version` is acceptable to this server.
+    ///
+    /// Accepted handshakes are answered with the features both sides support, the client's most preferred codec and
+    /// compression algorithm that the server also supports, and the server's `max_frame_bytes` limit.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::handshake::{Handshake, HandshakeReply, PROTOCOL_VERSION};
+    /// let handshake = Handshake::new("hw11-client/0.1.0");
+    /// let reply = handshake.evaluate(PROTOCOL_VERSION + 1, "hw11-server/0.1.0", 1024);
+    /// assert!(matches!(reply, HandshakeReply::Rejected { .. }));
+    /// ```
+    pub fn evaluate(
+        &self,
+        version: u16,
+        server_name: &str,
+        max_frame_bytes: u32,
+    ) -> HandshakeReply {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return HandshakeReply::Rejected {
+                reason: format!(
+                    "Unsupported protocol version {}; this server speaks versions {} through {}",
+                    version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            };
+        }
+
+        let features = self
+            .features
+            .iter()
+            .filter(|f| SUPPORTED_FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect();
+
+        HandshakeReply::Accepted {
+            server_name: server_name.to_string(),
+            features,
+            codec: codec::negotiate(&self.codecs).name().to_string(),
+            compression: Compression::negotiate(&self.compression).map(|c| c.name().to_string()),
+            max_frame_bytes,
+        }
+    }
+}
+
+impl HandshakeReply {
+    /// Returns a rejection for a peer whose handshake could not be read at all.
+    pub fn rejected(reason: &str) -> Self {
+        HandshakeReply::Rejected {
+            reason: reason.to_string(),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Sends the reply preamble to a remote stream.
+    ///
+    /// # Errors
+    /// This function returns an error if it fails to serialize the reply or write to the stream.
+    pub async fn send<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+        write_preamble(stream, self).await
+    }
+
+    /// Receives a reply preamble from a remote stream, returning the server's protocol version alongside it.
+    ///
+    /// # Errors
+    /// This function returns an error if the peer does not speak the chat protocol or the reply is malformed.
+    pub async fn recv<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<(u16, Self)> {
+        read_preamble(stream).await
+    }
+}
+
+/// Represents which of the optional features known to this build were negotiated for a connection.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::handshake::{Features, GOODBYE_FEATURE};
+/// let features = Features::from_names(&[GOODBYE_FEATURE.to_string()]);
+/// assert!(features.goodbyes);
+/// assert!(!features.chunked_transfers);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    pub chunked_transfers: bool,
+    pub acks: bool,
+    pub heartbeats: bool,
+    pub goodbyes: bool,
+    pub resume: bool,
+    pub backlog: bool,
+    pub accounts: bool,
+    pub renames: bool,
+    pub tokens: bool,
+    pub moderation: bool,
+    pub rooms: bool,
+    pub direct_messages: bool,
+    pub presence: bool,
+}
+
+impl Features {
+    /// Returns every feature this build supports. WebSocket clients, which don't handshake, are assumed to support all
+    /// of them but chunked transfers.
+    pub fn all() -> Self {
+        let names: Vec<String> = SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect();
+        Features::from_names(&names)
+    }
+
+    /// Returns the features named in a handshake reply. Names this build doesn't know are ignored.
+    pub fn from_names(names: &[String]) -> Self {
+        let has = |feature: &str| names.iter().any(|name| name == feature);
+        Features {
+            chunked_transfers: has(CHUNKED_TRANSFER_FEATURE),
+            acks: has(ACK_FEATURE),
+            heartbeats: has(HEARTBEAT_FEATURE),
+            goodbyes: has(GOODBYE_FEATURE),
+            resume: has(RESUME_FEATURE),
+            backlog: has(BACKLOG_FEATURE),
+            accounts: has(ACCOUNTS_FEATURE),
+            renames: has(RENAME_FEATURE),
+            tokens: has(TOKENS_FEATURE),
+            moderation: has(MODERATION_FEATURE),
+            rooms: has(ROOMS_FEATURE),
+            direct_messages: has(DIRECT_MESSAGES_FEATURE),
+            presence: has(PRESENCE_FEATURE),
+        }
+    }
+}
+
+/// Represents what a client and server agreed upon during the handshake.
+pub struct Negotiated {
+    pub features: Vec<String>,
+    pub codec: &'static dyn Codec,
+    pub compression: Option<Compression>,
+    /// Largest frame payload the server will send or accept.
+    pub max_frame_bytes: u32,
+}
+
+impl std::fmt::Debug for Negotiated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Negotiated")
+            .field("features", &self.features)
+            .field("codec", &self.codec.name())
+            .field("compression", &self.compression)
+            .field("max_frame_bytes", &self.max_frame_bytes)
+            .finish()
+    }
+}
+
+/// Performs the client side of the handshake on a freshly connected stream.
+///
+/// On success the features, codec and compression agreed upon with the server are returned, along with its frame size
+/// limit.
+///
+/// # Example
+/// ```ignore
+/// let negotiated = client_handshake(&mut stream, "hw11-client/0.1.0").await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Handshake` if the server rejects the connection or picks a codec or compression
+/// algorithm this build doesn't support, or an error if the stream fails.
+pub async fn client_handshake<T>(stream: &mut T, client_name: &str) -> Result<Negotiated>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    Handshake::new(client_name).send(stream).await?;
+
+    let (version, reply) = HandshakeReply::recv(stream).await?;
+    match reply {
+        HandshakeReply::Accepted {
+            server_name,
+            features,
+            codec,
+            compression,
+            max_frame_bytes,
+        } => {
+            let codec = codec::by_name(&codec).ok_or_else(|| {
+                AppError::Handshake(format!("Server picked an unsupported codec: {}", codec))
+            })?;
+            let compression = match compression {
+                Some(name) => Some(Compression::by_name(&name).ok_or_else(|| {
+                    AppError::Handshake(format!(
+                        "Server picked an unsupported compression: {}",
+                        name
+                    ))
+                })?),
+                None => None,
+            };
+            log::info!(
+                "Connected to {} (protocol v{}, {} codec, compression: {}), features: {:?}",
+                server_name,
+                version,
+                codec.name(),
+                compression.map_or("none", |c| c.name()),
+                features
+            );
+            Ok(Negotiated {
+                features,
+                codec,
+                compression,
+                max_frame_bytes,
+            })
+        }
+        HandshakeReply::Rejected { reason, .. } => Err(AppError::Handshake(reason).into()),
+    }
+}
+
+/// Returns the codec assumed for peers that don't negotiate one.
+fn default_codec() -> String {
+    JSON_CODEC.to_string()
+}
+
+/// Returns the frame size limit assumed for servers that don't advertise one.
+fn default_max_frame_bytes() -> u32 {
+    DEFAULT_MAX_FRAME_BYTES
+}
+
+/// Writes the magic bytes, protocol version and JSON body making up a handshake preamble.
+async fn write_preamble<T, B>(stream: &mut T, body: &B) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+    B: Serialize,
+{
+    let body = serde_json::to_vec(body).context("Failed to serialize handshake")?;
+
+    stream.write_all(&PROTOCOL_MAGIC).await?;
+    stream.write_all(&PROTOCOL_VERSION.to_be_bytes()).await?;
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    Ok(())
+}
+
+/// Reads and validates a handshake preamble, returning the announced protocol version and the decoded body.
+async fn read_preamble<T, B>(stream: &mut T) -> Result<(u16, B)>
+where
+    T: AsyncReadExt + Unpin,
+    B: DeserializeOwned,
+{
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic).await?;
+    if magic != PROTOCOL_MAGIC {
+        return Err(
+            AppError::Handshake("Peer does not speak the chat protocol".to_string()).into(),
+        );
+    }
+
+    let mut version_bytes = [0u8; 2];
+    stream.read_exact(&mut version_bytes).await?;
+    let version = u16::from_be_bytes(version_bytes);
+
+    let mut length_bytes = [0u8; 4];
+    stream.read_exact(&mut length_bytes).await?;
+    let len = u32::from_be_bytes(length_bytes);
+    if len > MAX_HANDSHAKE_LEN {
+        return Err(AppError::Handshake(format!("Handshake of {} bytes is too large", len)).into());
+    }
+
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    let body = serde_json::from_slice(&body)
+        .map_err(|e| AppError::Handshake(format!("Malformed handshake: {}", e)))?;
+
+    Ok((version, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_version_is_accepted() {
+        let handshake = Handshake::new("test-client");
+
+        let reply = handshake.evaluate(PROTOCOL_VERSION, "test-server", DEFAULT_MAX_FRAME_BYTES);
+
+        assert!(matches!(reply, HandshakeReply::Accepted { .. }));
+    }
+
+    #[test]
+    fn unknown_features_are_not_negotiated() {
+        let mut handshake = Handshake::new("test-client");
+        handshake.features.push("time-travel".to_string());
+
+        let reply = handshake.evaluate(PROTOCOL_VERSION, "test-server", DEFAULT_MAX_FRAME_BYTES);
+
+        match reply {
+            HandshakeReply::Accepted { features, .. } => {
+                assert!(!features.contains(&"time-travel".to_string()))
+            }
+            HandshakeReply::Rejected { .. } => panic!("handshake should have been accepted"),
+        }
+    }
+
+    #[test]
+    fn every_supported_feature_is_recognised() {
+        let names: Vec<String> = SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect();
+
+        let features = Features::from_names(&names);
+
+        assert_eq!(
+            features,
+            Features {
+                chunked_transfers: true,
+                acks: true,
+                heartbeats: true,
+                goodbyes: true,
+                resume: true,
+                backlog: true,
+                accounts: true,
+                renames: true,
+                tokens: true,
+                moderation: true,
+                rooms: true,
+                direct_messages: true,
+                presence: true,
+            }
+        );
+    }
+
+    #[test]
+    fn clients_without_codecs_are_spoken_to_in_json() {
+        let mut handshake = Handshake::new("test-client");
+        handshake.codecs.clear();
+
+        let reply = handshake.evaluate(PROTOCOL_VERSION, "test-server", DEFAULT_MAX_FRAME_BYTES);
+
+        assert!(matches!(reply, HandshakeReply::Accepted { codec, .. } if codec == JSON_CODEC));
+    }
+
+    #[test]
+    fn compression_is_optional() {
+        let mut handshake = Handshake::new("test-client");
+        handshake.compression = vec!["lz4".to_string()];
+
+        let reply = handshake.evaluate(PROTOCOL_VERSION, "test-server", DEFAULT_MAX_FRAME_BYTES);
+
+        assert!(matches!(
+            reply,
+            HandshakeReply::Accepted {
+                compression: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn the_frame_limit_is_advertised() {
+        let handshake = Handshake::new("test-client");
+
+        let reply = handshake.evaluate(PROTOCOL_VERSION, "test-server", 1024);
+
+        assert!(matches!(
+            reply,
+            HandshakeReply::Accepted {
+                max_frame_bytes: 1024,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn servers_without_a_frame_limit_are_assumed_to_use_the_default() {
+        let reply: HandshakeReply =
+            serde_json::from_str(r#"{"Accepted":{"server_name":"test-server","features":[]}}"#)
+                .unwrap();
+
+        assert!(matches!(
+            reply,
+            HandshakeReply::Accepted { max_frame_bytes, .. } if max_frame_bytes == DEFAULT_MAX_FRAME_BYTES
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_round_trips_over_a_stream() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = Handshake::new("test-client");
+
+        handshake.send(&mut client).await.unwrap();
+        let (version, received) = Handshake::recv(&mut server).await.unwrap();
+
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(received, handshake);
+    }
+
+    #[tokio::test]
+    async fn bad_magic_is_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let result = Handshake::recv(&mut server).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejection_is_reported_to_the_client() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        HandshakeReply::rejected("go away")
+            .send(&mut server)
+            .await
+            .unwrap();
+        let result = client_handshake(&mut client, "test-client").await;
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::Handshake(reason)) if reason == "go away"
+        ));
+    }
+}
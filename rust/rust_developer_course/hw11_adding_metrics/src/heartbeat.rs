@@ -0,0 +1,77 @@
+//! Heartbeats and idle timeouts.
+//!
+//! Peers that negotiate heartbeats send each other a `Ping` every `CHAT_HEARTBEAT_INTERVAL_SECS` and answer every
+//! `Ping` with a `Pong` carrying the same nonce. Since a live peer is never quiet for long, either side can then hang
+//! up once nothing at all has arrived for `CHAT_IDLE_TIMEOUT_SECS`, rather than waiting for a write to fail.
+
+use crate::{AppError, FrameHeader};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::{io::AsyncReadExt, time};
+
+/// Feature name advertised during the handshake by peers that send and answer heartbeats.
+pub const HEARTBEAT_FEATURE: &str = "heartbeat";
+
+/// Reads the next frame header, giving up if the peer stays silent for longer than `idle_timeout`.
+///
+/// # Example
+/// ```ignore
+/// let header = read_header(&mut stream, Some(config.heartbeat.idle_timeout)).await?;
+/// let message = receive_msg(&mut stream, &header, &format).await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::IdleTimeout` if no frame starts arriving in time, and otherwise the same errors as
+/// `FrameHeader::read`.
+pub async fn read_header<T: AsyncReadExt + Unpin>(
+    stream: &mut T,
+    idle_timeout: Option<Duration>,
+) -> Result<FrameHeader> {
+    match idle_timeout {
+        Some(idle_timeout) => time::timeout(idle_timeout, FrameHeader::read(stream))
+            .await
+            .map_err(|_| AppError::IdleTimeout(idle_timeout))?,
+        None => FrameHeader::read(stream).await,
+    }
+}
+
+/// Returns a ticker for sending heartbeats every `interval`, starting one interval from now.
+pub fn ticker(interval: Duration) -> time::Interval {
+    let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+    // A writer busy with a large transfer shouldn't follow up with a burst of pings
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    ticker
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageType, WireFormat};
+
+    #[tokio::test]
+    async fn silent_peers_time_out() {
+        let (_client, mut server) = tokio::io::duplex(1024);
+
+        let result = read_header(&mut server, Some(Duration::from_millis(20))).await;
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<AppError>(),
+            Some(AppError::IdleTimeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn frames_arriving_in_time_are_read() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        MessageType::Ping(1)
+            .send(&mut client, &WireFormat::default())
+            .await
+            .unwrap();
+
+        let header = read_header(&mut server, Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+
+        assert_eq!(header.kind, MessageType::Ping(1).kind());
+    }
+}
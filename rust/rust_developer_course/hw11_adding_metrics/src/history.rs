@@ -0,0 +1,405 @@
+//! Read access to the chat history stored by the server, served as JSON over HTTP.
+//!
+//! `GET /api/messages` lists stored messages, newest first unless `order=asc` is given, and `GET /api/messages/<id>`
+//! fetches a single one. Lists are paged by passing the id of the last message received as `before_id` (or
+//! `after_id`, when listing oldest first), and can be narrowed down to one or more rooms with `room`. Direct messages
+//! between users are private, and are never served.
+
+use crate::AppError;
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use sqlx::{Pool, QueryBuilder, Row, Sqlite};
+
+/// Path the message history is served on.
+pub const HISTORY_PATH: &str = "/api/messages";
+
+/// Number of messages returned when the request doesn't say.
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+
+/// Largest number of messages returned at once.
+pub const MAX_PAGE_SIZE: u32 = 500;
+
+/// Represents a stored message, as returned by the history API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    /// Name of the user who sent the message.
+    pub user: String,
+    /// Room the message was sent to.
+    pub room: String,
+    /// `text`, `file` or `image`; unknown for messages stored before kinds were recorded.
+    pub kind: Option<String>,
+    /// The text of the message, or the name of the file.
+    pub content: String,
+    /// When the server received the message, as RFC 3339; unknown for messages stored before times were recorded.
+    pub created_at: Option<String>,
+}
+
+/// Represents a page of stored messages, as returned by the history API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<StoredMessage>,
+}
+
+/// Represents the filters and paging of a history request.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::history::HistoryQuery;
+/// let query = HistoryQuery::from_query(Some("user=alice&room=rust&room=go&before_id=120&limit=20")).unwrap();
+/// assert_eq!(query.user.as_deref(), Some("alice"));
+/// assert_eq!(query.rooms, vec!["rust", "go"]);
+/// assert_eq!(query.before_id, Some(120));
+/// assert_eq!(query.limit, 20);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryQuery {
+    /// Only messages with a smaller id (`?before_id=`).
+    pub before_id: Option<i64>,
+    /// Only messages with a larger id (`?after_id=`).
+    pub after_id: Option<i64>,
+    /// Only messages received at or after this time (`?since=`, RFC 3339).
+    pub since: Option<DateTime<Utc>>,
+    /// Only messages received before this time (`?until=`, RFC 3339).
+    pub until: Option<DateTime<Utc>>,
+    /// Only messages sent by this user (`?user=`).
+    pub user: Option<String>,
+    /// Only messages sent to one of these rooms (`?room=`, which may be repeated); any room if empty.
+    pub rooms: Vec<String>,
+    /// Oldest first rather than newest first (`?order=asc`).
+    pub ascending: bool,
+    /// Largest number of messages to return (`?limit=`), capped at `MAX_PAGE_SIZE`.
+    pub limit: u32,
+}
+
+impl Default for HistoryQuery {
+    fn default() -> Self {
+        HistoryQuery {
+            before_id: None,
+            after_id: None,
+            since: None,
+            until: None,
+            user: None,
+            rooms: Vec::new(),
+            ascending: false,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+impl HistoryQuery {
+    /// Parses a request's query string. Unknown parameters are ignored.
+    ///
+    /// # Errors
+    /// This function returns `AppError::Message` describing the problem if a parameter has an invalid value.
+    pub fn from_query(query: Option<&str>) -> Result<Self, AppError> {
+        let mut parsed = HistoryQuery::default();
+
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if value.is_empty() {
+                continue;
+            }
+            let invalid = || AppError::Message(format!("Invalid value for {}: '{}'", key, value));
+            match key.as_ref() {
+                "before_id" => parsed.before_id = Some(value.parse().map_err(|_| invalid())?),
+                "after_id" => parsed.after_id = Some(value.parse().map_err(|_| invalid())?),
+                "since" => parsed.since = Some(parse_time(&value).ok_or_else(invalid)?),
+                "until" => parsed.until = Some(parse_time(&value).ok_or_else(invalid)?),
+                "user" => parsed.user = Some(value.into_owned()),
+                "room" => parsed
+                    .rooms
+                    .push(value.trim_start_matches('#').to_lowercase()),
+                "order" => {
+                    parsed.ascending = match value.as_ref() {
+                        "asc" => true,
+                        "desc" => false,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "limit" => {
+                    let limit: u32 = value.parse().map_err(|_| invalid())?;
+                    parsed.limit = limit.clamp(1, MAX_PAGE_SIZE);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Lists stored messages matching a query.
+///
+/// # Example
+/// ```ignore
+/// let messages = list_messages(&db, &HistoryQuery::default()).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database query fails.
+pub async fn list_messages(db: &Pool<Sqlite>, query: &HistoryQuery) -> Result<Vec<StoredMessage>> {
+    let mut sql = QueryBuilder::new(
+        "SELECT m.id, u.name, m.room, m.kind, m.content, m.created_at \
+         FROM messages m JOIN users u ON u.id = m.user_id WHERE m.recipient_id IS NULL",
+    );
+    if let Some(before_id) = query.before_id {
+        sql.push(" AND m.id < ").push_bind(before_id);
+    }
+    if let Some(after_id) = query.after_id {
+        sql.push(" AND m.id > ").push_bind(after_id);
+    }
+    if let Some(since) = query.since {
+        sql.push(" AND m.created_at >= ")
+            .push_bind(since.timestamp_millis());
+    }
+    if let Some(until) = query.until {
+        sql.push(" AND m.created_at < ")
+            .push_bind(until.timestamp_millis());
+    }
+    if let Some(user) = &query.user {
+        sql.push(" AND u.name = ").push_bind(user);
+    }
+    if !query.rooms.is_empty() {
+        sql.push(" AND m.room IN (");
+        let mut rooms = sql.separated(", ");
+        for room in &query.rooms {
+            rooms.push_bind(room);
+        }
+        sql.push(")");
+    }
+    sql.push(if query.ascending {
+        " ORDER BY m.id ASC"
+    } else {
+        " ORDER BY m.id DESC"
+    });
+    sql.push(" LIMIT ").push_bind(query.limit);
+
+    let rows = sql.build().fetch_all(db).await?;
+    Ok(rows.iter().map(stored_message).collect())
+}
+
+/// Lists the stored direct messages sent to or by the user `user_id` after `after_id`, newest first, along with the
+/// name of the user each was sent to.
+///
+/// # Example
+/// ```ignore
+/// for (recipient, message) in list_direct_messages(&db, user_id, 41, MAX_PAGE_SIZE).await? {
+///     println!("to {}: {}", recipient, message.content);
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database query fails.
+pub async fn list_direct_messages(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    after_id: i64,
+    limit: u32,
+) -> Result<Vec<(String, StoredMessage)>> {
+    let rows = sqlx::query(
+        "SELECT m.id, u.name, m.room, m.kind, m.content, m.created_at, r.name AS recipient \
+         FROM messages m JOIN users u ON u.id = m.user_id JOIN users r ON r.id = m.recipient_id \
+         WHERE (m.recipient_id = ? OR m.user_id = ?) AND m.id > ? ORDER BY m.id DESC LIMIT ?",
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(after_id)
+    .bind(limit)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("recipient"), stored_message(row)))
+        .collect())
+}
+
+/// Fetches a single stored message by id.
+///
+/// # Example
+/// ```ignore
+/// let message = get_message(&db, 42).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database query fails.
+pub async fn get_message(db: &Pool<Sqlite>, id: i64) -> Result<Option<StoredMessage>> {
+    let row = sqlx::query(
+        "SELECT m.id, u.name, m.room, m.kind, m.content, m.created_at \
+         FROM messages m JOIN users u ON u.id = m.user_id \
+         WHERE m.id = ? AND m.recipient_id IS NULL",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.as_ref().map(stored_message))
+}
+
+/// Returns the current time in the form stored in `messages.created_at`.
+pub fn now_millis() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn stored_message(row: &sqlx::sqlite::SqliteRow) -> StoredMessage {
+    let created_at: Option<i64> = row.get("created_at");
+    StoredMessage {
+        id: row.get("id"),
+        user: row.get("name"),
+        room: row.get("room"),
+        kind: row.get("kind"),
+        content: row.get("content"),
+        created_at: created_at.and_then(format_millis),
+    }
+}
+
+/// Formats a time in the form stored in `messages.created_at` as RFC 3339, the way the history API reports it.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::history::format_millis;
+/// assert_eq!(format_millis(1_000).as_deref(), Some("1970-01-01T00:00:01.000Z"));
+/// ```
+pub fn format_millis(millis: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(millis)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    async fn seeded_db() -> Pool<Sqlite> {
+        let db = test_db().await;
+
+        for (name, content, created_at, room) in [
+            ("alice", "one", 1_000, "lobby"),
+            ("bob", "two", 2_000, "lobby"),
+            ("alice", "three", 3_000, "rust"),
+        ] {
+            sqlx::query("INSERT INTO users (name) SELECT ? WHERE NOT EXISTS (SELECT 1 FROM users WHERE name = ?)")
+                .bind(name)
+                .bind(name)
+                .execute(&db)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO messages (content, user_id, kind, created_at, room) \
+                 SELECT ?, id, 'text', ?, ? FROM users WHERE name = ?",
+            )
+            .bind(content)
+            .bind(created_at)
+            .bind(room)
+            .bind(name)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        db
+    }
+
+    fn contents(messages: &[StoredMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn lists_are_newest_first_and_paged_by_id() {
+        let db = seeded_db().await;
+        let mut query = HistoryQuery {
+            limit: 2,
+            ..Default::default()
+        };
+
+        let first = list_messages(&db, &query).await.unwrap();
+        query.before_id = Some(first.last().unwrap().id);
+        let second = list_messages(&db, &query).await.unwrap();
+
+        assert_eq!(contents(&first), ["three", "two"]);
+        assert_eq!(contents(&second), ["one"]);
+    }
+
+    #[tokio::test]
+    async fn lists_filter_by_user_and_time() {
+        let db = seeded_db().await;
+        let query =
+            HistoryQuery::from_query(Some("user=alice&since=1970-01-01T00:00:00.500Z&order=asc"))
+                .unwrap();
+
+        let messages = list_messages(&db, &query).await.unwrap();
+
+        assert_eq!(contents(&messages), ["one", "three"]);
+        assert_eq!(messages[0].user, "alice");
+        assert_eq!(
+            messages[0].created_at.as_deref(),
+            Some("1970-01-01T00:00:01.000Z")
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_filter_by_room() {
+        let db = seeded_db().await;
+
+        let rust = HistoryQuery::from_query(Some("room=%23Rust")).unwrap();
+        let either = HistoryQuery::from_query(Some("room=rust&room=lobby&order=asc")).unwrap();
+
+        assert_eq!(
+            contents(&list_messages(&db, &rust).await.unwrap()),
+            ["three"]
+        );
+        assert_eq!(
+            contents(&list_messages(&db, &either).await.unwrap()),
+            ["one", "two", "three"]
+        );
+    }
+
+    #[tokio::test]
+    async fn single_messages_are_fetched_by_id() {
+        let db = seeded_db().await;
+
+        let message = get_message(&db, 2).await.unwrap().unwrap();
+
+        assert_eq!(message.user, "bob");
+        assert_eq!(message.room, "lobby");
+        assert_eq!(message.content, "two");
+        assert!(get_message(&db, 99).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_never_served() {
+        let db = seeded_db().await;
+        let id = sqlx::query(
+            "INSERT INTO messages (content, user_id, kind, created_at, recipient_id) \
+             VALUES ('psst', 1, 'text', 4000, 2)",
+        )
+        .execute(&db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+
+        let messages = list_messages(&db, &HistoryQuery::default()).await.unwrap();
+
+        assert_eq!(contents(&messages), ["three", "two", "one"]);
+        assert!(get_message(&db, id).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        assert!(HistoryQuery::from_query(Some("before_id=abc")).is_err());
+        assert!(HistoryQuery::from_query(Some("since=yesterday")).is_err());
+        assert!(HistoryQuery::from_query(Some("order=sideways")).is_err());
+        assert_eq!(
+            HistoryQuery::from_query(Some("limit=100000"))
+                .unwrap()
+                .limit,
+            MAX_PAGE_SIZE
+        );
+    }
+}
@@ -1,13 +1,53 @@
+use accounts::Password;
 use anyhow::{Context, Result};
+use codec::{Codec, JsonCodec};
+use compression::{Compression, DEFAULT_COMPRESSION_MIN_BYTES};
+use config::DEFAULT_MAX_FRAME_BYTES;
+use moderation::Moderation;
+use presence::{Presence, UserPresence};
+use rooms::{RoomInfo, RoomRequest};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use std::{error::Error, io};
 use thiserror::Error;
+use tokens::Token;
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
 };
+use transfer::Attachment;
+
+pub mod accounts;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod delivery;
+pub mod direct;
+pub mod events;
+pub mod filter;
+pub mod handshake;
+pub mod heartbeat;
+pub mod history;
+pub mod moderation;
+pub mod presence;
+pub mod resume;
+pub mod rooms;
+pub mod session;
+#[cfg(test)]
+mod test_support;
+pub mod throttle;
+pub mod tls;
+pub mod tokens;
+pub mod transfer;
+pub mod ws;
+
+/// Size, in bytes, of the header that precedes every frame on the wire.
+///
+/// The header is laid out as `[kind: u8][flags: u8][payload length: u32 BE]`.
+pub const FRAME_HEADER_LEN: usize = 6;
+
+/// Frame header flag marking a payload compressed with the connection's negotiated algorithm.
+pub const FLAG_COMPRESSED: u8 = 0x01;
 
 /// Represents a user.
 ///
@@ -15,6 +55,7 @@ use tokio::{
 ///
 /// # Example
 /// ```
+/// # use hw11_rust_metrics::User;
 /// let user = User { id: 1, name: "Alice".to_string() };
 /// ```
 #[derive(Clone, Debug, FromRow)]
@@ -23,16 +64,24 @@ pub struct User {
     pub name: String,
 }
 
-/// Represents internal messages, including user ID updates.
+/// Represents internal messages, such as frames addressed to a single client.
 ///
-/// This enum is used for internal communication within the server to handle user ID updates.
+/// This enum is used for internal communication within the server to let a client's reader task hand frames (e.g.
+/// protocol errors) to the writer task that owns the client's stream, and to have the writer replay the messages a
+/// reconnecting client missed. Who the client is logged in as is shared between the two through its
+/// `session::Session`.
 ///
 /// # Example
 /// ```
-/// let update = InternalMessage::UserIdUpdate(42);
+/// # use hw11_rust_metrics::{InternalMessage, MessageType};
+/// let reply = InternalMessage::Send(MessageType::Text(None, "Just for you".to_string()));
+/// let resume = InternalMessage::Resume(41);
+/// let joined = InternalMessage::Joined("rust".to_string());
 /// ```
 pub enum InternalMessage {
-    UserIdUpdate(i64),
+    Send(MessageType),
+    Resume(i64),    // (id of the last message the client saw)
+    Joined(String), // (room whose recent messages the client should be sent)
 }
 
 /// Represents a message consisting of text, an image, or a file.
@@ -41,17 +90,112 @@ pub enum InternalMessage {
 ///
 /// # Example
 /// ```
+/// # use hw11_rust_metrics::{accounts::Password, moderation::Moderation, tokens::Token, transfer::Attachment};
+/// # use hw11_rust_metrics::{presence::Presence, rooms::RoomRequest, ErrorCode, MessageType};
 /// let text_message = MessageType::Text(Some("Alice".to_string()), "Hello, World!".to_string());
 /// let image_message = MessageType::Image(Some("Alice".to_string()), vec![1, 2, 3]);
 /// let file_message = MessageType::File(Some("Alice".to_string()), "file.txt".to_string(), vec![1, 2, 3]);
 /// let register_message = MessageType::Register("Alice".to_string());
+/// let error_message = MessageType::Error(ErrorCode::FrameTooLarge, "too big".to_string());
+/// let start_message = MessageType::TransferStart(None, 1, Attachment::Image, 3);
+/// let chunk_message = MessageType::TransferChunk(1, 0, vec![1, 2, 3]);
+/// let end_message = MessageType::TransferEnd(1, crc32fast::hash(&[1, 2, 3]));
+/// let abort_message = MessageType::TransferAbort(1, "changed my mind".to_string());
+/// let submit_message = MessageType::Submit(7, Box::new(text_message.clone()));
+/// let stamped_message = MessageType::Stamped(42, 1_700_000_000_000, Box::new(text_message.clone()));
+/// let ack_message = MessageType::Ack(7, 42, 1_700_000_000_000);
+/// let rejected_message = MessageType::Rejected(7, ErrorCode::Muted, "You are muted".to_string());
+/// let ping_message = MessageType::Ping(3);
+/// let pong_message = MessageType::Pong(3);
+/// let goodbye_message = MessageType::Goodbye(Some("off to lunch".to_string()));
+/// let left_message = MessageType::Left("Alice".to_string(), Some("off to lunch".to_string()));
+/// let resume_message = MessageType::Resume(42);
+/// let history_message = MessageType::History(Box::new(stamped_message.clone()));
+/// let create_account_message = MessageType::CreateAccount("Alice".to_string(), Password::new("hunter22"));
+/// let login_message = MessageType::Login("Alice".to_string(), Password::new("hunter22"));
+/// let rename_message = MessageType::Rename("Alicia".to_string());
+/// let renamed_message = MessageType::Renamed("Alice".to_string(), "Alicia".to_string());
+/// let token_login_message = MessageType::TokenLogin(Token::new("3f9a0c"));
+/// let logout_message = MessageType::Logout;
+/// let moderate_message = MessageType::Moderate(Moderation::Kick("Mallory".to_string()));
+/// let notice_message = MessageType::Notice("Mallory was kicked".to_string());
+/// let room_message = MessageType::Room(RoomRequest::Join("rust".to_string()));
+/// let rooms_message = MessageType::Rooms("rust".to_string(), vec![]);
+/// let in_room_message = MessageType::InRoom("rust".to_string(), Box::new(text_message.clone()));
+/// let direct_message = MessageType::Direct("Bob".to_string(), Box::new(text_message.clone()));
+/// let who_message = MessageType::Who(Some("Bob".to_string()));
+/// let users_message = MessageType::Users(vec![]);
+/// let presence_message = MessageType::Presence("Bob".to_string(), Presence::Away);
 /// ```
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum MessageType {
-    Text(Option<String>, String),          // (username, message)
-    Image(Option<String>, Vec<u8>),        // (username, contents)
-    File(Option<String>, String, Vec<u8>), // (username, filepath, contents)
-    Register(String),                      // (username)
+    Text(Option<String>, String), // (username, message)
+    Image(Option<String>, #[serde(with = "serde_bytes")] Vec<u8>), // (username, contents)
+    File(
+        Option<String>,
+        String,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ), // (username, filepath, contents)
+    Register(String),             // (username)
+    Error(ErrorCode, String),     // (code, description)
+    TransferStart(Option<String>, u64, Attachment, u64), // (username, id, attachment, total bytes)
+    TransferChunk(u64, u64, #[serde(with = "serde_bytes")] Vec<u8>), // (id, offset, contents)
+    TransferEnd(u64, u32),        // (id, crc32 of contents)
+    TransferAbort(u64, String),   // (id, reason)
+    Submit(u64, Box<MessageType>), // (nonce, message)
+    Stamped(i64, i64, Box<MessageType>), // (id, unix time in ms, message)
+    Ack(u64, i64, i64),           // (nonce, id, unix time in ms)
+    Ping(u64),                    // (nonce)
+    Pong(u64),                    // (nonce of the ping being answered)
+    Goodbye(Option<String>),      // (reason)
+    Left(String, Option<String>), // (username, reason)
+    Resume(i64),                  // (id of the last message seen)
+    History(Box<MessageType>),    // (stamped message sent before the client connected)
+    CreateAccount(String, Password), // (username, password)
+    Login(String, Password),      // (username, password)
+    Rename(String),               // (new username)
+    Renamed(String, String),      // (old username, new username)
+    SessionToken(Token, i64),     // (token, expires at in ms since the Unix epoch)
+    TokenLogin(Token),            // (token)
+    Logout,
+    Moderate(Moderation),             // (action)
+    Notice(String),                   // (text)
+    Room(RoomRequest),                // (request)
+    Rooms(String, Vec<RoomInfo>),     // (current room, rooms)
+    InRoom(String, Box<MessageType>), // (room, message)
+    Direct(String, Box<MessageType>), // (recipient, message)
+    Who(Option<String>),              // (username, or everyone online)
+    Users(Vec<UserPresence>),         // (users)
+    Presence(String, Presence),       // (username, presence)
+    Rejected(u64, ErrorCode, String), // (nonce, code, reason)
+}
+
+/// Represents the reason a peer is reporting a protocol error.
+///
+/// Error codes are sent inside `MessageType::Error` frames so the receiving side can react to the failure without
+/// having to parse the human readable description. Codes added by newer peers are decoded as `Unknown`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    FrameTooLarge,
+    InvalidTransfer,
+    MalformedFrame,
+    UnexpectedFrameKind,
+    UnknownFrameKind,
+    TooManyErrors,
+    AuthFailed,
+    LockedOut,
+    WeakPassword,
+    NameTaken,
+    Forbidden,
+    UnknownUser,
+    Kicked,
+    Banned,
+    Muted,
+    Throttled,
+    Rejected,
+    Internal,
+    #[serde(other)]
+    Unknown,
 }
 
 impl MessageType {
@@ -61,6 +205,7 @@ impl MessageType {
     ///
     /// # Example
     /// ```
+    /// # use hw11_rust_metrics::MessageType;
     /// let message = MessageType::Text(Some("Alice".to_string()), "Hello, World!".to_string());
     /// let serialized = message.serialize_msg();
     /// println!("{}", serialized);
@@ -75,78 +220,235 @@ impl MessageType {
     ///
     /// # Example
     /// ```
+    /// # use hw11_rust_metrics::MessageType;
     /// let bytes = b"{\"Text\":[\"Alice\",\"Hello, World!\"]}";
-    /// let message = MessageType::Text(None, String::new()).deserialize_msg(bytes);
+    /// let message = MessageType::deserialize_msg(bytes).unwrap();
     /// println!("{:?}", message);
     /// ```
-    pub fn deserialize_msg(input: &[u8]) -> MessageType {
-        serde_json::from_slice(input).unwrap()
+    ///
+    /// # Errors
+    /// This function returns `AppError::Protocol` with `ErrorCode::MalformedFrame` if the bytes are not a valid
+    /// `MessageType`.
+    pub fn deserialize_msg(input: &[u8]) -> Result<MessageType, AppError> {
+        serde_json::from_slice(input).map_err(|e| {
+            AppError::Protocol(
+                ErrorCode::MalformedFrame,
+                format!("Malformed message payload: {}", e),
+            )
+        })
     }
 
-    /// Sends a serialized `MessageType` to a remote stream.
+    /// Returns the `FrameKind` used to tag this message in its frame header.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::{FrameKind, MessageType};
+    /// let message = MessageType::Register("Alice".to_string());
+    /// assert_eq!(message.kind(), FrameKind::Register);
+    /// ```
+    pub fn kind(&self) -> FrameKind {
+        match self {
+            MessageType::Text(..) => FrameKind::Text,
+            MessageType::Image(..) => FrameKind::Image,
+            MessageType::File(..) => FrameKind::File,
+            MessageType::Register(..) => FrameKind::Register,
+            MessageType::Error(..) => FrameKind::Error,
+            MessageType::TransferStart(..) => FrameKind::TransferStart,
+            MessageType::TransferChunk(..) => FrameKind::TransferChunk,
+            MessageType::TransferEnd(..) => FrameKind::TransferEnd,
+            MessageType::TransferAbort(..) => FrameKind::TransferAbort,
+            MessageType::Submit(..) => FrameKind::Submit,
+            MessageType::Stamped(..) => FrameKind::Stamped,
+            MessageType::Ack(..) => FrameKind::Ack,
+            MessageType::Ping(..) => FrameKind::Ping,
+            MessageType::Pong(..) => FrameKind::Pong,
+            MessageType::Goodbye(..) => FrameKind::Goodbye,
+            MessageType::Left(..) => FrameKind::Left,
+            MessageType::Resume(..) => FrameKind::Resume,
+            MessageType::History(..) => FrameKind::History,
+            MessageType::CreateAccount(..) => FrameKind::CreateAccount,
+            MessageType::Login(..) => FrameKind::Login,
+            MessageType::Rename(..) => FrameKind::Rename,
+            MessageType::Renamed(..) => FrameKind::Renamed,
+            MessageType::SessionToken(..) => FrameKind::SessionToken,
+            MessageType::TokenLogin(..) => FrameKind::TokenLogin,
+            MessageType::Logout => FrameKind::Logout,
+            MessageType::Moderate(..) => FrameKind::Moderate,
+            MessageType::Notice(..) => FrameKind::Notice,
+            MessageType::Room(..) => FrameKind::Room,
+            MessageType::Rooms(..) => FrameKind::Rooms,
+            MessageType::InRoom(..) => FrameKind::InRoom,
+            MessageType::Direct(..) => FrameKind::Direct,
+            MessageType::Who(..) => FrameKind::Who,
+            MessageType::Users(..) => FrameKind::Users,
+            MessageType::Presence(..) => FrameKind::Presence,
+            MessageType::Rejected(..) => FrameKind::Rejected,
+        }
+    }
+
+    /// Returns the message wrapped by a `Submit` or `Stamped` envelope, or the message itself for any other kind.
+    /// `History`, `InRoom` and `Direct` envelopes are looked through along with the envelope they hold.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::MessageType;
+    /// let text = MessageType::Text(None, "Hello".to_string());
+    /// let stamped = MessageType::Stamped(42, 0, Box::new(text.clone()));
+    /// assert_eq!(stamped.content(), &text);
+    /// assert_eq!(MessageType::History(Box::new(stamped)).content(), &text);
+    /// assert_eq!(text.content(), &text);
+    /// ```
+    pub fn content(&self) -> &MessageType {
+        match self {
+            MessageType::Submit(_, msg) | MessageType::Stamped(_, _, msg) => msg,
+            MessageType::History(msg)
+            | MessageType::InRoom(_, msg)
+            | MessageType::Direct(_, msg) => msg.content(),
+            other => other,
+        }
+    }
+
+    /// Returns the id the server stored this message with, if it is stamped.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::MessageType;
+    /// let stamped = MessageType::Stamped(42, 0, Box::new(MessageType::Text(None, "Hello".to_string())));
+    /// assert_eq!(stamped.id(), Some(42));
+    /// assert_eq!(MessageType::History(Box::new(stamped)).id(), Some(42));
+    /// assert_eq!(MessageType::Ping(1).id(), None);
+    /// ```
+    pub fn id(&self) -> Option<i64> {
+        match self {
+            MessageType::Stamped(id, ..) => Some(*id),
+            MessageType::History(msg)
+            | MessageType::InRoom(_, msg)
+            | MessageType::Direct(_, msg) => msg.id(),
+            _ => None,
+        }
+    }
+
+    /// Removes a `Stamped` envelope, for peers that didn't negotiate acknowledgements.
+    pub fn unstamped(self) -> MessageType {
+        match self {
+            MessageType::Stamped(_, _, msg) => *msg,
+            MessageType::History(msg) => MessageType::History(Box::new(msg.unstamped())),
+            MessageType::InRoom(room, msg) => MessageType::InRoom(room, Box::new(msg.unstamped())),
+            MessageType::Direct(recipient, msg) => {
+                MessageType::Direct(recipient, Box::new(msg.unstamped()))
+            }
+            other => other,
+        }
+    }
+
+    /// Returns the room this message was sent to, if it is in an `InRoom` envelope.
     ///
-    /// This function serializes the message, sends its length, and then sends the serialized message.
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::MessageType;
+    /// let text = MessageType::Text(None, "Hello".to_string());
+    /// assert_eq!(MessageType::InRoom("rust".to_string(), Box::new(text.clone())).room(), Some("rust"));
+    /// assert_eq!(text.room(), None);
+    /// ```
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            MessageType::InRoom(room, _) => Some(room),
+            _ => None,
+        }
+    }
+
+    /// Returns true if this message, or the message it wraps, is part of a chunked transfer.
     ///
     /// # Example
     /// ```
+    /// # use hw11_rust_metrics::MessageType;
+    /// assert!(MessageType::TransferEnd(1, 0).is_transfer());
+    /// assert!(MessageType::Stamped(42, 0, Box::new(MessageType::TransferEnd(1, 0))).is_transfer());
+    /// assert!(!MessageType::Register("Alice".to_string()).is_transfer());
+    /// ```
+    pub fn is_transfer(&self) -> bool {
+        matches!(
+            self.content(),
+            MessageType::TransferStart(..)
+                | MessageType::TransferChunk(..)
+                | MessageType::TransferEnd(..)
+                | MessageType::TransferAbort(..)
+        )
+    }
+
+    /// Sends a serialized `MessageType` to a remote stream.
+    ///
+    /// This function serializes the message with the connection's codec, compresses it if that is worthwhile, sends
+    /// the frame header (kind, flags and length), and then sends the serialized message. The sizes of the payload
+    /// before and after compression are returned.
+    ///
+    /// # Example
+    /// ```ignore
     /// let message = MessageType::Text(Some("Alice".to_string()), "Hello, World!".to_string());
-    /// message.send(&mut stream).await?;
+    /// message.send(&mut stream, &WireFormat::default()).await?;
     /// ```
     ///
     /// # Errors
-    /// This function returns an error if it fails to write to the stream.
-    pub async fn send<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
+    /// This function returns an error if it fails to encode the message or write to the stream.
+    pub async fn send<T: AsyncWriteExt + Unpin>(
+        &self,
+        stream: &mut T,
+        format: &WireFormat,
+    ) -> Result<FrameStats> {
         log::trace!("Entering MessageType::send()");
 
         // Serialize the msssage before transmitting
-        let serialized = self.serialize_msg();
+        let serialized = format.codec.encode(self)?;
+        let raw_bytes = serialized.len();
+        let (flags, payload) = format.compress(serialized)?;
 
-        // Send length of serialized message (as 4-byte value)
-        let len = serialized.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await?;
-
-        // Send the serialized message
-        stream.write_all(serialized.as_bytes()).await?;
-        log::info!("[SENT] {}", self.to_string());
+        // Send the frame header followed by the serialized message
+        let header = FrameHeader {
+            kind: self.kind(),
+            flags,
+            len: payload.len() as u32,
+        };
+        stream.write_all(&header.to_bytes()).await?;
+        stream.write_all(&payload).await?;
+        // Chunks of a large transfer, and heartbeats, would drown out everything else at the info level
+        if matches!(
+            header.kind,
+            FrameKind::TransferChunk | FrameKind::Ping | FrameKind::Pong
+        ) {
+            log::trace!("[SENT] {}", self);
+        } else {
+            log::info!("[SENT] {}", self);
+        }
 
         log::trace!("Exiting MessageType::send()");
 
-        Ok(())
+        Ok(FrameStats {
+            raw_bytes,
+            wire_bytes: payload.len(),
+        })
     }
 
     /// Receives a `MessageType` from a remote stream.
     ///
-    /// This function reads the length of the incoming message, reads the message, and then deserializes it.
+    /// This function reads the frame header of the incoming message, reads the message, and then decompresses and
+    /// deserializes it as negotiated for the connection.
     ///
     /// # Example
-    /// ```
-    /// let message = MessageType::recv(&mut stream).await?;
+    /// ```ignore
+    /// let message = MessageType::recv(&mut stream, &WireFormat::default()).await?;
     /// println!("{:?}", message);
     /// ```
     ///
     /// # Errors
     /// This function returns an error if it fails to read from the stream or deserialize the message.
-    pub async fn recv<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Self> {
+    pub async fn recv<T: AsyncReadExt + Unpin>(
+        stream: &mut T,
+        format: &WireFormat,
+    ) -> Result<Self> {
         log::trace!("Entering MessageType::recv()");
 
-        let mut length_bytes = [0; 4];
-
-        // Determine the length, in bytes, of the incoming message
-        stream
-            .read_exact(&mut length_bytes)
-            .await
-            .context("Failed to read msg length.")?;
-        let msg_len = u32::from_be_bytes(length_bytes) as usize;
-        let mut buffer = vec![0u8; msg_len];
-
-        // Read the incoming message from the stream buffer
-        stream
-            .read_exact(&mut buffer)
-            .await
-            .context("Failed to read stream")?;
-
-        // Deserialize message from buffer and return it
-        let msg = MessageType::deserialize_msg(&buffer);
+        let header = FrameHeader::read(stream).await?;
+        let msg = receive_msg(stream, &header, format).await?;
         log::debug!("Successfully received message.");
 
         log::trace!("Exiting MessageType::recv()");
@@ -154,6 +456,243 @@ impl MessageType {
     }
 }
 
+/// Identifies the kind of `MessageType` carried by a frame.
+///
+/// The kind is sent as the first byte of every frame header so a peer can tell what it is about to read before
+/// decoding the payload. Values are part of the wire protocol and must never be reused.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::FrameKind;
+/// assert_eq!(FrameKind::try_from(0x01).unwrap(), FrameKind::Text);
+/// assert!(FrameKind::try_from(0xFF).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Text = 0x01,
+    Image = 0x02,
+    File = 0x03,
+    Register = 0x04,
+    Error = 0x05,
+    TransferStart = 0x06,
+    TransferChunk = 0x07,
+    TransferEnd = 0x08,
+    TransferAbort = 0x09,
+    Submit = 0x0A,
+    Stamped = 0x0B,
+    Ack = 0x0C,
+    Ping = 0x0D,
+    Pong = 0x0E,
+    Goodbye = 0x0F,
+    Left = 0x10,
+    Resume = 0x11,
+    History = 0x12,
+    CreateAccount = 0x13,
+    Login = 0x14,
+    Rename = 0x15,
+    Renamed = 0x16,
+    SessionToken = 0x17,
+    TokenLogin = 0x18,
+    Logout = 0x19,
+    Moderate = 0x1A,
+    Notice = 0x1B,
+    Room = 0x1C,
+    Rooms = 0x1D,
+    InRoom = 0x1E,
+    Direct = 0x1F,
+    Who = 0x20,
+    Users = 0x21,
+    Presence = 0x22,
+    Rejected = 0x23,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = AppError;
+
+    fn try_from(value: u8) -> Result<Self, AppError> {
+        match value {
+            0x01 => Ok(FrameKind::Text),
+            0x02 => Ok(FrameKind::Image),
+            0x03 => Ok(FrameKind::File),
+            0x04 => Ok(FrameKind::Register),
+            0x05 => Ok(FrameKind::Error),
+            0x06 => Ok(FrameKind::TransferStart),
+            0x07 => Ok(FrameKind::TransferChunk),
+            0x08 => Ok(FrameKind::TransferEnd),
+            0x09 => Ok(FrameKind::TransferAbort),
+            0x0A => Ok(FrameKind::Submit),
+            0x0B => Ok(FrameKind::Stamped),
+            0x0C => Ok(FrameKind::Ack),
+            0x0D => Ok(FrameKind::Ping),
+            0x0E => Ok(FrameKind::Pong),
+            0x0F => Ok(FrameKind::Goodbye),
+            0x10 => Ok(FrameKind::Left),
+            0x11 => Ok(FrameKind::Resume),
+            0x12 => Ok(FrameKind::History),
+            0x13 => Ok(FrameKind::CreateAccount),
+            0x14 => Ok(FrameKind::Login),
+            0x15 => Ok(FrameKind::Rename),
+            0x16 => Ok(FrameKind::Renamed),
+            0x17 => Ok(FrameKind::SessionToken),
+            0x18 => Ok(FrameKind::TokenLogin),
+            0x19 => Ok(FrameKind::Logout),
+            0x1A => Ok(FrameKind::Moderate),
+            0x1B => Ok(FrameKind::Notice),
+            0x1C => Ok(FrameKind::Room),
+            0x1D => Ok(FrameKind::Rooms),
+            0x1E => Ok(FrameKind::InRoom),
+            0x1F => Ok(FrameKind::Direct),
+            0x20 => Ok(FrameKind::Who),
+            0x21 => Ok(FrameKind::Users),
+            0x22 => Ok(FrameKind::Presence),
+            0x23 => Ok(FrameKind::Rejected),
+            other => Err(AppError::Protocol(
+                ErrorCode::UnknownFrameKind,
+                format!("Unknown frame kind: {:#04x}", other),
+            )),
+        }
+    }
+}
+
+/// Represents the fixed-size header sent ahead of every `MessageType` payload.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{FrameHeader, FrameKind};
+/// let header = FrameHeader { kind: FrameKind::Text, flags: 0, len: 42 };
+/// assert_eq!(FrameHeader::from_bytes(header.to_bytes()).unwrap(), header);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: FrameKind,
+    pub flags: u8,
+    pub len: u32,
+}
+
+impl FrameHeader {
+    /// Encodes the header into its on-the-wire representation.
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_LEN] {
+        let mut bytes = [0u8; FRAME_HEADER_LEN];
+        bytes[0] = self.kind as u8;
+        bytes[1] = self.flags;
+        bytes[2..].copy_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a header from its on-the-wire representation.
+    ///
+    /// # Errors
+    /// This function returns an error if the kind byte is not a known `FrameKind`.
+    pub fn from_bytes(bytes: [u8; FRAME_HEADER_LEN]) -> Result<Self, AppError> {
+        let kind = FrameKind::try_from(bytes[0])?;
+        let len = u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+        Ok(FrameHeader {
+            kind,
+            flags: bytes[1],
+            len,
+        })
+    }
+
+    /// Reads a frame header from a remote stream.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let header = FrameHeader::read(&mut stream).await?;
+    /// let message = receive_msg(&mut stream, &header, &format).await?;
+    /// ```
+    ///
+    /// # Errors
+    /// This function returns an error if it fails to read from the stream or the header is invalid. Errors raised by
+    /// the underlying stream are returned as `std::io::Error` so callers can detect disconnects.
+    pub async fn read<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Self> {
+        let mut bytes = [0u8; FRAME_HEADER_LEN];
+        stream.read_exact(&mut bytes).await?;
+        Ok(FrameHeader::from_bytes(bytes)?)
+    }
+}
+
+/// Describes how frames are encoded on a connection, as negotiated during the handshake.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{codec::MessagePackCodec, compression::Compression, WireFormat};
+/// let format = WireFormat {
+///     codec: &MessagePackCodec,
+///     compression: Some(Compression::Zstd),
+///     ..WireFormat::default()
+/// };
+/// ```
+#[derive(Clone, Copy)]
+pub struct WireFormat {
+    pub codec: &'static dyn Codec,
+    pub compression: Option<Compression>,
+    /// Payloads smaller than this are sent uncompressed.
+    pub compression_min_bytes: usize,
+    /// Upper bound on the size of a received payload once decompressed.
+    pub max_payload_bytes: u32,
+}
+
+impl Default for WireFormat {
+    /// Returns the format spoken to peers that negotiated nothing: JSON without compression.
+    fn default() -> Self {
+        WireFormat {
+            codec: &JsonCodec,
+            compression: None,
+            compression_min_bytes: DEFAULT_COMPRESSION_MIN_BYTES,
+            max_payload_bytes: DEFAULT_MAX_FRAME_BYTES,
+        }
+    }
+}
+
+impl WireFormat {
+    /// Compresses a serialized payload if compression was negotiated and it is both large enough to bother and
+    /// actually shrinks, returning the frame flags to send alongside the payload.
+    fn compress(&self, payload: Vec<u8>) -> Result<(u8, Vec<u8>), AppError> {
+        let Some(compression) = self.compression else {
+            return Ok((0, payload));
+        };
+        if payload.len() < self.compression_min_bytes {
+            return Ok((0, payload));
+        }
+
+        let compressed = compression.compress(&payload)?;
+        if compressed.len() < payload.len() {
+            Ok((FLAG_COMPRESSED, compressed))
+        } else {
+            Ok((0, payload))
+        }
+    }
+
+    /// Reverses `compress` for a received payload according to its frame flags.
+    fn decompress(&self, flags: u8, payload: Vec<u8>) -> Result<Vec<u8>, AppError> {
+        if flags & !FLAG_COMPRESSED != 0 {
+            return Err(AppError::Protocol(
+                ErrorCode::MalformedFrame,
+                format!("Unknown frame flags: {:#04x}", flags),
+            ));
+        }
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(payload);
+        }
+
+        match self.compression {
+            Some(compression) => compression.decompress(&payload, self.max_payload_bytes as usize),
+            None => Err(AppError::Protocol(
+                ErrorCode::MalformedFrame,
+                "Received a compressed frame but no compression was negotiated".to_string(),
+            )),
+        }
+    }
+}
+
+/// Represents the size of a payload sent by `MessageType::send`, before and after compression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameStats {
+    pub raw_bytes: usize,
+    pub wire_bytes: usize,
+}
+
 impl std::fmt::Display for MessageType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -172,6 +711,81 @@ impl std::fmt::Display for MessageType {
             MessageType::Register(account) => {
                 write!(f, "<Registering user '{}' with the server>", account)
             }
+            MessageType::Error(code, description) => {
+                write!(f, "<Error {:?}: {}>", code, description)
+            }
+            MessageType::TransferStart(username, id, attachment, total) => write!(
+                f,
+                "[{}] <Transfer {}: {}, {} bytes>",
+                username.as_deref().unwrap_or("anonymous"),
+                id,
+                attachment,
+                total
+            ),
+            MessageType::TransferChunk(id, offset, data) => write!(
+                f,
+                "<Transfer {}: {} bytes at offset {}>",
+                id,
+                data.len(),
+                offset
+            ),
+            MessageType::TransferEnd(id, checksum) => {
+                write!(f, "<Transfer {} complete, crc32 {:08x}>", id, checksum)
+            }
+            MessageType::TransferAbort(id, reason) => {
+                write!(f, "<Transfer {} aborted: {}>", id, reason)
+            }
+            MessageType::Submit(nonce, msg) => write!(f, "{} <nonce {}>", msg, nonce),
+            MessageType::Stamped(id, _, msg) => write!(f, "{} <#{}>", msg, id),
+            MessageType::Ack(nonce, id, _) => {
+                write!(f, "<Message with nonce {} stored as #{}>", nonce, id)
+            }
+            MessageType::Ping(nonce) => write!(f, "<Ping {}>", nonce),
+            MessageType::Pong(nonce) => write!(f, "<Pong {}>", nonce),
+            MessageType::Goodbye(Some(reason)) => write!(f, "<Goodbye: {}>", reason),
+            MessageType::Goodbye(None) => write!(f, "<Goodbye>"),
+            MessageType::Left(username, Some(reason)) => {
+                write!(f, "<User '{}' left: {}>", username, reason)
+            }
+            MessageType::Left(username, None) => write!(f, "<User '{}' left>", username),
+            MessageType::Resume(id) => write!(f, "<Resume after #{}>", id),
+            MessageType::History(msg) => write!(f, "{} <history>", msg),
+            // Passwords are left out, since messages are logged
+            MessageType::CreateAccount(account, _) => {
+                write!(f, "<Creating account '{}' with the server>", account)
+            }
+            MessageType::Login(account, _) => write!(f, "<Logging in as '{}'>", account),
+            MessageType::Rename(name) => write!(f, "<Renaming to '{}'>", name),
+            MessageType::Renamed(old, new) => write!(f, "<User '{}' is now '{}'>", old, new),
+            MessageType::SessionToken(_, expires_at) => write!(
+                f,
+                "<Session token valid until {}>",
+                chrono::DateTime::from_timestamp_millis(*expires_at)
+                    .map_or_else(|| expires_at.to_string(), |time| time.to_rfc3339())
+            ),
+            MessageType::TokenLogin(_) => write!(f, "<Logging in with a session token>"),
+            MessageType::Logout => write!(f, "<Logging out>"),
+            MessageType::Moderate(action) => write!(f, "<Moderating: {}>", action),
+            MessageType::Notice(text) => write!(f, "<Notice: {}>", text),
+            MessageType::Room(request) => write!(f, "<Rooms: {}>", request),
+            MessageType::Rooms(current, rooms) => {
+                write!(f, "<{} rooms, talking in #{}>", rooms.len(), current)
+            }
+            MessageType::InRoom(room, msg) => write!(f, "[#{}] {}", room, msg),
+            MessageType::Direct(recipient, msg) => write!(f, "[to {}] {}", recipient, msg),
+            MessageType::Who(Some(username)) => write!(f, "<Is '{}' around?>", username),
+            MessageType::Who(None) => write!(f, "<Who is online?>"),
+            MessageType::Users(users) => write!(f, "<{} users>", users.len()),
+            MessageType::Presence(username, presence) => {
+                write!(f, "<User '{}' is {}>", username, presence)
+            }
+            MessageType::Rejected(nonce, code, reason) => {
+                write!(
+                    f,
+                    "<Message with nonce {} refused ({:?}): {}>",
+                    nonce, code, reason
+                )
+            }
         }
     }
 }
@@ -182,9 +796,18 @@ impl std::fmt::Display for MessageType {
 ///
 /// # Example
 /// ```
+/// # use hw11_rust_metrics::AppError;
+/// # use serde::de::Error;
+/// # use std::io;
 /// let io_error = AppError::Io(io::Error::new(io::ErrorKind::Other, "an error"));
 /// let serialization_error = AppError::Serialization(serde_json::Error::custom("an error"));
 /// let message_error = AppError::Message("an error".to_string());
+/// let handshake_error = AppError::Handshake("an error".to_string());
+/// let transfer_error = AppError::Transfer("an error".to_string());
+/// let tls_error = AppError::Tls("an error".to_string());
+/// let auth_error = AppError::Auth(hw11_rust_metrics::ErrorCode::AuthFailed, "an error".to_string());
+/// let idle_timeout_error = AppError::IdleTimeout(std::time::Duration::from_secs(45));
+/// let protocol_error = AppError::Protocol(hw11_rust_metrics::ErrorCode::MalformedFrame, "an error".to_string());
 /// let disconnected_error = AppError::Disconnected;
 /// let would_block_error = AppError::WouldBlock;
 /// let unknown_error = AppError::Unknown("an error".to_string());
@@ -200,6 +823,26 @@ pub enum AppError {
     #[error("Message Error: {0}")]
     Message(String),
 
+    #[error("Handshake Error: {0}")]
+    Handshake(String),
+
+    #[error("Transfer Error: {0}")]
+    Transfer(String),
+
+    #[error("TLS Error: {0}")]
+    Tls(String),
+
+    /// A login or registration was refused. The code is what should be reported back to the client.
+    #[error("Authentication Error ({0:?}): {1}")]
+    Auth(ErrorCode, String), // (code, description)
+
+    /// A peer broke the wire protocol. The code is what should be reported back to it in an `Error` frame.
+    #[error("Protocol Error ({0:?}): {1}")]
+    Protocol(ErrorCode, String), // (code, description)
+
+    #[error("Nothing received from the peer for {0:?}")]
+    IdleTimeout(std::time::Duration),
+
     #[error("Client or Server disconnected")]
     Disconnected,
 
@@ -210,20 +853,42 @@ pub enum AppError {
     Unknown(String),
 }
 
-/// Retrieves a message of specified length from a remote stream and attempts to construct and return a valid `MessageType`.
+/// Retrieves the payload described by a frame header from a remote stream and attempts to construct and return a
+/// valid `MessageType`.
 ///
-/// This function reads a message from the stream and deserializes it.
+/// This function reads a message from the stream, then decompresses and deserializes it as negotiated for the
+/// connection.
 ///
 /// # Example
-/// ```
-/// let message = receive_msg(&mut stream, 128).await?;
+/// ```ignore
+/// let header = FrameHeader::read(&mut stream).await?;
+/// let message = receive_msg(&mut stream, &header, &format).await?;
 /// println!("{:?}", message);
 /// ```
 ///
 /// # Errors
-/// This function returns an error if it fails to read from the stream or deserialize the message.
-pub async fn receive_msg(stream: &mut OwnedReadHalf, msg_len: usize) -> Result<MessageType> {
-    let mut buffer = vec![0u8; msg_len];
+/// This function returns an error if it fails to read from the stream. If the header announces a payload larger than
+/// the format's `max_payload_bytes`, `AppError::Protocol` with `ErrorCode::FrameTooLarge` is returned and the payload
+/// is left unread, so the caller must stop reading from the stream. If the payload was read but could not be decoded,
+/// or does not match the kind announced by the header, `AppError::Protocol` is returned as well; the stream is still
+/// positioned at the next frame in that case, so the caller may carry on reading.
+pub async fn receive_msg<T: AsyncReadExt + Unpin>(
+    stream: &mut T,
+    header: &FrameHeader,
+    format: &WireFormat,
+) -> Result<MessageType> {
+    // The length comes from the peer, so check it before allocating a buffer that size
+    if header.len > format.max_payload_bytes {
+        return Err(AppError::Protocol(
+            ErrorCode::FrameTooLarge,
+            format!(
+                "{}-byte {:?} frame is larger than the {}-byte limit",
+                header.len, header.kind, format.max_payload_bytes
+            ),
+        )
+        .into());
+    }
+    let mut buffer = vec![0u8; header.len as usize];
 
     stream
         .read_exact(&mut buffer)
@@ -231,7 +896,19 @@ pub async fn receive_msg(stream: &mut OwnedReadHalf, msg_len: usize) -> Result<M
         .context("Failed to read stream")?;
 
     // Deseralize message from buffer and return it
-    let msg = MessageType::deserialize_msg(&buffer);
+    let payload = format.decompress(header.flags, buffer)?;
+    let msg = format.codec.decode(&payload)?;
+    if msg.kind() != header.kind {
+        return Err(AppError::Protocol(
+            ErrorCode::UnexpectedFrameKind,
+            format!(
+                "Frame header announced {:?} but payload contained {:?}",
+                header.kind,
+                msg.kind()
+            ),
+        )
+        .into());
+    }
 
     Ok(msg)
 }
@@ -242,6 +919,7 @@ pub async fn receive_msg(stream: &mut OwnedReadHalf, msg_len: usize) -> Result<M
 ///
 /// # Example
 /// ```
+/// # use hw11_rust_metrics::get_hostname;
 /// let args = vec!["program".to_string(), "localhost".to_string(), "8080".to_string()];
 /// let address = get_hostname(args);
 /// println!("{}", address); // Outputs: localhost:8080
@@ -273,7 +951,9 @@ pub fn get_hostname(args: Vec<String>) -> String {
 ///
 /// # Example
 /// ```
-/// let command = Command::from_str(".help")?;
+/// # use hw11_rust_metrics::Command;
+/// # use std::str::FromStr;
+/// let command = Command::from_str(".help").unwrap();
 /// match command {
 ///     Command::Help => println!("Help command issued"),
 ///     _ => println!("Other command issued"),
@@ -282,13 +962,28 @@ pub fn get_hostname(args: Vec<String>) -> String {
 ///
 /// # Errors
 /// This function returns an error if it fails to parse the command.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Command {
+    Ban,
     File,
     Help,
     Image,
+    Join,
+    Kick,
+    Leave,
+    Login,
+    Logout,
+    Msg,
+    Mute,
     Register,
+    Rename,
+    Rooms,
+    Slowmode,
     Text,
+    Topic,
+    Unban,
+    Unmute,
+    Who,
     Quit,
 }
 
@@ -300,7 +995,22 @@ impl std::str::FromStr for Command {
             ".file" => Ok(Command::File),
             ".help" => Ok(Command::Help),
             ".image" => Ok(Command::Image),
+            ".login" => Ok(Command::Login),
             ".register" => Ok(Command::Register),
+            ".logout" => Ok(Command::Logout),
+            ".rename" => Ok(Command::Rename),
+            ".kick" => Ok(Command::Kick),
+            ".ban" => Ok(Command::Ban),
+            ".mute" => Ok(Command::Mute),
+            ".unban" => Ok(Command::Unban),
+            ".unmute" => Ok(Command::Unmute),
+            ".slowmode" => Ok(Command::Slowmode),
+            ".join" => Ok(Command::Join),
+            ".leave" => Ok(Command::Leave),
+            ".rooms" => Ok(Command::Rooms),
+            ".topic" => Ok(Command::Topic),
+            ".msg" => Ok(Command::Msg),
+            ".who" => Ok(Command::Who),
             ".quit" => Ok(Command::Quit),
             _ => Ok(Command::Text),
         }
@@ -313,6 +1023,7 @@ impl std::str::FromStr for Command {
 ///
 /// # Example
 /// ```
+/// # use hw11_rust_metrics::CommandParseError;
 /// let error = CommandParseError {};
 /// println!("{}", error);
 /// ```
@@ -326,3 +1037,116 @@ impl std::fmt::Display for CommandParseError {
         write!(f, "Problem parsing command input.")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_payload_is_a_protocol_error() {
+        let result = MessageType::deserialize_msg(b"{\"Text\":");
+
+        assert!(matches!(
+            result,
+            Err(AppError::Protocol(ErrorCode::MalformedFrame, _))
+        ));
+    }
+
+    #[test]
+    fn unknown_error_codes_are_tolerated() {
+        let bytes = b"{\"Error\":[\"SomethingNew\",\"from the future\"]}";
+
+        let message = MessageType::deserialize_msg(bytes).unwrap();
+
+        assert_eq!(
+            message,
+            MessageType::Error(ErrorCode::Unknown, "from the future".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_stays_in_sync_after_a_bad_frame() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let garbage = b"not json";
+        let header = FrameHeader {
+            kind: FrameKind::Text,
+            flags: 0,
+            len: garbage.len() as u32,
+        };
+        client.write_all(&header.to_bytes()).await.unwrap();
+        client.write_all(garbage).await.unwrap();
+        let message = MessageType::Text(None, "still here".to_string());
+        let format = WireFormat::default();
+        message.send(&mut client, &format).await.unwrap();
+
+        let first = MessageType::recv(&mut server, &format).await.unwrap_err();
+        let second = MessageType::recv(&mut server, &format).await.unwrap();
+
+        assert!(matches!(
+            first.downcast_ref::<AppError>(),
+            Some(AppError::Protocol(ErrorCode::MalformedFrame, _))
+        ));
+        assert_eq!(second, message);
+    }
+
+    #[tokio::test]
+    async fn large_payloads_are_compressed() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let format = WireFormat {
+            compression: Some(Compression::Deflate),
+            ..WireFormat::default()
+        };
+        let small = MessageType::Text(None, "hi".to_string());
+        let large = MessageType::Text(None, "spam ".repeat(1000));
+
+        let small_stats = small.send(&mut client, &format).await.unwrap();
+        let large_stats = large.send(&mut client, &format).await.unwrap();
+
+        assert_eq!(small_stats.raw_bytes, small_stats.wire_bytes);
+        assert!(large_stats.wire_bytes < large_stats.raw_bytes);
+        assert_eq!(
+            MessageType::recv(&mut server, &format).await.unwrap(),
+            small
+        );
+        assert_eq!(
+            MessageType::recv(&mut server, &format).await.unwrap(),
+            large
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_reading_the_payload() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let header = FrameHeader {
+            kind: FrameKind::Text,
+            flags: 0,
+            len: DEFAULT_MAX_FRAME_BYTES + 1,
+        };
+        client.write_all(&header.to_bytes()).await.unwrap();
+
+        let result = MessageType::recv(&mut server, &WireFormat::default()).await;
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<AppError>(),
+            Some(AppError::Protocol(ErrorCode::FrameTooLarge, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_require_negotiation() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let compressed = WireFormat {
+            compression: Some(Compression::Zstd),
+            ..WireFormat::default()
+        };
+        let message = MessageType::Text(None, "spam ".repeat(1000));
+        message.send(&mut client, &compressed).await.unwrap();
+
+        let result = MessageType::recv(&mut server, &WireFormat::default()).await;
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<AppError>(),
+            Some(AppError::Protocol(ErrorCode::MalformedFrame, _))
+        ));
+    }
+}
@@ -0,0 +1,587 @@
+//! Roles, and the moderation they allow.
+//!
+//! Every user has a role stored next to their account. Moderators and admins can kick, ban and mute users with a
+//! lower role than their own, by sending a `Moderate` frame. Bans cover the user's name and every address they were
+//! connected from, and are checked whenever a client connects or logs in; mutes stop the user from sending messages.
+//! Both last for a given time or until lifted, and every action is recorded in the audit log. Moderators can also put
+//! a room in slowmode, which `throttle` enforces.
+
+use crate::{
+    history,
+    rooms::{room_name, DEFAULT_ROOM},
+    AppError, Command,
+};
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::{fmt, net::IpAddr, str::FromStr, time::Duration};
+
+/// Feature name advertised during the handshake by peers that understand `Moderate` and `Notice` frames.
+pub const MODERATION_FEATURE: &str = "moderation";
+
+/// Represents what a user is allowed to do. Roles are ordered, so higher roles can moderate lower ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    /// Returns the name the role is stored under.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(AppError::Message(format!("Unknown role '{}'", s))),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Represents the body of a request to give a user a new role.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::moderation::{Role, RoleChange};
+/// let change: RoleChange = serde_json::from_str(r#"{"role":"moderator"}"#).unwrap();
+/// assert_eq!(change.role, Role::Moderator);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleChange {
+    pub role: Role,
+}
+
+/// Represents a moderation action a moderator asks the server to take against a user.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{moderation::Moderation, Command};
+/// let ban = Moderation::parse(Command::Ban, "bob 2h").unwrap();
+/// assert_eq!(ban, Moderation::Ban("bob".to_string(), Some(2 * 60 * 60)));
+/// assert_eq!(ban.target(), "bob");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Moderation {
+    Kick(String),              // (username)
+    Ban(String, Option<u64>),  // (username, seconds; until lifted if None)
+    Mute(String, Option<u64>), // (username, seconds; until lifted if None)
+    Unban(String),             // (username)
+    Unmute(String),            // (username)
+    Slowmode(String, u64),     // (room, seconds between messages; off if 0)
+}
+
+impl Moderation {
+    /// Builds the action for a moderation command from its arguments: the user's name, followed by how long for
+    /// bans and mutes. Slowmode takes the interval, or `off`, followed by the room, which defaults to the lobby.
+    ///
+    /// # Errors
+    /// This function returns an error if no user is named, the duration can't be parsed, or the command isn't a
+    /// moderation command.
+    pub fn parse(command: Command, args: &str) -> Result<Self> {
+        if command == Command::Slowmode {
+            return Self::parse_slowmode(args);
+        }
+
+        let mut args = args.split_whitespace();
+        let target = args
+            .next()
+            .ok_or_else(|| AppError::Message("Name the user to moderate".to_string()))?
+            .to_string();
+        let duration = args
+            .next()
+            .map(|duration| {
+                parse_duration(duration)
+                    .map(|duration| duration.as_secs())
+                    .ok_or_else(|| {
+                        AppError::Message(format!(
+                            "Invalid duration '{}'; use e.g. 90s, 10m, 2h or 7d",
+                            duration
+                        ))
+                    })
+            })
+            .transpose()?;
+
+        match command {
+            Command::Kick => Ok(Moderation::Kick(target)),
+            Command::Ban => Ok(Moderation::Ban(target, duration)),
+            Command::Mute => Ok(Moderation::Mute(target, duration)),
+            Command::Unban => Ok(Moderation::Unban(target)),
+            Command::Unmute => Ok(Moderation::Unmute(target)),
+            _ => {
+                Err(AppError::Message(format!("{:?} is not a moderation command", command)).into())
+            }
+        }
+    }
+
+    fn parse_slowmode(args: &str) -> Result<Self> {
+        let mut args = args.split_whitespace();
+        let secs = match args.next() {
+            Some("off") => 0,
+            Some(interval) => parse_duration(interval)
+                .map(|interval| interval.as_secs())
+                .ok_or_else(|| {
+                    AppError::Message(format!(
+                        "Invalid interval '{}'; use e.g. 30s or 2m, or off",
+                        interval
+                    ))
+                })?,
+            None => {
+                return Err(
+                    AppError::Message("Give the slowmode interval, or off".to_string()).into(),
+                )
+            }
+        };
+        let room = match args.next() {
+            Some(room) => room_name(room)?,
+            None => DEFAULT_ROOM.to_string(),
+        };
+        Ok(Moderation::Slowmode(room, secs))
+    }
+
+    /// Returns the name of the user the action is against, or of the room for slowmode.
+    pub fn target(&self) -> &str {
+        match self {
+            Moderation::Kick(target)
+            | Moderation::Ban(target, _)
+            | Moderation::Mute(target, _)
+            | Moderation::Unban(target)
+            | Moderation::Unmute(target)
+            | Moderation::Slowmode(target, _) => target,
+        }
+    }
+
+    /// Returns the name the action is recorded under in the audit log and metrics.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Moderation::Kick(_) => "kick",
+            Moderation::Ban(..) => "ban",
+            Moderation::Mute(..) => "mute",
+            Moderation::Unban(_) => "unban",
+            Moderation::Unmute(_) => "unmute",
+            Moderation::Slowmode(..) => "slowmode",
+        }
+    }
+}
+
+impl fmt::Display for Moderation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Moderation::Ban(target, Some(secs)) | Moderation::Mute(target, Some(secs)) => {
+                write!(f, "{} {} for {}s", self.action(), target, secs)
+            }
+            Moderation::Slowmode(room, 0) => write!(f, "slowmode off in {}", room),
+            Moderation::Slowmode(room, secs) => write!(f, "slowmode {}s in {}", secs, room),
+            _ => write!(f, "{} {}", self.action(), self.target()),
+        }
+    }
+}
+
+/// Represents how long a ban or mute lasts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiry {
+    /// Until a moderator lifts it.
+    Never,
+    /// Until the given time, in milliseconds since the Unix epoch.
+    At(i64),
+}
+
+impl Expiry {
+    /// Returns the expiry of a ban or mute lasting `secs` from now, or until lifted if `None`.
+    pub fn after(secs: Option<u64>) -> Self {
+        match secs {
+            Some(secs) => Expiry::At(history::now_millis().saturating_add(secs as i64 * 1000)),
+            None => Expiry::Never,
+        }
+    }
+
+    fn from_column(expires_at: Option<i64>) -> Self {
+        expires_at.map_or(Expiry::Never, Expiry::At)
+    }
+
+    fn to_column(self) -> Option<i64> {
+        match self {
+            Expiry::Never => None,
+            Expiry::At(expires_at) => Some(expires_at),
+        }
+    }
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expiry::Never => write!(f, "until lifted"),
+            Expiry::At(expires_at) => match DateTime::from_timestamp_millis(*expires_at) {
+                Some(time) => write!(f, "until {}", time.to_rfc3339()),
+                None => write!(f, "until {}", expires_at),
+            },
+        }
+    }
+}
+
+/// Parses a duration such as `90s`, `10m`, `2h`, `7d` or `1w`. Bare numbers are seconds.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::moderation::parse_duration;
+/// # use std::time::Duration;
+/// assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+/// assert_eq!(parse_duration("ten minutes"), None);
+/// ```
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_secs).map(Duration::from_secs)
+}
+
+/// Returns the role of the user `user_id`; users that don't exist have no more rights than any other.
+///
+/// # Example
+/// ```ignore
+/// if role(&db, session.user_id()).await? < Role::Moderator {
+///     // Refuse the action
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried, or holds a role this build doesn't know.
+pub async fn role(db: &Pool<Sqlite>, user_id: i64) -> Result<Role> {
+    let row = sqlx::query("SELECT role FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await
+        .context("Failed to look up role")?;
+    match row {
+        Some(row) => Ok(row.get::<String, _>("role").parse()?),
+        None => Ok(Role::User),
+    }
+}
+
+/// Gives the user called `name` the role `role`, returning false if there is no such user.
+///
+/// # Example
+/// ```ignore
+/// set_role(&db, "alice", Role::Moderator).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn set_role(db: &Pool<Sqlite>, name: &str, role: Role) -> Result<bool> {
+    let result = sqlx::query("UPDATE users SET role = ? WHERE name = ?")
+        .bind(role.as_str())
+        .bind(name)
+        .execute(db)
+        .await
+        .context("Failed to set role")?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Bans the user `user_id`, along with the addresses in `ips`, until `expiry`. A ban already in place is replaced.
+///
+/// # Example
+/// ```ignore
+/// ban(&db, user_id, &[addr.ip()], Expiry::after(Some(3600)), "alice").await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn ban(
+    db: &Pool<Sqlite>,
+    user_id: i64,
+    ips: &[IpAddr],
+    expiry: Expiry,
+    banned_by: &str,
+) -> Result<()> {
+    unban(db, user_id).await?;
+
+    let now = history::now_millis();
+    let ips = std::iter::once(None).chain(ips.iter().map(|ip| Some(ip.to_string())));
+    for ip in ips {
+        sqlx::query(
+            "INSERT INTO bans (user_id, ip, banned_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(ip)
+        .bind(banned_by)
+        .bind(now)
+        .bind(expiry.to_column())
+        .execute(db)
+        .await
+        .context("Failed to store ban")?;
+    }
+
+    Ok(())
+}
+
+/// Lifts the ban on the user `user_id` and the addresses banned with them, returning false if there was none.
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn unban(db: &Pool<Sqlite>, user_id: i64) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM bans WHERE user_id = ?")
+        .bind(user_id)
+        .execute(db)
+        .await
+        .context("Failed to lift ban")?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns how long the user `user_id`, or a client connecting from `ip`, is banned for, or `None` if neither is.
+///
+/// # Example
+/// ```ignore
+/// if let Some(expiry) = active_ban(&db, None, Some(addr.ip())).await? {
+///     // Turn the client away
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+pub async fn active_ban(
+    db: &Pool<Sqlite>,
+    user_id: Option<i64>,
+    ip: Option<IpAddr>,
+) -> Result<Option<Expiry>> {
+    let row = sqlx::query(
+        "SELECT expires_at FROM bans \
+         WHERE ((user_id = ? AND ip IS NULL) OR ip = ?) AND (expires_at IS NULL OR expires_at > ?) \
+         ORDER BY expires_at IS NOT NULL, expires_at DESC LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(ip.map(|ip| ip.to_string()))
+    .bind(history::now_millis())
+    .fetch_optional(db)
+    .await
+    .context("Failed to look up bans")?;
+    Ok(row.map(|row| Expiry::from_column(row.get("expires_at"))))
+}
+
+/// Mutes the user `user_id` until `expiry`, replacing any mute already in place.
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn mute(db: &Pool<Sqlite>, user_id: i64, expiry: Expiry, muted_by: &str) -> Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO mutes (user_id, muted_by, created_at, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(muted_by)
+    .bind(history::now_millis())
+    .bind(expiry.to_column())
+    .execute(db)
+    .await
+    .context("Failed to store mute")?;
+    Ok(())
+}
+
+/// Lets the user `user_id` speak again, returning false if they weren't muted.
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn unmute(db: &Pool<Sqlite>, user_id: i64) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM mutes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(db)
+        .await
+        .context("Failed to lift mute")?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns how long the user `user_id` is muted for, or `None` if they aren't.
+///
+/// # Example
+/// ```ignore
+/// if let Some(expiry) = active_mute(&db, session.user_id()).await? {
+///     // Drop the message
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+pub async fn active_mute(db: &Pool<Sqlite>, user_id: i64) -> Result<Option<Expiry>> {
+    let row = sqlx::query(
+        "SELECT expires_at FROM mutes WHERE user_id = ? AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(user_id)
+    .bind(history::now_millis())
+    .fetch_optional(db)
+    .await
+    .context("Failed to look up mutes")?;
+    Ok(row.map(|row| Expiry::from_column(row.get("expires_at"))))
+}
+
+/// Records a moderation action or role change in the audit log.
+///
+/// # Example
+/// ```ignore
+/// record(&db, "alice", "ban", "bob", Some("until lifted")).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn record(
+    db: &Pool<Sqlite>,
+    actor: &str,
+    action: &str,
+    target: &str,
+    detail: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO audit_log (actor, action, target, detail, created_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(actor)
+    .bind(action)
+    .bind(target)
+    .bind(detail)
+    .bind(history::now_millis())
+    .execute(db)
+    .await
+    .context("Failed to record action in the audit log")?;
+    log::info!(
+        "[AUDIT] {} {} {}{}",
+        actor,
+        action,
+        target,
+        detail
+            .map(|detail| format!(" ({})", detail))
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    async fn add_user(db: &Pool<Sqlite>, name: &str) -> i64 {
+        sqlx::query("INSERT INTO users (name) VALUES (?)")
+            .bind(name)
+            .execute(db)
+            .await
+            .unwrap()
+            .last_insert_rowid()
+    }
+
+    #[test]
+    fn durations_take_a_unit() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1w"), Some(Duration::from_secs(604_800)));
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert!(Moderation::parse(Command::Mute, "bob soon").is_err());
+        assert!(Moderation::parse(Command::Kick, " ").is_err());
+        assert_eq!(
+            Moderation::parse(Command::Mute, "bob").unwrap(),
+            Moderation::Mute("bob".to_string(), None)
+        );
+        assert_eq!(
+            Moderation::parse(Command::Slowmode, "30s").unwrap(),
+            Moderation::Slowmode(DEFAULT_ROOM.to_string(), 30)
+        );
+        assert_eq!(
+            Moderation::parse(Command::Slowmode, "off rust").unwrap(),
+            Moderation::Slowmode("rust".to_string(), 0)
+        );
+        assert!(Moderation::parse(Command::Slowmode, "").is_err());
+    }
+
+    #[tokio::test]
+    async fn roles_default_to_user() {
+        let db = test_db().await;
+        let alice = add_user(&db, "alice").await;
+
+        assert_eq!(role(&db, alice).await.unwrap(), Role::User);
+        assert!(set_role(&db, "alice", Role::Moderator).await.unwrap());
+        assert!(!set_role(&db, "nobody", Role::Admin).await.unwrap());
+
+        assert_eq!(role(&db, alice).await.unwrap(), Role::Moderator);
+        assert!(Role::Admin > Role::Moderator && Role::Moderator > Role::User);
+    }
+
+    #[tokio::test]
+    async fn bans_cover_the_user_and_their_addresses_until_lifted() {
+        let db = test_db().await;
+        let bob = add_user(&db, "bob").await;
+        let carol = add_user(&db, "carol").await;
+        let bobs_ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.8".parse().unwrap();
+
+        ban(&db, bob, &[bobs_ip], Expiry::Never, "alice")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            active_ban(&db, Some(bob), Some(other_ip)).await.unwrap(),
+            Some(Expiry::Never)
+        );
+        assert_eq!(
+            active_ban(&db, None, Some(bobs_ip)).await.unwrap(),
+            Some(Expiry::Never)
+        );
+        assert_eq!(
+            active_ban(&db, Some(carol), Some(other_ip)).await.unwrap(),
+            None
+        );
+
+        assert!(unban(&db, bob).await.unwrap());
+        assert_eq!(
+            active_ban(&db, Some(bob), Some(bobs_ip)).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn mutes_expire() {
+        let db = test_db().await;
+        let bob = add_user(&db, "bob").await;
+
+        mute(&db, bob, Expiry::after(Some(0)), "alice")
+            .await
+            .unwrap();
+        assert_eq!(active_mute(&db, bob).await.unwrap(), None);
+
+        mute(&db, bob, Expiry::after(Some(60)), "alice")
+            .await
+            .unwrap();
+        assert!(matches!(
+            active_mute(&db, bob).await.unwrap(),
+            Some(Expiry::At(_))
+        ));
+        assert!(unmute(&db, bob).await.unwrap());
+        assert!(!unmute(&db, bob).await.unwrap());
+    }
+}
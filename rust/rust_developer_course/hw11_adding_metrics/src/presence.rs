@@ -0,0 +1,302 @@
+//! Who is online.
+//!
+//! A user is online while any of their connections has done something recently, away once all of them have been idle
+//! for a while, and offline when none are left. Anonymous connections aren't anybody, so are left out. The server
+//! works out everyone's presence from its `Sessions` whenever users come and go, and every so often to notice who has
+//! gone idle, and broadcasts each change in a `Presence` frame. Users going offline have the time stored, so that
+//! clients can ask when they were last seen.
+
+use crate::session::Sessions;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Feature name advertised during the handshake by peers that understand `Who`, `Users` and `Presence` frames.
+pub const PRESENCE_FEATURE: &str = "presence";
+
+/// Represents whether a user is around.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::presence::Presence;
+/// assert_eq!(Presence::Away.to_string(), "away");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Online,
+    Away,
+    Offline,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away => write!(f, "away"),
+            Presence::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// Represents a user and whether they are around, as listed to clients.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub name: String,
+    pub presence: Presence,
+    /// When the user last did anything, or for offline users when they were last connected, in milliseconds since the
+    /// Unix epoch; `None` if they never have been.
+    pub last_seen: Option<i64>,
+}
+
+/// Remembers the presence last announced for every user that is connected, so that only changes are broadcast. Clones
+/// share the same record.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::presence::{Presence, PresenceRegistry, UserPresence};
+/// let registry = PresenceRegistry::default();
+/// let alice = UserPresence { name: "alice".to_string(), presence: Presence::Online, last_seen: Some(1_000) };
+///
+/// assert_eq!(registry.changes(&[alice.clone()], 2_000), vec![alice.clone()]);
+/// assert!(registry.changes(&[alice], 3_000).is_empty());
+/// assert_eq!(registry.changes(&[], 4_000)[0].presence, Presence::Offline);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PresenceRegistry {
+    announced: Arc<Mutex<HashMap<String, Presence>>>,
+}
+
+impl PresenceRegistry {
+    /// Compares `online`, everyone who is connected, with what was last announced, and returns the users whose
+    /// presence changed. Users who are no longer connected are returned as offline, last seen at `now`.
+    pub fn changes(&self, online: &[UserPresence], now: i64) -> Vec<UserPresence> {
+        let mut announced = self.announced.lock().unwrap();
+        let mut changes: Vec<_> = online
+            .iter()
+            .filter(|user| announced.get(&user.name) != Some(&user.presence))
+            .cloned()
+            .collect();
+        announced.retain(|name, _| {
+            let connected = online.iter().any(|user| &user.name == name);
+            if !connected {
+                changes.push(UserPresence {
+                    name: name.clone(),
+                    presence: Presence::Offline,
+                    last_seen: Some(now),
+                });
+            }
+            connected
+        });
+        for user in &changes {
+            if user.presence != Presence::Offline {
+                announced.insert(user.name.clone(), user.presence);
+            }
+        }
+        changes
+    }
+
+    /// Returns the presence last announced for the user called `name`.
+    pub fn presence(&self, name: &str) -> Presence {
+        self.announced
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(Presence::Offline)
+    }
+
+    /// Notes that the user called `old` has been renamed to `new`, so that it isn't taken for one user leaving and
+    /// another arriving.
+    pub fn rename(&self, old: &str, new: &str) {
+        let mut announced = self.announced.lock().unwrap();
+        if let Some(presence) = announced.remove(old) {
+            announced.insert(new.to_string(), presence);
+        }
+    }
+}
+
+/// Lists the users connected over `sessions` in alphabetical order, along with whether they are online or away at
+/// `now`. Users are away once every one of their connections has been idle for `away_after`, and never if it is
+/// `None`.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{presence::{online, Presence}, session::{Session, Sessions}};
+/// # use tokio::sync::mpsc;
+/// let sessions = Sessions::default();
+/// let session = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice");
+/// let (internal_tx, _internal_rx) = mpsc::channel(32);
+/// sessions.insert(session.clone(), internal_tx);
+///
+/// let users = online(&sessions, None, session.last_active());
+/// assert_eq!(users[0].name, "alice");
+/// assert_eq!(users[0].presence, Presence::Online);
+/// ```
+pub fn online(sessions: &Sessions, away_after: Option<Duration>, now: i64) -> Vec<UserPresence> {
+    let mut last_active = BTreeMap::new();
+    for session in sessions
+        .all()
+        .iter()
+        .filter(|session| !session.is_anonymous())
+    {
+        let active = last_active.entry(session.name()).or_insert(i64::MIN);
+        *active = session.last_active().max(*active);
+    }
+    last_active
+        .into_iter()
+        .map(|(name, last_active)| {
+            let idle = Duration::from_millis(now.saturating_sub(last_active).max(0) as u64);
+            let presence = match away_after {
+                Some(away_after) if idle >= away_after => Presence::Away,
+                _ => Presence::Online,
+            };
+            UserPresence {
+                name,
+                presence,
+                last_seen: Some(last_active),
+            }
+        })
+        .collect()
+}
+
+/// Stores `at`, in milliseconds since the Unix epoch, as the time the user called `name` was last seen.
+///
+/// # Example
+/// ```ignore
+/// record_last_seen(&db, &session.name(), history::now_millis()).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn record_last_seen(db: &Pool<Sqlite>, name: &str, at: i64) -> Result<()> {
+    sqlx::query("UPDATE users SET last_seen = ? WHERE name = ?")
+        .bind(at)
+        .bind(name)
+        .execute(db)
+        .await
+        .context("Failed to record when the user was last seen")?;
+    Ok(())
+}
+
+/// Returns the user called `name` as offline, along with when they were last seen, or `None` if there is no such
+/// user.
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+pub async fn last_seen(db: &Pool<Sqlite>, name: &str) -> Result<Option<UserPresence>> {
+    let row = sqlx::query("SELECT name, last_seen FROM users WHERE name = ?")
+        .bind(name)
+        .fetch_optional(db)
+        .await
+        .context("Failed to look up when the user was last seen")?;
+    Ok(row.map(|row| UserPresence {
+        name: row.get("name"),
+        presence: Presence::Offline,
+        last_seen: row.get("last_seen"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::Session, test_support::test_db};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn users_are_away_once_every_connection_is_idle() {
+        let sessions = Sessions::default();
+        let alice = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice");
+        let alice_elsewhere = Session::new("127.0.0.1:4001".parse().unwrap(), 2, "alice");
+        let bob = Session::new("127.0.0.1:4002".parse().unwrap(), 3, "bob");
+        let anonymous = Session::new("127.0.0.1:4003".parse().unwrap(), 1, "anonymous");
+        for session in [&alice, &alice_elsewhere, &bob, &anonymous] {
+            let (internal_tx, _) = mpsc::channel(1);
+            sessions.insert(session.clone(), internal_tx);
+        }
+        let away_after = Some(Duration::from_secs(60));
+        let later = bob.last_active() + 61_000;
+
+        alice_elsewhere.touch(later);
+        let users = online(&sessions, away_after, later);
+        let presences: Vec<_> = users
+            .iter()
+            .map(|user| (user.name.as_str(), user.presence))
+            .collect();
+        assert_eq!(
+            presences,
+            vec![("alice", Presence::Online), ("bob", Presence::Away)]
+        );
+        assert_eq!(users[0].last_seen, Some(later));
+    }
+
+    #[test]
+    fn only_changes_are_announced() {
+        let registry = PresenceRegistry::default();
+        let user = |name: &str, presence| UserPresence {
+            name: name.to_string(),
+            presence,
+            last_seen: Some(0),
+        };
+
+        let alice = user("alice", Presence::Online);
+        let bob = user("bob", Presence::Online);
+        assert_eq!(
+            registry.changes(&[alice.clone(), bob.clone()], 1),
+            vec![alice.clone(), bob.clone()]
+        );
+
+        let bob_away = user("bob", Presence::Away);
+        assert_eq!(
+            registry.changes(&[alice.clone(), bob_away.clone()], 2),
+            vec![bob_away]
+        );
+        assert_eq!(registry.presence("bob"), Presence::Away);
+
+        // Renamed users carry on where they were, and everyone else left
+        registry.rename("alice", "alicia");
+        let alicia = user("alicia", Presence::Online);
+        let changes = registry.changes(&[alicia], 3);
+        assert_eq!(
+            changes,
+            vec![UserPresence {
+                name: "bob".to_string(),
+                presence: Presence::Offline,
+                last_seen: Some(3),
+            }]
+        );
+        assert_eq!(registry.presence("bob"), Presence::Offline);
+    }
+
+    #[tokio::test]
+    async fn last_seen_is_remembered() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO users (name) VALUES ('alice')")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            last_seen(&db, "alice").await.unwrap().unwrap().last_seen,
+            None
+        );
+        record_last_seen(&db, "alice", 1_700_000_000_000)
+            .await
+            .unwrap();
+        assert_eq!(
+            last_seen(&db, "alice").await.unwrap(),
+            Some(UserPresence {
+                name: "alice".to_string(),
+                presence: Presence::Offline,
+                last_seen: Some(1_700_000_000_000),
+            })
+        );
+        assert!(last_seen(&db, "bob").await.unwrap().is_none());
+    }
+}
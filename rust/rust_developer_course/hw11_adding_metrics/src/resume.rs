@@ -0,0 +1,409 @@
+//! Reconnecting and catching up on missed messages.
+//!
+//! A client that loses its connection dials the server again after a jittered, exponentially growing delay. Once
+//! reconnected it registers under its previous account and, if both sides negotiated the feature, sends a `Resume`
+//! with the id of the last stamped message it saw. The server then replays the stored messages it missed in the rooms
+//! it is in, along with the direct messages sent to or by its user, oldest first, before carrying on with live
+//! traffic.
+//!
+//! Clients that negotiate the backlog feature are also sent the most recent messages in the lobby as soon as they
+//! connect, and in every other room as they join it, wrapped in `History` so they can be told apart from anything said
+//! since.
+
+use crate::{
+    config::ReconnectConfig,
+    history::{self, HistoryQuery, StoredMessage, MAX_PAGE_SIZE},
+    rooms::DEFAULT_ROOM,
+    MessageType,
+};
+use anyhow::Result;
+use chrono::DateTime;
+use rand::Rng;
+use sqlx::{Pool, Sqlite};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+/// Feature name advertised during the handshake by peers that can resume after reconnecting.
+pub const RESUME_FEATURE: &str = "resume";
+
+/// Feature name advertised during the handshake by peers that want to be sent recent messages as they connect.
+pub const BACKLOG_FEATURE: &str = "backlog";
+
+/// Largest number of messages replayed to a resuming client. Clients that missed more only get the most recent ones.
+pub const MAX_REPLAYED_MESSAGES: u32 = MAX_PAGE_SIZE;
+
+/// Fetches the stored messages sent to `rooms` after `last_seen`, oldest first, stamped with their ids and wrapped in
+/// the room they were sent to. If `user_id` is given, the direct messages sent to or by that user are fetched too,
+/// wrapped in a `Direct` naming the user they were sent to.
+///
+/// # Example
+/// ```ignore
+/// for msg in missed_messages(&db, 41, &session.rooms(), session.user_id_if_logged_in()).await? {
+///     msg.send(&mut stream, &format).await?;
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database query fails.
+pub async fn missed_messages(
+    db: &Pool<Sqlite>,
+    last_seen: i64,
+    rooms: &[String],
+    user_id: Option<i64>,
+) -> Result<Vec<MessageType>> {
+    let query = HistoryQuery {
+        after_id: Some(last_seen),
+        rooms: rooms.to_vec(),
+        limit: MAX_REPLAYED_MESSAGES,
+        ..Default::default()
+    };
+    // Newest first, so a client that missed too many gets the latest rather than the oldest
+    let mut missed: Vec<(i64, MessageType)> = history::list_messages(db, &query)
+        .await?
+        .iter()
+        .map(|stored| {
+            let msg = MessageType::InRoom(stored.room.clone(), Box::new(replayed(stored)));
+            (stored.id, msg)
+        })
+        .collect();
+    if let Some(user_id) = user_id {
+        let direct =
+            history::list_direct_messages(db, user_id, last_seen, MAX_REPLAYED_MESSAGES).await?;
+        missed.extend(direct.into_iter().map(|(recipient, stored)| {
+            (
+                stored.id,
+                MessageType::Direct(recipient, Box::new(replayed(&stored))),
+            )
+        }));
+        missed.sort_unstable_by_key(|(id, _)| std::cmp::Reverse(*id));
+        missed.truncate(MAX_REPLAYED_MESSAGES as usize);
+    }
+    missed.reverse();
+
+    Ok(missed.into_iter().map(|(_, msg)| msg).collect())
+}
+
+/// Fetches the `count` most recent stored messages sent to `room`, oldest first, stamped with their ids, marked as
+/// history and wrapped in the room.
+///
+/// # Example
+/// ```ignore
+/// for msg in backlog(&db, DEFAULT_ROOM, config.backlog_messages).await? {
+///     msg.send(&mut stream, &format).await?;
+/// }
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database query fails.
+pub async fn backlog(db: &Pool<Sqlite>, room: &str, count: u32) -> Result<Vec<MessageType>> {
+    let query = HistoryQuery {
+        rooms: vec![room.to_string()],
+        limit: count.min(MAX_REPLAYED_MESSAGES),
+        ..Default::default()
+    };
+    let mut recent = history::list_messages(db, &query).await?;
+    recent.reverse();
+
+    Ok(recent
+        .iter()
+        .map(|stored| {
+            let history = MessageType::History(Box::new(replayed(stored)));
+            MessageType::InRoom(room.to_string(), Box::new(history))
+        })
+        .collect())
+}
+
+/// Rebuilds the message a stored one was sent as, stamped with its id and time.
+///
+/// Only the names of attachments are stored, so files and images are replayed as a text notice.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{history::StoredMessage, resume::replayed, MessageType};
+/// let stored = StoredMessage {
+///     id: 42,
+///     user: "alice".to_string(),
+///     room: "lobby".to_string(),
+///     kind: Some("text".to_string()),
+///     content: "Hello".to_string(),
+///     created_at: Some("1970-01-01T00:00:01.000Z".to_string()),
+/// };
+/// let text = MessageType::Text(Some("alice".to_string()), "Hello".to_string());
+/// assert_eq!(replayed(&stored), MessageType::Stamped(42, 1_000, Box::new(text)));
+/// ```
+pub fn replayed(stored: &StoredMessage) -> MessageType {
+    let content = match stored.kind.as_deref() {
+        Some("file") => format!("<sent file '{}'>", stored.content),
+        Some("image") => "<sent an image>".to_string(),
+        _ => stored.content.clone(),
+    };
+    let created_at = stored
+        .created_at
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map_or(0, |time| time.timestamp_millis());
+
+    MessageType::Stamped(
+        stored.id,
+        created_at,
+        Box::new(MessageType::Text(Some(stored.user.clone()), content)),
+    )
+}
+
+/// Keeps track of the stored messages replayed to a client, as backlog or to resume, so that they aren't sent to it
+/// again when they come through live or in a later replay.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{resume::Replayed, MessageType};
+/// let stamped = |id| MessageType::Stamped(id, 0, Box::new(MessageType::Text(None, "Hi".to_string())));
+/// let in_room = |room: &str, id| MessageType::InRoom(room.to_string(), Box::new(stamped(id)));
+/// let mut replayed = Replayed::default();
+///
+/// replayed.note(&in_room("rust", 42));
+/// assert!(replayed.already_replayed(&in_room("rust", 41)));
+/// assert!(!replayed.already_replayed(&in_room("lobby", 41)));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Replayed {
+    /// Id of the newest message replayed in each room.
+    up_to: HashMap<String, i64>,
+    /// Ids of the messages sent as backlog.
+    backlog: HashSet<i64>,
+}
+
+impl Replayed {
+    /// Notes that a message has been replayed. Direct messages aren't broadcast to a room, so don't count.
+    pub fn note(&mut self, msg: &MessageType) {
+        if matches!(msg, MessageType::Direct(..)) {
+            return;
+        }
+        if let Some(id) = msg.id() {
+            let room = msg.room().unwrap_or(DEFAULT_ROOM).to_string();
+            let up_to = self.up_to.entry(room).or_insert(id);
+            *up_to = id.max(*up_to);
+        }
+    }
+
+    /// Notes that messages are about to be sent as backlog.
+    pub fn note_backlog(&mut self, messages: &[MessageType]) {
+        self.backlog
+            .extend(messages.iter().filter_map(MessageType::id));
+    }
+
+    /// Returns true if a live message was stored before the client caught up on its room, so it has been replayed
+    /// already.
+    pub fn already_replayed(&self, msg: &MessageType) -> bool {
+        let room = msg.room().unwrap_or(DEFAULT_ROOM);
+        msg.id()
+            .is_some_and(|id| self.up_to.get(room).is_some_and(|up_to| id <= *up_to))
+    }
+
+    /// Returns true if a message was sent as backlog.
+    pub fn in_backlog(&self, msg: &MessageType) -> bool {
+        msg.id().is_some_and(|id| self.backlog.contains(&id))
+    }
+}
+
+/// Works out how long to wait before each attempt to reconnect.
+///
+/// Delays double with every failed attempt, up to the configured maximum, and are jittered so that clients dropped at
+/// the same moment don't all come back at once: each delay is picked at random between half and all of its step.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{config::ReconnectConfig, resume::Backoff};
+/// let mut backoff = Backoff::new(ReconnectConfig::default());
+/// let first = backoff.next_delay().unwrap();
+/// assert!(first <= ReconnectConfig::default().initial_delay);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Backoff {
+            config,
+            attempts: 0,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, or `None` once `max_attempts` have been made.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_attempts
+            .is_some_and(|max| self.attempts >= max)
+        {
+            return None;
+        }
+
+        let step = self
+            .config
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.config.max_delay);
+        self.attempts += 1;
+
+        Some(rand::thread_rng().gen_range(step / 2..=step))
+    }
+
+    /// Returns the number of attempts made since the last successful connection.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Starts over from the initial delay, once a connection has succeeded.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    fn config(max_attempts: Option<u32>) -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1_000),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let mut backoff = Backoff::new(config(None));
+
+        let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay().unwrap()).collect();
+
+        for (attempt, delay) in delays.iter().enumerate() {
+            let step =
+                Duration::from_millis(100 * 2u64.pow(attempt as u32)).min(Duration::from_secs(1));
+            assert!(
+                *delay >= step / 2 && *delay <= step,
+                "attempt {} waited {:?}",
+                attempt,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn attempts_run_out_and_start_over_on_reset() {
+        let mut backoff = Backoff::new(config(Some(2)));
+
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert!(backoff.next_delay().unwrap() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backlog_is_remembered_separately_from_what_was_replayed() {
+        let in_room = |room: &str, id| {
+            let text = MessageType::Text(None, "Hi".to_string());
+            let stamped = MessageType::Stamped(id, 0, Box::new(text));
+            MessageType::InRoom(room.to_string(), Box::new(stamped))
+        };
+        let mut replayed = Replayed::default();
+        let backlog = vec![in_room("lobby", 8), in_room("lobby", 9)];
+
+        replayed.note_backlog(&backlog);
+        backlog.iter().for_each(|msg| replayed.note(msg));
+
+        assert!(replayed.in_backlog(&in_room("lobby", 9)));
+        assert!(!replayed.in_backlog(&in_room("lobby", 7)));
+        assert!(replayed.already_replayed(&in_room("lobby", 7)));
+        assert!(!replayed.already_replayed(&in_room("lobby", 10)));
+        assert!(!replayed.already_replayed(&in_room("rust", 5)));
+        assert!(!replayed.already_replayed(&MessageType::Ping(1)));
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_replayed_to_both_sides() {
+        let db = test_db().await;
+        for name in ["alice", "bob", "carol"] {
+            sqlx::query("INSERT INTO users (name) VALUES (?)")
+                .bind(name)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        // (sender, recipient, content): alice is 1, bob 2 and carol 3
+        for (sender, recipient, content) in [
+            (1, None, "hello all"),
+            (1, Some(2), "psst bob"),
+            (3, Some(1), "psst alice"),
+        ] {
+            sqlx::query(
+                "INSERT INTO messages (content, user_id, kind, created_at, room, recipient_id) \
+                 VALUES (?, ?, 'text', 0, 'lobby', ?)",
+            )
+            .bind(content)
+            .bind(sender)
+            .bind(recipient)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let lobby = vec![DEFAULT_ROOM.to_string()];
+        let summary = |messages: Vec<MessageType>| -> Vec<(Option<String>, i64)> {
+            messages
+                .iter()
+                .map(|msg| match msg {
+                    MessageType::Direct(recipient, _) => {
+                        (Some(recipient.clone()), msg.id().unwrap())
+                    }
+                    _ => (None, msg.id().unwrap()),
+                })
+                .collect()
+        };
+
+        let bob = missed_messages(&db, 0, &lobby, Some(2)).await.unwrap();
+        assert_eq!(summary(bob), [(None, 1), (Some("bob".to_string()), 2)]);
+
+        let alice = missed_messages(&db, 1, &lobby, Some(1)).await.unwrap();
+        assert_eq!(
+            summary(alice),
+            [(Some("bob".to_string()), 2), (Some("alice".to_string()), 3)]
+        );
+
+        let anonymous = missed_messages(&db, 0, &lobby, None).await.unwrap();
+        assert_eq!(summary(anonymous), [(None, 1)]);
+    }
+
+    #[test]
+    fn attachments_are_replayed_as_notices() {
+        let stored = StoredMessage {
+            id: 7,
+            user: "bob".to_string(),
+            room: "lobby".to_string(),
+            kind: Some("file".to_string()),
+            content: "notes.txt".to_string(),
+            created_at: None,
+        };
+
+        let MessageType::Stamped(id, created_at, msg) = replayed(&stored) else {
+            panic!("Expected a stamped message");
+        };
+
+        assert_eq!((id, created_at), (7, 0));
+        assert_eq!(
+            *msg,
+            MessageType::Text(
+                Some("bob".to_string()),
+                "<sent file 'notes.txt'>".to_string()
+            )
+        );
+    }
+}
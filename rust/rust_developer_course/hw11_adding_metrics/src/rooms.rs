@@ -0,0 +1,278 @@
+//! Named rooms.
+//!
+//! Every connection is in the lobby, and can join any number of other rooms, which are created by the first user to
+//! join them. Messages are sent to the connection's current room, the one it joined last, and broadcast wrapped in an
+//! `InRoom` envelope so that only connections in that room are sent them. The rooms a user has joined are stored in
+//! the database and restored whenever they log in.
+
+use crate::{history, session::Session, AppError, MessageType};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::fmt;
+
+/// Feature name advertised during the handshake by peers that understand `Room`, `Rooms` and `InRoom` frames.
+pub const ROOMS_FEATURE: &str = "rooms";
+
+/// Name of the room every connection is in, and the only one clients without the rooms feature know about.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Longest room name allowed, in characters.
+pub const MAX_ROOM_NAME_CHARS: usize = 32;
+
+/// Represents something a client asks the server to do about rooms.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::rooms::RoomRequest;
+/// let join = RoomRequest::Join("rust".to_string());
+/// assert_eq!(join.to_string(), "join #rust");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomRequest {
+    Join(String),  // (room)
+    Leave(String), // (room)
+    List,
+    Topic(String), // (topic for the current room)
+}
+
+impl fmt::Display for RoomRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomRequest::Join(room) => write!(f, "join #{}", room),
+            RoomRequest::Leave(room) => write!(f, "leave #{}", room),
+            RoomRequest::List => write!(f, "list rooms"),
+            RoomRequest::Topic(topic) => write!(f, "set topic '{}'", topic),
+        }
+    }
+}
+
+/// Represents a room, as listed to clients.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    /// Number of users who have joined the room; everyone is in the lobby, so it counts nobody.
+    pub members: i64,
+    /// Whether the connection the list is sent to is in the room.
+    pub joined: bool,
+}
+
+/// Checks a room name typed by a user, returning it without any leading `#` and in lowercase.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::rooms::room_name;
+/// assert_eq!(room_name("#Rust").unwrap(), "rust");
+/// assert!(room_name("two words").is_err());
+/// ```
+///
+/// # Errors
+/// This function returns an error if the name is empty, too long, or has characters other than letters, digits, `-`
+/// and `_`.
+pub fn room_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_ROOM_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(AppError::Message(format!(
+            "Room names are 1 to {} letters, digits, '-' or '_'",
+            MAX_ROOM_NAME_CHARS
+        )))
+    }
+}
+
+/// Returns a broadcast message as it should be sent to the connection `session`, or `None` if it is for a room the
+/// connection isn't in. Clients without the rooms feature are only ever in the lobby, and are sent its messages
+/// without the `InRoom` envelope.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{rooms::deliverable, session::Session, MessageType};
+/// let session = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice");
+/// let text = MessageType::Text(Some("bob".to_string()), "Hi".to_string());
+///
+/// let in_lobby = MessageType::InRoom("lobby".to_string(), Box::new(text.clone()));
+/// assert_eq!(deliverable(in_lobby, &session), Some(text.clone()));
+///
+/// let elsewhere = MessageType::InRoom("rust".to_string(), Box::new(text));
+/// assert_eq!(deliverable(elsewhere, &session), None);
+/// ```
+pub fn deliverable(msg: MessageType, session: &Session) -> Option<MessageType> {
+    match msg {
+        MessageType::InRoom(room, _) if !session.in_room(&room) => None,
+        MessageType::InRoom(_, msg) if !session.features().rooms => Some(*msg),
+        msg => Some(msg),
+    }
+}
+
+/// Adds the user `user_id` to `room`, creating it if nobody has joined it before, and returns true if it was created.
+/// Anonymous users, who are passed as `None`, can join rooms but aren't remembered as members.
+///
+/// # Example
+/// ```ignore
+/// let user_id = (!session.is_anonymous()).then(|| session.user_id());
+/// join(&db, user_id, "rust", &session.name()).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn join(
+    db: &Pool<Sqlite>,
+    user_id: Option<i64>,
+    room: &str,
+    joined_by: &str,
+) -> Result<bool> {
+    let now = history::now_millis();
+    let created =
+        sqlx::query("INSERT OR IGNORE INTO rooms (name, created_by, created_at) VALUES (?, ?, ?)")
+            .bind(room)
+            .bind(joined_by)
+            .bind(now)
+            .execute(db)
+            .await
+            .context("Failed to create room")?
+            .rows_affected()
+            > 0;
+
+    if let Some(user_id) = user_id.filter(|_| room != DEFAULT_ROOM) {
+        sqlx::query(
+            "INSERT OR IGNORE INTO room_members (user_id, room, joined_at) VALUES (?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(room)
+        .bind(now)
+        .execute(db)
+        .await
+        .context("Failed to join room")?;
+    }
+    Ok(created)
+}
+
+/// Removes the user `user_id` from `room`.
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn leave(db: &Pool<Sqlite>, user_id: i64, room: &str) -> Result<()> {
+    sqlx::query("DELETE FROM room_members WHERE user_id = ? AND room = ?")
+        .bind(user_id)
+        .bind(room)
+        .execute(db)
+        .await
+        .context("Failed to leave room")?;
+    Ok(())
+}
+
+/// Returns the rooms the user `user_id` has joined, other than the lobby, in alphabetical order.
+///
+/// # Example
+/// ```ignore
+/// session.set_rooms(memberships(&db, session.user_id()).await?);
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+pub async fn memberships(db: &Pool<Sqlite>, user_id: i64) -> Result<Vec<String>> {
+    let rows = sqlx::query("SELECT room FROM room_members WHERE user_id = ? ORDER BY room")
+        .bind(user_id)
+        .fetch_all(db)
+        .await
+        .context("Failed to look up rooms")?;
+    Ok(rows.iter().map(|row| row.get("room")).collect())
+}
+
+/// Lists every room in alphabetical order, marking those the connection `session` is in.
+///
+/// # Errors
+/// This function returns an error if the database can't be queried.
+pub async fn list(db: &Pool<Sqlite>, session: &Session) -> Result<Vec<RoomInfo>> {
+    let rows = sqlx::query(
+        "SELECT r.name, r.topic, COUNT(m.user_id) AS members \
+         FROM rooms r LEFT JOIN room_members m ON m.room = r.name \
+         GROUP BY r.name ORDER BY r.name",
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to list rooms")?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let name: String = row.get("name");
+            RoomInfo {
+                joined: session.in_room(&name),
+                name,
+                topic: row.get("topic"),
+                members: row.get("members"),
+            }
+        })
+        .collect())
+}
+
+/// Sets the topic of `room`. An empty topic clears it.
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn set_topic(db: &Pool<Sqlite>, room: &str, topic: &str) -> Result<()> {
+    let topic = Some(topic.trim()).filter(|topic| !topic.is_empty());
+    sqlx::query("UPDATE rooms SET topic = ? WHERE name = ?")
+        .bind(topic)
+        .bind(room)
+        .execute(db)
+        .await
+        .context("Failed to set topic")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn room_names_are_checked() {
+        assert_eq!(room_name(" ##Off-Topic_2 ").unwrap(), "off-topic_2");
+        assert!(room_name("#").is_err());
+        assert!(room_name("a.b").is_err());
+        assert!(room_name(&"x".repeat(MAX_ROOM_NAME_CHARS + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn memberships_are_remembered_for_users() {
+        let db = test_db().await;
+        let alice = sqlx::query("INSERT INTO users (name) VALUES ('alice')")
+            .execute(&db)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+
+        assert!(join(&db, Some(alice), "rust", "alice").await.unwrap());
+        assert!(!join(&db, None, "rust", "anonymous").await.unwrap());
+        join(&db, Some(alice), "go", "alice").await.unwrap();
+        join(&db, Some(alice), DEFAULT_ROOM, "alice").await.unwrap();
+        assert_eq!(memberships(&db, alice).await.unwrap(), vec!["go", "rust"]);
+
+        leave(&db, alice, "go").await.unwrap();
+        set_topic(&db, "rust", "Fearless chat").await.unwrap();
+
+        let session = Session::new("127.0.0.1:4000".parse().unwrap(), alice, "alice");
+        session.set_rooms(memberships(&db, alice).await.unwrap());
+        let rooms = list(&db, &session).await.unwrap();
+        let names: Vec<_> = rooms.iter().map(|room| room.name.as_str()).collect();
+        assert_eq!(names, vec!["go", "lobby", "rust"]);
+        assert_eq!(
+            rooms[2],
+            RoomInfo {
+                name: "rust".to_string(),
+                topic: Some("Fearless chat".to_string()),
+                members: 1,
+                joined: true,
+            }
+        );
+        assert!(rooms[1].joined && !rooms[0].joined);
+    }
+}
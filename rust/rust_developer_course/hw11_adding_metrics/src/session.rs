@@ -0,0 +1,378 @@
+//! Who is on the other end of a connection.
+//!
+//! The server keeps a `Session` for every connection, shared by the task reading from the client and the one writing
+//! to it, so that both agree on who the client is as soon as it logs in or changes its name. Every live session is
+//! also listed in the server's `Sessions`, so that frames can be addressed to a user wherever they are connected.
+//! Sessions also track the rooms the connection is in, which of them it is talking in, and when the user last did
+//! anything, which `Sessions` uses to work out who is online.
+
+use crate::{
+    accounts::ANONYMOUS_USER, handshake::Features, history, presence::PresenceRegistry,
+    rooms::DEFAULT_ROOM, InternalMessage,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Represents the user a connection is logged in as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub user_id: i64,
+    pub name: String,
+}
+
+impl Identity {
+    pub fn new(user_id: i64, name: impl Into<String>) -> Self {
+        Identity {
+            user_id,
+            name: name.into(),
+        }
+    }
+}
+
+/// Represents a single client connection, the features it negotiated and the user it is logged in as. Clones share the
+/// same identity.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::session::Session;
+/// let session = Session::new("127.0.0.1:4000".parse().unwrap(), 1, "anonymous");
+/// let writer_session = session.clone();
+///
+/// session.log_in(2, "alice");
+/// assert_eq!(writer_session.user_id(), 2);
+/// assert_eq!(writer_session.name(), "alice");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Session {
+    addr: SocketAddr,
+    features: Features,
+    state: Arc<RwLock<State>>,
+    closing: CancellationToken,
+}
+
+/// Represents what changes about a session as the client logs in and out.
+#[derive(Debug)]
+struct State {
+    identity: Identity,
+    /// Id of the session token the client logged in with or was issued, if any.
+    token_id: Option<i64>,
+    /// Rooms the connection is in, always including the lobby.
+    rooms: BTreeSet<String>,
+    /// Room the connection's messages are sent to.
+    room: String,
+    /// When the user last sent anything other than a heartbeat, in milliseconds since the Unix epoch.
+    last_active: i64,
+}
+
+impl State {
+    fn new(identity: Identity) -> Self {
+        State {
+            identity,
+            token_id: None,
+            rooms: BTreeSet::from([DEFAULT_ROOM.to_string()]),
+            room: DEFAULT_ROOM.to_string(),
+            last_active: history::now_millis(),
+        }
+    }
+}
+
+impl Session {
+    pub fn new(addr: SocketAddr, user_id: i64, name: impl Into<String>) -> Self {
+        Session {
+            addr,
+            features: Features::default(),
+            state: Arc::new(RwLock::new(State::new(Identity::new(user_id, name)))),
+            closing: CancellationToken::new(),
+        }
+    }
+
+    /// Notes the features the client negotiated during the handshake.
+    pub fn with_features(self, features: Features) -> Self {
+        Session { features, ..self }
+    }
+
+    /// Returns the address the client connected from.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the features the client negotiated.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Returns the id of the user the client is logged in as.
+    pub fn user_id(&self) -> i64 {
+        self.state.read().unwrap().identity.user_id
+    }
+
+    /// Returns the name of the user the client is logged in as.
+    pub fn name(&self) -> String {
+        self.state.read().unwrap().identity.name.clone()
+    }
+
+    /// Returns the user the client is logged in as.
+    pub fn identity(&self) -> Identity {
+        self.state.read().unwrap().identity.clone()
+    }
+
+    /// Returns true if the client hasn't logged in, registered or presented a client certificate.
+    pub fn is_anonymous(&self) -> bool {
+        self.state.read().unwrap().identity.name == ANONYMOUS_USER
+    }
+
+    /// Returns the user the client is logged in as, or `None` while it is anonymous. Anonymous clients all share the
+    /// one anonymous user, so its id says nothing about who they are and must never be used to tell them apart, ban
+    /// them, remember their rooms or limit them as a whole.
+    pub fn user_id_if_logged_in(&self) -> Option<i64> {
+        (!self.is_anonymous()).then(|| self.user_id())
+    }
+
+    /// Switches the connection over to the user it just logged in or registered as, forgetting any session token and
+    /// leaving every room but the lobby.
+    pub fn log_in(&self, user_id: i64, name: impl Into<String>) {
+        *self.state.write().unwrap() = State::new(Identity::new(user_id, name));
+    }
+
+    /// Notes that the user the client is logged in as has been renamed.
+    pub fn rename(&self, name: impl Into<String>) {
+        self.state.write().unwrap().identity.name = name.into();
+    }
+
+    /// Returns the id of the session token the client logged in with or was issued, if any.
+    pub fn token_id(&self) -> Option<i64> {
+        self.state.read().unwrap().token_id
+    }
+
+    /// Notes the session token the client logged in with or was issued.
+    pub fn set_token_id(&self, token_id: i64) {
+        self.state.write().unwrap().token_id = Some(token_id);
+    }
+
+    /// Returns the room the connection's messages are sent to.
+    pub fn room(&self) -> String {
+        self.state.read().unwrap().room.clone()
+    }
+
+    /// Returns the rooms the connection is in, in alphabetical order.
+    pub fn rooms(&self) -> Vec<String> {
+        self.state.read().unwrap().rooms.iter().cloned().collect()
+    }
+
+    /// Returns true if the connection is in `room`.
+    pub fn in_room(&self, room: &str) -> bool {
+        self.state.read().unwrap().rooms.contains(room)
+    }
+
+    /// Adds the connection to `room` without sending its messages there, e.g. because the user joined it from another
+    /// connection.
+    pub fn join_room(&self, room: impl Into<String>) {
+        self.state.write().unwrap().rooms.insert(room.into());
+    }
+
+    /// Adds the connection to `room` if need be and sends its messages there from now on.
+    pub fn enter_room(&self, room: impl Into<String>) {
+        let room = room.into();
+        let mut state = self.state.write().unwrap();
+        state.rooms.insert(room.clone());
+        state.room = room;
+    }
+
+    /// Takes the connection out of `room`, returning false if it wasn't in it or it is the lobby, which can't be left.
+    /// Messages go back to the lobby if they were being sent to `room`.
+    pub fn leave_room(&self, room: &str) -> bool {
+        let mut state = self.state.write().unwrap();
+        if room == DEFAULT_ROOM || !state.rooms.remove(room) {
+            return false;
+        }
+        if state.room == room {
+            state.room = DEFAULT_ROOM.to_string();
+        }
+        true
+    }
+
+    /// Puts the connection in `rooms` as well as the lobby, e.g. those the user joined before logging in, and sends its
+    /// messages to the lobby.
+    pub fn set_rooms(&self, rooms: impl IntoIterator<Item = String>) {
+        let mut state = self.state.write().unwrap();
+        state.rooms = rooms.into_iter().collect();
+        state.rooms.insert(DEFAULT_ROOM.to_string());
+        state.room = DEFAULT_ROOM.to_string();
+    }
+
+    /// Returns when the user last did anything over the connection, in milliseconds since the Unix epoch.
+    pub fn last_active(&self) -> i64 {
+        self.state.read().unwrap().last_active
+    }
+
+    /// Notes that the user did something over the connection at `now`, in milliseconds since the Unix epoch.
+    pub fn touch(&self, now: i64) {
+        let mut state = self.state.write().unwrap();
+        state.last_active = state.last_active.max(now);
+    }
+
+    /// Asks the task reading from the client to hang up, e.g. because a moderator kicked it.
+    pub fn close(&self) {
+        self.closing.cancel();
+    }
+
+    /// Waits until the connection is asked to hang up.
+    pub async fn closed(&self) {
+        self.closing.cancelled().await;
+    }
+}
+
+/// Lists the live connections, along with the channel to the task writing to each of them, and remembers the presence
+/// last announced for their users. Clones share the same list.
+///
+/// Connections are added once their handshake is accepted, and must be removed by the task reading from the client
+/// when it hangs up, since the writer keeps going until every sender for its channel is dropped.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::session::{Session, Sessions};
+/// # use tokio::sync::mpsc;
+/// let sessions = Sessions::default();
+/// let session = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice");
+/// let (internal_tx, _internal_rx) = mpsc::channel(32);
+///
+/// sessions.insert(session.clone(), internal_tx);
+/// assert_eq!(sessions.of_user("alice").len(), 1);
+///
+/// sessions.remove(session.addr());
+/// assert!(sessions.of_user("alice").is_empty());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Sessions {
+    connections: Arc<Mutex<HashMap<SocketAddr, Connection>>>,
+    presence: PresenceRegistry,
+}
+
+/// Represents a live connection and the channel to the task writing to it.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub session: Session,
+    pub internal_tx: mpsc::Sender<InternalMessage>,
+}
+
+impl Sessions {
+    /// Adds a connection whose handshake was accepted.
+    pub fn insert(&self, session: Session, internal_tx: mpsc::Sender<InternalMessage>) {
+        let addr = session.addr();
+        let connection = Connection {
+            session,
+            internal_tx,
+        };
+        self.connections.lock().unwrap().insert(addr, connection);
+    }
+
+    /// Removes the connection from `addr` once it hangs up.
+    pub fn remove(&self, addr: SocketAddr) {
+        self.connections.lock().unwrap().remove(&addr);
+    }
+
+    /// Returns the connections logged in as the user called `name`.
+    pub fn of_user(&self, name: &str) -> Vec<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.session.name() == name)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns every live connection's session.
+    pub fn all(&self) -> Vec<Session> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| connection.session.clone())
+            .collect()
+    }
+
+    /// Returns the presence last announced for the users of the live connections.
+    pub fn presence(&self) -> &PresenceRegistry {
+        &self.presence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_keep_the_user_id() {
+        let session = Session::new("127.0.0.1:4000".parse().unwrap(), 1, ANONYMOUS_USER);
+        assert!(session.is_anonymous());
+        assert_eq!(session.user_id_if_logged_in(), None);
+
+        session.log_in(7, "alice");
+        session.set_token_id(3);
+        session.rename("alicia");
+
+        assert_eq!(
+            session.identity(),
+            Identity {
+                user_id: 7,
+                name: "alicia".to_string()
+            }
+        );
+        assert!(!session.is_anonymous());
+        assert_eq!(session.user_id_if_logged_in(), Some(7));
+        assert_eq!(session.token_id(), Some(3));
+
+        // Logging in as someone else leaves the old token behind
+        session.log_in(8, "bob");
+        assert_eq!(session.token_id(), None);
+    }
+
+    #[test]
+    fn the_lobby_cannot_be_left() {
+        let session = Session::new("127.0.0.1:4000".parse().unwrap(), 7, "alice");
+        session.set_rooms(vec!["rust".to_string()]);
+        session.enter_room("go");
+        session.join_room("zig");
+        assert_eq!(session.room(), "go");
+        assert_eq!(session.rooms(), vec!["go", "lobby", "rust", "zig"]);
+
+        assert!(!session.leave_room(DEFAULT_ROOM));
+        assert!(!session.leave_room("python"));
+        assert!(session.leave_room("go"));
+        assert_eq!(session.room(), DEFAULT_ROOM);
+        assert!(!session.in_room("go"));
+
+        // Logging in as someone else leaves every room but the lobby
+        session.log_in(8, "bob");
+        assert_eq!(session.rooms(), vec![DEFAULT_ROOM]);
+    }
+
+    #[tokio::test]
+    async fn connections_are_found_under_their_current_name() {
+        let sessions = Sessions::default();
+        let alice = Session::new("127.0.0.1:4000".parse().unwrap(), 2, "alice");
+        let alice_elsewhere = Session::new("127.0.0.1:4001".parse().unwrap(), 2, "alice");
+        let bob = Session::new("127.0.0.1:4002".parse().unwrap(), 3, "bob");
+        for session in [&alice, &alice_elsewhere, &bob] {
+            let (internal_tx, _) = mpsc::channel(1);
+            sessions.insert(session.clone(), internal_tx);
+        }
+
+        assert_eq!(sessions.of_user("alice").len(), 2);
+
+        bob.rename("robert");
+        assert!(sessions.of_user("bob").is_empty());
+        let robert = sessions.of_user("robert");
+        assert_eq!(robert.len(), 1);
+
+        // Closing a session is seen by every clone of it
+        robert[0].session.close();
+        bob.closed().await;
+    }
+}
@@ -0,0 +1,21 @@
+//! Fixtures shared by the unit tests.
+
+use sqlx::{migrate::Migrator, sqlite::SqlitePoolOptions, Pool, Sqlite};
+use std::path::Path;
+
+/// Returns a fresh in-memory database with every migration applied.
+pub(crate) async fn test_db() -> Pool<Sqlite> {
+    // Every connection to an in-memory database gets a fresh one, so stick to a single connection
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    Migrator::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations"))
+        .await
+        .unwrap()
+        .run(&db)
+        .await
+        .unwrap();
+    db
+}
@@ -0,0 +1,460 @@
+//! How quickly clients may send.
+//!
+//! Every connection, and every logged in user across all of their connections, has a token bucket for text messages
+//! and another for attachments, refilled at the rates in `RateLimitConfig`. Moderators can also put a room in
+//! slowmode, so that each user may only speak there once per interval. Slowmode settings are stored in the database
+//! and survive restarts.
+
+use crate::{
+    config::{Budget, RateLimit, RateLimitConfig},
+    history, MessageType,
+};
+use anyhow::{Context, Result};
+use sqlx::{Pool, Row, Sqlite};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Represents a token bucket that holds up to `burst` tokens and gains `per_minute` of them every minute.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{config::RateLimit, throttle::TokenBucket};
+/// # use std::time::{Duration, Instant};
+/// let start = Instant::now();
+/// let mut bucket = TokenBucket::new(RateLimit { per_minute: 60, burst: 1 }, start);
+///
+/// assert_eq!(bucket.wait(start), None);
+/// bucket.take();
+/// assert_eq!(bucket.wait(start), Some(Duration::from_secs(1)));
+/// assert_eq!(bucket.wait(start + Duration::from_secs(1)), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Refills the bucket up to `now`, and returns how long until it holds a token, or `None` if it holds one already.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        if self.limit.is_unlimited() {
+            return None;
+        }
+
+        let per_sec = self.limit.per_minute as f64 / 60.0;
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(self.limit.burst as f64);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+
+    /// Spends a token. Call `wait` first to check there is one.
+    pub fn take(&mut self) {
+        if !self.limit.is_unlimited() {
+            self.tokens = (self.tokens - 1.0).max(0.0);
+        }
+    }
+}
+
+/// Represents which budget a message is paid from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Traffic {
+    Text,
+    Attachment,
+}
+
+impl Traffic {
+    /// Returns the budget `message` is paid from, or `None` if it isn't limited.
+    ///
+    /// # Example
+    /// ```
+    /// # use hw11_rust_metrics::{throttle::Traffic, MessageType};
+    /// assert_eq!(Traffic::of(&MessageType::Text(None, "hi".to_string())), Some(Traffic::Text));
+    /// assert_eq!(Traffic::of(&MessageType::Ping(1)), None);
+    /// ```
+    pub fn of(message: &MessageType) -> Option<Self> {
+        match message {
+            MessageType::Text(..) => Some(Traffic::Text),
+            MessageType::File(..) | MessageType::Image(..) | MessageType::TransferStart(..) => {
+                Some(Traffic::Attachment)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Represents a text bucket and an attachment bucket.
+#[derive(Clone, Debug)]
+struct Buckets {
+    text: TokenBucket,
+    attachments: TokenBucket,
+}
+
+impl Buckets {
+    fn new(budget: Budget, now: Instant) -> Self {
+        Buckets {
+            text: TokenBucket::new(budget.text, now),
+            attachments: TokenBucket::new(budget.attachments, now),
+        }
+    }
+
+    fn get(&mut self, traffic: Traffic) -> &mut TokenBucket {
+        match traffic {
+            Traffic::Text => &mut self.text,
+            Traffic::Attachment => &mut self.attachments,
+        }
+    }
+}
+
+/// Represents why a message was turned away, and how long the sender should wait before trying again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttled {
+    Slowmode(Duration),   // (wait)
+    Connection(Duration), // (wait)
+    User(Duration),       // (wait)
+}
+
+impl Throttled {
+    /// Returns the name of the limit that was hit, as used in metrics.
+    pub fn limit(&self) -> &'static str {
+        match self {
+            Throttled::Slowmode(_) => "slowmode",
+            Throttled::Connection(_) => "connection",
+            Throttled::User(_) => "user",
+        }
+    }
+
+    /// Returns how long the sender should wait before trying again.
+    pub fn wait(&self) -> Duration {
+        match self {
+            Throttled::Slowmode(wait) | Throttled::Connection(wait) | Throttled::User(wait) => {
+                *wait
+            }
+        }
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Round up, so that the sender never retries a moment too soon
+        let secs = self.wait().as_secs_f64().ceil().max(1.0) as u64;
+        match self {
+            Throttled::Slowmode(_) => write!(f, "This room is in slowmode; wait {}s", secs),
+            _ => write!(f, "You're sending too quickly; wait {}s", secs),
+        }
+    }
+}
+
+/// Represents who slowmode applies to: the user if logged in, the connection otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Speaker {
+    User(i64),
+    Connection(SocketAddr),
+}
+
+/// Keeps track of how quickly every connection and user is sending, and which rooms are in slowmode. Clones share the
+/// same state.
+///
+/// # Example
+/// ```ignore
+/// let throttle = Throttle::load(&db, config.rate_limits).await?;
+/// let user_id = (!session.is_anonymous()).then(|| session.user_id());
+/// if let Err(throttled) = throttle.check(addr, user_id, &session.room(), Traffic::Text, Instant::now(), false) {
+///     // Tell the client to slow down
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    limits: RateLimitConfig,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connections: HashMap<SocketAddr, Buckets>,
+    users: HashMap<i64, Buckets>,
+    slowmodes: HashMap<String, Duration>,
+    spoke_at: HashMap<(String, Speaker), Instant>,
+}
+
+impl Throttle {
+    /// Creates a throttle that enforces `limits`, with no rooms in slowmode.
+    pub fn new(limits: RateLimitConfig) -> Self {
+        Throttle {
+            limits,
+            state: Arc::default(),
+        }
+    }
+
+    /// Creates a throttle that enforces `limits`, with the slowmode settings stored in the database.
+    ///
+    /// # Errors
+    /// This function returns an error if the database can't be queried.
+    pub async fn load(db: &Pool<Sqlite>, limits: RateLimitConfig) -> Result<Self> {
+        let rows = sqlx::query("SELECT room, interval_secs FROM slowmode")
+            .fetch_all(db)
+            .await
+            .context("Failed to load slowmode settings")?;
+
+        let throttle = Throttle::new(limits);
+        throttle.state.lock().unwrap().slowmodes = rows
+            .into_iter()
+            .map(|row| {
+                let secs: i64 = row.get("interval_secs");
+                (row.get("room"), Duration::from_secs(secs as u64))
+            })
+            .collect();
+        Ok(throttle)
+    }
+
+    /// Checks whether the connection from `addr`, logged in as `user_id` unless anonymous, may send a message to
+    /// `room` at `now`, and charges it for the message if so. Moderators are `slowmode_exempt`.
+    ///
+    /// Nothing is charged for a message that is turned away.
+    ///
+    /// # Errors
+    /// This function returns the first limit the message would break.
+    pub fn check(
+        &self,
+        addr: SocketAddr,
+        user_id: Option<i64>,
+        room: &str,
+        traffic: Traffic,
+        now: Instant,
+        slowmode_exempt: bool,
+    ) -> Result<(), Throttled> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            connections,
+            users,
+            slowmodes,
+            spoke_at,
+        } = &mut *state;
+
+        let speaker = (
+            room.to_string(),
+            user_id.map_or(Speaker::Connection(addr), Speaker::User),
+        );
+        let interval = slowmodes.get(room).filter(|_| !slowmode_exempt);
+        if let (Some(interval), Some(last)) = (interval, spoke_at.get(&speaker)) {
+            let since = now.saturating_duration_since(*last);
+            if since < *interval {
+                return Err(Throttled::Slowmode(*interval - since));
+            }
+        }
+
+        let connection = connections
+            .entry(addr)
+            .or_insert_with(|| Buckets::new(self.limits.connection, now))
+            .get(traffic);
+        if let Some(wait) = connection.wait(now) {
+            return Err(Throttled::Connection(wait));
+        }
+        let mut user = user_id.map(|user_id| {
+            users
+                .entry(user_id)
+                .or_insert_with(|| Buckets::new(self.limits.user, now))
+                .get(traffic)
+        });
+        if let Some(wait) = user.as_mut().and_then(|user| user.wait(now)) {
+            return Err(Throttled::User(wait));
+        }
+
+        connection.take();
+        if let Some(user) = user {
+            user.take();
+        }
+        if interval.is_some() {
+            spoke_at.insert(speaker, now);
+        }
+        Ok(())
+    }
+
+    /// Returns the slowmode interval of `room`, or `None` if it isn't in slowmode.
+    pub fn slowmode(&self, room: &str) -> Option<Duration> {
+        self.state.lock().unwrap().slowmodes.get(room).copied()
+    }
+
+    /// Puts `room` in slowmode with the given interval, or takes it out of slowmode if `None`, and stores the setting.
+    ///
+    /// # Example
+    /// ```ignore
+    /// throttle.set_slowmode(&db, DEFAULT_ROOM, Some(Duration::from_secs(30)), "alice").await?;
+    /// ```
+    ///
+    /// # Errors
+    /// This function returns an error if the database can't be updated.
+    pub async fn set_slowmode(
+        &self,
+        db: &Pool<Sqlite>,
+        room: &str,
+        interval: Option<Duration>,
+        set_by: &str,
+    ) -> Result<()> {
+        match interval {
+            Some(interval) => {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO slowmode (room, interval_secs, set_by, set_at) VALUES (?, ?, ?, ?)",
+                )
+                .bind(room)
+                .bind(interval.as_secs() as i64)
+                .bind(set_by)
+                .bind(history::now_millis())
+                .execute(db)
+                .await
+                .context("Failed to store slowmode")?;
+            }
+            None => {
+                sqlx::query("DELETE FROM slowmode WHERE room = ?")
+                    .bind(room)
+                    .execute(db)
+                    .await
+                    .context("Failed to lift slowmode")?;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match interval {
+            Some(interval) => state.slowmodes.insert(room.to_string(), interval),
+            None => state.slowmodes.remove(room),
+        };
+        state.spoke_at.retain(|(spoken_in, _), _| spoken_in != room);
+        Ok(())
+    }
+
+    /// Forgets the connection from `addr` once it hangs up. Users keep their budget, so reconnecting doesn't refill it.
+    pub fn disconnected(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.remove(&addr);
+        state
+            .spoke_at
+            .retain(|(_, speaker), _| *speaker != Speaker::Connection(addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rooms::DEFAULT_ROOM, test_support::test_db};
+
+    fn limits(connection: u32, user: u32) -> RateLimitConfig {
+        let budget = |burst| Budget {
+            text: RateLimit {
+                per_minute: 60,
+                burst,
+            },
+            attachments: RateLimit {
+                per_minute: 6,
+                burst: 1,
+            },
+        };
+        RateLimitConfig {
+            connection: budget(connection),
+            user: budget(user),
+        }
+    }
+
+    #[test]
+    fn users_share_a_budget_across_connections() {
+        let throttle = Throttle::new(limits(2, 3));
+        let first: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let now = Instant::now();
+        let check =
+            |addr, traffic| throttle.check(addr, Some(7), DEFAULT_ROOM, traffic, now, false);
+
+        assert!(check(first, Traffic::Text).is_ok());
+        assert!(check(first, Traffic::Text).is_ok());
+        assert_eq!(
+            check(first, Traffic::Text),
+            Err(Throttled::Connection(Duration::from_secs(1)))
+        );
+        assert!(check(second, Traffic::Text).is_ok());
+        assert_eq!(
+            check(second, Traffic::Text),
+            Err(Throttled::User(Duration::from_secs(1)))
+        );
+
+        // Attachments have a budget of their own
+        assert!(check(second, Traffic::Attachment).is_ok());
+        assert_eq!(
+            check(second, Traffic::Attachment),
+            Err(Throttled::Connection(Duration::from_secs(10)))
+        );
+
+        // Anonymous clients are only limited per connection
+        let anonymous: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        for _ in 0..2 {
+            assert!(throttle
+                .check(anonymous, None, DEFAULT_ROOM, Traffic::Text, now, false)
+                .is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn slowmode_spaces_out_messages_and_is_stored() {
+        let db = test_db().await;
+
+        let throttle = Throttle::new(limits(10, 10));
+        throttle
+            .set_slowmode(&db, DEFAULT_ROOM, Some(Duration::from_secs(30)), "alice")
+            .await
+            .unwrap();
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let now = Instant::now();
+        let check = |after, exempt| {
+            throttle.check(
+                addr,
+                Some(7),
+                DEFAULT_ROOM,
+                Traffic::Text,
+                now + Duration::from_secs(after),
+                exempt,
+            )
+        };
+
+        assert!(check(0, false).is_ok());
+        assert_eq!(
+            check(10, false),
+            Err(Throttled::Slowmode(Duration::from_secs(20)))
+        );
+        assert!(check(10, true).is_ok());
+        assert!(check(30, false).is_ok());
+
+        let reloaded = Throttle::load(&db, limits(10, 10)).await.unwrap();
+        assert_eq!(
+            reloaded.slowmode(DEFAULT_ROOM),
+            Some(Duration::from_secs(30))
+        );
+
+        throttle
+            .set_slowmode(&db, DEFAULT_ROOM, None, "alice")
+            .await
+            .unwrap();
+        assert!(check(31, false).is_ok());
+        let reloaded = Throttle::load(&db, limits(10, 10)).await.unwrap();
+        assert_eq!(reloaded.slowmode(DEFAULT_ROOM), None);
+    }
+}
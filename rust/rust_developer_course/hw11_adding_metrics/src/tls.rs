@@ -0,0 +1,531 @@
+//! Optional TLS for chat connections, built on rustls.
+//!
+//! The server is given a PEM certificate chain and private key. Clients verify the server against the public web
+//! roots by default, or against a custom CA bundle, and may additionally pin the SHA-256 fingerprint of the server's
+//! certificate. A pinned certificate is trusted on its own when no CA bundle is given, which is the simplest way to
+//! talk to a server using a self-signed certificate.
+//!
+//! The server may also ask clients for a certificate issued by a CA of its choosing (mutual TLS). The common name in
+//! a client certificate's subject is the chat account it logs in as.
+
+use crate::{
+    config::{ClientTlsConfig, ServerTlsConfig},
+    AppError,
+};
+use anyhow::{Context, Result};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Builds the acceptor used by the server to wrap incoming connections in TLS.
+///
+/// If `config` names a client CA, clients are asked for a certificate issued by it; whether they must present one
+/// is up to `config.require_client_cert`.
+///
+/// # Example
+/// ```ignore
+/// let acceptor = server_acceptor(&config)?;
+/// let stream = acceptor.accept(tcp_stream).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the certificate chain, key or client CA bundle cannot be read, or the
+/// certificate and key do not match each other.
+pub fn server_acceptor(config: &ServerTlsConfig) -> Result<TlsAcceptor> {
+    let provider = provider();
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+    log::info!(
+        "Serving TLS certificate {} (SHA-256 fingerprint {})",
+        config.cert_path.display(),
+        fingerprint(&certs[0])
+    );
+
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(ca_path)?),
+                provider,
+            );
+            let verifier = if config.require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            log::info!(
+                "Accepting client certificates issued by {} ({})",
+                ca_path.display(),
+                if config.require_client_cert {
+                    "required"
+                } else {
+                    "optional"
+                }
+            );
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|e| AppError::Tls(format!("Invalid client CA bundle: {}", e)))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| AppError::Tls(format!("Invalid certificate or key: {}", e)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds the connector used by the client to wrap its connection in TLS.
+///
+/// The server is verified against `config.ca_path` if given, or the public web roots otherwise. If `config.pin` is
+/// given, the server's certificate must also have that SHA-256 fingerprint (hex, optionally colon separated);
+/// without a CA bundle, a certificate matching the pin is trusted regardless of who issued it. The client presents
+/// `config.cert_path` to servers that ask for a certificate, if set.
+///
+/// # Example
+/// ```ignore
+/// let config = ClientTlsConfig { ca_path: Some("ca.pem".into()), ..Default::default() };
+/// let connector = client_connector(&config)?;
+/// let stream = connector.connect(server_name("localhost")?, tcp_stream).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the CA bundle or client certificate cannot be read, or the pin is not a
+/// SHA-256 fingerprint.
+pub fn client_connector(config: &ClientTlsConfig) -> Result<TlsConnector> {
+    let provider = provider();
+    let ca_roots = config.ca_path.as_deref().map(load_roots).transpose()?;
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &config.pin {
+        Some(pin) => {
+            let roots = match ca_roots {
+                Some(roots) => Some(
+                    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()
+                        .map_err(|e| AppError::Tls(format!("Invalid CA bundle: {}", e)))?,
+                ),
+                None => None,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                    roots,
+                    pin: parse_pin(pin)?,
+                    provider,
+                }))
+        }
+        None => builder.with_root_certificates(ca_roots.unwrap_or_else(|| RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })),
+    };
+
+    let config = match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+            .map_err(|e| AppError::Tls(format!("Invalid client certificate or key: {}", e)))?,
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Returns the name the client expects the server's certificate to be issued for, given the host it dials.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::tls::server_name;
+/// assert!(server_name("localhost").is_ok());
+/// assert!(server_name("127.0.0.1").is_ok());
+/// ```
+///
+/// # Errors
+/// This function returns an error if `host` is neither a valid DNS name nor an IP address.
+pub fn server_name(host: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string())
+        .map_err(|e| AppError::Tls(format!("Invalid server name '{}': {}", host, e)).into())
+}
+
+/// Returns the common name in the subject of a DER encoded certificate, which names the account a client logs in as.
+///
+/// # Errors
+/// This function returns an error if the certificate cannot be parsed or its subject has no usable common name.
+pub fn subject_common_name(cert: &[u8]) -> Result<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| AppError::Tls(format!("Invalid client certificate: {}", e)))?;
+    let subject = cert.subject();
+
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::trim)
+        .filter(|cn| !cn.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            AppError::Tls(format!(
+                "Client certificate subject '{}' has no common name",
+                subject
+            ))
+        })?;
+    Ok(common_name)
+}
+
+/// Returns the SHA-256 fingerprint of a DER encoded certificate as lowercase hex, the format expected for pins.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Returns the crypto provider used for every TLS connection.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Reads every certificate from a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(AppError::Tls(format!("No certificates found in {}", path.display())).into());
+    }
+    Ok(certs)
+}
+
+/// Reads a CA bundle from a PEM file.
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| AppError::Tls(format!("Invalid CA certificate: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+/// Reads the first private key from a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))?
+        .ok_or_else(|| AppError::Tls(format!("No private key found in {}", path.display())).into())
+}
+
+/// Parses a hex SHA-256 fingerprint, ignoring case and any `:` separators.
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let invalid = || AppError::Tls(format!("Invalid SHA-256 certificate pin: {}", pin));
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid().into());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Verifies the server's certificate against a pinned fingerprint, and a set of roots if given.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    roots: Option<Arc<WebPkiServerVerifier>>,
+    pin: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(roots) = &self.roots {
+            roots.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        if Sha256::digest(end_entity).as_slice() != self.pin {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::DEFAULT_MAX_FRAME_BYTES,
+        handshake::{client_handshake, Handshake},
+        MessageType, WireFormat,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// A CA, plus a server certificate for localhost and a client certificate for "bot" it issued, written out as PEM
+    /// files.
+    struct TestPki {
+        dir: TempDir,
+        fingerprint: String,
+    }
+
+    impl TestPki {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+
+            let mut ca_params = CertificateParams::new(vec![]).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["localhost".to_string()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+
+            let mut client_params = CertificateParams::new(vec![]).unwrap();
+            client_params
+                .distinguished_name
+                .push(DnType::CommonName, "bot");
+            let client_key = KeyPair::generate().unwrap();
+            let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+            std::fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+            std::fs::write(dir.path().join("cert.pem"), cert.pem()).unwrap();
+            std::fs::write(dir.path().join("key.pem"), key.serialize_pem()).unwrap();
+            std::fs::write(dir.path().join("client.pem"), client_cert.pem()).unwrap();
+            std::fs::write(
+                dir.path().join("client-key.pem"),
+                client_key.serialize_pem(),
+            )
+            .unwrap();
+
+            TestPki {
+                fingerprint: fingerprint(cert.der()),
+                dir,
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn server_config(&self) -> ServerTlsConfig {
+            ServerTlsConfig {
+                cert_path: self.path("cert.pem"),
+                key_path: self.path("key.pem"),
+                client_ca_path: None,
+                require_client_cert: false,
+            }
+        }
+
+        fn client_config(&self) -> ClientTlsConfig {
+            ClientTlsConfig {
+                ca_path: Some(self.path("ca.pem")),
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Connects a client to a server over TLS and the chat handshake, then has the client send one message and
+    /// returns the account named by the client's certificate, if any, and the message the server received.
+    async fn exchange(
+        server_config: &ServerTlsConfig,
+        client_config: &ClientTlsConfig,
+    ) -> Result<(Option<String>, MessageType)> {
+        let acceptor = server_acceptor(server_config)?;
+        let connector = client_connector(client_config)?;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            let identity = match stream.get_ref().1.peer_certificates() {
+                Some(certs) => Some(subject_common_name(&certs[0])?),
+                None => None,
+            };
+            let (version, handshake) = Handshake::recv(&mut stream).await?;
+            handshake
+                .evaluate(version, "test-server", DEFAULT_MAX_FRAME_BYTES)
+                .send(&mut stream)
+                .await?;
+            let message = MessageType::recv(&mut stream, &WireFormat::default()).await?;
+            Ok::<_, anyhow::Error>((identity, message))
+        });
+
+        let mut stream = connector.connect(server_name("localhost")?, client).await?;
+        client_handshake(&mut stream, "test-client").await?;
+        MessageType::Text(None, "Hello over TLS".to_string())
+            .send(&mut stream, &WireFormat::default())
+            .await?;
+
+        server.await?
+    }
+
+    #[test]
+    fn pins_accept_colons_and_either_case() {
+        let pin = "AB:".repeat(31) + "ab";
+
+        assert_eq!(parse_pin(&pin).unwrap(), [0xab; 32]);
+    }
+
+    #[test]
+    fn short_pins_are_rejected() {
+        assert!(parse_pin("abcd").is_err());
+    }
+
+    #[tokio::test]
+    async fn servers_signed_by_a_trusted_ca_are_accepted() {
+        let pki = TestPki::new();
+
+        let (identity, received) = exchange(&pki.server_config(), &pki.client_config())
+            .await
+            .unwrap();
+
+        assert_eq!(identity, None);
+        assert_eq!(
+            received,
+            MessageType::Text(None, "Hello over TLS".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_certificates_are_trusted_without_a_ca() {
+        let pki = TestPki::new();
+        let client_config = ClientTlsConfig {
+            pin: Some(pki.fingerprint.clone()),
+            ..Default::default()
+        };
+
+        assert!(exchange(&pki.server_config(), &client_config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn certificates_not_matching_the_pin_are_rejected() {
+        let pki = TestPki::new();
+        let client_config = ClientTlsConfig {
+            pin: Some("00".repeat(32)),
+            ..pki.client_config()
+        };
+
+        assert!(exchange(&pki.server_config(), &client_config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn certificates_from_unknown_cas_are_rejected() {
+        let pki = TestPki::new();
+        let other_pki = TestPki::new();
+
+        assert!(exchange(&pki.server_config(), &other_pki.client_config())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificates_name_the_account() {
+        let pki = TestPki::new();
+        let server_config = ServerTlsConfig {
+            client_ca_path: Some(pki.path("ca.pem")),
+            require_client_cert: true,
+            ..pki.server_config()
+        };
+        let client_config = ClientTlsConfig {
+            cert_path: Some(pki.path("client.pem")),
+            key_path: Some(pki.path("client-key.pem")),
+            ..pki.client_config()
+        };
+
+        let (identity, _) = exchange(&server_config, &client_config).await.unwrap();
+
+        assert_eq!(identity.as_deref(), Some("bot"));
+    }
+
+    #[tokio::test]
+    async fn required_client_certificates_must_be_presented() {
+        let pki = TestPki::new();
+        let server_config = ServerTlsConfig {
+            client_ca_path: Some(pki.path("ca.pem")),
+            require_client_cert: true,
+            ..pki.server_config()
+        };
+
+        assert!(exchange(&server_config, &pki.client_config())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_optional_unless_required() {
+        let pki = TestPki::new();
+        let server_config = ServerTlsConfig {
+            client_ca_path: Some(pki.path("ca.pem")),
+            ..pki.server_config()
+        };
+
+        let (identity, _) = exchange(&server_config, &pki.client_config())
+            .await
+            .unwrap();
+
+        assert_eq!(identity, None);
+    }
+}
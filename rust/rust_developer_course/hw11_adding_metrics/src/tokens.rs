@@ -0,0 +1,281 @@
+//! Session tokens, which log a client back in without its password.
+//!
+//! Peers that negotiate the session tokens feature are sent a `SessionToken` after logging in with a password, and can
+//! present it with `TokenLogin` after reconnecting instead. Tokens are random and only their SHA-256 hash is stored,
+//! next to when they expire. `Logout` revokes the token of the connection it is sent over, and administrators can
+//! revoke every token of a user with `DELETE /api/users/<name>/tokens`.
+
+use crate::{history, session::Identity, AppError, ErrorCode};
+use anyhow::{Context, Result};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Row, Sqlite};
+use std::time::Duration;
+
+/// Feature name advertised during the handshake by peers that log in again with a session token.
+pub const TOKENS_FEATURE: &str = "session-tokens";
+
+/// Path the administrative user endpoints are served under.
+pub const USERS_PATH: &str = "/api/users";
+
+/// Number of random bytes in a session token.
+pub const TOKEN_BYTES: usize = 32;
+
+/// Represents a bearer token, kept out of debug output so it can't end up in a log.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::tokens::Token;
+/// let token = Token::generate();
+/// assert!(token.matches(token.expose()));
+/// assert!(!format!("{:?}", token).contains(token.expose()));
+/// ```
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Token(String);
+
+impl Token {
+    pub fn new(token: impl Into<String>) -> Self {
+        Token(token.into())
+    }
+
+    /// Returns a new random token, as lowercase hex.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        Token(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Returns the token itself, for sending it or checking it against another.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the SHA-256 hash of the token as lowercase hex, the form it is stored in.
+    pub fn hash(&self) -> String {
+        Sha256::digest(self.0.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Returns true if `presented` is this token. Hashes are compared rather than the tokens themselves, so how long
+    /// the comparison takes gives nothing away about the token.
+    pub fn matches(&self, presented: &str) -> bool {
+        Sha256::digest(presented.as_bytes()) == Sha256::digest(self.0.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Token(<redacted>)")
+    }
+}
+
+/// Issues a session token for the user `user_id`, valid for `ttl`. Returns the id it was stored with, the token and
+/// when it expires, in milliseconds since the Unix epoch.
+///
+/// Tokens that have already expired are cleared out on the way.
+///
+/// # Example
+/// ```ignore
+/// let (token_id, token, expires_at) = issue(&db, session.user_id(), config.session_token_ttl).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the token can't be stored.
+pub async fn issue(db: &Pool<Sqlite>, user_id: i64, ttl: Duration) -> Result<(i64, Token, i64)> {
+    let now = history::now_millis();
+    sqlx::query("DELETE FROM session_tokens WHERE expires_at <= ?")
+        .bind(now)
+        .execute(db)
+        .await
+        .context("Failed to clear out expired session tokens")?;
+
+    let token = Token::generate();
+    let expires_at = now + ttl.as_millis() as i64;
+    let token_id = sqlx::query(
+        "INSERT INTO session_tokens (user_id, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(token.hash())
+    .bind(now)
+    .bind(expires_at)
+    .execute(db)
+    .await
+    .context("Failed to store session token")?
+    .last_insert_rowid();
+    log::debug!("Session token {} issued to user ID {}", token_id, user_id);
+
+    Ok((token_id, token, expires_at))
+}
+
+/// Looks up the user a session token was issued to, returning the id the token was stored with and the user.
+///
+/// # Example
+/// ```ignore
+/// let (token_id, identity) = redeem(&db, &token).await?;
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Auth` with `ErrorCode::AuthFailed` if the token was never issued, has expired or
+/// has been revoked. It returns other errors if the database can't be queried.
+pub async fn redeem(db: &Pool<Sqlite>, token: &Token) -> Result<(i64, Identity)> {
+    let row = sqlx::query(
+        "SELECT session_tokens.id, users.id AS user_id, users.name FROM session_tokens \
+         JOIN users ON users.id = session_tokens.user_id \
+         WHERE token_hash = ? AND expires_at > ?",
+    )
+    .bind(token.hash())
+    .bind(history::now_millis())
+    .fetch_optional(db)
+    .await
+    .context("Failed to look up session token")?
+    .ok_or_else(|| {
+        AppError::Auth(
+            ErrorCode::AuthFailed,
+            "Session token is invalid or has expired; log in again".to_string(),
+        )
+    })?;
+
+    Ok((
+        row.get("id"),
+        Identity {
+            user_id: row.get("user_id"),
+            name: row.get("name"),
+        },
+    ))
+}
+
+/// Revokes the session token stored with `token_id`, returning true if it hadn't been already.
+///
+/// # Example
+/// ```ignore
+/// revoke(&db, token_id).await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn revoke(db: &Pool<Sqlite>, token_id: i64) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM session_tokens WHERE id = ?")
+        .bind(token_id)
+        .execute(db)
+        .await
+        .context("Failed to revoke session token")?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every session token issued to the user called `name`, returning how many there were, or `None` if there
+/// is no such user. Clients that already logged in with one stay logged in until they disconnect.
+///
+/// # Example
+/// ```ignore
+/// let revoked = revoke_all(&db, "alice").await?;
+/// ```
+///
+/// # Errors
+/// This function returns an error if the database can't be updated.
+pub async fn revoke_all(db: &Pool<Sqlite>, name: &str) -> Result<Option<u64>> {
+    let Some(row) = sqlx::query("SELECT id FROM users WHERE name = ?")
+        .bind(name)
+        .fetch_optional(db)
+        .await
+        .context("Failed to look up user")?
+    else {
+        return Ok(None);
+    };
+    let user_id: i64 = row.get("id");
+
+    let result = sqlx::query("DELETE FROM session_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(db)
+        .await
+        .context("Failed to revoke session tokens")?;
+    log::info!(
+        "Revoked {} session tokens of {}",
+        result.rows_affected(),
+        name
+    );
+
+    Ok(Some(result.rows_affected()))
+}
+
+/// Splits a `/api/users/<name>/<resource>` path into the user's name and the resource, or returns `None` for any
+/// other path.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::tokens::user_path;
+/// assert_eq!(user_path("/api/users/alice/tokens"), Some(("alice", "tokens")));
+/// assert_eq!(user_path("/api/users/alice/role"), Some(("alice", "role")));
+/// assert_eq!(user_path("/api/users/alice"), None);
+/// ```
+pub fn user_path(path: &str) -> Option<(&str, &str)> {
+    path.strip_prefix(USERS_PATH)?
+        .strip_prefix('/')?
+        .split_once('/')
+        .filter(|(name, resource)| {
+            !name.is_empty() && !resource.is_empty() && !resource.contains('/')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    async fn add_user(db: &Pool<Sqlite>, name: &str) -> i64 {
+        sqlx::query("INSERT INTO users (name) VALUES (?)")
+            .bind(name)
+            .execute(db)
+            .await
+            .unwrap()
+            .last_insert_rowid()
+    }
+
+    #[tokio::test]
+    async fn tokens_log_in_until_revoked() {
+        let db = test_db().await;
+        let alice = add_user(&db, "alice").await;
+        let (token_id, token, _) = issue(&db, alice, Duration::from_secs(60)).await.unwrap();
+
+        let (redeemed_id, identity) = redeem(&db, &token).await.unwrap();
+        assert_eq!(redeemed_id, token_id);
+        assert_eq!(identity.user_id, alice);
+        assert_eq!(identity.name, "alice");
+
+        assert!(revoke(&db, token_id).await.unwrap());
+        assert!(redeem(&db, &token).await.is_err());
+        assert!(redeem(&db, &Token::generate()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_refused() {
+        let db = test_db().await;
+        let alice = add_user(&db, "alice").await;
+        let (_, token, _) = issue(&db, alice, Duration::ZERO).await.unwrap();
+
+        let error = redeem(&db, &token).await.unwrap_err();
+
+        assert!(matches!(
+            error.downcast::<AppError>(),
+            Ok(AppError::Auth(ErrorCode::AuthFailed, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn revoking_all_tokens_leaves_other_users_alone() {
+        let db = test_db().await;
+        let alice = add_user(&db, "alice").await;
+        let bob = add_user(&db, "bob").await;
+        let ttl = Duration::from_secs(60);
+        issue(&db, alice, ttl).await.unwrap();
+        issue(&db, alice, ttl).await.unwrap();
+        let (_, bobs_token, _) = issue(&db, bob, ttl).await.unwrap();
+
+        assert_eq!(revoke_all(&db, "alice").await.unwrap(), Some(2));
+        assert_eq!(revoke_all(&db, "carol").await.unwrap(), None);
+        assert!(redeem(&db, &bobs_token).await.is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+//! Chunked file and image transfers.
+//!
+//! Rather than shipping an attachment as one giant `MessageType::File`/`MessageType::Image` frame, a sender streams
+//! it as a `TransferStart`, a run of `TransferChunk`s and a closing `TransferEnd` carrying a CRC32 of the contents.
+//! Chunks of different transfers, and ordinary text messages, may be interleaved freely on the same connection.
+
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Feature name advertised during the handshake by peers that understand chunked transfers.
+pub const CHUNKED_TRANSFER_FEATURE: &str = "chunked-transfer";
+
+/// Number of bytes carried by each `TransferChunk` sent by this build.
+pub const TRANSFER_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Describes what is being transferred.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::transfer::Attachment;
+/// let file = Attachment::File("notes.txt".to_string());
+/// let image = Attachment::Image;
+/// ```
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Attachment {
+    File(String), // (file name)
+    Image,
+}
+
+impl std::fmt::Display for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attachment::File(name) => write!(f, "file '{}'", name),
+            Attachment::Image => write!(f, "image"),
+        }
+    }
+}
+
+/// State kept by the server for a transfer a client is in the middle of sending.
+struct ActiveTransfer {
+    server_id: u64,
+    /// Room the transfer was started in, where the rest of it is sent even if the sender moves on.
+    room: String,
+    attachment: Attachment,
+    total: u64,
+    received: u64,
+    hasher: crc32fast::Hasher,
+}
+
+/// Validates the transfers a single client is sending.
+///
+/// The server never buffers attachment contents; it only checks that chunks arrive in order, that the sender does
+/// not exceed the size it announced (or the server's limit), and that the final checksum matches what was relayed.
+/// Client-chosen transfer ids are mapped to server-wide ids so transfers from different clients never collide.
+///
+/// Once a transfer has been failed, the rest of its frames are quietly ignored (`Ok(None)`) so the sender is only
+/// told about the problem once.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::transfer::{Attachment, TransferTracker};
+/// let mut tracker = TransferTracker::new(1024);
+/// let server_id = tracker.start(1, 100, Attachment::Image, 3, "lobby").unwrap();
+/// assert_eq!(tracker.room(1), Some("lobby"));
+/// tracker.chunk(1, 0, b"abc").unwrap();
+/// let (finished_id, _) = tracker.finish(1, crc32fast::hash(b"abc")).unwrap().unwrap();
+/// assert_eq!(server_id, finished_id);
+/// ```
+pub struct TransferTracker {
+    active: HashMap<u64, ActiveTransfer>,
+    failed: HashSet<u64>,
+    max_transfer_bytes: u64,
+}
+
+impl TransferTracker {
+    /// Returns a tracker refusing transfers larger than `max_transfer_bytes`.
+    pub fn new(max_transfer_bytes: u64) -> Self {
+        TransferTracker {
+            active: HashMap::new(),
+            failed: HashSet::new(),
+            max_transfer_bytes,
+        }
+    }
+
+    /// Registers a new transfer sent to `room`, returning the server-wide id it has been assigned.
+    ///
+    /// # Errors
+    /// This function returns an error if the client id is already in use or the transfer is too large.
+    pub fn start(
+        &mut self,
+        client_id: u64,
+        server_id: u64,
+        attachment: Attachment,
+        total: u64,
+        room: impl Into<String>,
+    ) -> Result<u64, AppError> {
+        self.failed.remove(&client_id);
+        if self.active.contains_key(&client_id) {
+            return Err(AppError::Transfer(format!(
+                "Transfer {} is already in progress",
+                client_id
+            )));
+        }
+        if total > self.max_transfer_bytes {
+            return Err(AppError::Transfer(format!(
+                "Transfer of {} bytes exceeds the {}-byte limit",
+                total, self.max_transfer_bytes
+            )));
+        }
+
+        self.active.insert(
+            client_id,
+            ActiveTransfer {
+                server_id,
+                room: room.into(),
+                attachment,
+                total,
+                received: 0,
+                hasher: crc32fast::Hasher::new(),
+            },
+        );
+        Ok(server_id)
+    }
+
+    /// Returns the room an active transfer was started in.
+    pub fn room(&self, client_id: u64) -> Option<&str> {
+        self.active
+            .get(&client_id)
+            .map(|transfer| transfer.room.as_str())
+    }
+
+    /// Accounts for a chunk of an active transfer, returning the transfer's server-wide id, or `None` if the
+    /// transfer has already been failed.
+    ///
+    /// # Errors
+    /// This function returns an error if the transfer is unknown, the chunk is out of order, or the chunk would
+    /// exceed the announced size.
+    pub fn chunk(
+        &mut self,
+        client_id: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Option<u64>, AppError> {
+        if self.failed.contains(&client_id) {
+            return Ok(None);
+        }
+
+        let transfer = self
+            .active
+            .get_mut(&client_id)
+            .ok_or_else(|| AppError::Transfer(format!("Unknown transfer {}", client_id)))?;
+
+        if offset != transfer.received {
+            return Err(AppError::Transfer(format!(
+                "Transfer {} expected offset {} but got {}",
+                client_id, transfer.received, offset
+            )));
+        }
+        if transfer.received + data.len() as u64 > transfer.total {
+            return Err(AppError::Transfer(format!(
+                "Transfer {} sent more than the {} bytes it announced",
+                client_id, transfer.total
+            )));
+        }
+
+        transfer.received += data.len() as u64;
+        transfer.hasher.update(data);
+        Ok(Some(transfer.server_id))
+    }
+
+    /// Completes an active transfer, returning its server-wide id and what was transferred, or `None` if the
+    /// transfer has already been failed.
+    ///
+    /// # Errors
+    /// This function returns an error if the transfer is unknown, incomplete, or the checksum does not match.
+    pub fn finish(
+        &mut self,
+        client_id: u64,
+        checksum: u32,
+    ) -> Result<Option<(u64, Attachment)>, AppError> {
+        if self.failed.remove(&client_id) {
+            return Ok(None);
+        }
+
+        let transfer = self
+            .active
+            .get(&client_id)
+            .ok_or_else(|| AppError::Transfer(format!("Unknown transfer {}", client_id)))?;
+
+        if transfer.received != transfer.total {
+            return Err(AppError::Transfer(format!(
+                "Transfer {} ended after {} of {} bytes",
+                client_id, transfer.received, transfer.total
+            )));
+        }
+        if transfer.hasher.clone().finalize() != checksum {
+            return Err(AppError::Transfer(format!(
+                "Transfer {} failed its checksum",
+                client_id
+            )));
+        }
+
+        let transfer = self.active.remove(&client_id).expect("transfer is active");
+        Ok(Some((transfer.server_id, transfer.attachment)))
+    }
+
+    /// Forgets a transfer the sender gave up on, returning its server-wide id if it was active.
+    pub fn abort(&mut self, client_id: u64) -> Option<u64> {
+        self.failed.remove(&client_id);
+        self.active.remove(&client_id).map(|t| t.server_id)
+    }
+
+    /// Marks a transfer as failed so its remaining frames are ignored, returning its server-wide id if it was active.
+    pub fn fail(&mut self, client_id: u64) -> Option<u64> {
+        self.failed.insert(client_id);
+        self.active.remove(&client_id).map(|t| t.server_id)
+    }
+
+    /// Forgets every active transfer, returning their server-wide ids and rooms. Used when the sender disconnects.
+    pub fn abort_all(&mut self) -> Vec<(u64, String)> {
+        self.failed.clear();
+        self.active
+            .drain()
+            .map(|(_, t)| (t.server_id, t.room))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_chunks_are_rejected() {
+        let mut tracker = TransferTracker::new(1024);
+        tracker.start(1, 10, Attachment::Image, 6, "lobby").unwrap();
+
+        let result = tracker.chunk(1, 3, b"def");
+
+        assert!(matches!(result, Err(AppError::Transfer(_))));
+    }
+
+    #[test]
+    fn oversized_transfers_are_rejected() {
+        let mut tracker = TransferTracker::new(1024);
+
+        let result = tracker.start(1, 10, Attachment::Image, 4096, "lobby");
+
+        assert!(matches!(result, Err(AppError::Transfer(_))));
+    }
+
+    #[test]
+    fn sending_more_than_announced_is_rejected() {
+        let mut tracker = TransferTracker::new(1024);
+        tracker.start(1, 10, Attachment::Image, 2, "lobby").unwrap();
+
+        let result = tracker.chunk(1, 0, b"abc");
+
+        assert!(matches!(result, Err(AppError::Transfer(_))));
+    }
+
+    #[test]
+    fn bad_checksum_fails_the_transfer() {
+        let mut tracker = TransferTracker::new(1024);
+        tracker
+            .start(1, 10, Attachment::File("a.txt".to_string()), 3, "lobby")
+            .unwrap();
+        tracker.chunk(1, 0, b"abc").unwrap();
+
+        let result = tracker.finish(1, crc32fast::hash(b"xyz"));
+
+        assert!(matches!(result, Err(AppError::Transfer(_))));
+        assert_eq!(tracker.fail(1), Some(10));
+    }
+
+    #[test]
+    fn failed_transfers_are_ignored_afterwards() {
+        let mut tracker = TransferTracker::new(1024);
+        tracker.start(1, 10, Attachment::Image, 6, "lobby").unwrap();
+        tracker.chunk(1, 3, b"def").unwrap_err();
+        tracker.fail(1);
+
+        assert_eq!(tracker.chunk(1, 0, b"abc").unwrap(), None);
+        assert_eq!(tracker.finish(1, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn abort_all_returns_server_ids_and_rooms() {
+        let mut tracker = TransferTracker::new(1024);
+        tracker.start(1, 10, Attachment::Image, 3, "lobby").unwrap();
+        tracker.start(2, 11, Attachment::Image, 3, "rust").unwrap();
+
+        let mut aborted = tracker.abort_all();
+        aborted.sort();
+
+        assert_eq!(
+            aborted,
+            vec![(10, "lobby".to_string()), (11, "rust".to_string())]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! WebSocket gateway support, so browser clients can join the chat.
+//!
+//! Browsers can't open raw TCP sockets, so the server also accepts WebSocket connections on its HTTP port. There is
+//! no handshake or framing beyond what WebSocket provides: every WebSocket message carries exactly one `MessageType`,
+//! encoded as JSON the same way the JSON codec encodes frame payloads.
+
+use crate::{codec::Codec, codec::JsonCodec, AppError, ErrorCode, MessageType};
+use hyper::{header, Body, Request, Response, StatusCode};
+use tokio_tungstenite::tungstenite::{handshake::derive_accept_key, Message};
+
+/// Path the WebSocket gateway is served on.
+pub const WEBSOCKET_PATH: &str = "/ws";
+
+/// Builds the `101 Switching Protocols` response accepting a WebSocket upgrade request.
+///
+/// The connection itself only switches over once the response has been sent; use `hyper::upgrade::on` to wait for
+/// that.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::ws::upgrade_response;
+/// # use hyper::{Body, Request};
+/// let req = Request::get("/ws")
+///     .header("Connection", "Upgrade")
+///     .header("Upgrade", "websocket")
+///     .header("Sec-WebSocket-Version", "13")
+///     .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+///     .body(Body::empty())
+///     .unwrap();
+/// assert_eq!(upgrade_response(&req).unwrap().status(), 101);
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Handshake` if the request is not a valid WebSocket upgrade.
+pub fn upgrade_response(req: &Request<Body>) -> Result<Response<Body>, AppError> {
+    let headers = req.headers();
+    let has_token = |name: header::HeaderName, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    if !has_token(header::CONNECTION, "upgrade") || !has_token(header::UPGRADE, "websocket") {
+        return Err(AppError::Handshake(
+            "Expected a WebSocket upgrade request".to_string(),
+        ));
+    }
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return Err(AppError::Handshake(
+            "Unsupported WebSocket version".to_string(),
+        ));
+    }
+    let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return Err(AppError::Handshake(
+            "Missing Sec-WebSocket-Key header".to_string(),
+        ));
+    };
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        )
+        .body(Body::empty())
+        .map_err(|e| AppError::Handshake(e.to_string()))
+}
+
+/// Encodes a message for a WebSocket client.
+///
+/// # Errors
+/// This function returns an error if the message cannot be encoded as JSON.
+pub fn encode(msg: &MessageType) -> Result<Message, AppError> {
+    let json = JsonCodec.encode(msg)?;
+    // serde_json only ever produces UTF-8
+    Ok(Message::Text(
+        String::from_utf8(json).map_err(|e| AppError::Message(e.to_string()))?,
+    ))
+}
+
+/// Decodes a message sent by a WebSocket client, returning `None` for control messages that carry no chat message.
+///
+/// Both text and binary messages are accepted, as long as they hold a JSON encoded `MessageType`.
+///
+/// # Example
+/// ```
+/// # use hw11_rust_metrics::{ws::decode, MessageType};
+/// # use tokio_tungstenite::tungstenite::Message;
+/// let msg = Message::Text(r#"{"Text":[null,"Hello"]}"#.to_string());
+/// assert_eq!(decode(&msg).unwrap(), Some(MessageType::Text(None, "Hello".to_string())));
+/// ```
+///
+/// # Errors
+/// This function returns `AppError::Protocol` with `ErrorCode::MalformedFrame` if the message is not a JSON encoded
+/// `MessageType`.
+pub fn decode(msg: &Message) -> Result<Option<MessageType>, AppError> {
+    match msg {
+        Message::Text(text) => JsonCodec.decode(text.as_bytes()).map(Some),
+        Message::Binary(data) => JsonCodec.decode(data).map(Some),
+        Message::Ping(_) | Message::Pong(_) | Message::Close(_) => Ok(None),
+        Message::Frame(_) => Err(AppError::Protocol(
+            ErrorCode::MalformedFrame,
+            "Unexpected raw WebSocket frame".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request() -> hyper::http::request::Builder {
+        Request::get(WEBSOCKET_PATH)
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+    }
+
+    #[test]
+    fn upgrades_answer_the_key_challenge() {
+        // The sample handshake from RFC 6455
+        let req = upgrade_request()
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = upgrade_response(&req).unwrap();
+
+        assert_eq!(
+            response.headers()[header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn plain_requests_are_not_upgraded() {
+        let req = Request::get(WEBSOCKET_PATH).body(Body::empty()).unwrap();
+
+        assert!(matches!(
+            upgrade_response(&req),
+            Err(AppError::Handshake(_))
+        ));
+    }
+
+    #[test]
+    fn messages_round_trip_as_json_text() {
+        let message = MessageType::Text(Some("Alice".to_string()), "Hello".to_string());
+
+        let encoded = encode(&message).unwrap();
+
+        assert!(encoded.is_text());
+        assert_eq!(decode(&encoded).unwrap(), Some(message));
+    }
+
+    #[test]
+    fn garbage_is_a_protocol_error() {
+        let result = decode(&Message::Text("not json".to_string()));
+
+        assert!(matches!(
+            result,
+            Err(AppError::Protocol(ErrorCode::MalformedFrame, _))
+        ));
+    }
+}